@@ -267,7 +267,9 @@ impl QuantMethod for BnbLinear {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::Gguf { .. } | QuantMethodConfig::Unquantized(_) => unreachable!(),
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Lora { .. } => unreachable!(),
             QuantMethodConfig::Bnb4bit {
                 weight,
                 bias,
@@ -385,4 +387,10 @@ impl QuantMethod for BnbLinear {
             Self::Fp4Nf4 { weight, .. } | Self::Int8 { weight, .. } => weight.device().clone(),
         }
     }
+
+    fn add_delta_w(&self, _delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        diffusion_rs_common::bail!(
+            "Adding a weight delta is not supported for bitsandbytes layers."
+        )
+    }
 }
@@ -1,7 +1,10 @@
 use std::sync::Arc;
 
 use diffusion_rs_common::core::Device;
-use diffusion_rs_common::core::{quantized::QMatMul, DType, Result, Tensor};
+use diffusion_rs_common::core::{
+    quantized::{QMatMul, QTensor},
+    DType, Result, Tensor,
+};
 use diffusion_rs_common::nn::Module;
 
 use crate::{QuantMethod, QuantMethodConfig};
@@ -22,7 +25,9 @@ impl QuantMethod for GgufMatMul {
                 w: QMatMul::from_arc(q_weight)?,
                 b,
             }),
-            QuantMethodConfig::Unquantized(_) | QuantMethodConfig::Bnb4bit { .. } => unreachable!(),
+            QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Lora { .. } => unreachable!(),
         }
     }
 
@@ -78,4 +83,19 @@ impl QuantMethod for GgufMatMul {
             QMatMul::Tensor(t) | QMatMul::TensorF16(t) => t.device().clone(),
         }
     }
+
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        let w = match &self.w {
+            QMatMul::QTensor(q) => {
+                let w = (q.dequantize(&q.device())? + delta.to_dtype(DType::F32)?)?;
+                QMatMul::QTensor(Arc::new(QTensor::quantize(&w, q.dtype())?))
+            }
+            QMatMul::Tensor(t) => QMatMul::Tensor((t + delta.to_dtype(t.dtype())?)?),
+            QMatMul::TensorF16(t) => QMatMul::TensorF16((t + delta.to_dtype(t.dtype())?)?),
+        };
+        Ok(Arc::new(Self {
+            w,
+            b: self.b.clone(),
+        }))
+    }
 }
@@ -14,11 +14,13 @@ mod metal_kernels;
 mod bitsandbytes;
 mod cublaslt;
 mod gguf;
+mod lora;
 pub mod ops;
 mod unquantized;
 
 pub use bitsandbytes::{BnbLinear, BnbQuantParmas, BnbQuantType};
 pub use gguf::GgufMatMul;
+pub use lora::{ActiveLoras, LoraLinear};
 pub use unquantized::UnquantLinear;
 
 use diffusion_rs_common::nn::{Linear, Module};
@@ -83,6 +85,14 @@ pub enum QuantMethodConfig {
         params: BnbQuantParmas,
         quant_ty: BnbQuantType,
     },
+    Lora {
+        base: Arc<dyn QuantMethod>,
+        name: String,
+        down: Tensor,
+        up: Tensor,
+        scale: f64,
+        active: ActiveLoras,
+    },
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Hash, Eq)]
@@ -186,6 +196,15 @@ pub trait QuantMethod: Send + Sync + Debug {
     fn device(&self) -> Device;
 
     fn size_in_bytes(&self) -> Result<usize>;
+
+    /// Add a delta to the (dequantized) weight, returning the new layer. Quantized weights are requantized to
+    /// the same type.
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>>;
+
+    /// Remove the runtime LoRA adapter `name` from this layer, returning the new layer if it was present.
+    fn remove_lora(&self, _name: &str) -> Option<Arc<dyn QuantMethod>> {
+        None
+    }
 }
 
 impl Module for dyn QuantMethod {
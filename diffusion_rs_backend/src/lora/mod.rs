@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+use crate::{QuantMethod, QuantMethodConfig};
+
+/// The active runtime LoRA adapters and their scales, shared by all [`LoraLinear`] layers of a model.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveLoras(Arc<RwLock<HashMap<String, f64>>>);
+
+impl ActiveLoras {
+    /// Set the active adapters. Adapters which are not present are disabled.
+    pub fn set(&self, scales: HashMap<String, f64>) {
+        *self.0.write().expect("Could not lock active LoRAs!") = scales;
+    }
+
+    /// The scale of the given adapter, if it is active.
+    pub fn scale(&self, name: &str) -> Option<f64> {
+        self.0
+            .read()
+            .expect("Could not lock active LoRAs!")
+            .get(name)
+            .copied()
+    }
+}
+
+/// A LoRA adapter which is applied at runtime, without modifying the base layer: `base(x) + scale * B(A x)`.
+///
+/// This keeps quantized weights untouched. Several adapters are stacked by wrapping layers multiple times.
+#[derive(Debug)]
+pub struct LoraLinear {
+    base: Arc<dyn QuantMethod>,
+    name: String,
+    /// `(rank, in_features)`
+    down: Tensor,
+    /// `(out_features, rank)`
+    up: Tensor,
+    /// `alpha / rank`, applied in addition to the active scale.
+    scale: f64,
+    active: ActiveLoras,
+}
+
+impl LoraLinear {
+    fn with_base(&self, base: Arc<dyn QuantMethod>) -> Self {
+        Self {
+            base,
+            name: self.name.clone(),
+            down: self.down.clone(),
+            up: self.up.clone(),
+            scale: self.scale,
+            active: self.active.clone(),
+        }
+    }
+
+    fn add_lora(&self, a: &Tensor, out: Tensor) -> Result<Tensor> {
+        let Some(scale) = self.active.scale(&self.name) else {
+            return Ok(out);
+        };
+        let xs = a.to_dtype(self.down.dtype())?;
+        let xs = xs
+            .broadcast_matmul(&self.down.t()?)?
+            .broadcast_matmul(&self.up.t()?)?;
+        let xs = (xs * (scale * self.scale))?.to_dtype(out.dtype())?;
+        out + xs
+    }
+}
+
+impl QuantMethod for LoraLinear {
+    fn new(method: QuantMethodConfig) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match method {
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Unquantized(_)
+            | QuantMethodConfig::Bnb4bit { .. } => unreachable!(),
+            QuantMethodConfig::Lora {
+                base,
+                name,
+                down,
+                up,
+                scale,
+                active,
+            } => Ok(Self {
+                base,
+                name,
+                down,
+                up,
+                scale,
+                active,
+            }),
+        }
+    }
+
+    fn dequantize_w(&self, out_ty: DType) -> Result<Tensor> {
+        let w = self.base.dequantize_w(out_ty)?;
+        match self.active.scale(&self.name) {
+            Some(scale) => {
+                let delta = self
+                    .up
+                    .to_dtype(DType::F32)?
+                    .matmul(&self.down.to_dtype(DType::F32)?)?;
+                w + (delta * (scale * self.scale))?.to_dtype(out_ty)?
+            }
+            None => Ok(w),
+        }
+    }
+
+    fn forward(&self, a: &Tensor) -> Result<Tensor> {
+        let out = self.base.forward(a)?;
+        self.add_lora(a, out)
+    }
+
+    fn forward_via_half(&self, a: &Tensor) -> Result<Tensor> {
+        let out = self.base.forward_via_half(a)?;
+        self.add_lora(a, out)
+    }
+
+    fn quantized_act_type(&self) -> Option<DType> {
+        self.base.quantized_act_type()
+    }
+
+    fn to_device(&self, dev: &Device) -> Result<Arc<dyn QuantMethod>> {
+        Ok(Arc::new(Self {
+            base: self.base.to_device(dev)?,
+            name: self.name.clone(),
+            down: self.down.to_device(dev)?,
+            up: self.up.to_device(dev)?,
+            scale: self.scale,
+            active: self.active.clone(),
+        }))
+    }
+
+    fn device(&self) -> Device {
+        self.base.device()
+    }
+
+    fn size_in_bytes(&self) -> Result<usize> {
+        let lora_size = self.down.dtype().size_in_bytes() * self.down.elem_count()
+            + self.up.dtype().size_in_bytes() * self.up.elem_count();
+        Ok(self.base.size_in_bytes()? + lora_size)
+    }
+
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        Ok(Arc::new(self.with_base(self.base.add_delta_w(delta)?)))
+    }
+
+    fn remove_lora(&self, name: &str) -> Option<Arc<dyn QuantMethod>> {
+        if self.name == name {
+            Some(self.base.clone())
+        } else {
+            self.base
+                .remove_lora(name)
+                .map(|base| Arc::new(self.with_base(base)) as Arc<dyn QuantMethod>)
+        }
+    }
+}
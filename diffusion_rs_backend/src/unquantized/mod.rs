@@ -19,7 +19,9 @@ impl QuantMethod for UnquantLinear {
         Self: Sized,
     {
         match method {
-            QuantMethodConfig::Gguf { .. } | QuantMethodConfig::Bnb4bit { .. } => unreachable!(),
+            QuantMethodConfig::Gguf { .. }
+            | QuantMethodConfig::Bnb4bit { .. }
+            | QuantMethodConfig::Lora { .. } => unreachable!(),
             QuantMethodConfig::Unquantized(l) => Ok(Self {
                 w: l.weight().clone(),
                 b: l.bias().cloned(),
@@ -103,4 +105,12 @@ impl QuantMethod for UnquantLinear {
     fn device(&self) -> Device {
         self.w.device().clone()
     }
+
+    fn add_delta_w(&self, delta: &Tensor) -> Result<Arc<dyn QuantMethod>> {
+        let w = (&self.w + delta.to_dtype(self.w.dtype())?)?;
+        Ok(Arc::new(Self {
+            w,
+            b: self.b.clone(),
+        }))
+    }
 }
@@ -3,12 +3,16 @@ use std::{path::PathBuf, time::Instant};
 
 use clap::{Parser, Subcommand};
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    ControlImage, DiffusionGenerationParams, ImagePrompt, InitImage, ModelDType, ModelSource,
+    NoiseSource, Offloading, Pipeline, SamplerType, SigmaSpacing, TokenSource,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
 const GUIDANCE_SCALE_DEFAULT: f64 = 0.0;
+const TRUE_CFG_SCALE_DEFAULT: f64 = 1.0;
+const ETA_DEFAULT: f64 = 1.0;
+const REFINER_START_DEFAULT: f64 = 0.8;
 
 #[derive(Debug, Subcommand)]
 pub enum SourceCommand {
@@ -41,14 +45,151 @@ struct Args {
     #[arg(short, long)]
     scale: Option<f64>,
 
+    /// Scale for true classifier-free guidance, which runs the model on the negative prompt too. It is enabled
+    /// when greater than 1. If not specified, defaults to 1.0.
+    #[arg(long)]
+    true_cfg_scale: Option<f64>,
+
+    /// Negative prompt for true classifier-free guidance. If not specified, an empty prompt is used.
+    #[arg(long)]
+    negative_prompt: Option<String>,
+
     /// Number of denoising steps. This is model specific. A higher number of steps often means higher quality.
     #[arg(short, long)]
     num_steps: usize,
 
+    /// Spacing of the sigma schedule. If not specified, it is chosen from the scheduler config of the model.
+    #[arg(long)]
+    sigma_spacing: Option<SigmaSpacing>,
+
+    /// Explicit comma-separated sigma schedule, decreasing in (0, 1], without the terminal 0. It overrides the
+    /// number of steps.
+    #[arg(long, value_delimiter = ',')]
+    sigmas: Option<Vec<f64>>,
+
+    /// Sampling method. If not specified, it is chosen from the scheduler config of the model.
+    #[arg(long)]
+    sampler: Option<SamplerType>,
+
+    /// Amount of fresh noise injected at each step by stochastic samplers, between 0 and 1. If not specified,
+    /// defaults to 1.0.
+    #[arg(long)]
+    eta: Option<f64>,
+
     /// Offloading setting to use for this model
     #[arg(short, long)]
     offloading: Option<Offloading>,
 
+    /// Seed for the random noise, to make generations reproducible. If not specified, a random seed is used.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Random number generator for the noise. `torch` reproduces the noise of a PyTorch CPU generator with the same seed.
+    #[arg(long, default_value = "native")]
+    noise_source: NoiseSource,
+
+    /// Initial image for image-to-image generation.
+    #[arg(long)]
+    init_image: Option<PathBuf>,
+
+    /// How much to transform the initial image, between 0 and 1. If not specified, defaults to 1 for FLUX.1 Fill
+    /// models and to 0.6 otherwise.
+    #[arg(long, requires = "init_image")]
+    strength: Option<f64>,
+
+    /// Mask for inpainting the initial image: white areas are regenerated, black areas are kept.
+    #[arg(long, requires = "init_image")]
+    mask: Option<PathBuf>,
+
+    /// Image to edit as instructed by the prompt, for instruction-based editing models such as FLUX.1 Kontext.
+    #[arg(long)]
+    reference_image: Option<PathBuf>,
+
+    /// Image prompt for image variation or style consistency, alongside or instead of the text prompt. May be
+    /// given several times. Requires a prior such as FLUX.1 Redux, set with `--prior`, or an IP-Adapter, set
+    /// with `--ip-adapter`.
+    #[arg(long)]
+    image_prompt: Vec<PathBuf>,
+
+    /// Weight for each image prompt, in the same order as `--image-prompt`. If not specified, defaults to 1.
+    #[arg(long, requires = "image_prompt")]
+    image_prompt_weight: Vec<f64>,
+
+    /// Prior model to encode the image prompts, such as FLUX.1 Redux: a `.dduf` file or a model ID.
+    #[arg(long)]
+    prior: Option<String>,
+
+    /// IP-Adapter (`.safetensors`) conditioning the generation on the image prompts, such as the XLabs FLUX
+    /// IP-Adapter. May be given several times.
+    #[arg(long, requires = "image_prompt")]
+    ip_adapter: Vec<PathBuf>,
+
+    /// CLIP image encoder of the IP-Adapters: a model ID or a local path. If not specified, defaults to
+    /// `openai/clip-vit-large-patch14`.
+    #[arg(long, requires = "ip_adapter")]
+    ip_adapter_image_encoder: Option<String>,
+
+    /// Scale for each IP-Adapter, in the same order as `--ip-adapter`. If not specified, defaults to 1.
+    #[arg(long, requires = "ip_adapter")]
+    ip_adapter_scale: Vec<f64>,
+
+    /// Textual inversion embedding (`.safetensors` or `.pt`) for the CLIP text encoders. Its token is the file
+    /// name without the extension, unless the file names it. May be given several times.
+    #[arg(long)]
+    textual_inversion: Vec<PathBuf>,
+
+    /// ControlNet for structural control, such as canny, depth or pose: a `.dduf` file or a model ID. May be
+    /// given several times, with one `--control-image` for each.
+    #[arg(long, requires = "control_image")]
+    controlnet: Vec<String>,
+
+    /// Control image, such as an edge, depth or pose map. May be given several times: with a single ControlNet,
+    /// they all use it, else each uses the ControlNet in the same position. Without `--controlnet`, this is the
+    /// control image of a structural conditioning model such as FLUX.1 Depth or Canny.
+    #[arg(long)]
+    control_image: Vec<PathBuf>,
+
+    /// Scale for each control image, in the same order as `--control-image`. If not specified, defaults to 1.
+    #[arg(long, requires = "controlnet")]
+    control_scale: Vec<f64>,
+
+    /// Control mode of a Union ControlNet for each control image, in the same order as `--control-image`. For
+    /// the InstantX Union ControlNet: 0 canny, 1 tile, 2 depth, 3 blur, 4 pose, 5 gray and 6 low quality.
+    #[arg(long, requires = "controlnet")]
+    control_mode: Vec<usize>,
+
+    /// Fraction of the denoising schedule at which the control images start to apply. If not specified,
+    /// defaults to 0.
+    #[arg(long, requires = "controlnet")]
+    control_start: Option<f64>,
+
+    /// Fraction of the denoising schedule at which the control images stop to apply. If not specified,
+    /// defaults to 1.
+    #[arg(long, requires = "controlnet")]
+    control_end: Option<f64>,
+
+    /// LoRA adapter (`.safetensors`) to merge into the model. May be given several times to stack adapters.
+    #[arg(long)]
+    lora: Vec<PathBuf>,
+
+    /// Scale for each LoRA adapter, in the same order as `--lora`. If not specified, defaults to 1.
+    #[arg(long, requires = "lora")]
+    lora_scale: Vec<f64>,
+
+    /// Apply the LoRA adapters at runtime instead of merging them into the weights. This is exact for quantized
+    /// models, at the cost of some speed.
+    #[arg(long, requires = "lora")]
+    runtime_lora: bool,
+
+    /// Refiner model to run the last denoising steps, such as the SDXL refiner: a `.dduf` file or a model ID.
+    #[arg(long)]
+    refiner: Option<String>,
+
+    /// Fraction of the denoising schedule run by the base model before the refiner takes over. If not
+    /// specified, defaults to 0.8.
+    #[arg(long, requires = "refiner")]
+    refiner_start: Option<f64>,
+
     /// DType for the model. The default is to use an automatic strategy with a fallback pattern: BF16 -> F16 -> F32
     #[arg(short, long, default_value = "auto")]
     dtype: ModelDType,
@@ -71,7 +212,165 @@ fn main() -> anyhow::Result<()> {
         .map(TokenSource::Literal)
         .unwrap_or(TokenSource::CacheToken);
 
-    let pipeline = Pipeline::load(source, false, token, None, args.offloading, &args.dtype)?;
+    let init_image = match args.init_image {
+        Some(path) => Some(InitImage {
+            image: image::open(path)?,
+            strength: args.strength,
+            mask: args.mask.map(image::open).transpose()?,
+        }),
+        None => None,
+    };
+
+    let reference_image = args.reference_image.map(image::open).transpose()?;
+
+    if !args.lora_scale.is_empty() && args.lora_scale.len() != args.lora.len() {
+        anyhow::bail!("Expected one `--lora-scale` per `--lora`.");
+    }
+
+    if !args.ip_adapter_scale.is_empty() && args.ip_adapter_scale.len() != args.ip_adapter.len() {
+        anyhow::bail!("Expected one `--ip-adapter-scale` per `--ip-adapter`.");
+    }
+
+    if !args.image_prompt_weight.is_empty()
+        && args.image_prompt_weight.len() != args.image_prompt.len()
+    {
+        anyhow::bail!("Expected one `--image-prompt-weight` per `--image-prompt`.");
+    }
+    let image_prompts = args
+        .image_prompt
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            Ok(ImagePrompt {
+                image: image::open(path)?,
+                weight: args.image_prompt_weight.get(i).copied().unwrap_or(1.),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if args.controlnet.len() > 1 && args.controlnet.len() != args.control_image.len() {
+        anyhow::bail!("Expected one `--control-image` per `--controlnet`.");
+    }
+    if !args.control_scale.is_empty() && args.control_scale.len() != args.control_image.len() {
+        anyhow::bail!("Expected one `--control-scale` per `--control-image`.");
+    }
+    if !args.control_mode.is_empty() && args.control_mode.len() != args.control_image.len() {
+        anyhow::bail!("Expected one `--control-mode` per `--control-image`.");
+    }
+    let control_image = if args.controlnet.is_empty() {
+        match args.control_image.as_slice() {
+            [] => None,
+            [path] => Some(image::open(path)?),
+            _ => anyhow::bail!("Expected a single `--control-image` without `--controlnet`."),
+        }
+    } else {
+        None
+    };
+    let control_images = args
+        .control_image
+        .iter()
+        .filter(|_| !args.controlnet.is_empty())
+        .enumerate()
+        .map(|(i, path)| {
+            Ok(ControlImage {
+                image: image::open(path)?,
+                controlnet: if args.controlnet.len() > 1 { i } else { 0 },
+                scale: args.control_scale.get(i).copied().unwrap_or(1.),
+                start: args.control_start.unwrap_or(0.),
+                end: args.control_end.unwrap_or(1.),
+                mode: args.control_mode.get(i).copied(),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let pipeline = Pipeline::load(
+        source,
+        false,
+        token.clone(),
+        None,
+        args.offloading,
+        &args.dtype,
+    )?;
+
+    if let Some(refiner) = args.refiner {
+        let source = if refiner.ends_with(".dduf") {
+            ModelSource::dduf(refiner)?
+        } else {
+            ModelSource::from_model_id(refiner)
+        };
+        let refiner = Pipeline::load(
+            source,
+            false,
+            token.clone(),
+            None,
+            args.offloading,
+            &args.dtype,
+        )?;
+        pipeline.set_refiner(&refiner)?;
+    }
+
+    if let Some(prior) = args.prior {
+        let source = if prior.ends_with(".dduf") {
+            ModelSource::dduf(prior)?
+        } else {
+            ModelSource::from_model_id(prior)
+        };
+        let prior = Pipeline::load(
+            source,
+            false,
+            token.clone(),
+            None,
+            args.offloading,
+            &args.dtype,
+        )?;
+        pipeline.set_prior(&prior)?;
+    }
+
+    for controlnet in &args.controlnet {
+        let source = if controlnet.ends_with(".dduf") {
+            ModelSource::dduf(controlnet)?
+        } else {
+            ModelSource::from_model_id(controlnet)
+        };
+        pipeline.load_controlnet(source, false, token.clone(), None)?;
+    }
+
+    let image_encoder = args
+        .ip_adapter_image_encoder
+        .unwrap_or_else(|| "openai/clip-vit-large-patch14".to_string());
+    for (i, path) in args.ip_adapter.iter().enumerate() {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| format!("ip_adapter_{i}"));
+        let scale = args.ip_adapter_scale.get(i).copied().unwrap_or(1.);
+        pipeline.load_ip_adapter(
+            name,
+            path,
+            ModelSource::from_model_id(image_encoder.clone()),
+            scale,
+            false,
+            token.clone(),
+            None,
+        )?;
+    }
+
+    for path in &args.textual_inversion {
+        pipeline.load_textual_inversion(path, None)?;
+    }
+
+    for (i, path) in args.lora.iter().enumerate() {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| format!("lora_{i}"));
+        let scale = args.lora_scale.get(i).copied().unwrap_or(1.);
+        if args.runtime_lora {
+            pipeline.load_runtime_lora(name, path, scale)?;
+        } else {
+            pipeline.load_lora(name, path, scale)?;
+        }
+    }
 
     let height: usize = input("Height:")
         .default_input("720")
@@ -113,7 +412,25 @@ fn main() -> anyhow::Result<()> {
                 height,
                 width,
                 num_steps: args.num_steps,
+                sigma_spacing: args.sigma_spacing,
+                sigmas: args.sigmas.clone(),
                 guidance_scale: args.scale.unwrap_or(GUIDANCE_SCALE_DEFAULT),
+                true_cfg_scale: args.true_cfg_scale.unwrap_or(TRUE_CFG_SCALE_DEFAULT),
+                sampler: args.sampler,
+                eta: args.eta.unwrap_or(ETA_DEFAULT),
+                negative_prompt: args.negative_prompt.clone(),
+                seed: args.seed,
+                seeds: None,
+                noise_source: args.noise_source,
+                init_image: init_image.clone(),
+                reference_image: reference_image.clone(),
+                image_prompts: image_prompts.clone(),
+                control_images: control_images.clone(),
+                control_image: control_image.clone(),
+                refiner_start: args.refiner_start.unwrap_or(REFINER_START_DEFAULT),
+                loras: None,
+                ip_adapters: None,
+                textual_inversions: Vec::new(),
             },
         )?;
 
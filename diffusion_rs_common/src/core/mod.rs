@@ -36,6 +36,7 @@ mod tensor;
 mod tensor_cat;
 mod tensor_indexing;
 pub mod test_utils;
+pub mod torch_generator;
 pub mod utils;
 mod variable;
 
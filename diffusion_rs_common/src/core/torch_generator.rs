@@ -0,0 +1,354 @@
+//! A port of PyTorch's CPU random number generator.
+//!
+//! [`TorchGenerator`] produces the same numbers as `torch.Generator("cpu").manual_seed(seed)`, which makes it
+//! possible to start from exactly the same noise as a PyTorch implementation (e.g. diffusers).
+//!
+//! ```python
+//! import torch
+//! g = torch.Generator("cpu").manual_seed(0)
+//! x = torch.randn((1, 16, 128, 128), generator=g, dtype=torch.bfloat16)
+//! ```
+//!
+//! is reproduced by:
+//!
+//! ```rust
+//! use diffusion_rs_common::core::{torch_generator::TorchGenerator, DType, Device};
+//!
+//! let mut g = TorchGenerator::new(0);
+//! let x = g.randn((1, 16, 128, 128), DType::BF16, &Device::Cpu)?;
+//! # Ok::<(), diffusion_rs_common::core::Error>(())
+//! ```
+use half::{bf16, f16};
+
+use crate::core::{DType, Device, Result, Shape, Tensor};
+
+const MT_N: usize = 624;
+const MT_M: usize = 397;
+const MATRIX_A: u32 = 0x9908b0df;
+const UPPER_MASK: u32 = 0x80000000;
+const LOWER_MASK: u32 = 0x7fffffff;
+
+/// The 32 bit Mersenne Twister, seeded the same way as PyTorch's `at::mt19937`.
+#[derive(Debug, Clone)]
+struct Mt19937 {
+    state: [u32; MT_N],
+    left: usize,
+    next: usize,
+}
+
+impl Mt19937 {
+    fn new(seed: u64) -> Self {
+        let mut state = [0u32; MT_N];
+        state[0] = (seed & 0xffffffff) as u32;
+        for j in 1..MT_N {
+            state[j] = 1812433253u32
+                .wrapping_mul(state[j - 1] ^ (state[j - 1] >> 30))
+                .wrapping_add(j as u32);
+        }
+        Self {
+            state,
+            left: 1,
+            next: 0,
+        }
+    }
+
+    fn mix_bits(u: u32, v: u32) -> u32 {
+        (u & UPPER_MASK) | (v & LOWER_MASK)
+    }
+
+    fn twist(u: u32, v: u32) -> u32 {
+        (Self::mix_bits(u, v) >> 1) ^ (if v & 1 != 0 { MATRIX_A } else { 0 })
+    }
+
+    fn next_state(&mut self) {
+        self.left = MT_N;
+        self.next = 0;
+        for j in 0..MT_N - MT_M {
+            self.state[j] = self.state[j + MT_M] ^ Self::twist(self.state[j], self.state[j + 1]);
+        }
+        for j in MT_N - MT_M..MT_N - 1 {
+            self.state[j] =
+                self.state[j + MT_M - MT_N] ^ Self::twist(self.state[j], self.state[j + 1]);
+        }
+        self.state[MT_N - 1] =
+            self.state[MT_M - 1] ^ Self::twist(self.state[MT_N - 1], self.state[0]);
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.left -= 1;
+        if self.left == 0 {
+            self.next_state();
+        }
+        let mut y = self.state[self.next];
+        self.next += 1;
+        y ^= y >> 11;
+        y ^= (y << 7) & 0x9d2c5680;
+        y ^= (y << 15) & 0xefc60000;
+        y ^ (y >> 18)
+    }
+}
+
+/// PyTorch's CPU generator: `torch.Generator("cpu").manual_seed(seed)`.
+#[derive(Debug, Clone)]
+pub struct TorchGenerator {
+    engine: Mt19937,
+    next_double_normal_sample: Option<f64>,
+}
+
+impl TorchGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            engine: Mt19937::new(seed),
+            next_double_normal_sample: None,
+        }
+    }
+
+    /// A random 32 bit value, as `CPUGeneratorImpl::random`.
+    pub fn random(&mut self) -> u32 {
+        self.engine.next_u32()
+    }
+
+    /// A random 64 bit value, as `CPUGeneratorImpl::random64`.
+    pub fn random64(&mut self) -> u64 {
+        let hi = self.random() as u64;
+        let lo = self.random() as u64;
+        (hi << 32) | lo
+    }
+
+    /// A uniform value in `[0, 1)` with `digits` bits of mantissa, as `at::uniform_real_distribution`.
+    fn uniform(&mut self, digits: u32) -> f64 {
+        let val = if digits > 24 {
+            self.random64()
+        } else {
+            self.random() as u64
+        };
+        let mask = (1u64 << digits) - 1;
+        (val & mask) as f64 / (1u64 << digits) as f64
+    }
+
+    /// A standard normal sample, as `at::normal_distribution<double>`. Samples are generated in pairs and the
+    /// second one is cached in the generator.
+    fn normal_f64(&mut self) -> f64 {
+        if let Some(ret) = self.next_double_normal_sample.take() {
+            return ret;
+        }
+        let u1 = self.uniform(53);
+        let u2 = self.uniform(53);
+        let r = (-2.0 * (-u2).ln_1p()).sqrt();
+        let theta = 2.0 * std::f64::consts::PI * u1;
+        self.next_double_normal_sample = Some(r * theta.sin());
+        r * theta.cos()
+    }
+
+    /// Equivalent to `torch.randn(shape, generator=self, dtype=dtype)`.
+    ///
+    /// The values are generated on the CPU and then copied to `device`. Supported dtypes are F16, BF16, F32 and
+    /// F64: the dtype changes the generated values, so it should match the one used by the reference.
+    pub fn randn<S: Into<Shape>>(
+        &mut self,
+        shape: S,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let shape: Shape = shape.into();
+        let numel = shape.elem_count();
+        let t = match dtype {
+            DType::F16 => Tensor::from_vec(self.normal::<f16>(numel), shape, &Device::Cpu)?,
+            DType::BF16 => Tensor::from_vec(self.normal::<bf16>(numel), shape, &Device::Cpu)?,
+            DType::F32 => Tensor::from_vec(self.normal::<f32>(numel), shape, &Device::Cpu)?,
+            DType::F64 => Tensor::from_vec(self.normal::<f64>(numel), shape, &Device::Cpu)?,
+            other => crate::bail!("TorchGenerator::randn does not support dtype {other:?}"),
+        };
+        t.to_device(device)
+    }
+
+    /// The CPU `normal_kernel`: contiguous tensors of at least 16 elements use the vectorizable Box-Muller
+    /// `normal_fill`, smaller ones are sampled one at a time.
+    fn normal<T: TorchFloat>(&mut self, numel: usize) -> Vec<T> {
+        if numel < 16 {
+            return (0..numel).map(|_| T::cast(self.normal_f64())).collect();
+        }
+        let mut data = (0..numel)
+            .map(|_| T::cast(self.uniform(T::DIGITS)))
+            .collect::<Vec<_>>();
+        for i in (0..numel - 15).step_by(16) {
+            T::normal_fill_16(&mut data[i..i + 16]);
+        }
+        if !numel.is_multiple_of(16) {
+            // Recompute the last 16 values.
+            let tail = &mut data[numel - 16..];
+            for x in tail.iter_mut() {
+                *x = T::cast(self.uniform(T::DIGITS));
+            }
+            T::normal_fill_16(tail);
+        }
+        data
+    }
+}
+
+/// Floating point types with PyTorch's rounding behavior. Every intermediate value of `normal_fill_16` is
+/// stored in the tensor's scalar type, so the rounding must be reproduced at each step.
+trait TorchFloat: Copy + crate::core::WithDType {
+    /// `std::numeric_limits<T>::digits`
+    const DIGITS: u32;
+
+    /// `static_cast<T>(v)`
+    fn cast(v: f64) -> Self;
+
+    /// `normal_fill_16` with mean 0 and standard deviation 1.
+    fn normal_fill_16(data: &mut [Self]);
+}
+
+macro_rules! half_torch_float {
+    ($t:ty, $digits:expr) => {
+        impl TorchFloat for $t {
+            const DIGITS: u32 = $digits;
+
+            fn cast(v: f64) -> Self {
+                // c10 converts through `float`.
+                <$t>::from_f32(v as f32)
+            }
+
+            fn normal_fill_16(data: &mut [Self]) {
+                let r = <$t>::from_f32;
+                for j in 0..8 {
+                    let u1 = r(1. - data[j].to_f32());
+                    let u2 = data[j + 8];
+                    let radius = r(r(-2. * r(u1.to_f32().ln()).to_f32()).to_f32().sqrt());
+                    let theta = Self::cast(2. * std::f64::consts::PI * u2.to_f64());
+                    data[j] = r(radius.to_f32() * r(theta.to_f32().cos()).to_f32());
+                    data[j + 8] = r(radius.to_f32() * r(theta.to_f32().sin()).to_f32());
+                }
+            }
+        }
+    };
+}
+
+half_torch_float!(f16, 11);
+half_torch_float!(bf16, 8);
+
+impl TorchFloat for f32 {
+    const DIGITS: u32 = 24;
+
+    fn cast(v: f64) -> Self {
+        v as f32
+    }
+
+    fn normal_fill_16(data: &mut [Self]) {
+        for j in 0..8 {
+            let u1 = 1. - data[j];
+            let u2 = data[j + 8];
+            let radius = (-2. * u1.ln()).sqrt();
+            let theta = (2. * std::f64::consts::PI * u2 as f64) as f32;
+            data[j] = radius * theta.cos();
+            data[j + 8] = radius * theta.sin();
+        }
+    }
+}
+
+impl TorchFloat for f64 {
+    const DIGITS: u32 = 53;
+
+    fn cast(v: f64) -> Self {
+        v
+    }
+
+    fn normal_fill_16(data: &mut [Self]) {
+        for j in 0..8 {
+            let u1 = 1. - data[j];
+            let u2 = data[j + 8];
+            let radius = (-2. * u1.ln()).sqrt();
+            let theta = 2. * std::f64::consts::PI * u2;
+            data[j] = radius * theta.cos();
+            data[j + 8] = radius * theta.sin();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mt19937_matches_reference() {
+        // First outputs of `std::mt19937(5489)` and `std::mt19937(0)`.
+        let mut g = TorchGenerator::new(5489);
+        assert_eq!(g.random(), 3499211612);
+        let mut g = TorchGenerator::new(0);
+        assert_eq!(g.random(), 2357136044);
+    }
+
+    #[test]
+    fn randn_small() -> Result<()> {
+        // torch.manual_seed(0); torch.randn(4)
+        let mut g = TorchGenerator::new(0);
+        let xs = g.randn(4, DType::F32, &Device::Cpu)?.to_vec1::<f32>()?;
+        let expected = [1.5410, -0.2934, -2.1788, 0.5684];
+        for (x, e) in xs.iter().zip(expected) {
+            assert!((x - e).abs() < 1e-4, "{xs:?}");
+        }
+        Ok(())
+    }
+
+    fn assert_close(xs: &[f32], expected: &[f32], tol: f32) {
+        assert_eq!(xs.len(), expected.len());
+        for (x, e) in xs.iter().zip(expected) {
+            assert!((x - e).abs() <= tol * e.abs().max(1.), "{xs:?}");
+        }
+    }
+
+    #[test]
+    fn randn_normal_fill() -> Result<()> {
+        // torch.manual_seed(0); torch.randn(4, 4)
+        let mut g = TorchGenerator::new(0);
+        let xs = g.randn((4, 4), DType::F32, &Device::Cpu)?;
+        let expected = [
+            -1.1258, -1.1524, -0.2506, -0.4339, 0.8487, 0.6920, -0.3160, -2.1152, 0.3223, -1.2633,
+            0.3500, 0.3081, 0.1198, 1.2377, 1.1168, -0.2473,
+        ];
+        assert_close(&xs.flatten_all()?.to_vec1::<f32>()?, &expected, 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn randn_tail() -> Result<()> {
+        // torch.manual_seed(0); torch.randn(37): the last 16 values are recomputed from fresh samples.
+        let mut g = TorchGenerator::new(0);
+        let xs = g.randn(37, DType::F32, &Device::Cpu)?.to_vec1::<f32>()?;
+        let expected = [
+            -1.1258, -1.1524, -0.2506, -0.4339, 0.8487, 0.6920, -0.3160, -2.1152, 0.3223, -1.2633,
+            0.3500, 0.3081, 0.1198, 1.2377, 1.1168, -0.2473, -1.3527, -1.6959, 0.5667, 0.7935,
+            0.5988, 0.1124, 0.6408, 0.4412, -0.2159, -0.7425, 0.5627, 0.2596, -0.1740, 2.3022,
+            -1.4689, -1.5867, 1.2032, 0.0845, -1.2001, -0.0048, -0.5181,
+        ];
+        assert_close(&xs, &expected, 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn randn_bf16() -> Result<()> {
+        // torch.manual_seed(0); torch.randn(16, dtype=torch.bfloat16)
+        let mut g = TorchGenerator::new(0);
+        let xs = g.randn(16, DType::BF16, &Device::Cpu)?;
+        let expected = [
+            1.4609375,
+            0.29101562,
+            0.9609375,
+            1.5703125,
+            0.49414062,
+            -1.515625,
+            -0.25,
+            0.57421875,
+            0.32617188,
+            -0.56640625,
+            0.54296875,
+            -0.5625,
+            0.6015625,
+            2.375,
+            1.671875,
+            -0.8359375,
+        ];
+        assert_close(&xs.to_dtype(DType::F32)?.to_vec1::<f32>()?, &expected, 0.);
+        Ok(())
+    }
+}
@@ -18,7 +18,7 @@ pub mod metal_kernels;
 pub use model_source::*;
 pub use nn_wrap::*;
 pub use progress::NiceProgressBar;
-pub use tokenizer::load_bpe_tokenizer;
+pub use tokenizer::load_clip_tokenizer;
 pub use tokens::get_token;
 pub use tokens::TokenSource;
 pub use varbuilder::VarBuilder;
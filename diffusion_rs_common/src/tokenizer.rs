@@ -1,10 +1,35 @@
 use std::collections::HashMap;
 
-use tokenizers::{models::bpe::BPE, ModelWrapper, Tokenizer};
+use tokenizers::{
+    decoders::byte_level::ByteLevel,
+    models::bpe::BPE,
+    normalizers::{
+        replace::ReplacePattern, Lowercase, Replace, Sequence as NormalizerSequence, NFC,
+    },
+    pre_tokenizers::{
+        sequence::Sequence as PreTokenizerSequence,
+        split::{Split, SplitPattern},
+    },
+    processors::roberta::RobertaProcessing,
+    AddedToken, SplitDelimiterBehavior, Tokenizer,
+};
 
 use crate::{FileData, ModelSource};
 
-pub fn load_bpe_tokenizer(
+const CLIP_BOS_TOKEN: &str = "<|startoftext|>";
+const CLIP_EOS_TOKEN: &str = "<|endoftext|>";
+
+/// The words of the CLIP tokenizer: the special tokens, common contractions, letter runs, single digits and
+/// runs of other characters.
+const CLIP_PATTERN: &str =
+    r"<\|startoftext\|>|<\|endoftext\|>|'s|'t|'re|'ve|'m|'ll|'d|[\p{L}]+|[\p{N}]|[^\s\p{L}\p{N}]+";
+
+/// Build the CLIP tokenizer from its `vocab.json` and `merges.txt`, as the reference `CLIPTokenizerFast`: the
+/// text is normalized, lowercased and split into words, whose last token has the `</w>` end-of-word suffix. When
+/// special tokens are requested, the tokens are wrapped in `<|startoftext|>` and `<|endoftext|>`.
+///
+/// Padding and truncation to the context length are left to the caller.
+pub fn load_clip_tokenizer(
     vocab_file: &FileData,
     merges_file: &FileData,
     src: &ModelSource,
@@ -19,5 +44,97 @@ pub fn load_bpe_tokenizer(
         .map(|x| (x[0].to_string(), x[1].to_string()))
         .collect();
 
-    Ok(Tokenizer::new(ModelWrapper::BPE(BPE::new(vocab, merges))))
+    clip_tokenizer(vocab, merges)
+}
+
+fn clip_tokenizer(
+    vocab: HashMap<String, u32>,
+    merges: Vec<(String, String)>,
+) -> anyhow::Result<Tokenizer> {
+    let token_id = |token: &str| {
+        vocab
+            .get(token)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("CLIP vocabulary has no `{token}` token"))
+    };
+    let bos = (CLIP_BOS_TOKEN.to_string(), token_id(CLIP_BOS_TOKEN)?);
+    let eos = (CLIP_EOS_TOKEN.to_string(), token_id(CLIP_EOS_TOKEN)?);
+
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .unk_token(CLIP_EOS_TOKEN.to_string())
+        .end_of_word_suffix("</w>".to_string())
+        .build()
+        .map_err(anyhow::Error::msg)?;
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer
+        .with_normalizer(Some(NormalizerSequence::new(vec![
+            NFC.into(),
+            Replace::new(ReplacePattern::Regex(r"\s+".to_string()), " ")
+                .map_err(anyhow::Error::msg)?
+                .into(),
+            Lowercase.into(),
+        ])))
+        .with_pre_tokenizer(Some(PreTokenizerSequence::new(vec![
+            Split::new(
+                SplitPattern::Regex(CLIP_PATTERN.to_string()),
+                SplitDelimiterBehavior::Removed,
+                true,
+            )
+            .map_err(anyhow::Error::msg)?
+            .into(),
+            ByteLevel::new(false, true, false).into(),
+        ])))
+        .with_post_processor(Some(
+            RobertaProcessing::new(eos, bos)
+                .trim_offsets(false)
+                .add_prefix_space(false),
+        ))
+        .with_decoder(Some(ByteLevel::default()));
+    tokenizer.add_special_tokens(&[
+        AddedToken::from(CLIP_BOS_TOKEN, true),
+        AddedToken::from(CLIP_EOS_TOKEN, true),
+    ]);
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    #[test]
+    fn clip_tokenizer() -> anyhow::Result<()> {
+        let vocab: HashMap<String, u32> = [
+            "a",
+            "c",
+            "t",
+            "!",
+            "a</w>",
+            "t</w>",
+            "!</w>",
+            "ca",
+            "cat</w>",
+            "<|startoftext|>",
+            "<|endoftext|>",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+        let merges = vec![
+            ("c".to_string(), "a".to_string()),
+            ("ca".to_string(), "t</w>".to_string()),
+        ];
+        let tokenizer = super::clip_tokenizer(vocab, merges)?;
+
+        let encoding = tokenizer
+            .encode("A  CAT!", true)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(encoding.get_ids(), [9, 4, 8, 6, 10]);
+        let encoding = tokenizer
+            .encode("cat<|endoftext|>", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(encoding.get_ids(), [8, 10]);
+        Ok(())
+    }
 }
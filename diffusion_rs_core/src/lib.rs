@@ -25,6 +25,7 @@
 //!         width: 1280,
 //!         num_steps: 50,
 //!         guidance_scale: 3.5,
+//!         ..Default::default()
 //!     },
 //! )?;
 //!
@@ -41,5 +42,8 @@ mod pipelines;
 mod util;
 
 pub use diffusion_rs_common::{ModelSource, TokenSource};
-pub use pipelines::{DiffusionGenerationParams, Offloading, Pipeline};
+pub use pipelines::{
+    ControlImage, DiffusionGenerationParams, ImagePrompt, InitImage, NoiseSource, Offloading,
+    Pipeline, SamplerType, SigmaSpacing, TextualInversion,
+};
 pub use util::{ModelDType, TryIntoDType};
@@ -1,3 +1,5 @@
 mod text;
+mod vision;
 
-pub use text::{ClipTextConfig, ClipTextTransformer};
+pub use text::{ClipTextConfig, ClipTextModelWithProjection, ClipTextTransformer};
+pub use vision::{ClipVisionConfig, ClipVisionModelWithProjection};
@@ -8,12 +8,15 @@ use serde::Deserialize;
 pub enum Activation {
     #[serde(rename = "quick_gelu")]
     QuickGelu,
+    #[serde(rename = "gelu")]
+    Gelu,
 }
 
 impl Module for Activation {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         match self {
             Activation::QuickGelu => xs * sigmoid(&(xs * 1.702f64)?)?,
+            Activation::Gelu => xs.gelu_erf(),
         }
     }
 }
@@ -21,6 +24,7 @@ impl Module for Activation {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClipTextConfig {
     pub vocab_size: usize,
+    pub hidden_size: usize,
     pub projection_dim: usize,
     pub hidden_act: Activation,
     pub intermediate_size: usize,
@@ -29,6 +33,28 @@ pub struct ClipTextConfig {
     pub num_attention_heads: usize,
 }
 
+/// The parameters of the transformer encoder, which the text and vision models share.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ClipEncoderConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub hidden_act: Activation,
+}
+
+impl From<&ClipTextConfig> for ClipEncoderConfig {
+    fn from(c: &ClipTextConfig) -> Self {
+        Self {
+            hidden_size: c.hidden_size,
+            intermediate_size: c.intermediate_size,
+            num_hidden_layers: c.num_hidden_layers,
+            num_attention_heads: c.num_attention_heads,
+            hidden_act: c.hidden_act,
+        }
+    }
+}
+
 // ClipTextEmbeddings mostly based on the existing implementation in the stable diffision model.
 // TODO rewrite to be more similar to https://github.com/huggingface/transformers/blob/f6fa0f0bf0796ac66f201f23bdb8585de1609add/src/transformers/models/clip/modeling_clip.py#L142
 #[derive(Clone, Debug)]
@@ -40,14 +66,11 @@ struct ClipTextEmbeddings {
 
 impl ClipTextEmbeddings {
     fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
-        let token_embedding = diffusion_rs_common::embedding(
-            c.vocab_size,
-            c.projection_dim,
-            vs.pp("token_embedding"),
-        )?;
+        let token_embedding =
+            diffusion_rs_common::embedding(c.vocab_size, c.hidden_size, vs.pp("token_embedding"))?;
         let position_embedding = diffusion_rs_common::embedding(
             c.max_position_embeddings,
-            c.projection_dim,
+            c.hidden_size,
             vs.pp("position_embedding"),
         )?;
         let position_ids =
@@ -58,6 +81,22 @@ impl ClipTextEmbeddings {
             position_ids,
         })
     }
+
+    fn vocab_size(&self) -> Result<usize> {
+        self.token_embedding.embeddings().dim(0)
+    }
+
+    /// Append `(n, hidden_size)` token embeddings, returning the id of the first one.
+    fn append(&mut self, vectors: &Tensor) -> Result<u32> {
+        let embeddings = self.token_embedding.embeddings();
+        let (vocab_size, hidden_size) = embeddings.dims2()?;
+        let vectors = vectors
+            .to_dtype(embeddings.dtype())?
+            .to_device(embeddings.device())?;
+        let embeddings = Tensor::cat(&[embeddings, &vectors], 0)?;
+        self.token_embedding = diffusion_rs_common::nn::Embedding::new(embeddings, hidden_size);
+        Ok(vocab_size as u32)
+    }
 }
 
 impl Module for ClipTextEmbeddings {
@@ -82,15 +121,14 @@ struct ClipAttention {
 }
 
 impl ClipAttention {
-    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
-        let projection_dim = c.projection_dim;
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
+        let hidden_size = c.hidden_size;
         let num_attention_heads = c.num_attention_heads;
-        let k_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("k_proj"))?;
-        let v_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("v_proj"))?;
-        let q_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("q_proj"))?;
-        let out_proj =
-            diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("out_proj"))?;
-        let head_dim = projection_dim / num_attention_heads;
+        let k_proj = diffusion_rs_common::linear(hidden_size, hidden_size, vs.pp("k_proj"))?;
+        let v_proj = diffusion_rs_common::linear(hidden_size, hidden_size, vs.pp("v_proj"))?;
+        let q_proj = diffusion_rs_common::linear(hidden_size, hidden_size, vs.pp("q_proj"))?;
+        let out_proj = diffusion_rs_common::linear(hidden_size, hidden_size, vs.pp("out_proj"))?;
+        let head_dim = hidden_size / num_attention_heads;
         let scale = (head_dim as f64).powf(-0.5);
 
         Ok(ClipAttention {
@@ -112,7 +150,7 @@ impl ClipAttention {
 
     fn forward(&self, xs: &Tensor, causal_attention_mask: Option<&Tensor>) -> Result<Tensor> {
         let in_dtype = xs.dtype();
-        let (bsz, seq_len, projection_dim) = xs.dims3()?;
+        let (bsz, seq_len, hidden_size) = xs.dims3()?;
 
         let query_states = (self.q_proj.forward(xs)? * self.scale)?;
         let proj_shape = (bsz * self.num_attention_heads, seq_len, self.head_dim);
@@ -147,7 +185,7 @@ impl ClipAttention {
         let attn_output = attn_output
             .reshape((bsz, self.num_attention_heads, seq_len, self.head_dim))?
             .transpose(1, 2)?
-            .reshape((bsz, seq_len, projection_dim))?;
+            .reshape((bsz, seq_len, hidden_size))?;
         self.out_proj.forward(&attn_output)
     }
 }
@@ -160,9 +198,9 @@ struct ClipMlp {
 }
 
 impl ClipMlp {
-    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
-        let fc1 = diffusion_rs_common::linear(c.projection_dim, c.intermediate_size, vs.pp("fc1"))?;
-        let fc2 = diffusion_rs_common::linear(c.intermediate_size, c.projection_dim, vs.pp("fc2"))?;
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
+        let fc1 = diffusion_rs_common::linear(c.hidden_size, c.intermediate_size, vs.pp("fc1"))?;
+        let fc2 = diffusion_rs_common::linear(c.intermediate_size, c.hidden_size, vs.pp("fc2"))?;
 
         Ok(ClipMlp {
             fc1,
@@ -188,13 +226,13 @@ struct ClipEncoderLayer {
 }
 
 impl ClipEncoderLayer {
-    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
         let self_attn = ClipAttention::new(vs.pp("self_attn"), c)?;
         let layer_norm1 =
-            diffusion_rs_common::layer_norm(c.projection_dim, 1e-5, vs.pp("layer_norm1"))?;
+            diffusion_rs_common::layer_norm(c.hidden_size, 1e-5, vs.pp("layer_norm1"))?;
         let mlp = ClipMlp::new(vs.pp("mlp"), c)?;
         let layer_norm2 =
-            diffusion_rs_common::layer_norm(c.projection_dim, 1e-5, vs.pp("layer_norm2"))?;
+            diffusion_rs_common::layer_norm(c.hidden_size, 1e-5, vs.pp("layer_norm2"))?;
 
         Ok(ClipEncoderLayer {
             self_attn,
@@ -218,12 +256,12 @@ impl ClipEncoderLayer {
 }
 
 #[derive(Clone, Debug)]
-struct ClipEncoder {
+pub(super) struct ClipEncoder {
     layers: Vec<ClipEncoderLayer>,
 }
 
 impl ClipEncoder {
-    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
         let vs = vs.pp("layers");
         let mut layers: Vec<ClipEncoderLayer> = Vec::new();
         for index in 0..c.num_hidden_layers {
@@ -240,6 +278,21 @@ impl ClipEncoder {
         }
         Ok(xs)
     }
+
+    /// Like [`ClipEncoder::forward`], also returning the hidden states input to the last layer.
+    pub fn forward_with_penultimate(
+        &self,
+        xs: &Tensor,
+        causal_attention_mask: Option<&Tensor>,
+    ) -> Result<(Tensor, Tensor)> {
+        let mut xs = xs.clone();
+        let mut penultimate = xs.clone();
+        for layer in self.layers.iter() {
+            penultimate = xs;
+            xs = layer.forward(&penultimate, causal_attention_mask)?;
+        }
+        Ok((xs, penultimate))
+    }
 }
 
 /// A CLIP transformer based model.
@@ -248,19 +301,24 @@ pub struct ClipTextTransformer {
     embeddings: ClipTextEmbeddings,
     encoder: ClipEncoder,
     final_layer_norm: diffusion_rs_common::nn::LayerNorm,
+    hidden_size: usize,
+    /// The size of the trained vocabulary, without the added token embeddings.
+    vocab_size: usize,
     device: Device,
 }
 
 impl ClipTextTransformer {
     pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
         let embeddings = ClipTextEmbeddings::new(vs.pp("embeddings"), c)?;
-        let encoder = ClipEncoder::new(vs.pp("encoder"), c)?;
+        let encoder = ClipEncoder::new(vs.pp("encoder"), &c.into())?;
         let final_layer_norm =
-            diffusion_rs_common::layer_norm(c.projection_dim, 1e-5, vs.pp("final_layer_norm"))?;
+            diffusion_rs_common::layer_norm(c.hidden_size, 1e-5, vs.pp("final_layer_norm"))?;
         Ok(ClipTextTransformer {
             embeddings,
             encoder,
             final_layer_norm,
+            hidden_size: c.hidden_size,
+            vocab_size: c.vocab_size,
             device: vs.device().clone(),
         })
     }
@@ -269,6 +327,26 @@ impl ClipTextTransformer {
         &self.device
     }
 
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    /// Append `(n, hidden_size)` token embeddings, such as those of a textual inversion, returning the id of the
+    /// first one.
+    pub fn add_token_embeddings(&mut self, vectors: &Tensor) -> Result<u32> {
+        let (_, hidden_size) = vectors.dims2()?;
+        if hidden_size != self.hidden_size {
+            diffusion_rs_common::bail!(
+                "expected token embeddings of size {}, got {hidden_size}",
+                self.hidden_size
+            )
+        }
+        if self.embeddings.vocab_size()? + vectors.dim(0)? > u32::MAX as usize {
+            diffusion_rs_common::bail!("too many token embeddings")
+        }
+        self.embeddings.append(vectors)
+    }
+
     // TODO: rewrrite to newer version
     fn build_causal_attention_mask(
         bsz: usize,
@@ -301,12 +379,33 @@ impl ClipTextTransformer {
             .forward(&input_ids, Some(&causal_attention_mask))?;
         self.final_layer_norm.forward(&input_ids)
     }
-}
 
-impl Module for ClipTextTransformer {
-    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
-        let output = self.forward_with_mask(input_ids, usize::MAX)?;
-        let sequence_max_indices = input_ids.argmax(D::Minus1)?.to_dtype(DType::I64)?;
+    /// Like [`ClipTextTransformer::forward_with_mask`], also returning the hidden states of the penultimate
+    /// layer, without the final layer norm.
+    pub fn forward_with_penultimate(
+        &self,
+        input_ids: &Tensor,
+        mask_after: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let (bsz, seq_len) = input_ids.dims2()?;
+        let input_ids = self.embeddings.forward(input_ids)?;
+        let causal_attention_mask =
+            Self::build_causal_attention_mask(bsz, seq_len, mask_after, input_ids.device())?;
+        let (output, penultimate) = self
+            .encoder
+            .forward_with_penultimate(&input_ids, Some(&causal_attention_mask))?;
+        Ok((self.final_layer_norm.forward(&output)?, penultimate))
+    }
+
+    /// Pool the output of the model: take the hidden state of the end-of-text token, which has the largest id
+    /// of the trained vocabulary. Added tokens are ignored.
+    pub fn pool(&self, output: &Tensor, input_ids: &Tensor) -> Result<Tensor> {
+        let trained = input_ids
+            .lt(self.vocab_size as u32)?
+            .to_dtype(input_ids.dtype())?;
+        let sequence_max_indices = (input_ids * trained)?
+            .argmax(D::Minus1)?
+            .to_dtype(DType::I64)?;
 
         let mut indices = Vec::new();
         for (batch_idx, &seq_idx) in sequence_max_indices.to_vec1::<i64>()?.iter().enumerate() {
@@ -316,3 +415,47 @@ impl Module for ClipTextTransformer {
         Tensor::cat(&indices, 0)
     }
 }
+
+impl Module for ClipTextTransformer {
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let output = self.forward_with_mask(input_ids, usize::MAX)?;
+        self.pool(&output, input_ids)
+    }
+}
+
+/// A CLIP transformer with a projection of the pooled output, as `CLIPTextModelWithProjection`.
+#[derive(Clone, Debug)]
+pub struct ClipTextModelWithProjection {
+    text_model: ClipTextTransformer,
+    text_projection: diffusion_rs_common::nn::Linear,
+}
+
+impl ClipTextModelWithProjection {
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+        Ok(Self {
+            text_model: ClipTextTransformer::new(vs.pp("text_model"), c)?,
+            text_projection: diffusion_rs_common::linear_no_bias(
+                c.hidden_size,
+                c.projection_dim,
+                vs.pp("text_projection"),
+            )?,
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        self.text_model.device()
+    }
+
+    pub fn text_model_mut(&mut self) -> &mut ClipTextTransformer {
+        &mut self.text_model
+    }
+
+    /// Return the hidden states of the penultimate layer and the projected pooled output.
+    pub fn forward_with_penultimate(&self, input_ids: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (output, penultimate) = self
+            .text_model
+            .forward_with_penultimate(input_ids, usize::MAX)?;
+        let pooled = self.text_model.pool(&output, input_ids)?;
+        Ok((penultimate, self.text_projection.forward(&pooled)?))
+    }
+}
@@ -0,0 +1,172 @@
+use diffusion_rs_common::core::{IndexOp, Result, Tensor, D};
+use diffusion_rs_common::nn::{Conv2dConfig, Module};
+use serde::Deserialize;
+
+use super::text::{Activation, ClipEncoder, ClipEncoderConfig};
+
+fn default_num_channels() -> usize {
+    3
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::QuickGelu
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClipVisionConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub projection_dim: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+}
+
+impl From<&ClipVisionConfig> for ClipEncoderConfig {
+    fn from(c: &ClipVisionConfig) -> Self {
+        Self {
+            hidden_size: c.hidden_size,
+            intermediate_size: c.intermediate_size,
+            num_hidden_layers: c.num_hidden_layers,
+            num_attention_heads: c.num_attention_heads,
+            hidden_act: c.hidden_act,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ClipVisionEmbeddings {
+    class_embedding: Tensor,
+    patch_embedding: diffusion_rs_common::nn::Conv2d,
+    position_embedding: diffusion_rs_common::nn::Embedding,
+}
+
+impl ClipVisionEmbeddings {
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        let class_embedding = vs.get(c.hidden_size, "class_embedding")?;
+        let patch_embedding = diffusion_rs_common::conv2d_no_bias(
+            c.num_channels,
+            c.hidden_size,
+            c.patch_size,
+            Conv2dConfig {
+                stride: c.patch_size,
+                ..Default::default()
+            },
+            vs.pp("patch_embedding"),
+        )?;
+        let num_positions = (c.image_size / c.patch_size).pow(2) + 1;
+        let position_embedding = diffusion_rs_common::embedding(
+            num_positions,
+            c.hidden_size,
+            vs.pp("position_embedding"),
+        )?;
+        Ok(Self {
+            class_embedding,
+            patch_embedding,
+            position_embedding,
+        })
+    }
+}
+
+impl Module for ClipVisionEmbeddings {
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let b = pixel_values.dim(0)?;
+        let patches = self
+            .patch_embedding
+            .forward(pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let class = self.class_embedding.reshape((1, 1, ()))?.broadcast_as((
+            b,
+            1,
+            patches.dim(D::Minus1)?,
+        ))?;
+        Tensor::cat(&[&class, &patches], 1)?
+            .broadcast_add(&self.position_embedding.embeddings().unsqueeze(0)?)
+    }
+}
+
+/// The vision tower of CLIP, as `CLIPVisionTransformer`.
+#[derive(Clone, Debug)]
+pub struct ClipVisionTransformer {
+    embeddings: ClipVisionEmbeddings,
+    pre_layrnorm: diffusion_rs_common::nn::LayerNorm,
+    encoder: ClipEncoder,
+    post_layernorm: diffusion_rs_common::nn::LayerNorm,
+    image_size: usize,
+}
+
+impl ClipVisionTransformer {
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        Ok(Self {
+            embeddings: ClipVisionEmbeddings::new(vs.pp("embeddings"), c)?,
+            pre_layrnorm: diffusion_rs_common::layer_norm(
+                c.hidden_size,
+                1e-5,
+                vs.pp("pre_layrnorm"),
+            )?,
+            encoder: ClipEncoder::new(vs.pp("encoder"), &c.into())?,
+            post_layernorm: diffusion_rs_common::layer_norm(
+                c.hidden_size,
+                1e-5,
+                vs.pp("post_layernorm"),
+            )?,
+            image_size: c.image_size,
+        })
+    }
+
+    /// The height and width of the input images.
+    pub fn image_size(&self) -> usize {
+        self.image_size
+    }
+}
+
+impl Module for ClipVisionTransformer {
+    /// Encode `(b, c, image_size, image_size)` normalized pixel values into the pooled output, the normalized
+    /// hidden state of the class token, of shape `(b, hidden_size)`.
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let xs = self
+            .pre_layrnorm
+            .forward(&self.embeddings.forward(pixel_values)?)?;
+        let xs = self.encoder.forward(&xs, None)?;
+        self.post_layernorm.forward(&xs.i((.., 0))?)
+    }
+}
+
+/// A CLIP vision tower with a projection of the pooled output, as `CLIPVisionModelWithProjection`.
+#[derive(Clone, Debug)]
+pub struct ClipVisionModelWithProjection {
+    vision_model: ClipVisionTransformer,
+    visual_projection: diffusion_rs_common::nn::Linear,
+}
+
+impl ClipVisionModelWithProjection {
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        Ok(Self {
+            vision_model: ClipVisionTransformer::new(vs.pp("vision_model"), c)?,
+            visual_projection: diffusion_rs_common::linear_no_bias(
+                c.hidden_size,
+                c.projection_dim,
+                vs.pp("visual_projection"),
+            )?,
+        })
+    }
+
+    /// The height and width of the input images.
+    pub fn image_size(&self) -> usize {
+        self.vision_model.image_size()
+    }
+}
+
+impl Module for ClipVisionModelWithProjection {
+    /// Compute the image embeddings, of shape `(b, projection_dim)`.
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        self.visual_projection
+            .forward(&self.vision_model.forward(pixel_values)?)
+    }
+}
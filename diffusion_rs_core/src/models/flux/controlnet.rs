@@ -0,0 +1,277 @@
+use std::sync::Arc;
+
+use diffusion_rs_backend::QuantMethod;
+use diffusion_rs_common::core::{Result, Tensor};
+use diffusion_rs_common::nn::Embedding;
+use diffusion_rs_common::{embedding, NiceProgressBar, VarBuilder};
+use serde::Deserialize;
+
+use super::model::{
+    txt_attention_mask, Conditioning, Config as FluxConfig, DoubleStreamBlock, EmbedNd,
+    SingleStreamBlock,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub base: FluxConfig,
+    /// InstantX Union: the number of control modes, each with a learned token prepended to the text tokens.
+    pub num_mode: Option<usize>,
+    /// XLabs: the output channels of the convolutional hint block, which encodes the control image in pixel
+    /// space. These checkpoints are not supported.
+    pub conditioning_embedding_channels: Option<usize>,
+}
+
+/// The residuals of a FLUX ControlNet, added to the image tokens of the transformer blocks.
+///
+/// A ControlNet usually has fewer blocks than the transformer: each residual is then shared by consecutive blocks.
+#[derive(Debug, Clone)]
+pub struct ControlNetResiduals {
+    double: Vec<Tensor>,
+    single: Vec<Tensor>,
+}
+
+impl ControlNetResiduals {
+    /// Sum the residuals of two ControlNets, which must have the same number of blocks.
+    pub fn add(&self, other: &Self) -> Result<Self> {
+        if self.double.len() != other.double.len() || self.single.len() != other.single.len() {
+            diffusion_rs_common::bail!(
+                "cannot combine ControlNets with different numbers of blocks: {}/{} and {}/{}",
+                self.double.len(),
+                self.single.len(),
+                other.double.len(),
+                other.single.len()
+            )
+        }
+        let add = |a: &[Tensor], b: &[Tensor]| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| a + b)
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            double: add(&self.double, &other.double)?,
+            single: add(&self.single, &other.single)?,
+        })
+    }
+
+    /// The residual for double stream block `idx` out of `num_blocks`, if any.
+    pub(super) fn double(&self, idx: usize, num_blocks: usize) -> Option<&Tensor> {
+        Self::residual(&self.double, idx, num_blocks)
+    }
+
+    /// The residual for the image tokens of single stream block `idx` out of `num_blocks`, if any.
+    pub(super) fn single(&self, idx: usize, num_blocks: usize) -> Option<&Tensor> {
+        Self::residual(&self.single, idx, num_blocks)
+    }
+
+    fn residual(samples: &[Tensor], idx: usize, num_blocks: usize) -> Option<&Tensor> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.get(idx / num_blocks.div_ceil(samples.len()))
+    }
+}
+
+/// A FLUX ControlNet, as `FluxControlNetModel`: a copy of the first transformer blocks, given the packed latents
+/// of a control image, whose hidden states are projected into residuals for the transformer.
+#[derive(Debug, Clone)]
+pub struct FluxControlNet {
+    img_in: Arc<dyn QuantMethod>,
+    txt_in: Arc<dyn QuantMethod>,
+    cond_in: Arc<dyn QuantMethod>,
+    mode_embedder: Option<Embedding>,
+    conditioning: Conditioning,
+    pe_embedder: EmbedNd,
+    double_blocks: Vec<DoubleStreamBlock>,
+    single_blocks: Vec<SingleStreamBlock>,
+    double_projs: Vec<Arc<dyn QuantMethod>>,
+    single_projs: Vec<Arc<dyn QuantMethod>>,
+}
+
+impl FluxControlNet {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        if cfg.conditioning_embedding_channels.is_some() {
+            diffusion_rs_common::bail!(
+                "ControlNets with a pixel space hint block are not supported"
+            )
+        }
+        let base = &cfg.base;
+        if base.is_chroma() {
+            diffusion_rs_common::bail!("Chroma ControlNets are not supported")
+        }
+        let hidden_size = base.hidden_size();
+        let qcfg = &base.quantization_config;
+
+        let img_in =
+            diffusion_rs_backend::linear(base.in_channels, hidden_size, qcfg, vb.pp("x_embedder"))?;
+        let txt_in = diffusion_rs_backend::linear(
+            base.joint_attention_dim,
+            hidden_size,
+            qcfg,
+            vb.pp("context_embedder"),
+        )?;
+        let cond_in = diffusion_rs_backend::linear(
+            base.in_channels,
+            hidden_size,
+            qcfg,
+            vb.pp("controlnet_x_embedder"),
+        )?;
+        let mode_embedder = match cfg.num_mode {
+            Some(num_mode) => Some(embedding(
+                num_mode,
+                hidden_size,
+                vb.pp("controlnet_mode_embedder"),
+            )?),
+            None => None,
+        };
+
+        let mut double_blocks = Vec::with_capacity(base.num_layers);
+        let mut double_projs = Vec::with_capacity(base.num_layers);
+        for idx in NiceProgressBar::<_, 'r'>(0..base.num_layers, "Loading ControlNet double blocks")
+        {
+            double_blocks.push(DoubleStreamBlock::new(
+                base,
+                vb.pp("transformer_blocks").pp(idx),
+            )?);
+            double_projs.push(diffusion_rs_backend::linear(
+                hidden_size,
+                hidden_size,
+                qcfg,
+                vb.pp("controlnet_blocks").pp(idx),
+            )?);
+        }
+        let mut single_blocks = Vec::with_capacity(base.num_single_layers);
+        let mut single_projs = Vec::with_capacity(base.num_single_layers);
+        for idx in NiceProgressBar::<_, 'r'>(
+            0..base.num_single_layers,
+            "Loading ControlNet single blocks",
+        ) {
+            single_blocks.push(SingleStreamBlock::new(
+                base,
+                vb.pp("single_transformer_blocks").pp(idx),
+            )?);
+            single_projs.push(diffusion_rs_backend::linear(
+                hidden_size,
+                hidden_size,
+                qcfg,
+                vb.pp("controlnet_single_blocks").pp(idx),
+            )?);
+        }
+
+        Ok(Self {
+            img_in,
+            txt_in,
+            cond_in,
+            mode_embedder,
+            conditioning: Conditioning::new(base, vb)?,
+            pe_embedder: EmbedNd::new(
+                base.attention_head_dim,
+                base.rope_theta,
+                base.axes_dims_rope.clone(),
+            ),
+            double_blocks,
+            single_blocks,
+            double_projs,
+            single_projs,
+        })
+    }
+
+    /// Whether this is a Union ControlNet, which requires a control mode.
+    pub fn is_union(&self) -> bool {
+        self.mode_embedder.is_some()
+    }
+
+    pub fn is_guidance(&self) -> bool {
+        self.conditioning.is_guidance()
+    }
+
+    /// Compute the residuals for the packed latents `img` and the packed latents of the control image `cond`,
+    /// scaled by `scale`. `mode` is the control mode of a Union ControlNet. The other inputs are those of the
+    /// transformer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &self,
+        img: &Tensor,
+        img_ids: &Tensor,
+        cond: &Tensor,
+        mode: Option<usize>,
+        txt: &Tensor,
+        txt_ids: &Tensor,
+        txt_mask: Option<&Tensor>,
+        timesteps: &Tensor,
+        y: Option<&Tensor>,
+        guidance: Option<&Tensor>,
+        scale: f64,
+    ) -> Result<ControlNetResiduals> {
+        let dtype = img.dtype();
+        let mut img =
+            (self.img_in.forward_autocast(img)? + self.cond_in.forward_autocast(cond)?)?;
+        let mut txt = self.txt_in.forward_autocast(txt)?;
+        let mut txt_ids = txt_ids.clone();
+        let mut txt_mask = txt_mask.cloned();
+        match (&self.mode_embedder, mode) {
+            (Some(mode_embedder), Some(mode)) => {
+                // The control mode token is prepended to the text tokens, with a zero position id.
+                let b = txt.dim(0)?;
+                #[allow(clippy::cast_possible_truncation)]
+                let mode = Tensor::full(mode as u32, (b, 1), txt.device())?
+                    .apply(mode_embedder)?
+                    .to_dtype(txt.dtype())?;
+                txt = Tensor::cat(&[&mode, &txt], 1)?;
+                txt_ids = Tensor::cat(&[&txt_ids.narrow(1, 0, 1)?, &txt_ids], 1)?;
+                if let Some(mask) = &txt_mask {
+                    let ones = Tensor::ones((b, 1), mask.dtype(), mask.device())?;
+                    txt_mask = Some(Tensor::cat(&[&ones, mask], 1)?);
+                }
+            }
+            (Some(_), None) => {
+                diffusion_rs_common::bail!("this Union ControlNet requires a control mode")
+            }
+            (None, Some(_)) => {
+                diffusion_rs_common::bail!("this ControlNet does not support control modes")
+            }
+            (None, None) => (),
+        }
+
+        let pe = Tensor::cat(&[&txt_ids, img_ids], 1)?.apply(&self.pe_embedder)?;
+        let mask = txt_attention_mask(txt_mask.as_ref(), img.dim(1)?)?;
+        let mods = self.conditioning.modulations(
+            timesteps,
+            y,
+            guidance,
+            dtype,
+            self.double_blocks.len(),
+            self.single_blocks.len(),
+        )?;
+
+        let mut double = Vec::with_capacity(self.double_blocks.len());
+        for (i, (block, proj)) in self
+            .double_blocks
+            .iter()
+            .zip(&self.double_projs)
+            .enumerate()
+        {
+            let (img_vec, txt_vec) = mods.double(i)?;
+            (img, txt) =
+                block.forward(&img, &txt, (&img_vec, &txt_vec), &pe, mask.as_ref(), &[])?;
+            double.push((proj.forward_autocast(&img)? * scale)?);
+        }
+
+        let (txt_len, img_len) = (txt.dim(1)?, img.dim(1)?);
+        let mut xs = Tensor::cat(&[&txt, &img], 1)?;
+        let mut single = Vec::with_capacity(self.single_blocks.len());
+        for (i, (block, proj)) in self
+            .single_blocks
+            .iter()
+            .zip(&self.single_projs)
+            .enumerate()
+        {
+            xs = block.forward(&xs, &mods.single(i)?, &pe, mask.as_ref())?;
+            let img = xs.narrow(1, txt_len, img_len)?;
+            single.push((proj.forward_autocast(&img)? * scale)?);
+        }
+
+        Ok(ControlNetResiduals { double, single })
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use diffusion_rs_common::core::{DType, Device, Error, Result, Tensor};
+use diffusion_rs_common::nn::{LayerNorm, Linear, Module};
+
+/// The weight names of an IP-Adapter checkpoint layout.
+struct Layout {
+    image_proj: &'static str,
+    block_key: fn(usize) -> String,
+    block_value: fn(usize) -> String,
+}
+
+const XLABS_LAYOUT: Layout = Layout {
+    image_proj: "ip_adapter_proj_model",
+    block_key: |i| format!("double_blocks.{i}.processor.ip_adapter_double_stream_k_proj"),
+    block_value: |i| format!("double_blocks.{i}.processor.ip_adapter_double_stream_v_proj"),
+};
+
+/// The XLabs layout, as converted by diffusers.
+const DIFFUSERS_LAYOUT: Layout = Layout {
+    image_proj: "image_proj",
+    block_key: |i| format!("ip_adapter.{i}.to_k_ip"),
+    block_value: |i| format!("ip_adapter.{i}.to_v_ip"),
+};
+
+/// The keys and values of the image tokens of an IP-Adapter for each double stream block, for one generation.
+#[derive(Debug, Clone)]
+pub struct IpAdapterConditioning {
+    keys: Vec<Tensor>,
+    values: Vec<Tensor>,
+    scale: f64,
+}
+
+impl IpAdapterConditioning {
+    /// Concatenate two conditionings of the same adapter along the batch dimension.
+    pub fn cat(&self, other: &Self) -> Result<Self> {
+        let cat = |a: &[Tensor], b: &[Tensor]| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| Tensor::cat(&[a, b], 0))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            keys: cat(&self.keys, &other.keys)?,
+            values: cat(&self.values, &other.values)?,
+            scale: self.scale,
+        })
+    }
+
+    /// The `(b, num_tokens, hidden_size)` keys and values for double stream block `idx`, and the adapter scale.
+    pub(super) fn block(&self, idx: usize) -> Option<(&Tensor, &Tensor, f64)> {
+        Some((self.keys.get(idx)?, self.values.get(idx)?, self.scale))
+    }
+}
+
+/// A FLUX IP-Adapter, in the XLabs layout: the CLIP image embeddings are projected into a few tokens, which the
+/// image stream of each double stream block attends to through extra key and value projections.
+#[derive(Debug, Clone)]
+pub struct FluxIpAdapter {
+    proj: Linear,
+    norm: LayerNorm,
+    num_tokens: usize,
+    context_dim: usize,
+    keys: Vec<Linear>,
+    values: Vec<Linear>,
+}
+
+impl FluxIpAdapter {
+    pub fn new(
+        tensors: HashMap<String, Tensor>,
+        num_double_blocks: usize,
+        hidden_size: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let layout = if tensors.contains_key("ip_adapter_proj_model.proj.weight") {
+            XLABS_LAYOUT
+        } else if tensors.contains_key("image_proj.proj.weight") {
+            DIFFUSERS_LAYOUT
+        } else {
+            diffusion_rs_common::bail!(
+                "unsupported IP-Adapter: only the XLabs layout, with an image projection into tokens, is supported"
+            )
+        };
+        let get = |name: String| -> Result<Tensor> {
+            tensors
+                .get(&name)
+                .ok_or_else(|| Error::Msg(format!("missing IP-Adapter weight `{name}`")))?
+                .to_dtype(dtype)?
+                .to_device(device)
+        };
+        let linear = |prefix: String| -> Result<Linear> {
+            Ok(Linear::new(
+                get(format!("{prefix}.weight"))?,
+                Some(get(format!("{prefix}.bias"))?),
+            ))
+        };
+
+        let proj = linear(format!("{}.proj", layout.image_proj))?;
+        let norm = LayerNorm::new(
+            get(format!("{}.norm.weight", layout.image_proj))?,
+            get(format!("{}.norm.bias", layout.image_proj))?,
+            1e-5,
+        );
+        let context_dim = norm.weight().dim(0)?;
+        let num_tokens = proj.weight().dim(0)? / context_dim;
+
+        let mut keys = Vec::with_capacity(num_double_blocks);
+        let mut values = Vec::with_capacity(num_double_blocks);
+        for i in 0..num_double_blocks {
+            keys.push(linear((layout.block_key)(i))?);
+            values.push(linear((layout.block_value)(i))?);
+        }
+        if keys[0].weight().dims2()? != (hidden_size, context_dim) {
+            diffusion_rs_common::bail!(
+                "the IP-Adapter projections of shape {:?} do not match the hidden size {hidden_size}",
+                keys[0].weight().shape()
+            )
+        }
+
+        Ok(Self {
+            proj,
+            norm,
+            num_tokens,
+            context_dim,
+            keys,
+            values,
+        })
+    }
+
+    /// The dimension of the image embeddings the adapter expects.
+    pub fn image_embedding_dim(&self) -> Result<usize> {
+        self.proj.weight().dim(1)
+    }
+
+    /// Compute the conditioning for `(num_images, image_embedding_dim)` image embeddings, repeated to a batch of
+    /// `batch_size`. The tokens of all images are attended to together, and the values of the tokens of each image
+    /// are scaled by its weight.
+    pub fn conditioning(
+        &self,
+        image_embeds: &Tensor,
+        weights: &[f64],
+        scale: f64,
+        batch_size: usize,
+    ) -> Result<IpAdapterConditioning> {
+        let num_images = image_embeds.dim(0)?;
+        let tokens = self
+            .proj
+            .forward(image_embeds)?
+            .reshape((num_images, self.num_tokens, self.context_dim))?
+            .apply(&self.norm)?;
+        let weights = Tensor::from_vec(
+            weights.iter().map(|w| *w as f32).collect(),
+            (num_images, 1, 1),
+            tokens.device(),
+        )?
+        .to_dtype(tokens.dtype())?;
+        let flatten = |xs: Tensor| -> Result<Tensor> {
+            xs.reshape((1, num_images * self.num_tokens, ()))?
+                .repeat((batch_size, 1, 1))
+        };
+
+        let mut keys = Vec::with_capacity(self.keys.len());
+        let mut values = Vec::with_capacity(self.values.len());
+        for (key, value) in self.keys.iter().zip(&self.values) {
+            keys.push(flatten(key.forward(&tokens)?)?);
+            values.push(flatten(value.forward(&tokens)?.broadcast_mul(&weights)?)?);
+        }
+        Ok(IpAdapterConditioning {
+            keys,
+            values,
+            scale,
+        })
+    }
+}
@@ -0,0 +1,407 @@
+//! Conversion of FLUX LoRA adapters to the diffusers layer names used by the model.
+//!
+//! The supported key formats are:
+//! - diffusers/PEFT: `transformer.transformer_blocks.0.attn.to_q.lora_A.weight`
+//! - kohya: `lora_unet_double_blocks_0_img_attn_qkv.lora_down.weight` and `lora_transformer_...`
+//! - BFL: `diffusion_model.double_blocks.0.img_attn.qkv.lora_A.weight`
+//!
+//! The kohya and BFL formats use the original layers, where the attention projections are fused. Their `up`
+//! weights are split over the corresponding diffusers layers.
+
+use std::collections::HashMap;
+
+use diffusion_rs_common::core::{DType, Result, Tensor};
+use tracing::warn;
+
+use crate::models::lora::{LoraAdapter, LoraWeights};
+
+use super::model::Flux;
+
+const DOWN_SUFFIXES: &[&str] = &[".lora_A.weight", ".lora_down.weight", ".lora.down.weight"];
+const UP_SUFFIXES: &[&str] = &[".lora_B.weight", ".lora_up.weight", ".lora.up.weight"];
+const PREFIXES: &[&str] = &[
+    "transformer.",
+    "base_model.model.",
+    "model.diffusion_model.",
+    "diffusion_model.",
+];
+
+/// How the `up` weight of an original layer maps onto the diffusers layers.
+enum Split {
+    None,
+    /// Rows of the `up` weight for each target layer but the last, which takes the remaining rows.
+    Rows(Vec<usize>),
+    /// The final modulation is `(shift, scale)` in the original model and `(scale, shift)` in diffusers.
+    SwapScaleShift,
+}
+
+struct OriginalLayer {
+    targets: Vec<String>,
+    split: Split,
+}
+
+impl OriginalLayer {
+    fn new(target: &str) -> Self {
+        Self {
+            targets: vec![target.to_string()],
+            split: Split::None,
+        }
+    }
+
+    fn split(targets: &[String], rows: Vec<usize>) -> Self {
+        Self {
+            targets: targets.to_vec(),
+            split: Split::Rows(rows),
+        }
+    }
+
+    fn convert(&self, weights: LoraWeights) -> Result<Vec<(String, LoraWeights)>> {
+        let ups = match &self.split {
+            Split::None => vec![weights.up.clone()],
+            Split::Rows(rows) => {
+                let total = weights.up.dim(0)?;
+                let mut ups = Vec::with_capacity(self.targets.len());
+                let mut start = 0;
+                for i in 0..self.targets.len() {
+                    let n = rows.get(i).copied().unwrap_or(total - start);
+                    ups.push(weights.up.narrow(0, start, n)?.contiguous()?);
+                    start += n;
+                }
+                ups
+            }
+            Split::SwapScaleShift => {
+                let half = weights.up.dim(0)? / 2;
+                vec![Tensor::cat(
+                    &[
+                        weights.up.narrow(0, half, half)?,
+                        weights.up.narrow(0, 0, half)?,
+                    ],
+                    0,
+                )?]
+            }
+        };
+        Ok(self
+            .targets
+            .iter()
+            .cloned()
+            .zip(ups)
+            .map(|(target, up)| {
+                (
+                    target,
+                    LoraWeights {
+                        down: weights.down.clone(),
+                        up,
+                        alpha: weights.alpha,
+                    },
+                )
+            })
+            .collect())
+    }
+}
+
+/// Names of the original (BFL) layers, mapped to the diffusers layers.
+fn original_layers(
+    num_double_blocks: usize,
+    num_single_blocks: usize,
+    hidden_size: usize,
+) -> HashMap<String, OriginalLayer> {
+    let mut layers = HashMap::new();
+    let h = hidden_size;
+    for (original, target) in [
+        ("img_in", "x_embedder"),
+        ("txt_in", "context_embedder"),
+        (
+            "time_in.in_layer",
+            "time_text_embed.timestep_embedder.linear_1",
+        ),
+        (
+            "time_in.out_layer",
+            "time_text_embed.timestep_embedder.linear_2",
+        ),
+        (
+            "vector_in.in_layer",
+            "time_text_embed.text_embedder.linear_1",
+        ),
+        (
+            "vector_in.out_layer",
+            "time_text_embed.text_embedder.linear_2",
+        ),
+        (
+            "guidance_in.in_layer",
+            "time_text_embed.guidance_embedder.linear_1",
+        ),
+        (
+            "guidance_in.out_layer",
+            "time_text_embed.guidance_embedder.linear_2",
+        ),
+        ("final_layer.linear", "proj_out"),
+    ] {
+        layers.insert(original.to_string(), OriginalLayer::new(target));
+    }
+    layers.insert(
+        "final_layer.adaLN_modulation.1".to_string(),
+        OriginalLayer {
+            targets: vec!["norm_out.linear".to_string()],
+            split: Split::SwapScaleShift,
+        },
+    );
+
+    for i in 0..num_double_blocks {
+        let original = format!("double_blocks.{i}");
+        let target = format!("transformer_blocks.{i}");
+        for (stream, q, k, v, proj, mlp, modulation) in [
+            ("img", "to_q", "to_k", "to_v", "to_out.0", "ff", "norm1"),
+            (
+                "txt",
+                "add_q_proj",
+                "add_k_proj",
+                "add_v_proj",
+                "to_add_out",
+                "ff_context",
+                "norm1_context",
+            ),
+        ] {
+            let qkv = [q, k, v].map(|x| format!("{target}.attn.{x}"));
+            layers.insert(
+                format!("{original}.{stream}_attn.qkv"),
+                OriginalLayer::split(&qkv, vec![h, h]),
+            );
+            for (original_suffix, target_suffix) in [
+                (format!("{stream}_attn.proj"), format!("attn.{proj}")),
+                (format!("{stream}_mlp.0"), format!("{mlp}.net.0.proj")),
+                (format!("{stream}_mlp.2"), format!("{mlp}.net.2")),
+                (format!("{stream}_mod.lin"), format!("{modulation}.linear")),
+            ] {
+                layers.insert(
+                    format!("{original}.{original_suffix}"),
+                    OriginalLayer::new(&format!("{target}.{target_suffix}")),
+                );
+            }
+        }
+    }
+
+    for i in 0..num_single_blocks {
+        let original = format!("single_blocks.{i}");
+        let target = format!("single_transformer_blocks.{i}");
+        let linear1 =
+            ["attn.to_q", "attn.to_k", "attn.to_v", "proj_mlp"].map(|x| format!("{target}.{x}"));
+        layers.insert(
+            format!("{original}.linear1"),
+            OriginalLayer::split(&linear1, vec![h, h, h]),
+        );
+        layers.insert(
+            format!("{original}.linear2"),
+            OriginalLayer::new(&format!("{target}.proj_out")),
+        );
+        layers.insert(
+            format!("{original}.modulation.lin"),
+            OriginalLayer::new(&format!("{target}.norm.linear")),
+        );
+    }
+    layers
+}
+
+#[derive(Default)]
+struct RawWeights {
+    down: Option<Tensor>,
+    up: Option<Tensor>,
+    alpha: Option<f64>,
+}
+
+impl Flux {
+    /// Convert the tensors of a LoRA file to an adapter for this model.
+    pub(crate) fn lora_adapter(&mut self, tensors: HashMap<String, Tensor>) -> Result<LoraAdapter> {
+        let layer_names = self
+            .named_layers()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        lora_adapter_from_tensors(
+            tensors,
+            &layer_names,
+            self.num_double_blocks(),
+            self.num_single_blocks(),
+            self.hidden_size(),
+        )
+    }
+}
+
+/// Convert the tensors of a LoRA file to an adapter for the given layers.
+fn lora_adapter_from_tensors(
+    tensors: HashMap<String, Tensor>,
+    layer_names: &[String],
+    num_double_blocks: usize,
+    num_single_blocks: usize,
+    hidden_size: usize,
+) -> Result<LoraAdapter> {
+    let mut raw = HashMap::<String, RawWeights>::new();
+    for (key, tensor) in tensors {
+        if let Some(prefix) = DOWN_SUFFIXES.iter().find_map(|s| key.strip_suffix(s)) {
+            raw.entry(prefix.to_string()).or_default().down = Some(tensor);
+        } else if let Some(prefix) = UP_SUFFIXES.iter().find_map(|s| key.strip_suffix(s)) {
+            raw.entry(prefix.to_string()).or_default().up = Some(tensor);
+        } else if let Some(prefix) = key.strip_suffix(".alpha") {
+            let alpha = tensor.flatten_all()?.to_dtype(DType::F64)?;
+            raw.entry(prefix.to_string()).or_default().alpha = Some(alpha.to_vec1::<f64>()?[0]);
+        } else {
+            diffusion_rs_common::bail!("Unsupported LoRA tensor `{key}`.");
+        }
+    }
+
+    let original = original_layers(num_double_blocks, num_single_blocks, hidden_size);
+    let mut kohya = HashMap::new();
+    for name in original.keys() {
+        kohya.insert(
+            format!("lora_unet_{}", name.replace('.', "_")),
+            name.clone(),
+        );
+    }
+    for name in layer_names {
+        kohya.insert(
+            format!("lora_transformer_{}", name.replace('.', "_")),
+            name.clone(),
+        );
+    }
+
+    let mut weights = HashMap::new();
+    let mut skipped = 0;
+    for (prefix, RawWeights { down, up, alpha }) in raw {
+        if prefix.starts_with("lora_te") || prefix.starts_with("text_encoder") {
+            skipped += 1;
+            continue;
+        }
+        let (Some(down), Some(up)) = (down, up) else {
+            diffusion_rs_common::bail!("Incomplete LoRA weights for `{prefix}`.");
+        };
+        let lora = LoraWeights { down, up, alpha };
+
+        let name = kohya.get(&prefix).cloned().unwrap_or_else(|| {
+            PREFIXES
+                .iter()
+                .find_map(|p| prefix.strip_prefix(p))
+                .unwrap_or(&prefix)
+                .to_string()
+        });
+        match original.get(&name) {
+            Some(layer) => weights.extend(layer.convert(lora)?),
+            None => {
+                weights.insert(name, lora);
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!("ignoring {skipped} LoRA layers for the text encoders");
+    }
+
+    Ok(LoraAdapter { weights })
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+    use super::lora_adapter_from_tensors;
+
+    const HIDDEN_SIZE: usize = 4;
+    const RANK: usize = 2;
+
+    fn layer_names() -> Vec<String> {
+        ["x_embedder", "proj_out", "transformer_blocks.0.attn.to_q"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    /// LoRA weights whose `up` rows are numbered, to check how they are split.
+    fn lora(prefix: &str, out_features: usize) -> Result<Vec<(String, Tensor)>> {
+        let up = Tensor::arange(0f32, (out_features * RANK) as f32, &Device::Cpu)?
+            .reshape((out_features, RANK))?;
+        Ok(vec![
+            (
+                format!("{prefix}.lora_down.weight"),
+                Tensor::ones((RANK, HIDDEN_SIZE), DType::F32, &Device::Cpu)?,
+            ),
+            (format!("{prefix}.lora_up.weight"), up),
+            (
+                format!("{prefix}.alpha"),
+                Tensor::new(&[1f32], &Device::Cpu)?,
+            ),
+        ])
+    }
+
+    fn first_up_rows(weights: &super::LoraWeights) -> Result<Vec<f32>> {
+        weights.up.narrow(1, 0, 1)?.flatten_all()?.to_vec1::<f32>()
+    }
+
+    #[test]
+    fn kohya_fused_qkv_is_split() -> Result<()> {
+        let tensors = lora("lora_unet_double_blocks_0_img_attn_qkv", 3 * HIDDEN_SIZE)?;
+        let adapter = lora_adapter_from_tensors(
+            tensors.into_iter().collect(),
+            &layer_names(),
+            1,
+            1,
+            HIDDEN_SIZE,
+        )?;
+
+        let mut names = adapter.weights.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "transformer_blocks.0.attn.to_k",
+                "transformer_blocks.0.attn.to_q",
+                "transformer_blocks.0.attn.to_v"
+            ]
+        );
+        let to_k = &adapter.weights["transformer_blocks.0.attn.to_k"];
+        assert_eq!(first_up_rows(to_k)?, [8., 10., 12., 14.]);
+        assert_eq!(to_k.alpha, Some(1.));
+        assert_eq!(to_k.alpha_scale()?, 0.5);
+        Ok(())
+    }
+
+    #[test]
+    fn bfl_names_are_converted() -> Result<()> {
+        let tensors = lora("diffusion_model.single_blocks.0.linear1", 4 * HIDDEN_SIZE)?
+            .into_iter()
+            .chain(lora(
+                "diffusion_model.final_layer.adaLN_modulation.1",
+                2 * HIDDEN_SIZE,
+            )?)
+            .chain(lora("diffusion_model.img_in", HIDDEN_SIZE)?)
+            .collect();
+        let adapter = lora_adapter_from_tensors(tensors, &layer_names(), 1, 1, HIDDEN_SIZE)?;
+
+        let proj_mlp = &adapter.weights["single_transformer_blocks.0.proj_mlp"];
+        assert_eq!(first_up_rows(proj_mlp)?, [24., 26., 28., 30.]);
+        assert!(adapter.weights.contains_key("x_embedder"));
+        // The shift and scale halves are swapped.
+        let norm_out = &adapter.weights["norm_out.linear"];
+        assert_eq!(
+            first_up_rows(norm_out)?,
+            [8., 10., 12., 14., 0., 2., 4., 6.]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diffusers_and_kohya_transformer_names() -> Result<()> {
+        let tensors = lora("transformer.proj_out", HIDDEN_SIZE)?
+            .into_iter()
+            .chain(lora(
+                "lora_transformer_transformer_blocks_0_attn_to_q",
+                HIDDEN_SIZE,
+            )?)
+            .chain(lora(
+                "lora_te1_text_model_encoder_layers_0_mlp_fc1",
+                HIDDEN_SIZE,
+            )?)
+            .collect();
+        let adapter = lora_adapter_from_tensors(tensors, &layer_names(), 1, 1, HIDDEN_SIZE)?;
+
+        let mut names = adapter.weights.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["proj_out", "transformer_blocks.0.attn.to_q"]);
+        Ok(())
+    }
+}
@@ -1,3 +1,10 @@
+mod controlnet;
+mod ip_adapter;
+mod lora;
 mod model;
+mod redux;
 
+pub use controlnet::{Config as ControlNetConfig, ControlNetResiduals, FluxControlNet};
+pub use ip_adapter::{FluxIpAdapter, IpAdapterConditioning};
 pub use model::{Config as FluxConfig, Flux as FluxModel};
+pub use redux::{Config as ReduxConfig, ReduxImageEncoder};
@@ -13,23 +13,72 @@ use tracing::{span, Span};
 
 use crate::models::{QuantizedModel, QuantizedModelLayer};
 
+use super::controlnet::ControlNetResiduals;
+use super::ip_adapter::IpAdapterConditioning;
+
 const MLP_RATIO: f64 = 4.;
-const HIDDEN_SIZE: usize = 3072;
-const AXES_DIM: &[usize] = &[16, 56, 56];
-const THETA: usize = 10000;
+
+fn default_attention_head_dim() -> usize {
+    128
+}
+
+fn default_axes_dims_rope() -> Vec<usize> {
+    vec![16, 56, 56]
+}
+
+fn default_rope_theta() -> usize {
+    10000
+}
+
+fn default_pooled_projection_dim() -> usize {
+    768
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub in_channels: usize,
+    /// Defaults to `in_channels`. Models with extra conditioning channels, such as FLUX.1 Fill, set this to the
+    /// number of (packed) latent channels.
+    pub out_channels: Option<usize>,
+    #[serde(default = "default_pooled_projection_dim")]
     pub pooled_projection_dim: usize,
     pub joint_attention_dim: usize,
     pub num_attention_heads: usize,
+    #[serde(default = "default_attention_head_dim")]
+    pub attention_head_dim: usize,
+    /// Rotary embedding dimensions of each position id axis. These must sum to `attention_head_dim`.
+    #[serde(default = "default_axes_dims_rope")]
+    pub axes_dims_rope: Vec<usize>,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: usize,
     pub num_layers: usize,
     pub num_single_layers: usize,
+    #[serde(default)]
     pub guidance_embeds: bool,
+    /// Chroma: input channels of the distilled guidance layer which replaces the per-block modulation linears.
+    pub approximator_num_channels: Option<usize>,
+    /// Chroma: hidden size of the distilled guidance layer.
+    pub approximator_hidden_dim: Option<usize>,
+    /// Chroma: number of residual MLP layers of the distilled guidance layer.
+    pub approximator_layers: Option<usize>,
     pub quantization_config: Option<QuantizedConfig>,
 }
 
+impl Config {
+    pub fn out_channels(&self) -> usize {
+        self.out_channels.unwrap_or(self.in_channels)
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.num_attention_heads * self.attention_head_dim
+    }
+
+    /// Whether the modulations are computed by Chroma's distilled guidance layer.
+    pub fn is_chroma(&self) -> bool {
+        self.approximator_layers.is_some()
+    }
+}
+
 fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
     let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
     // Hack: use bias as 0s to take advantage of the fast kernel
@@ -37,29 +86,49 @@ fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
     Ok(LayerNorm::new(ws, bs, 1e-6))
 }
 
-fn scaled_dot_product_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+/// Add a ControlNet residual to the `residual.dim(1)` tokens of `xs` starting at `offset`. The tokens after them,
+/// such as those of a reference image, are left unchanged.
+fn add_residual(xs: &Tensor, offset: usize, residual: &Tensor) -> Result<Tensor> {
+    let (len, total) = (residual.dim(1)?, xs.dim(1)?);
+    let mut parts = Vec::with_capacity(3);
+    if offset > 0 {
+        parts.push(xs.narrow(1, 0, offset)?);
+    }
+    parts.push((xs.narrow(1, offset, len)? + residual)?);
+    if offset + len < total {
+        parts.push(xs.narrow(1, offset + len, total - offset - len)?);
+    }
+    Tensor::cat(&parts, 1)
+}
+
+/// `mask` is an additive attention bias broadcastable to (b, heads, seq, seq), used to ignore text padding.
+fn scaled_dot_product_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
     let dim = q.dim(D::Minus1)?;
     let scale_factor = 1.0 / (dim as f64).sqrt();
-    diffusion_rs_backend::ops::sdpa(
-        &q.to_dtype(DType::F32)?,
-        &k.to_dtype(DType::F32)?,
-        &v.to_dtype(DType::F32)?,
-        scale_factor as f32,
-        1.0,
-    )?
-    .to_dtype(q.dtype())
-
-    // let mut batch_dims = q.dims().to_vec();
-    // batch_dims.pop();
-    // batch_dims.pop();
-    // let q = q.flatten_to(batch_dims.len() - 1)?;
-    // let k = k.flatten_to(batch_dims.len() - 1)?;
-    // let v = v.flatten_to(batch_dims.len() - 1)?;
-    // let attn_weights = (q.matmul(&k.t()?)? * scale_factor)?;
-    // let attn_scores = diffusion_rs_common::nn::ops::softmax_last_dim(&attn_weights)?.matmul(&v)?;
-    // batch_dims.push(attn_scores.dim(D::Minus2)?);
-    // batch_dims.push(attn_scores.dim(D::Minus1)?);
-    // attn_scores.reshape(batch_dims)
+    let Some(mask) = mask else {
+        return diffusion_rs_backend::ops::sdpa(
+            &q.to_dtype(DType::F32)?,
+            &k.to_dtype(DType::F32)?,
+            &v.to_dtype(DType::F32)?,
+            scale_factor as f32,
+            1.0,
+        )?
+        .to_dtype(q.dtype());
+    };
+
+    let attn_weights = (q
+        .to_dtype(DType::F32)?
+        .matmul(&k.to_dtype(DType::F32)?.t()?)?
+        * scale_factor)?
+        .broadcast_add(mask)?;
+    diffusion_rs_common::nn::ops::softmax_last_dim(&attn_weights)?
+        .matmul(&v.to_dtype(DType::F32)?)?
+        .to_dtype(q.dtype())
 }
 
 fn rope(pos: &Tensor, dim: usize, theta: usize) -> Result<Tensor> {
@@ -94,10 +163,29 @@ fn apply_rope(x: &Tensor, freq_cis: &Tensor) -> Result<Tensor> {
     (fr0.broadcast_mul(&x0)? + fr1.broadcast_mul(&x1)?)?.reshape(dims.to_vec())
 }
 
-fn attention(q: &Tensor, k: &Tensor, v: &Tensor, pe: &Tensor) -> Result<Tensor> {
+fn attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    pe: &Tensor,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
     let q = apply_rope(q, pe)?.contiguous()?;
     let k = apply_rope(k, pe)?.contiguous()?;
-    let x = scaled_dot_product_attention(&q, &k, v)?;
+    let x = scaled_dot_product_attention(&q, &k, &v.contiguous()?, mask)?;
+    x.transpose(1, 2)?.flatten_from(2)
+}
+
+/// The attention of the image queries `q`, before RoPE, to the `(b, num_tokens, hidden_size)` image prompt tokens
+/// of an IP-Adapter.
+fn ip_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+    let (b, heads, _, head_dim) = q.dims4()?;
+    let tokens = |xs: &Tensor| -> Result<Tensor> {
+        xs.reshape((b, (), heads, head_dim))?
+            .transpose(1, 2)?
+            .contiguous()
+    };
+    let x = scaled_dot_product_attention(&q.contiguous()?, &tokens(k)?, &tokens(v)?, None)?;
     x.transpose(1, 2)?.flatten_from(2)
 }
 
@@ -130,7 +218,7 @@ pub struct EmbedNd {
 }
 
 impl EmbedNd {
-    fn new(dim: usize, theta: usize, axes_dim: Vec<usize>) -> Self {
+    pub(super) fn new(dim: usize, theta: usize, axes_dim: Vec<usize>) -> Self {
         Self {
             dim,
             theta,
@@ -225,16 +313,26 @@ impl ModulationOut {
     }
 }
 
+/// Projects the conditioning vector into modulation parameters. Chroma has no projection: the block is given its
+/// slice of the distilled guidance layer output, of shape (b, 3, dim), instead.
 #[derive(Debug, Clone)]
 struct Modulation1 {
-    lin: Arc<dyn QuantMethod>,
+    lin: Option<Arc<dyn QuantMethod>>,
     mod1: Span,
 }
 
 impl Modulation1 {
     fn new(dim: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let lin =
-            diffusion_rs_backend::linear(dim, 3 * dim, &cfg.quantization_config, vb.pp("linear"))?;
+        let lin = if cfg.is_chroma() {
+            None
+        } else {
+            Some(diffusion_rs_backend::linear(
+                dim,
+                3 * dim,
+                &cfg.quantization_config,
+                vb.pp("linear"),
+            )?)
+        };
         Ok(Self {
             lin,
             mod1: span!(tracing::Level::TRACE, "flux-mod1"),
@@ -243,11 +341,13 @@ impl Modulation1 {
 
     fn forward(&self, vec_: &Tensor) -> Result<ModulationOut> {
         let _span = self.mod1.enter();
-        let ys = self
-            .lin
-            .forward_autocast(&vec_.silu()?)?
-            .unsqueeze(1)?
-            .chunk(3, D::Minus1)?;
+        let ys = match &self.lin {
+            Some(lin) => lin
+                .forward_autocast(&vec_.silu()?)?
+                .unsqueeze(1)?
+                .chunk(3, D::Minus1)?,
+            None => vec_.chunk(3, 1)?,
+        };
         if ys.len() != 3 {
             diffusion_rs_common::bail!("unexpected len from chunk {ys:?}")
         }
@@ -259,16 +359,26 @@ impl Modulation1 {
     }
 }
 
+/// Like [`Modulation1`], for both the attention and the MLP of a double stream block. Chroma's slice is of shape
+/// (b, 6, dim).
 #[derive(Debug, Clone)]
 struct Modulation2 {
-    lin: Arc<dyn QuantMethod>,
+    lin: Option<Arc<dyn QuantMethod>>,
     mod2: Span,
 }
 
 impl Modulation2 {
     fn new(dim: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let lin =
-            diffusion_rs_backend::linear(dim, 6 * dim, &cfg.quantization_config, vb.pp("linear"))?;
+        let lin = if cfg.is_chroma() {
+            None
+        } else {
+            Some(diffusion_rs_backend::linear(
+                dim,
+                6 * dim,
+                &cfg.quantization_config,
+                vb.pp("linear"),
+            )?)
+        };
         Ok(Self {
             lin,
             mod2: span!(tracing::Level::TRACE, "flux-mod2"),
@@ -277,11 +387,13 @@ impl Modulation2 {
 
     fn forward(&self, vec_: &Tensor) -> Result<(ModulationOut, ModulationOut)> {
         let _span = self.mod2.enter();
-        let ys = self
-            .lin
-            .forward_autocast(&vec_.silu()?)?
-            .unsqueeze(1)?
-            .chunk(6, D::Minus1)?;
+        let ys = match &self.lin {
+            Some(lin) => lin
+                .forward_autocast(&vec_.silu()?)?
+                .unsqueeze(1)?
+                .chunk(6, D::Minus1)?,
+            None => vec_.chunk(6, 1)?,
+        };
         if ys.len() != 6 {
             diffusion_rs_common::bail!("unexpected len from chunk {ys:?}")
         }
@@ -430,7 +542,8 @@ impl SelfAttention {
     fn forward(&self, xs: &Tensor, pe: &Tensor) -> Result<Tensor> {
         let _span = self.fwd.enter();
         let (q, k, v) = self.qkv(xs)?;
-        self.proj.forward_autocast(&attention(&q, &k, &v, pe)?)
+        self.proj
+            .forward_autocast(&attention(&q, &k, &v, pe, None)?)
     }
 }
 
@@ -478,8 +591,8 @@ pub struct DoubleStreamBlock {
 }
 
 impl DoubleStreamBlock {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let h_sz = HIDDEN_SIZE;
+    pub(super) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let h_sz = cfg.hidden_size();
         let mlp_sz = (h_sz as f64 * MLP_RATIO) as usize;
         let img_mod = Modulation2::new(h_sz, cfg, vb.pp("norm1"))?;
         let img_norm1 = layer_norm(h_sz, vb.pp("img_norm1"))?;
@@ -520,15 +633,17 @@ impl DoubleStreamBlock {
         })
     }
 
-    fn forward(
+    pub(super) fn forward(
         &self,
         img: &Tensor,
         txt: &Tensor,
-        vec_: &Tensor,
+        (img_vec, txt_vec): (&Tensor, &Tensor),
         pe: &Tensor,
+        mask: Option<&Tensor>,
+        ip_adapters: &[(&Tensor, &Tensor, f64)],
     ) -> Result<(Tensor, Tensor)> {
-        let (img_mod1, img_mod2) = self.img_mod.forward(vec_)?; // shift, scale, gate
-        let (txt_mod1, txt_mod2) = self.txt_mod.forward(vec_)?; // shift, scale, gate
+        let (img_mod1, img_mod2) = self.img_mod.forward(img_vec)?; // shift, scale, gate
+        let (txt_mod1, txt_mod2) = self.txt_mod.forward(txt_vec)?; // shift, scale, gate
         let img_modulated = img.apply(&self.img_norm1)?;
         let img_modulated = img_mod1.scale_shift(&img_modulated)?;
         let (img_q, img_k, img_v) = self.img_attn.qkv(&img_modulated)?;
@@ -537,11 +652,11 @@ impl DoubleStreamBlock {
         let txt_modulated = txt_mod1.scale_shift(&txt_modulated)?;
         let (txt_q, txt_k, txt_v) = self.txt_attn.qkv(&txt_modulated)?;
 
-        let q = Tensor::cat(&[txt_q, img_q], 2)?;
+        let q = Tensor::cat(&[&txt_q, &img_q], 2)?;
         let k = Tensor::cat(&[txt_k, img_k], 2)?;
         let v = Tensor::cat(&[txt_v, img_v], 2)?;
 
-        let attn = attention(&q, &k, &v, pe)?;
+        let attn = attention(&q, &k, &v, pe, mask)?;
         let txt_attn = attn.narrow(1, 0, txt.dim(1)?)?;
         let img_attn = attn.narrow(1, txt.dim(1)?, attn.dim(1)? - txt.dim(1)?)?;
 
@@ -552,6 +667,9 @@ impl DoubleStreamBlock {
                     .scale_shift(&img.apply(&self.img_norm2)?)?
                     .apply(&self.img_mlp)?,
             )?)?;
+        let img = ip_adapters.iter().try_fold(img, |img, (k, v, scale)| {
+            img + (ip_attention(&img_q, k, v)? * *scale)?
+        })?;
 
         let txt = (txt + txt_mod1.gate(&self.txt_attn.proj.forward_autocast(&txt_attn)?))?;
         let txt = (&txt
@@ -579,10 +697,10 @@ pub struct SingleStreamBlock {
 }
 
 impl SingleStreamBlock {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let h_sz = HIDDEN_SIZE;
+    pub(super) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let h_sz = cfg.hidden_size();
         let mlp_sz = (h_sz as f64 * MLP_RATIO) as usize;
-        let head_dim = h_sz / cfg.num_attention_heads;
+        let head_dim = cfg.attention_head_dim;
 
         let q = diffusion_rs_backend::linear_b(
             h_sz,
@@ -635,7 +753,13 @@ impl SingleStreamBlock {
         })
     }
 
-    fn forward(&self, xs: &Tensor, vec_: &Tensor, pe: &Tensor) -> Result<Tensor> {
+    pub(super) fn forward(
+        &self,
+        xs: &Tensor,
+        vec_: &Tensor,
+        pe: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let mod_ = self.modulation.forward(vec_)?;
         let x_mod = mod_.scale_shift(&xs.apply(&self.pre_norm)?)?;
         let mut q = self.q.forward_autocast(&x_mod)?;
@@ -654,7 +778,7 @@ impl SingleStreamBlock {
         q = q.apply(&self.norm.query_norm)?;
         k = k.apply(&self.norm.key_norm)?;
         let mlp = self.proj_mlp.forward_autocast(&x_mod)?;
-        let attn = attention(&q, &k, &v, pe)?;
+        let attn = attention(&q, &k, &v, pe, mask)?;
         let output = self
             .linear2
             .forward_autocast(&Tensor::cat(&[attn, mlp.gelu()?], 2)?)?;
@@ -666,7 +790,8 @@ impl SingleStreamBlock {
 pub struct LastLayer {
     norm_final: LayerNorm,
     linear: Arc<dyn QuantMethod>,
-    ada_ln_modulation: Arc<dyn QuantMethod>,
+    /// `None` for Chroma, which is given its (b, 2, dim) shift and scale by the distilled guidance layer.
+    ada_ln_modulation: Option<Arc<dyn QuantMethod>>,
 }
 
 impl LastLayer {
@@ -678,12 +803,16 @@ impl LastLayer {
             &cfg.quantization_config,
             vb.pp("proj_out"),
         )?;
-        let ada_ln_modulation = diffusion_rs_backend::linear(
-            h_sz,
-            2 * h_sz,
-            &cfg.quantization_config,
-            vb.pp("norm_out.linear"),
-        )?;
+        let ada_ln_modulation = if cfg.is_chroma() {
+            None
+        } else {
+            Some(diffusion_rs_backend::linear(
+                h_sz,
+                2 * h_sz,
+                &cfg.quantization_config,
+                vb.pp("norm_out.linear"),
+            )?)
+        };
         Ok(Self {
             norm_final,
             linear,
@@ -692,43 +821,323 @@ impl LastLayer {
     }
 
     fn forward(&self, xs: &Tensor, vec: &Tensor) -> Result<Tensor> {
-        let chunks = self
-            .ada_ln_modulation
-            .forward_autocast(&vec.silu()?)?
-            .chunk(2, 1)?;
-        let (scale, shift) = (&chunks[0], &chunks[1]);
+        let (scale, shift) = match &self.ada_ln_modulation {
+            Some(lin) => {
+                let chunks = lin
+                    .forward_autocast(&vec.silu()?)?
+                    .unsqueeze(1)?
+                    .chunk(2, D::Minus1)?;
+                (chunks[0].clone(), chunks[1].clone())
+            }
+            // Chroma orders the final modulation as shift, scale.
+            None => (vec.narrow(1, 1, 1)?, vec.narrow(1, 0, 1)?),
+        };
         let xs = xs
             .apply(&self.norm_final)?
-            .broadcast_mul(&(scale.unsqueeze(1)? + 1.0)?)?
-            .broadcast_add(&shift.unsqueeze(1)?)?;
+            .broadcast_mul(&(scale + 1.0)?)?
+            .broadcast_add(&shift)?;
         self.linear.forward_autocast(&xs)
     }
 }
 
+/// Chroma's distilled guidance layer, which replaces the per-block modulation linears: a residual MLP computing
+/// every modulation vector from the timestep and the index of the vector.
+#[derive(Debug, Clone)]
+pub struct Approximator {
+    in_proj: Arc<dyn QuantMethod>,
+    layers: Vec<MlpEmbedder>,
+    /// The RMSNorm weights. The epsilon depends on the activation dtype.
+    norms: Vec<Tensor>,
+    out_proj: Arc<dyn QuantMethod>,
+    num_channels: usize,
+    num_mods: usize,
+}
+
+impl Approximator {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let num_channels = cfg.approximator_num_channels.unwrap_or(64);
+        let hidden_dim = cfg.approximator_hidden_dim.unwrap_or(5120);
+        let num_layers = cfg.approximator_layers.unwrap_or(5);
+        if !num_channels.is_multiple_of(4) {
+            diffusion_rs_common::bail!(
+                "approximator_num_channels {num_channels} is not a multiple of 4"
+            )
+        }
+
+        let in_proj = diffusion_rs_backend::linear(
+            num_channels,
+            hidden_dim,
+            &cfg.quantization_config,
+            vb.pp("in_proj"),
+        )?;
+        let mut layers = Vec::with_capacity(num_layers);
+        let mut norms = Vec::with_capacity(num_layers);
+        for i in 0..num_layers {
+            layers.push(MlpEmbedder::new(
+                hidden_dim,
+                hidden_dim,
+                cfg,
+                vb.pp("layers").pp(i),
+            )?);
+            norms.push(vb.pp("norms").pp(i).get(hidden_dim, "weight")?);
+        }
+        let out_proj = diffusion_rs_backend::linear(
+            hidden_dim,
+            cfg.hidden_size(),
+            &cfg.quantization_config,
+            vb.pp("out_proj"),
+        )?;
+
+        Ok(Self {
+            in_proj,
+            layers,
+            norms,
+            out_proj,
+            num_channels,
+            // 3 per single block, 6 per stream of each double block and 2 for the final layer.
+            num_mods: 3 * cfg.num_single_layers + 12 * cfg.num_layers + 2,
+        })
+    }
+
+    /// Returns the modulations, of shape (b, num_mods, hidden_size).
+    fn forward(&self, timesteps: &Tensor, dtype: DType) -> Result<Tensor> {
+        let dev = timesteps.device();
+        let b = timesteps.dim(0)?;
+        let proj_dim = self.num_channels / 4;
+
+        let t_emb = timestep_embedding(timesteps, proj_dim, dtype)?;
+        // Chroma is distilled without guidance, so the guidance embedding is always that of 0.
+        let g_emb = timestep_embedding(&Tensor::zeros(b, DType::F32, dev)?, proj_dim, dtype)?;
+        let mod_idx = Tensor::arange(0, self.num_mods as u32, dev)?.to_dtype(DType::F32)?;
+        let mod_emb = timestep_embedding(&mod_idx, 2 * proj_dim, dtype)?;
+
+        let tg = Tensor::cat(&[t_emb, g_emb], 1)?
+            .unsqueeze(1)?
+            .broadcast_as((b, self.num_mods, 2 * proj_dim))?;
+        let mod_emb = mod_emb
+            .unsqueeze(0)?
+            .broadcast_as((b, self.num_mods, 2 * proj_dim))?;
+        let mut xs = self
+            .in_proj
+            .forward_autocast(&Tensor::cat(&[tg, mod_emb], 2)?)?;
+        // `torch.nn.RMSNorm` defaults to the machine epsilon of the activation dtype.
+        let eps = machine_epsilon(xs.dtype());
+        for (layer, norm) in self.layers.iter().zip(&self.norms) {
+            let norm = RmsNorm::<RmsNormNonQuantized>::new(norm.clone(), eps);
+            xs = (&xs + xs.apply(&norm)?.apply(layer)?)?;
+        }
+        self.out_proj.forward_autocast(&xs)
+    }
+}
+
+/// The machine epsilon of `dtype`, as `torch.finfo(dtype).eps`.
+fn machine_epsilon(dtype: DType) -> f64 {
+    match dtype {
+        DType::BF16 => half::bf16::EPSILON.to_f64(),
+        DType::F16 => half::f16::EPSILON.to_f64(),
+        DType::F64 => f64::EPSILON,
+        _ => f64::from(f32::EPSILON),
+    }
+}
+
+/// How the blocks are conditioned on the timestep.
+#[derive(Debug, Clone)]
+pub(super) enum Conditioning {
+    /// FLUX: the timestep, guidance and pooled text embeddings are summed into a single vector which each block
+    /// projects with its own modulation linears.
+    Vector {
+        time_in: MlpEmbedder,
+        vector_in: MlpEmbedder,
+        guidance_in: Option<MlpEmbedder>,
+    },
+    /// Chroma: the modulations of every block are computed at once.
+    Approximator(Approximator),
+}
+
+impl Conditioning {
+    pub(super) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        if cfg.is_chroma() {
+            return Ok(Self::Approximator(Approximator::new(
+                cfg,
+                vb.pp("distilled_guidance_layer"),
+            )?));
+        }
+        let hidden_size = cfg.hidden_size();
+        let time_in = MlpEmbedder::new(
+            256,
+            hidden_size,
+            cfg,
+            vb.pp("time_text_embed.timestep_embedder"),
+        )?;
+        let vector_in = MlpEmbedder::new(
+            cfg.pooled_projection_dim,
+            hidden_size,
+            cfg,
+            vb.pp("time_text_embed.text_embedder"),
+        )?;
+        let guidance_in = if cfg.guidance_embeds {
+            let mlp = MlpEmbedder::new(
+                256,
+                hidden_size,
+                cfg,
+                vb.pp("time_text_embed.guidance_embedder"),
+            )?;
+            Some(mlp)
+        } else {
+            None
+        };
+        Ok(Self::Vector {
+            time_in,
+            vector_in,
+            guidance_in,
+        })
+    }
+
+    pub(super) fn is_guidance(&self) -> bool {
+        matches!(
+            self,
+            Self::Vector {
+                guidance_in: Some(_),
+                ..
+            }
+        )
+    }
+
+    pub(super) fn modulations(
+        &self,
+        timesteps: &Tensor,
+        y: Option<&Tensor>,
+        guidance: Option<&Tensor>,
+        dtype: DType,
+        num_double: usize,
+        num_single: usize,
+    ) -> Result<Modulations> {
+        match self {
+            Self::Vector {
+                time_in,
+                vector_in,
+                guidance_in,
+            } => {
+                let Some(y) = y else {
+                    diffusion_rs_common::bail!("FLUX requires the pooled text embedding `y`")
+                };
+                let vec_ = timestep_embedding(timesteps, 256, dtype)?.apply(time_in)?;
+                let vec_ = match (guidance_in.as_ref(), guidance) {
+                    (Some(g_in), Some(guidance)) => {
+                        (vec_ + timestep_embedding(guidance, 256, dtype)?.apply(g_in))?
+                    }
+                    _ => vec_,
+                };
+                Ok(Modulations::Vector((vec_ + y.apply(vector_in))?))
+            }
+            Self::Approximator(approximator) => Ok(Modulations::PerBlock {
+                mods: approximator.forward(timesteps, dtype)?,
+                num_double,
+                num_single,
+            }),
+        }
+    }
+}
+
+/// Build the additive attention bias over the `txt` then `img_len` image tokens from an optional (b, txt_len) text
+/// mask, which is 1 for the text tokens to attend to and 0 for padding.
+pub(super) fn txt_attention_mask(
+    txt_mask: Option<&Tensor>,
+    img_len: usize,
+) -> Result<Option<Tensor>> {
+    let Some(txt_mask) = txt_mask else {
+        return Ok(None);
+    };
+    let b = txt_mask.dim(0)?;
+    let keep = Tensor::cat(
+        &[
+            txt_mask.to_dtype(DType::F32)?,
+            Tensor::ones((b, img_len), DType::F32, txt_mask.device())?,
+        ],
+        1,
+    )?;
+    // Additive bias over the keys, broadcast over the heads and queries.
+    Ok(Some(((keep - 1.)? * 1e9)?.unsqueeze(1)?.unsqueeze(1)?))
+}
+
+/// The modulation inputs of the blocks for one forward pass.
+pub(super) enum Modulations {
+    Vector(Tensor),
+    /// Chroma's (b, num_mods, hidden_size) modulations: the single blocks, the image then text streams of the
+    /// double blocks and finally the last layer.
+    PerBlock {
+        mods: Tensor,
+        num_double: usize,
+        num_single: usize,
+    },
+}
+
+impl Modulations {
+    pub(super) fn double(&self, idx: usize) -> Result<(Tensor, Tensor)> {
+        match self {
+            Self::Vector(vec_) => Ok((vec_.clone(), vec_.clone())),
+            Self::PerBlock {
+                mods,
+                num_double,
+                num_single,
+            } => {
+                let img_offset = 3 * num_single;
+                let txt_offset = img_offset + 6 * num_double;
+                Ok((
+                    mods.narrow(1, img_offset + 6 * idx, 6)?,
+                    mods.narrow(1, txt_offset + 6 * idx, 6)?,
+                ))
+            }
+        }
+    }
+
+    pub(super) fn single(&self, idx: usize) -> Result<Tensor> {
+        match self {
+            Self::Vector(vec_) => Ok(vec_.clone()),
+            Self::PerBlock { mods, .. } => mods.narrow(1, 3 * idx, 3),
+        }
+    }
+
+    fn last(&self) -> Result<Tensor> {
+        match self {
+            Self::Vector(vec_) => Ok(vec_.clone()),
+            Self::PerBlock { mods, .. } => mods.narrow(1, mods.dim(1)? - 2, 2),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Flux {
     img_in: Arc<dyn QuantMethod>,
     txt_in: Arc<dyn QuantMethod>,
-    time_in: MlpEmbedder,
-    vector_in: MlpEmbedder,
-    guidance_in: Option<MlpEmbedder>,
+    conditioning: Conditioning,
     pe_embedder: EmbedNd,
     double_blocks: Vec<DoubleStreamBlock>,
     single_blocks: Vec<SingleStreamBlock>,
     final_layer: LastLayer,
+    hidden_size: usize,
 }
 
 impl Flux {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let hidden_size = cfg.hidden_size();
+        if cfg.axes_dims_rope.iter().sum::<usize>() != cfg.attention_head_dim {
+            diffusion_rs_common::bail!(
+                "axes_dims_rope {:?} do not sum to attention_head_dim {}",
+                cfg.axes_dims_rope,
+                cfg.attention_head_dim
+            )
+        }
+
         let img_in = diffusion_rs_backend::linear(
             cfg.in_channels,
-            HIDDEN_SIZE,
+            hidden_size,
             &cfg.quantization_config,
             vb.pp("x_embedder"),
         )?;
         let txt_in = diffusion_rs_backend::linear(
             cfg.joint_attention_dim,
-            HIDDEN_SIZE,
+            hidden_size,
             &cfg.quantization_config,
             vb.pp("context_embedder"),
         )?;
@@ -746,56 +1155,50 @@ impl Flux {
             let sb = SingleStreamBlock::new(cfg, vb_s.pp(idx))?;
             single_blocks.push(sb)
         }
-        let time_in = MlpEmbedder::new(
-            256,
-            HIDDEN_SIZE,
-            cfg,
-            vb.pp("time_text_embed.timestep_embedder"),
-        )?;
-        let vector_in = MlpEmbedder::new(
-            cfg.pooled_projection_dim,
-            HIDDEN_SIZE,
-            cfg,
-            vb.pp("time_text_embed.text_embedder"),
-        )?;
-        let guidance_in = if cfg.guidance_embeds {
-            let mlp = MlpEmbedder::new(
-                256,
-                HIDDEN_SIZE,
-                cfg,
-                vb.pp("time_text_embed.guidance_embedder"),
-            )?;
-            Some(mlp)
-        } else {
-            None
-        };
-        let final_layer = LastLayer::new(HIDDEN_SIZE, 1, cfg.in_channels, cfg, vb)?;
-        let pe_dim = HIDDEN_SIZE / cfg.num_attention_heads;
-        let pe_embedder = EmbedNd::new(pe_dim, THETA, AXES_DIM.to_vec());
+        let conditioning = Conditioning::new(cfg, vb.clone())?;
+        let final_layer = LastLayer::new(hidden_size, 1, cfg.out_channels(), cfg, vb)?;
+        let pe_embedder = EmbedNd::new(
+            cfg.attention_head_dim,
+            cfg.rope_theta,
+            cfg.axes_dims_rope.clone(),
+        );
 
         Ok(Self {
             img_in,
             txt_in,
-            time_in,
-            vector_in,
-            guidance_in,
+            conditioning,
             pe_embedder,
             double_blocks,
             single_blocks,
             final_layer,
+            hidden_size,
         })
     }
 
+    /// `y` is the pooled CLIP embedding, which Chroma does not use. `txt_mask` is an optional (b, txt_len) mask
+    /// which is 1 for the text tokens to attend to and 0 for padding.
+    ///
+    /// `ref_img` holds extra image tokens and their ids, such as the FLUX.1 Kontext reference image. They are
+    /// appended to `img` and attended to, but only the predictions for the tokens of `img` are returned.
+    ///
+    /// `controlnet` holds the residuals of a ControlNet, which are added to the image tokens after each block.
+    ///
+    /// `ip_adapters` holds the image prompt tokens of IP-Adapters, which the image tokens of the double stream
+    /// blocks attend to.
     #[allow(clippy::too_many_arguments)]
     pub fn forward(
         &self,
         img: &Tensor,
         img_ids: &Tensor,
+        ref_img: Option<(&Tensor, &Tensor)>,
         txt: &Tensor,
         txt_ids: &Tensor,
+        txt_mask: Option<&Tensor>,
         timesteps: &Tensor,
-        y: &Tensor,
+        y: Option<&Tensor>,
         guidance: Option<&Tensor>,
+        controlnet: Option<&ControlNetResiduals>,
+        ip_adapters: &[IpAdapterConditioning],
     ) -> Result<Tensor> {
         if txt.rank() != 3 {
             diffusion_rs_common::bail!("unexpected shape for txt {:?}", txt.shape())
@@ -804,36 +1207,180 @@ impl Flux {
             diffusion_rs_common::bail!("unexpected shape for img {:?}", img.shape())
         }
         let dtype = img.dtype();
+        let num_img_tokens = img.dim(1)?;
+        let (img, img_ids) = match ref_img {
+            Some((ref_img, ref_img_ids)) => (
+                Tensor::cat(&[img, ref_img], 1)?,
+                Tensor::cat(&[img_ids, ref_img_ids], 1)?,
+            ),
+            None => (img.clone(), img_ids.clone()),
+        };
         let pe = {
-            let ids = Tensor::cat(&[txt_ids, img_ids], 1)?;
+            let ids = Tensor::cat(&[txt_ids, &img_ids], 1)?;
             ids.apply(&self.pe_embedder)?
         };
+        let mask = txt_attention_mask(txt_mask, img.dim(1)?)?;
         let mut txt = self.txt_in.forward_autocast(txt)?;
-        let mut img = self.img_in.forward_autocast(img)?;
-        let vec_ = timestep_embedding(timesteps, 256, dtype)?.apply(&self.time_in)?;
-        let vec_ = match (self.guidance_in.as_ref(), guidance) {
-            (Some(g_in), Some(guidance)) => {
-                (vec_ + timestep_embedding(guidance, 256, dtype)?.apply(g_in))?
-            }
-            _ => vec_,
-        };
-        let vec_ = (vec_ + y.apply(&self.vector_in))?;
+        let mut img = self.img_in.forward_autocast(&img)?;
+        let mods = self.conditioning.modulations(
+            timesteps,
+            y,
+            guidance,
+            dtype,
+            self.double_blocks.len(),
+            self.single_blocks.len(),
+        )?;
 
         // Double blocks
-        for block in self.double_blocks.iter() {
-            (img, txt) = block.forward(&img, &txt, &vec_, &pe)?;
+        for (i, block) in self.double_blocks.iter().enumerate() {
+            let (img_vec, txt_vec) = mods.double(i)?;
+            let ip_adapters = ip_adapters
+                .iter()
+                .filter_map(|ip_adapter| ip_adapter.block(i))
+                .collect::<Vec<_>>();
+            (img, txt) = block.forward(
+                &img,
+                &txt,
+                (&img_vec, &txt_vec),
+                &pe,
+                mask.as_ref(),
+                &ip_adapters,
+            )?;
+            if let Some(residuals) = controlnet {
+                if let Some(residual) = residuals.double(i, self.double_blocks.len()) {
+                    img = add_residual(&img, 0, residual)?;
+                }
+            }
         }
         // Single blocks
+        let txt_len = txt.dim(1)?;
         let mut img = Tensor::cat(&[&txt, &img], 1)?;
-        for block in self.single_blocks.iter() {
-            img = block.forward(&img, &vec_, &pe)?;
+        for (i, block) in self.single_blocks.iter().enumerate() {
+            img = block.forward(&img, &mods.single(i)?, &pe, mask.as_ref())?;
+            if let Some(residuals) = controlnet {
+                if let Some(residual) = residuals.single(i, self.single_blocks.len()) {
+                    img = add_residual(&img, txt_len, residual)?;
+                }
+            }
         }
-        let img = img.i((.., txt.dim(1)?..))?;
-        self.final_layer.forward(&img, &vec_)
+        let img = img.i((.., txt_len..txt_len + num_img_tokens))?;
+        self.final_layer.forward(&img, &mods.last()?)
     }
 
     pub fn is_guidance(&self) -> bool {
-        self.guidance_in.is_some()
+        self.conditioning.is_guidance()
+    }
+
+    /// Whether this is a Chroma model, which has no pooled CLIP embedding input.
+    pub fn is_chroma(&self) -> bool {
+        matches!(self.conditioning, Conditioning::Approximator(_))
+    }
+
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size
+    }
+
+    pub fn num_double_blocks(&self) -> usize {
+        self.double_blocks.len()
+    }
+
+    pub fn num_single_blocks(&self) -> usize {
+        self.single_blocks.len()
+    }
+
+    /// Return all linear layers, named by their diffusers weight prefix.
+    pub(crate) fn named_layers(&mut self) -> Vec<(String, &mut Arc<dyn QuantMethod>)> {
+        let mut layers = vec![
+            ("context_embedder".to_string(), &mut self.txt_in),
+            ("x_embedder".to_string(), &mut self.img_in),
+            ("proj_out".to_string(), &mut self.final_layer.linear),
+        ];
+        if let Some(layer) = &mut self.final_layer.ada_ln_modulation {
+            layers.push(("norm_out.linear".to_string(), layer));
+        }
+        match &mut self.conditioning {
+            Conditioning::Vector {
+                time_in,
+                vector_in,
+                guidance_in,
+            } => {
+                let mut embedders =
+                    vec![("timestep_embedder", time_in), ("text_embedder", vector_in)];
+                if let Some(layer) = guidance_in {
+                    embedders.push(("guidance_embedder", layer));
+                }
+                for (name, embedder) in embedders {
+                    layers.push((
+                        format!("time_text_embed.{name}.linear_1"),
+                        &mut embedder.in_layer,
+                    ));
+                    layers.push((
+                        format!("time_text_embed.{name}.linear_2"),
+                        &mut embedder.out_layer,
+                    ));
+                }
+            }
+            Conditioning::Approximator(approximator) => {
+                let prefix = "distilled_guidance_layer";
+                layers.push((format!("{prefix}.in_proj"), &mut approximator.in_proj));
+                layers.push((format!("{prefix}.out_proj"), &mut approximator.out_proj));
+                for (i, layer) in approximator.layers.iter_mut().enumerate() {
+                    layers.push((format!("{prefix}.layers.{i}.linear_1"), &mut layer.in_layer));
+                    layers.push((
+                        format!("{prefix}.layers.{i}.linear_2"),
+                        &mut layer.out_layer,
+                    ));
+                }
+            }
+        }
+
+        for (i, block) in self.double_blocks.iter_mut().enumerate() {
+            let prefix = format!("transformer_blocks.{i}");
+            layers.extend([
+                (format!("{prefix}.attn.to_q"), &mut block.img_attn.q),
+                (format!("{prefix}.attn.to_k"), &mut block.img_attn.k),
+                (format!("{prefix}.attn.to_v"), &mut block.img_attn.v),
+                (format!("{prefix}.attn.to_out.0"), &mut block.img_attn.proj),
+                (format!("{prefix}.ff.net.0.proj"), &mut block.img_mlp.lin1),
+                (format!("{prefix}.ff.net.2"), &mut block.img_mlp.lin2),
+                (format!("{prefix}.attn.add_q_proj"), &mut block.txt_attn.q),
+                (format!("{prefix}.attn.add_k_proj"), &mut block.txt_attn.k),
+                (format!("{prefix}.attn.add_v_proj"), &mut block.txt_attn.v),
+                (
+                    format!("{prefix}.attn.to_add_out"),
+                    &mut block.txt_attn.proj,
+                ),
+                (
+                    format!("{prefix}.ff_context.net.0.proj"),
+                    &mut block.txt_mlp.lin1,
+                ),
+                (
+                    format!("{prefix}.ff_context.net.2"),
+                    &mut block.txt_mlp.lin2,
+                ),
+            ]);
+            if let Some(lin) = &mut block.img_mod.lin {
+                layers.push((format!("{prefix}.norm1.linear"), lin));
+            }
+            if let Some(lin) = &mut block.txt_mod.lin {
+                layers.push((format!("{prefix}.norm1_context.linear"), lin));
+            }
+        }
+
+        for (i, block) in self.single_blocks.iter_mut().enumerate() {
+            let prefix = format!("single_transformer_blocks.{i}");
+            layers.extend([
+                (format!("{prefix}.attn.to_q"), &mut block.q),
+                (format!("{prefix}.attn.to_k"), &mut block.k),
+                (format!("{prefix}.attn.to_v"), &mut block.v),
+                (format!("{prefix}.proj_mlp"), &mut block.proj_mlp),
+                (format!("{prefix}.proj_out"), &mut block.linear2),
+            ]);
+            if let Some(lin) = &mut block.modulation.lin {
+                layers.push((format!("{prefix}.norm.linear"), lin));
+            }
+        }
+        layers
     }
 }
 
@@ -845,6 +1392,12 @@ impl QuantizedModel for Flux {
             ada_ln_modulation: self.final_layer.ada_ln_modulation.clone(),
         };
 
+        if let Conditioning::Approximator(approximator) = &mut self.conditioning {
+            for norm in &mut approximator.norms {
+                *norm = norm.to_device(dev)?;
+            }
+        }
+
         for block in &mut self.double_blocks {
             block.img_attn.norm = block.img_attn.norm.to_device(dev)?;
             block.txt_attn.norm = block.txt_attn.norm.to_device(dev)?;
@@ -867,63 +1420,91 @@ impl QuantizedModel for Flux {
         let mut layers = Vec::new();
 
         {
-            let mut pre_layer_ct = vec![
-                &mut self.txt_in,
-                &mut self.img_in,
-                &mut self.time_in.in_layer,
-                &mut self.time_in.out_layer,
-                &mut self.vector_in.in_layer,
-                &mut self.vector_in.out_layer,
-            ];
-
-            if let Some(layer) = &mut self.guidance_in {
-                pre_layer_ct.push(&mut layer.in_layer);
-                pre_layer_ct.push(&mut layer.out_layer);
+            let mut pre_layer_ct = vec![&mut self.txt_in, &mut self.img_in];
+
+            match &mut self.conditioning {
+                Conditioning::Vector {
+                    time_in,
+                    vector_in,
+                    guidance_in,
+                } => {
+                    pre_layer_ct.extend([
+                        &mut time_in.in_layer,
+                        &mut time_in.out_layer,
+                        &mut vector_in.in_layer,
+                        &mut vector_in.out_layer,
+                    ]);
+                    if let Some(layer) = guidance_in {
+                        pre_layer_ct.push(&mut layer.in_layer);
+                        pre_layer_ct.push(&mut layer.out_layer);
+                    }
+                }
+                Conditioning::Approximator(approximator) => {
+                    pre_layer_ct.push(&mut approximator.in_proj);
+                    pre_layer_ct.push(&mut approximator.out_proj);
+                    for layer in &mut approximator.layers {
+                        pre_layer_ct.push(&mut layer.in_layer);
+                        pre_layer_ct.push(&mut layer.out_layer);
+                    }
+                }
             }
             layers.push(QuantizedModelLayer(pre_layer_ct));
         }
 
         {
-            let layer_ct = vec![
-                &mut self.final_layer.ada_ln_modulation,
-                &mut self.final_layer.linear,
-            ];
+            let mut layer_ct = vec![&mut self.final_layer.linear];
+            if let Some(layer) = &mut self.final_layer.ada_ln_modulation {
+                layer_ct.push(layer);
+            }
             layers.push(QuantizedModelLayer(layer_ct));
         }
 
         for block in &mut self.double_blocks {
-            let layer_ct = vec![
+            let mut layer_ct = vec![
                 &mut block.img_attn.q,
                 &mut block.img_attn.k,
                 &mut block.img_attn.v,
                 &mut block.img_attn.proj,
                 &mut block.img_mlp.lin1,
                 &mut block.img_mlp.lin2,
-                &mut block.img_mod.lin,
                 &mut block.txt_attn.q,
                 &mut block.txt_attn.k,
                 &mut block.txt_attn.v,
                 &mut block.txt_attn.proj,
                 &mut block.txt_mlp.lin1,
                 &mut block.txt_mlp.lin2,
-                &mut block.txt_mod.lin,
             ];
+            layer_ct.extend(block.img_mod.lin.as_mut());
+            layer_ct.extend(block.txt_mod.lin.as_mut());
 
             layers.push(QuantizedModelLayer(layer_ct));
         }
 
         for block in &mut self.single_blocks {
-            let layer_ct = vec![
+            let mut layer_ct = vec![
                 &mut block.q,
                 &mut block.k,
                 &mut block.v,
-                &mut block.modulation.lin,
                 &mut block.proj_mlp,
                 &mut block.linear2,
             ];
+            layer_ct.extend(block.modulation.lin.as_mut());
 
             layers.push(QuantizedModelLayer(layer_ct));
         }
         Ok(layers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::DType;
+
+    #[test]
+    fn machine_epsilon_matches_torch() {
+        // `torch.finfo(dtype).eps`
+        assert_eq!(super::machine_epsilon(DType::BF16), 0.0078125);
+        assert_eq!(super::machine_epsilon(DType::F16), 0.0009765625);
+        assert_eq!(super::machine_epsilon(DType::F32), 1.1920928955078125e-7);
+    }
+}
@@ -0,0 +1,40 @@
+use diffusion_rs_common::core::{Module, Result, Tensor};
+use diffusion_rs_common::nn::Linear;
+use diffusion_rs_common::{linear, VarBuilder};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Hidden size of the SigLIP image features.
+    pub redux_dim: usize,
+    /// Hidden size of the T5 text embeddings.
+    pub txt_in_features: usize,
+}
+
+/// The FLUX.1 Redux image embedder, which projects SigLIP image features into T5-space tokens.
+#[derive(Debug, Clone)]
+pub struct ReduxImageEncoder {
+    redux_up: Linear,
+    redux_down: Linear,
+}
+
+impl ReduxImageEncoder {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let redux_up = linear(cfg.redux_dim, cfg.txt_in_features * 3, vb.pp("redux_up"))?;
+        let redux_down = linear(
+            cfg.txt_in_features * 3,
+            cfg.txt_in_features,
+            vb.pp("redux_down"),
+        )?;
+        Ok(Self {
+            redux_up,
+            redux_down,
+        })
+    }
+}
+
+impl Module for ReduxImageEncoder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.redux_down.forward(&self.redux_up.forward(xs)?.silu()?)
+    }
+}
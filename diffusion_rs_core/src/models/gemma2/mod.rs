@@ -0,0 +1,354 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+// Gemma 2 Text Model, used as a text encoder
+// https://github.com/huggingface/transformers/blob/main/src/transformers/models/gemma2/modeling_gemma2.py
+
+use diffusion_rs_backend::{linear_no_bias, QuantMethod, QuantizedConfig};
+use diffusion_rs_common::core::{DType, Device, Module, Result, Tensor, D};
+use diffusion_rs_common::nn::{Activation, Embedding};
+use diffusion_rs_common::{embedding, VarBuilder};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use super::{QuantizedModel, QuantizedModelLayer};
+
+fn default_rope_theta() -> f64 {
+    10000.
+}
+
+fn default_hidden_activation() -> Activation {
+    Activation::GeluPytorchTanh
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Gemma2Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f64,
+    #[serde(default = "default_hidden_activation")]
+    pub hidden_activation: Activation,
+    pub query_pre_attn_scalar: usize,
+    pub attn_logit_softcapping: Option<f64>,
+    /// Every other layer, starting with the first, only attends to this many previous tokens.
+    pub sliding_window: Option<usize>,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+/// RMS norm with the weight stored as an offset from 1.
+#[derive(Debug, Clone)]
+struct Gemma2RmsNorm {
+    weight: Tensor,
+    eps: f64,
+}
+
+impl Gemma2RmsNorm {
+    fn load(h: usize, eps: f64, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            weight: vb.get(h, "weight")?,
+            eps,
+        })
+    }
+
+    fn to_device(&self, dev: &Device) -> Result<Self> {
+        Ok(Self {
+            weight: self.weight.to_device(dev)?,
+            eps: self.eps,
+        })
+    }
+}
+
+impl Module for Gemma2RmsNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let dtype = xs.dtype();
+        let xs = xs.to_dtype(DType::F32)?;
+        let variance = xs.sqr()?.mean_keepdim(D::Minus1)?;
+        let xs = xs.broadcast_div(&(variance + self.eps)?.sqrt()?)?;
+        let weight = (self.weight.to_dtype(DType::F32)? + 1.)?;
+        xs.broadcast_mul(&weight)?.to_dtype(dtype)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(seq_len: usize, cfg: &Gemma2Config, dtype: DType, dev: &Device) -> Result<Self> {
+        let dim = cfg.head_dim;
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, dim / 2), dev)?;
+        let t = Tensor::arange(0u32, seq_len as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((seq_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+        })
+    }
+
+    fn apply(&self, xs: &Tensor) -> Result<Tensor> {
+        diffusion_rs_common::nn::rotary_emb::rope(&xs.contiguous()?, &self.cos, &self.sin)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Gemma2Mlp {
+    gate_proj: Arc<dyn QuantMethod>,
+    up_proj: Arc<dyn QuantMethod>,
+    down_proj: Arc<dyn QuantMethod>,
+    act: Activation,
+}
+
+impl Gemma2Mlp {
+    fn load(vb: VarBuilder, cfg: &Gemma2Config) -> Result<Self> {
+        let (h, i) = (cfg.hidden_size, cfg.intermediate_size);
+        Ok(Self {
+            gate_proj: linear_no_bias(h, i, &cfg.quantization_config, vb.pp("gate_proj"))?,
+            up_proj: linear_no_bias(h, i, &cfg.quantization_config, vb.pp("up_proj"))?,
+            down_proj: linear_no_bias(i, h, &cfg.quantization_config, vb.pp("down_proj"))?,
+            act: cfg.hidden_activation,
+        })
+    }
+}
+
+impl Module for Gemma2Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = self.act.forward(&self.gate_proj.forward_autocast(xs)?)?;
+        let xs = (gate * self.up_proj.forward_autocast(xs)?)?;
+        self.down_proj.forward_autocast(&xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Gemma2Attention {
+    q_proj: Arc<dyn QuantMethod>,
+    k_proj: Arc<dyn QuantMethod>,
+    v_proj: Arc<dyn QuantMethod>,
+    o_proj: Arc<dyn QuantMethod>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    scale: f64,
+    softcap: Option<f64>,
+}
+
+impl Gemma2Attention {
+    fn load(vb: VarBuilder, cfg: &Gemma2Config) -> Result<Self> {
+        let h = cfg.hidden_size;
+        let q_dim = cfg.num_attention_heads * cfg.head_dim;
+        let kv_dim = cfg.num_key_value_heads * cfg.head_dim;
+        Ok(Self {
+            q_proj: linear_no_bias(h, q_dim, &cfg.quantization_config, vb.pp("q_proj"))?,
+            k_proj: linear_no_bias(h, kv_dim, &cfg.quantization_config, vb.pp("k_proj"))?,
+            v_proj: linear_no_bias(h, kv_dim, &cfg.quantization_config, vb.pp("v_proj"))?,
+            o_proj: linear_no_bias(q_dim, h, &cfg.quantization_config, vb.pp("o_proj"))?,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.head_dim,
+            scale: (cfg.query_pre_attn_scalar as f64).powf(-0.5),
+            softcap: cfg.attn_logit_softcapping,
+        })
+    }
+
+    /// Repeat the key/value heads for grouped query attention.
+    fn repeat_kv(&self, xs: Tensor) -> Result<Tensor> {
+        let n_rep = self.num_heads / self.num_kv_heads;
+        if n_rep == 1 {
+            return Ok(xs);
+        }
+        let (b, h, t, d) = xs.dims4()?;
+        xs.unsqueeze(2)?
+            .broadcast_as((b, h, n_rep, t, d))?
+            .reshape((b, h * n_rep, t, d))
+    }
+
+    fn forward(&self, xs: &Tensor, rotary: &RotaryEmbedding, mask: &Tensor) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        let q = self
+            .q_proj
+            .forward_autocast(xs)?
+            .reshape((b, t, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward_autocast(xs)?
+            .reshape((b, t, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward_autocast(xs)?
+            .reshape((b, t, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let q = rotary.apply(&q)?;
+        let k = self.repeat_kv(rotary.apply(&k)?)?.contiguous()?;
+        let v = self.repeat_kv(v)?.contiguous()?;
+
+        let dtype = q.dtype();
+        let scores = (q.matmul(&k.t()?)? * self.scale)?.to_dtype(DType::F32)?;
+        let scores = match self.softcap {
+            Some(softcap) => ((scores / softcap)?.tanh()? * softcap)?,
+            None => scores,
+        };
+        let probs = diffusion_rs_common::nn::ops::softmax_last_dim(&scores.broadcast_add(mask)?)?;
+        let xs = probs.to_dtype(dtype)?.matmul(&v)?;
+        let xs = xs
+            .transpose(1, 2)?
+            .reshape((b, t, self.num_heads * self.head_dim))?;
+        self.o_proj.forward_autocast(&xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Gemma2DecoderLayer {
+    self_attn: Gemma2Attention,
+    mlp: Gemma2Mlp,
+    input_layernorm: Gemma2RmsNorm,
+    post_attention_layernorm: Gemma2RmsNorm,
+    pre_feedforward_layernorm: Gemma2RmsNorm,
+    post_feedforward_layernorm: Gemma2RmsNorm,
+    sliding_window: Option<usize>,
+}
+
+impl Gemma2DecoderLayer {
+    fn load(vb: VarBuilder, cfg: &Gemma2Config, sliding_window: Option<usize>) -> Result<Self> {
+        let norm = |name| Gemma2RmsNorm::load(cfg.hidden_size, cfg.rms_norm_eps, vb.pp(name));
+        Ok(Self {
+            self_attn: Gemma2Attention::load(vb.pp("self_attn"), cfg)?,
+            mlp: Gemma2Mlp::load(vb.pp("mlp"), cfg)?,
+            input_layernorm: norm("input_layernorm")?,
+            post_attention_layernorm: norm("post_attention_layernorm")?,
+            pre_feedforward_layernorm: norm("pre_feedforward_layernorm")?,
+            post_feedforward_layernorm: norm("post_feedforward_layernorm")?,
+            sliding_window,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, rotary: &RotaryEmbedding, mask: &Tensor) -> Result<Tensor> {
+        let h = self.input_layernorm.forward(xs)?;
+        let h = self.self_attn.forward(&h, rotary, mask)?;
+        let xs = (xs + self.post_attention_layernorm.forward(&h)?)?;
+        let h = self.pre_feedforward_layernorm.forward(&xs)?;
+        let h = self.mlp.forward(&h)?;
+        xs + self.post_feedforward_layernorm.forward(&h)?
+    }
+}
+
+/// Additive causal mask, optionally restricted to a window of previous tokens.
+fn get_mask(size: usize, sliding_window: Option<usize>, device: &Device) -> Result<Tensor> {
+    let window = sliding_window.unwrap_or(usize::MAX);
+    let mask: Vec<_> = (0..size)
+        .flat_map(|i| {
+            (0..size).map(move |j| {
+                if j > i || i - j >= window {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+        })
+        .collect();
+    Tensor::from_slice(&mask, (size, size), device)
+}
+
+/// The Gemma 2 decoder without the language modeling head. Its last hidden state is the text embedding.
+#[derive(Debug, Clone)]
+pub struct Gemma2Model {
+    embed_tokens: Embedding,
+    layers: Vec<Gemma2DecoderLayer>,
+    norm: Gemma2RmsNorm,
+    cfg: Gemma2Config,
+}
+
+impl Gemma2Model {
+    pub fn new(vb: VarBuilder, cfg: &Gemma2Config) -> Result<Self> {
+        // `Gemma2ForCausalLM` checkpoints nest the decoder under `model`.
+        let vb = if vb.contains_tensor("model.embed_tokens.weight") {
+            vb.pp("model")
+        } else {
+            vb
+        };
+        let embed_tokens = embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("embed_tokens"))?;
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| {
+                let sliding_window = cfg.sliding_window.filter(|_| i % 2 == 0);
+                Gemma2DecoderLayer::load(vb.pp("layers").pp(i), cfg, sliding_window)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let norm = Gemma2RmsNorm::load(cfg.hidden_size, cfg.rms_norm_eps, vb.pp("norm"))?;
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            cfg: cfg.clone(),
+        })
+    }
+
+    /// Compute the last hidden state of a `(batch, seq_len)` batch of token ids, with causal attention.
+    pub fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let (_, seq_len) = input_ids.dims2()?;
+        let xs = self.embed_tokens.forward(input_ids)?;
+        let device = xs.device();
+        let mut xs = (&xs * (self.cfg.hidden_size as f64).sqrt())?;
+        let rotary = RotaryEmbedding::new(seq_len, &self.cfg, xs.dtype(), device)?;
+        let full_mask = get_mask(seq_len, None, device)?;
+        let window_mask = match self.cfg.sliding_window {
+            Some(window) if window < seq_len => get_mask(seq_len, Some(window), device)?,
+            _ => full_mask.clone(),
+        };
+        for layer in &self.layers {
+            let mask = if layer.sliding_window.is_some() {
+                &window_mask
+            } else {
+                &full_mask
+            };
+            xs = layer.forward(&xs, &rotary, mask)?;
+        }
+        self.norm.forward(&xs)
+    }
+}
+
+impl QuantizedModel for Gemma2Model {
+    fn match_devices_all_layers(&mut self, dev: &Device) -> Result<()> {
+        self.embed_tokens = Embedding::new(
+            self.embed_tokens.embeddings().to_device(dev)?,
+            self.embed_tokens.hidden_size(),
+        );
+        for layer in &mut self.layers {
+            layer.input_layernorm = layer.input_layernorm.to_device(dev)?;
+            layer.post_attention_layernorm = layer.post_attention_layernorm.to_device(dev)?;
+            layer.pre_feedforward_layernorm = layer.pre_feedforward_layernorm.to_device(dev)?;
+            layer.post_feedforward_layernorm = layer.post_feedforward_layernorm.to_device(dev)?;
+        }
+        self.norm = self.norm.to_device(dev)?;
+        Ok(())
+    }
+
+    fn aggregate_layers(&mut self) -> Result<Vec<QuantizedModelLayer<'_>>> {
+        let mut layers = Vec::new();
+        for layer in &mut self.layers {
+            layers.push(QuantizedModelLayer(vec![
+                &mut layer.self_attn.q_proj,
+                &mut layer.self_attn.k_proj,
+                &mut layer.self_attn.v_proj,
+                &mut layer.self_attn.o_proj,
+                &mut layer.mlp.gate_proj,
+                &mut layer.mlp.up_proj,
+                &mut layer.mlp.down_proj,
+            ]));
+        }
+        Ok(layers)
+    }
+}
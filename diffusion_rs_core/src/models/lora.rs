@@ -0,0 +1,107 @@
+use std::{collections::HashMap, sync::Arc};
+
+use diffusion_rs_backend::{ActiveLoras, LoraLinear, QuantMethod, QuantMethodConfig};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+/// Low-rank weights for one linear layer. The weight delta is `alpha / rank * up @ down`.
+#[derive(Debug, Clone)]
+pub(crate) struct LoraWeights {
+    /// `(rank, in_features)`
+    pub down: Tensor,
+    /// `(out_features, rank)`
+    pub up: Tensor,
+    /// If not specified, `alpha` is the rank.
+    pub alpha: Option<f64>,
+}
+
+impl LoraWeights {
+    /// The scale applied to `up @ down`, before the adapter scale.
+    pub fn alpha_scale(&self) -> Result<f64> {
+        let rank = self.down.dim(0)?;
+        Ok(self.alpha.map(|alpha| alpha / rank as f64).unwrap_or(1.))
+    }
+
+    /// Compute the weight delta in F32, multiplied by `scale`.
+    pub fn delta(&self, scale: f64, device: &Device) -> Result<Tensor> {
+        let up = self.up.to_device(device)?.to_dtype(DType::F32)?;
+        let down = self.down.to_device(device)?.to_dtype(DType::F32)?;
+        up.matmul(&down)? * (scale * self.alpha_scale()?)
+    }
+}
+
+/// A LoRA adapter, with weights keyed by the name of the layer they apply to.
+#[derive(Debug, Clone)]
+pub(crate) struct LoraAdapter {
+    pub weights: HashMap<String, LoraWeights>,
+}
+
+impl LoraAdapter {
+    /// Merge the adapter into the given named layers, scaled by `scale`. Merging it again with `-scale` removes it.
+    ///
+    /// Every layer of the adapter must be present and merge successfully, otherwise no layer is modified.
+    pub fn merge(
+        &self,
+        layers: Vec<(String, &mut Arc<dyn QuantMethod>)>,
+        scale: f64,
+    ) -> Result<()> {
+        self.check_layers(&layers)?;
+        let mut merged = Vec::with_capacity(self.weights.len());
+        for (name, layer) in layers {
+            if let Some(weights) = self.weights.get(&name) {
+                let delta = weights.delta(scale, &layer.device())?;
+                let new_layer = layer.add_delta_w(&delta)?;
+                merged.push((layer, new_layer));
+            }
+        }
+        for (layer, new_layer) in merged {
+            *layer = new_layer;
+        }
+        Ok(())
+    }
+
+    /// Attach the adapter to the given named layers without modifying their weights. It is applied at runtime
+    /// when `name` is set in `active`.
+    pub fn attach(
+        &self,
+        layers: Vec<(String, &mut Arc<dyn QuantMethod>)>,
+        name: &str,
+        dtype: DType,
+        active: &ActiveLoras,
+    ) -> Result<()> {
+        self.check_layers(&layers)?;
+        for (layer_name, layer) in layers {
+            if let Some(weights) = self.weights.get(&layer_name) {
+                let device = layer.device();
+                *layer = Arc::new(LoraLinear::new(QuantMethodConfig::Lora {
+                    base: layer.clone(),
+                    name: name.to_string(),
+                    down: weights.down.to_device(&device)?.to_dtype(dtype)?,
+                    up: weights.up.to_device(&device)?.to_dtype(dtype)?,
+                    scale: weights.alpha_scale()?,
+                    active: active.clone(),
+                })?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove an adapter attached with [`LoraAdapter::attach`].
+    pub fn detach(layers: Vec<(String, &mut Arc<dyn QuantMethod>)>, name: &str) {
+        for (_, layer) in layers {
+            if let Some(base) = layer.remove_lora(name) {
+                *layer = base;
+            }
+        }
+    }
+
+    fn check_layers(&self, layers: &[(String, &mut Arc<dyn QuantMethod>)]) -> Result<()> {
+        if let Some(missing) = self
+            .weights
+            .keys()
+            .find(|name| !layers.iter().any(|(layer, _)| layer == *name))
+        {
+            diffusion_rs_common::bail!("LoRA adapter targets unknown layer `{missing}`");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,634 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use diffusion_rs_backend::{QuantMethod, QuantizedConfig};
+use diffusion_rs_common::core::{DType, Device, Module, Result, Tensor, D};
+use diffusion_rs_common::nn::{
+    layer_norm::RmsNormNonQuantized, Conv2d, Conv2dConfig, LayerNorm, RmsNorm,
+};
+use diffusion_rs_common::{NiceProgressBar, VarBuilder};
+use serde::Deserialize;
+use tracing::{span, Span};
+
+use crate::models::{QuantizedModel, QuantizedModelLayer};
+
+const TIMESTEP_DIM: usize = 256;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub patch_size: usize,
+    pub in_channels: usize,
+    pub out_channels: usize,
+    pub num_layers: usize,
+    pub attention_head_dim: usize,
+    pub num_attention_heads: usize,
+    pub joint_attention_dim: usize,
+    pub caption_projection_dim: usize,
+    pub pooled_projection_dim: usize,
+    pub pos_embed_max_size: usize,
+    /// Layers with an additional image self-attention (SD3.5 Medium).
+    #[serde(default)]
+    pub dual_attention_layers: Vec<usize>,
+    /// `rms_norm` to normalize the queries and keys (SD3.5), or none (SD3).
+    pub qk_norm: Option<String>,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+impl Config {
+    fn hidden_size(&self) -> usize {
+        self.num_attention_heads * self.attention_head_dim
+    }
+}
+
+fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
+    let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
+    // Hack: use bias as 0s to take advantage of the fast kernel
+    let bs = ws.zeros_like()?;
+    Ok(LayerNorm::new(ws, bs, 1e-6))
+}
+
+fn scaled_dot_product_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+    let dim = q.dim(D::Minus1)?;
+    let scale_factor = 1.0 / (dim as f64).sqrt();
+    diffusion_rs_backend::ops::sdpa(
+        &q.to_dtype(DType::F32)?,
+        &k.to_dtype(DType::F32)?,
+        &v.to_dtype(DType::F32)?,
+        scale_factor as f32,
+        1.0,
+    )?
+    .to_dtype(q.dtype())
+}
+
+/// Sinusoidal embedding of the timesteps, given in `[0, 1]`.
+fn timestep_embedding(t: &Tensor, dim: usize, dtype: DType) -> Result<Tensor> {
+    const TIME_FACTOR: f64 = 1000.;
+    const MAX_PERIOD: f64 = 10000.;
+    let half = dim / 2;
+    let t = (t.to_dtype(DType::F32)? * TIME_FACTOR)?;
+    let freqs = (Tensor::arange(0, half as u32, t.device())?.to_dtype(DType::F32)?
+        * (-MAX_PERIOD.ln() / half as f64))?
+        .exp()?;
+    let args = t.unsqueeze(1)?.broadcast_mul(&freqs.unsqueeze(0)?)?;
+    Tensor::cat(&[args.cos()?, args.sin()?], D::Minus1)?.to_dtype(dtype)
+}
+
+#[derive(Debug, Clone)]
+struct MlpEmbedder {
+    linear_1: Arc<dyn QuantMethod>,
+    linear_2: Arc<dyn QuantMethod>,
+}
+
+impl MlpEmbedder {
+    fn new(in_sz: usize, h_sz: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            linear_1: diffusion_rs_backend::linear(
+                in_sz,
+                h_sz,
+                &cfg.quantization_config,
+                vb.pp("linear_1"),
+            )?,
+            linear_2: diffusion_rs_backend::linear(
+                h_sz,
+                h_sz,
+                &cfg.quantization_config,
+                vb.pp("linear_2"),
+            )?,
+        })
+    }
+}
+
+impl Module for MlpEmbedder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.linear_2
+            .forward_autocast(&self.linear_1.forward_autocast(xs)?.silu()?)
+    }
+}
+
+/// Adaptive layer norm modulation: `n` chunks of shift, scale and gate parameters projected from the conditioning.
+#[derive(Debug, Clone)]
+struct Modulation {
+    lin: Arc<dyn QuantMethod>,
+    chunks: usize,
+}
+
+impl Modulation {
+    fn new(dim: usize, chunks: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let lin = diffusion_rs_backend::linear(
+            dim,
+            chunks * dim,
+            &cfg.quantization_config,
+            vb.pp("linear"),
+        )?;
+        Ok(Self { lin, chunks })
+    }
+
+    /// The chunks, each of shape `(b, 1, dim)`.
+    fn forward(&self, vec_: &Tensor) -> Result<Vec<Tensor>> {
+        self.lin
+            .forward_autocast(&vec_.silu()?)?
+            .unsqueeze(1)?
+            .chunk(self.chunks, D::Minus1)
+    }
+}
+
+fn scale_shift(xs: &Tensor, scale: &Tensor, shift: &Tensor) -> Result<Tensor> {
+    xs.broadcast_mul(&(scale + 1.)?)?.broadcast_add(shift)
+}
+
+/// Query, key and value projections of one stream, with the optional query and key norms and output projection.
+#[derive(Debug, Clone)]
+struct QkvProjections {
+    q: Arc<dyn QuantMethod>,
+    k: Arc<dyn QuantMethod>,
+    v: Arc<dyn QuantMethod>,
+    norm: Option<(RmsNorm<RmsNormNonQuantized>, RmsNorm<RmsNormNonQuantized>)>,
+    out: Option<Arc<dyn QuantMethod>>,
+}
+
+impl QkvProjections {
+    /// `names` are the query, key, value, query norm, key norm and output projection names.
+    fn new(
+        dim: usize,
+        head_dim: usize,
+        names: [&str; 6],
+        has_out: bool,
+        cfg: &Config,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let [q, k, v, norm_q, norm_k, out] = names;
+        let linear = |name: &str| {
+            diffusion_rs_backend::linear(dim, dim, &cfg.quantization_config, vb.pp(name))
+        };
+        let norm = match cfg.qk_norm.as_deref() {
+            None => None,
+            Some("rms_norm") => Some((
+                RmsNorm::<RmsNormNonQuantized>::new(vb.pp(norm_q).get(head_dim, "weight")?, 1e-6),
+                RmsNorm::<RmsNormNonQuantized>::new(vb.pp(norm_k).get(head_dim, "weight")?, 1e-6),
+            )),
+            Some(other) => diffusion_rs_common::bail!("Unsupported MMDiT qk norm `{other}`"),
+        };
+        Ok(Self {
+            q: linear(q)?,
+            k: linear(k)?,
+            v: linear(v)?,
+            norm,
+            out: if has_out { Some(linear(out)?) } else { None },
+        })
+    }
+
+    /// The queries, keys and values, of shape `(b, heads, seq_len, head_dim)`.
+    fn qkv(&self, xs: &Tensor, heads: usize) -> Result<(Tensor, Tensor, Tensor)> {
+        let (b, l, _) = xs.dims3()?;
+        let split_heads = |xs: Tensor| xs.reshape((b, l, heads, ()))?.transpose(1, 2);
+        let mut q = split_heads(self.q.forward_autocast(xs)?)?;
+        let mut k = split_heads(self.k.forward_autocast(xs)?)?;
+        let v = split_heads(self.v.forward_autocast(xs)?)?;
+        if let Some((norm_q, norm_k)) = &self.norm {
+            q = q.apply(norm_q)?;
+            k = k.apply(norm_k)?;
+        }
+        Ok((q, k, v))
+    }
+
+    fn match_devices(&mut self, dev: &Device) -> Result<()> {
+        if let Some((norm_q, norm_k)) = &mut self.norm {
+            *norm_q = norm_q.to_device(dev)?;
+            *norm_k = norm_k.to_device(dev)?;
+        }
+        Ok(())
+    }
+
+    fn layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        let mut layers = vec![&mut self.q, &mut self.k, &mut self.v];
+        layers.extend(self.out.as_mut());
+        layers
+    }
+}
+
+/// Attention over the image tokens, jointly with the text tokens if it has context projections.
+#[derive(Debug, Clone)]
+struct Attention {
+    img: QkvProjections,
+    context: Option<QkvProjections>,
+    heads: usize,
+}
+
+impl Attention {
+    fn new(joint: bool, context_pre_only: bool, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        let head_dim = cfg.attention_head_dim;
+        let img = QkvProjections::new(
+            dim,
+            head_dim,
+            ["to_q", "to_k", "to_v", "norm_q", "norm_k", "to_out.0"],
+            true,
+            cfg,
+            vb.clone(),
+        )?;
+        let context = if joint {
+            Some(QkvProjections::new(
+                dim,
+                head_dim,
+                [
+                    "add_q_proj",
+                    "add_k_proj",
+                    "add_v_proj",
+                    "norm_added_q",
+                    "norm_added_k",
+                    "to_add_out",
+                ],
+                !context_pre_only,
+                cfg,
+                vb,
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            img,
+            context,
+            heads: cfg.num_attention_heads,
+        })
+    }
+
+    /// Attend over the image tokens followed by the text tokens, if any. Return the projected outputs for the
+    /// image and the text tokens. There is no text output in the last block.
+    fn forward(&self, img: &Tensor, txt: Option<&Tensor>) -> Result<(Tensor, Option<Tensor>)> {
+        let (mut q, mut k, mut v) = self.img.qkv(img, self.heads)?;
+        if let (Some(context), Some(txt)) = (&self.context, txt) {
+            let (txt_q, txt_k, txt_v) = context.qkv(txt, self.heads)?;
+            q = Tensor::cat(&[&q, &txt_q], 2)?;
+            k = Tensor::cat(&[&k, &txt_k], 2)?;
+            v = Tensor::cat(&[&v, &txt_v], 2)?;
+        }
+        let attn =
+            scaled_dot_product_attention(&q.contiguous()?, &k.contiguous()?, &v.contiguous()?)?
+                .transpose(1, 2)?
+                .flatten_from(2)?;
+        let img_len = img.dim(1)?;
+        let img_out = attn.narrow(1, 0, img_len)?;
+        let img_out = match &self.img.out {
+            Some(out) => out.forward_autocast(&img_out)?,
+            None => img_out,
+        };
+        let txt_out = match (&self.context, txt) {
+            (Some(QkvProjections { out: Some(out), .. }), Some(_)) => {
+                let txt_out = attn.narrow(1, img_len, attn.dim(1)? - img_len)?;
+                Some(out.forward_autocast(&txt_out)?)
+            }
+            _ => None,
+        };
+        Ok((img_out, txt_out))
+    }
+
+    fn match_devices(&mut self, dev: &Device) -> Result<()> {
+        self.img.match_devices(dev)?;
+        if let Some(context) = &mut self.context {
+            context.match_devices(dev)?;
+        }
+        Ok(())
+    }
+
+    fn layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        let mut layers = self.img.layers();
+        if let Some(context) = &mut self.context {
+            layers.extend(context.layers());
+        }
+        layers
+    }
+}
+
+/// Feed-forward layer with a tanh-approximated GELU activation.
+#[derive(Debug, Clone)]
+struct Mlp {
+    lin1: Arc<dyn QuantMethod>,
+    lin2: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn new(dim: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            lin1: diffusion_rs_backend::linear(
+                dim,
+                4 * dim,
+                &cfg.quantization_config,
+                vb.pp("net.0.proj"),
+            )?,
+            lin2: diffusion_rs_backend::linear(
+                4 * dim,
+                dim,
+                &cfg.quantization_config,
+                vb.pp("net.2"),
+            )?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.lin2
+            .forward_autocast(&self.lin1.forward_autocast(xs)?.gelu()?)
+    }
+}
+
+/// The text stream of a block: modulation, then either the full update, or only the input of the joint
+/// attention in the last block.
+#[derive(Debug, Clone)]
+struct ContextStream {
+    modulation: Modulation,
+    mlp: Option<Mlp>,
+}
+
+/// A joint transformer block, as diffusers `JointTransformerBlock`.
+#[derive(Debug, Clone)]
+struct JointTransformerBlock {
+    img_mod: Modulation,
+    context: ContextStream,
+    attn: Attention,
+    attn2: Option<Attention>,
+    norm: LayerNorm,
+    mlp: Mlp,
+    span: Span,
+}
+
+impl JointTransformerBlock {
+    fn new(
+        context_pre_only: bool,
+        dual_attention: bool,
+        cfg: &Config,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        let img_mod =
+            Modulation::new(dim, if dual_attention { 9 } else { 6 }, cfg, vb.pp("norm1"))?;
+        let context = ContextStream {
+            modulation: Modulation::new(
+                dim,
+                if context_pre_only { 2 } else { 6 },
+                cfg,
+                vb.pp("norm1_context"),
+            )?,
+            mlp: if context_pre_only {
+                None
+            } else {
+                Some(Mlp::new(dim, cfg, vb.pp("ff_context"))?)
+            },
+        };
+        let attn2 = if dual_attention {
+            Some(Attention::new(false, false, cfg, vb.pp("attn2"))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            img_mod,
+            context,
+            attn: Attention::new(true, context_pre_only, cfg, vb.pp("attn"))?,
+            attn2,
+            norm: layer_norm(dim, vb.clone())?,
+            mlp: Mlp::new(dim, cfg, vb.pp("ff"))?,
+            span: span!(tracing::Level::TRACE, "mmdit-block"),
+        })
+    }
+
+    fn forward(
+        &self,
+        img: &Tensor,
+        txt: &Tensor,
+        temb: &Tensor,
+    ) -> Result<(Tensor, Option<Tensor>)> {
+        let _span = self.span.enter();
+        // shift, scale and gate of the attention and of the MLP, then of the second attention.
+        let m = self.img_mod.forward(temb)?;
+        let img_normed = img.apply(&self.norm)?;
+        let img_modulated = scale_shift(&img_normed, &m[1], &m[0])?;
+
+        let c = self.context.modulation.forward(temb)?;
+        let txt_normed = txt.apply(&self.norm)?;
+        let txt_modulated = match &self.context.mlp {
+            // The last block only has a scale and a shift for its text tokens.
+            None => scale_shift(&txt_normed, &c[0], &c[1])?,
+            Some(_) => scale_shift(&txt_normed, &c[1], &c[0])?,
+        };
+
+        let (img_attn, txt_attn) = self.attn.forward(&img_modulated, Some(&txt_modulated))?;
+        let mut img = (img + m[2].broadcast_mul(&img_attn)?)?;
+        if let Some(attn2) = &self.attn2 {
+            let img_modulated2 = scale_shift(&img_normed, &m[7], &m[6])?;
+            let (img_attn2, _) = attn2.forward(&img_modulated2, None)?;
+            img = (img + m[8].broadcast_mul(&img_attn2)?)?;
+        }
+        let img_mlp = scale_shift(&img.apply(&self.norm)?, &m[4], &m[3])?.apply(&self.mlp)?;
+        let img = (&img + m[5].broadcast_mul(&img_mlp)?)?;
+
+        let txt = match (&self.context.mlp, txt_attn) {
+            (Some(mlp), Some(txt_attn)) => {
+                let txt = (txt + c[2].broadcast_mul(&txt_attn)?)?;
+                let txt_mlp = scale_shift(&txt.apply(&self.norm)?, &c[4], &c[3])?.apply(mlp)?;
+                Some((&txt + c[5].broadcast_mul(&txt_mlp)?)?)
+            }
+            _ => None,
+        };
+        Ok((img, txt))
+    }
+
+    fn layers(&mut self) -> Vec<&mut Arc<dyn QuantMethod>> {
+        let mut layers = vec![
+            &mut self.img_mod.lin,
+            &mut self.context.modulation.lin,
+            &mut self.mlp.lin1,
+            &mut self.mlp.lin2,
+        ];
+        if let Some(mlp) = &mut self.context.mlp {
+            layers.extend([&mut mlp.lin1, &mut mlp.lin2]);
+        }
+        layers.extend(self.attn.layers());
+        if let Some(attn2) = &mut self.attn2 {
+            layers.extend(attn2.layers());
+        }
+        layers
+    }
+}
+
+/// The multimodal diffusion transformer of Stable Diffusion 3 and 3.5, as diffusers `SD3Transformer2DModel`.
+#[derive(Debug, Clone)]
+pub struct MMDiT {
+    patch_embed: Conv2d,
+    pos_embed: Tensor,
+    time_in: MlpEmbedder,
+    vector_in: MlpEmbedder,
+    context_embedder: Arc<dyn QuantMethod>,
+    blocks: Vec<JointTransformerBlock>,
+    norm_out: Modulation,
+    norm_final: LayerNorm,
+    proj_out: Arc<dyn QuantMethod>,
+    patch_size: usize,
+    pos_embed_max_size: usize,
+    out_channels: usize,
+}
+
+impl MMDiT {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        if cfg.caption_projection_dim != dim {
+            diffusion_rs_common::bail!(
+                "Expected the caption projection dim to be the hidden size {dim}, got {}",
+                cfg.caption_projection_dim
+            );
+        }
+        let p = cfg.patch_size;
+        let patch_embed = diffusion_rs_common::conv2d(
+            cfg.in_channels,
+            dim,
+            p,
+            Conv2dConfig {
+                stride: p,
+                ..Default::default()
+            },
+            vb.pp("pos_embed.proj"),
+        )?;
+        let max = cfg.pos_embed_max_size;
+        let pos_embed = vb.pp("pos_embed").get((1, max * max, dim), "pos_embed")?;
+
+        let mut blocks = Vec::with_capacity(cfg.num_layers);
+        let vb_b = vb.pp("transformer_blocks");
+        for idx in NiceProgressBar::<_, 'r'>(0..cfg.num_layers, "Loading joint blocks") {
+            blocks.push(JointTransformerBlock::new(
+                idx + 1 == cfg.num_layers,
+                cfg.dual_attention_layers.contains(&idx),
+                cfg,
+                vb_b.pp(idx),
+            )?);
+        }
+
+        Ok(Self {
+            patch_embed,
+            pos_embed,
+            time_in: MlpEmbedder::new(
+                TIMESTEP_DIM,
+                dim,
+                cfg,
+                vb.pp("time_text_embed.timestep_embedder"),
+            )?,
+            vector_in: MlpEmbedder::new(
+                cfg.pooled_projection_dim,
+                dim,
+                cfg,
+                vb.pp("time_text_embed.text_embedder"),
+            )?,
+            context_embedder: diffusion_rs_backend::linear(
+                cfg.joint_attention_dim,
+                dim,
+                &cfg.quantization_config,
+                vb.pp("context_embedder"),
+            )?,
+            blocks,
+            norm_out: Modulation::new(dim, 2, cfg, vb.pp("norm_out"))?,
+            norm_final: layer_norm(dim, vb.clone())?,
+            proj_out: diffusion_rs_backend::linear(
+                dim,
+                p * p * cfg.out_channels,
+                &cfg.quantization_config,
+                vb.pp("proj_out"),
+            )?,
+            patch_size: p,
+            pos_embed_max_size: max,
+            out_channels: cfg.out_channels,
+        })
+    }
+
+    /// The position embeddings of a `h` by `w` grid of patches, cropped from the center of the full grid.
+    fn cropped_pos_embed(&self, h: usize, w: usize) -> Result<Tensor> {
+        let max = self.pos_embed_max_size;
+        if h > max || w > max {
+            diffusion_rs_common::bail!(
+                "The image is too large: {h}x{w} patches, the maximum is {max}x{max}"
+            );
+        }
+        let dim = self.pos_embed.dim(D::Minus1)?;
+        self.pos_embed
+            .reshape((1, max, max, dim))?
+            .narrow(1, (max - h) / 2, h)?
+            .narrow(2, (max - w) / 2, w)?
+            .reshape((1, h * w, dim))
+    }
+
+    /// Predict the flow velocity of the latents `img` of shape `(b, c, h, w)` at the `timesteps` in `[0, 1]`,
+    /// conditioned on the text embeddings `txt` and the pooled text embeddings `y`.
+    pub fn forward(
+        &self,
+        img: &Tensor,
+        txt: &Tensor,
+        timesteps: &Tensor,
+        y: &Tensor,
+    ) -> Result<Tensor> {
+        let (b, _c, h, w) = img.dims4()?;
+        let p = self.patch_size;
+        let (h, w) = (h / p, w / p);
+        let dtype = img.dtype();
+
+        let mut img = img
+            .apply(&self.patch_embed)?
+            .flatten_from(2)?
+            .transpose(1, 2)?
+            .broadcast_add(&self.cropped_pos_embed(h, w)?)?;
+        let vec_ = (timestep_embedding(timesteps, TIMESTEP_DIM, dtype)?.apply(&self.time_in)?
+            + y.apply(&self.vector_in)?)?;
+        let mut txt = Some(self.context_embedder.forward_autocast(txt)?);
+
+        for block in &self.blocks {
+            let Some(context) = &txt else {
+                diffusion_rs_common::bail!("Missing text tokens before the last block");
+            };
+            (img, txt) = block.forward(&img, context, &vec_)?;
+        }
+
+        let m = self.norm_out.forward(&vec_)?;
+        let img = scale_shift(&img.apply(&self.norm_final)?, &m[0], &m[1])?;
+        let img = self.proj_out.forward_autocast(&img)?;
+        // (b, h * w, p * p * c) -> (b, c, h * p, w * p)
+        img.reshape((b, h, w, p, p, self.out_channels))?
+            .permute((0, 5, 1, 3, 2, 4))?
+            .reshape((b, self.out_channels, h * p, w * p))
+    }
+}
+
+impl QuantizedModel for MMDiT {
+    fn match_devices_all_layers(&mut self, dev: &Device) -> Result<()> {
+        self.patch_embed = Conv2d::new(
+            self.patch_embed.weight().to_device(dev)?,
+            self.patch_embed
+                .bias()
+                .map(|b| b.to_device(dev))
+                .transpose()?,
+            *self.patch_embed.config(),
+        );
+        self.pos_embed = self.pos_embed.to_device(dev)?;
+        self.norm_final = self.norm_final.to_device(dev)?;
+        for block in &mut self.blocks {
+            block.norm = block.norm.to_device(dev)?;
+            block.attn.match_devices(dev)?;
+            if let Some(attn2) = &mut block.attn2 {
+                attn2.match_devices(dev)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn aggregate_layers(&mut self) -> Result<Vec<QuantizedModelLayer<'_>>> {
+        let mut layers = vec![QuantizedModelLayer(vec![
+            &mut self.context_embedder,
+            &mut self.time_in.linear_1,
+            &mut self.time_in.linear_2,
+            &mut self.vector_in.linear_1,
+            &mut self.vector_in.linear_2,
+            &mut self.norm_out.lin,
+            &mut self.proj_out,
+        ])];
+        for block in &mut self.blocks {
+            layers.push(QuantizedModelLayer(block.layers()));
+        }
+        Ok(layers)
+    }
+}
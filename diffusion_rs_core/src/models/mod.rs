@@ -1,15 +1,38 @@
 mod clip;
 mod flux;
+mod gemma2;
+mod lora;
+mod mmdit;
+mod pixart;
+mod sana;
+mod siglip;
 mod t5;
+mod unet;
 mod vaes;
 
 use std::sync::Arc;
 
-pub use clip::{ClipTextConfig, ClipTextTransformer};
+pub use clip::{
+    ClipTextConfig, ClipTextModelWithProjection, ClipTextTransformer, ClipVisionConfig,
+    ClipVisionModelWithProjection,
+};
 use diffusion_rs_backend::QuantMethod;
 use diffusion_rs_common::core::{Device, Result};
-pub use flux::{FluxConfig, FluxModel};
+pub use flux::{
+    ControlNetConfig as FluxControlNetConfig, ControlNetResiduals as FluxControlNetResiduals,
+    FluxConfig, FluxControlNet, FluxIpAdapter, FluxModel,
+    IpAdapterConditioning as FluxIpAdapterConditioning, ReduxConfig, ReduxImageEncoder,
+};
+pub use gemma2::{Gemma2Config, Gemma2Model};
+pub(crate) use lora::LoraAdapter;
+#[cfg(test)]
+pub(crate) use lora::LoraWeights;
+pub use mmdit::{MMDiTConfig, MMDiTModel};
+pub use pixart::{PixArtConfig, PixArtModel};
+pub use sana::{SanaConfig, SanaModel};
+pub use siglip::{SiglipVisionConfig, SiglipVisionTransformer};
 pub use t5::{T5Config, T5EncoderModel};
+pub use unet::{AddedCondition, UNet2DConditionConfig, UNet2DConditionModel};
 
 pub(crate) use vaes::{dispatch_load_vae_model, VAEModel};
 
@@ -0,0 +1,4 @@
+mod model;
+
+pub(crate) use model::{sincos_pos_embed, timestep_embedding};
+pub use model::{Config as PixArtConfig, PixArt as PixArtModel};
@@ -0,0 +1,443 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use diffusion_rs_backend::{QuantMethod, QuantizedConfig};
+use diffusion_rs_common::core::{DType, Device, Module, Result, Tensor, D};
+use diffusion_rs_common::nn::{Conv2d, Conv2dConfig, LayerNorm};
+use diffusion_rs_common::{NiceProgressBar, VarBuilder};
+use serde::Deserialize;
+use tracing::{span, Span};
+
+const TIMESTEP_DIM: usize = 256;
+
+fn default_norm_eps() -> f64 {
+    1e-6
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub num_attention_heads: usize,
+    pub attention_head_dim: usize,
+    pub in_channels: usize,
+    /// Twice `in_channels` when the model also predicts the variance, which is discarded.
+    pub out_channels: Option<usize>,
+    pub num_layers: usize,
+    pub patch_size: usize,
+    pub sample_size: usize,
+    pub caption_channels: usize,
+    pub cross_attention_dim: Option<usize>,
+    /// Scale of the position embedding grid. Defaults to `sample_size / 64`.
+    pub interpolation_scale: Option<f64>,
+    /// Whether the resolution and aspect ratio condition the model (PixArt-Alpha 1024px). Defaults to true when
+    /// `sample_size` is 128.
+    pub use_additional_conditions: Option<bool>,
+    pub norm_type: Option<String>,
+    #[serde(default = "default_norm_eps")]
+    pub norm_eps: f64,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+impl Config {
+    fn hidden_size(&self) -> usize {
+        self.num_attention_heads * self.attention_head_dim
+    }
+
+    pub fn out_channels(&self) -> usize {
+        self.out_channels.unwrap_or(self.in_channels)
+    }
+}
+
+fn layer_norm(dim: usize, eps: f64, vb: VarBuilder) -> Result<LayerNorm> {
+    let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
+    // Hack: use bias as 0s to take advantage of the fast kernel
+    let bs = ws.zeros_like()?;
+    Ok(LayerNorm::new(ws, bs, eps))
+}
+
+/// Sinusoidal embedding of the discrete timesteps, as `[cos, sin]`.
+pub(crate) fn timestep_embedding(t: &Tensor, dim: usize, dtype: DType) -> Result<Tensor> {
+    const MAX_PERIOD: f64 = 10000.;
+    let half = dim / 2;
+    let t = t.to_dtype(DType::F32)?;
+    let freqs = (Tensor::arange(0, half as u32, t.device())?.to_dtype(DType::F32)?
+        * (-MAX_PERIOD.ln() / half as f64))?
+        .exp()?;
+    let args = t.unsqueeze(1)?.broadcast_mul(&freqs.unsqueeze(0)?)?;
+    Tensor::cat(&[args.cos()?, args.sin()?], D::Minus1)?.to_dtype(dtype)
+}
+
+/// 1D sinusoidal embeddings of the positions, as `[sin, cos]`.
+fn sincos_embedding(dim: usize, pos: &[f64]) -> Vec<Vec<f32>> {
+    let half = dim / 2;
+    pos.iter()
+        .map(|p| {
+            let args = (0..half).map(|i| p / 10000f64.powf(i as f64 / half as f64));
+            args.clone()
+                .map(f64::sin)
+                .chain(args.map(f64::cos))
+                .map(|x| x as f32)
+                .collect()
+        })
+        .collect()
+}
+
+/// The fixed 2D sinusoidal position embeddings of a `h` by `w` grid of patches, as diffusers
+/// `get_2d_sincos_pos_embed`. The grid is scaled so that it spans `base_size / interpolation_scale` units.
+pub(crate) fn sincos_pos_embed(
+    dim: usize,
+    h: usize,
+    w: usize,
+    base_size: usize,
+    interpolation_scale: f64,
+    device: &Device,
+) -> Result<Tensor> {
+    let grid = |n: usize| {
+        (0..n)
+            .map(|i| i as f64 / (n as f64 / base_size as f64) / interpolation_scale)
+            .collect::<Vec<_>>()
+    };
+    let emb_w = sincos_embedding(dim / 2, &grid(w));
+    let emb_h = sincos_embedding(dim / 2, &grid(h));
+    let mut data = Vec::with_capacity(h * w * dim);
+    for row in &emb_h {
+        for col in &emb_w {
+            data.extend_from_slice(col);
+            data.extend_from_slice(row);
+        }
+    }
+    Tensor::from_vec(data, (1, h * w, dim), device)
+}
+
+#[derive(Debug, Clone)]
+struct MlpEmbedder {
+    linear_1: Arc<dyn QuantMethod>,
+    linear_2: Arc<dyn QuantMethod>,
+    /// Whether the activation is a tanh-approximated GELU instead of SiLU.
+    gelu: bool,
+}
+
+impl MlpEmbedder {
+    fn new(in_sz: usize, h_sz: usize, gelu: bool, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            linear_1: diffusion_rs_backend::linear(
+                in_sz,
+                h_sz,
+                &cfg.quantization_config,
+                vb.pp("linear_1"),
+            )?,
+            linear_2: diffusion_rs_backend::linear(
+                h_sz,
+                h_sz,
+                &cfg.quantization_config,
+                vb.pp("linear_2"),
+            )?,
+            gelu,
+        })
+    }
+}
+
+impl Module for MlpEmbedder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.linear_1.forward_autocast(xs)?;
+        let xs = if self.gelu { xs.gelu()? } else { xs.silu()? };
+        self.linear_2.forward_autocast(&xs)
+    }
+}
+
+fn scale_shift(xs: &Tensor, scale: &Tensor, shift: &Tensor) -> Result<Tensor> {
+    xs.broadcast_mul(&(scale + 1.)?)?.broadcast_add(shift)
+}
+
+/// Multi-head attention, optionally over a context with a key mask.
+#[derive(Debug, Clone)]
+struct Attention {
+    to_q: Arc<dyn QuantMethod>,
+    to_k: Arc<dyn QuantMethod>,
+    to_v: Arc<dyn QuantMethod>,
+    to_out: Arc<dyn QuantMethod>,
+    heads: usize,
+}
+
+impl Attention {
+    fn new(context_dim: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        let linear = |in_dim: usize, name: &str| {
+            diffusion_rs_backend::linear(in_dim, dim, &cfg.quantization_config, vb.pp(name))
+        };
+        Ok(Self {
+            to_q: linear(dim, "to_q")?,
+            to_k: linear(context_dim, "to_k")?,
+            to_v: linear(context_dim, "to_v")?,
+            to_out: linear(dim, "to_out.0")?,
+            heads: cfg.num_attention_heads,
+        })
+    }
+
+    /// Attend from `xs` to `context` (or to `xs` itself). `mask_bias` is added to the attention logits, of shape
+    /// `(b, 1, 1, context_len)`.
+    fn forward(
+        &self,
+        xs: &Tensor,
+        context: Option<&Tensor>,
+        mask_bias: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let context = context.unwrap_or(xs);
+        let b = xs.dim(0)?;
+        let split_heads = |xs: Tensor| -> Result<Tensor> {
+            let l = xs.dim(1)?;
+            xs.reshape((b, l, self.heads, ()))?
+                .transpose(1, 2)?
+                .contiguous()?
+                .to_dtype(DType::F32)
+        };
+        let q = split_heads(self.to_q.forward_autocast(xs)?)?;
+        let k = split_heads(self.to_k.forward_autocast(context)?)?;
+        let v = split_heads(self.to_v.forward_autocast(context)?)?;
+        let scale = 1. / (q.dim(D::Minus1)? as f64).sqrt();
+        let attn = match mask_bias {
+            None => diffusion_rs_backend::ops::sdpa(&q, &k, &v, scale as f32, 1.0)?,
+            Some(bias) => {
+                let logits = (q.matmul(&k.t()?)? * scale)?.broadcast_add(bias)?;
+                diffusion_rs_common::nn::ops::softmax_last_dim(&logits)?.matmul(&v)?
+            }
+        };
+        let attn = attn
+            .to_dtype(xs.dtype())?
+            .transpose(1, 2)?
+            .flatten_from(2)?;
+        self.to_out.forward_autocast(&attn)
+    }
+}
+
+/// Feed-forward layer with a tanh-approximated GELU activation.
+#[derive(Debug, Clone)]
+struct Mlp {
+    lin1: Arc<dyn QuantMethod>,
+    lin2: Arc<dyn QuantMethod>,
+}
+
+impl Mlp {
+    fn new(dim: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            lin1: diffusion_rs_backend::linear(
+                dim,
+                4 * dim,
+                &cfg.quantization_config,
+                vb.pp("net.0.proj"),
+            )?,
+            lin2: diffusion_rs_backend::linear(
+                4 * dim,
+                dim,
+                &cfg.quantization_config,
+                vb.pp("net.2"),
+            )?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.lin2
+            .forward_autocast(&self.lin1.forward_autocast(xs)?.gelu()?)
+    }
+}
+
+/// A transformer block with adaLN-single modulation: self-attention, cross-attention to the text, then MLP.
+#[derive(Debug, Clone)]
+struct TransformerBlock {
+    scale_shift_table: Tensor,
+    norm: LayerNorm,
+    attn1: Attention,
+    attn2: Attention,
+    ff: Mlp,
+    span: Span,
+}
+
+impl TransformerBlock {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        Ok(Self {
+            scale_shift_table: vb.get((6, dim), "scale_shift_table")?,
+            norm: layer_norm(dim, cfg.norm_eps, vb.clone())?,
+            attn1: Attention::new(dim, cfg, vb.pp("attn1"))?,
+            attn2: Attention::new(cfg.cross_attention_dim.unwrap_or(dim), cfg, vb.pp("attn2"))?,
+            ff: Mlp::new(dim, cfg, vb.pp("ff"))?,
+            span: span!(tracing::Level::TRACE, "pixart-block"),
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        context: &Tensor,
+        mask_bias: &Tensor,
+        temb: &Tensor,
+    ) -> Result<Tensor> {
+        let _span = self.span.enter();
+        let b = xs.dim(0)?;
+        // shift, scale and gate of the self-attention, then of the MLP.
+        let m = self
+            .scale_shift_table
+            .unsqueeze(0)?
+            .broadcast_add(&temb.reshape((b, 6, ()))?)?
+            .chunk(6, 1)?;
+
+        let normed = scale_shift(&xs.apply(&self.norm)?, &m[1], &m[0])?;
+        let xs = (xs + m[2].broadcast_mul(&self.attn1.forward(&normed, None, None)?)?)?;
+        let xs = (&xs + self.attn2.forward(&xs, Some(context), Some(mask_bias))?)?;
+        let normed = scale_shift(&xs.apply(&self.norm)?, &m[4], &m[3])?;
+        &xs + m[5].broadcast_mul(&normed.apply(&self.ff)?)?
+    }
+}
+
+/// The PixArt diffusion transformer, as diffusers `PixArtTransformer2DModel`. It predicts the noise of the
+/// latents of shape `(b, c, h, w)` at the discrete timesteps, cross-attending to the T5 embeddings of the
+/// prompt.
+#[derive(Debug, Clone)]
+pub struct PixArt {
+    patch_embed: Conv2d,
+    time_in: MlpEmbedder,
+    adaln: Arc<dyn QuantMethod>,
+    caption_projection: MlpEmbedder,
+    blocks: Vec<TransformerBlock>,
+    norm_out: LayerNorm,
+    scale_shift_table: Tensor,
+    proj_out: Arc<dyn QuantMethod>,
+    patch_size: usize,
+    base_size: usize,
+    interpolation_scale: f64,
+    in_channels: usize,
+    out_channels: usize,
+}
+
+impl PixArt {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        if let Some(norm_type) = cfg.norm_type.as_deref().filter(|x| *x != "ada_norm_single") {
+            diffusion_rs_common::bail!("Unsupported PixArt norm type `{norm_type}`");
+        }
+        if cfg
+            .use_additional_conditions
+            .unwrap_or(cfg.sample_size == 128)
+        {
+            diffusion_rs_common::bail!(
+                "PixArt models conditioned on the resolution and aspect ratio are not supported"
+            );
+        }
+        let dim = cfg.hidden_size();
+        let p = cfg.patch_size;
+        let patch_embed = diffusion_rs_common::conv2d(
+            cfg.in_channels,
+            dim,
+            p,
+            Conv2dConfig {
+                stride: p,
+                ..Default::default()
+            },
+            vb.pp("pos_embed.proj"),
+        )?;
+
+        let mut blocks = Vec::with_capacity(cfg.num_layers);
+        let vb_b = vb.pp("transformer_blocks");
+        for idx in NiceProgressBar::<_, 'r'>(0..cfg.num_layers, "Loading transformer blocks") {
+            blocks.push(TransformerBlock::new(cfg, vb_b.pp(idx))?);
+        }
+
+        Ok(Self {
+            patch_embed,
+            time_in: MlpEmbedder::new(
+                TIMESTEP_DIM,
+                dim,
+                false,
+                cfg,
+                vb.pp("adaln_single.emb.timestep_embedder"),
+            )?,
+            adaln: diffusion_rs_backend::linear(
+                dim,
+                6 * dim,
+                &cfg.quantization_config,
+                vb.pp("adaln_single.linear"),
+            )?,
+            caption_projection: MlpEmbedder::new(
+                cfg.caption_channels,
+                dim,
+                true,
+                cfg,
+                vb.pp("caption_projection"),
+            )?,
+            blocks,
+            norm_out: layer_norm(dim, cfg.norm_eps, vb.clone())?,
+            scale_shift_table: vb.get((2, dim), "scale_shift_table")?,
+            proj_out: diffusion_rs_backend::linear(
+                dim,
+                p * p * cfg.out_channels(),
+                &cfg.quantization_config,
+                vb.pp("proj_out"),
+            )?,
+            patch_size: p,
+            base_size: cfg.sample_size / p,
+            interpolation_scale: cfg
+                .interpolation_scale
+                .unwrap_or((cfg.sample_size / 64).max(1) as f64),
+            in_channels: cfg.in_channels,
+            out_channels: cfg.out_channels(),
+        })
+    }
+
+    /// Predict the noise of the latents `xs` at the `timesteps`, conditioned on the text embeddings `context`.
+    /// `context_mask` is 1 for the text tokens and 0 for the padding, of shape `(b, context_len)`. If the model
+    /// also predicts the variance, it is discarded.
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        timesteps: &Tensor,
+        context: &Tensor,
+        context_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let (b, _c, h, w) = xs.dims4()?;
+        let p = self.patch_size;
+        let (h, w) = (h / p, w / p);
+        let dtype = xs.dtype();
+
+        let dim = self.scale_shift_table.dim(1)?;
+        let pos_embed = sincos_pos_embed(
+            dim,
+            h,
+            w,
+            self.base_size,
+            self.interpolation_scale,
+            xs.device(),
+        )?
+        .to_dtype(dtype)?;
+        let mut xs = xs
+            .apply(&self.patch_embed)?
+            .flatten_from(2)?
+            .transpose(1, 2)?
+            .broadcast_add(&pos_embed)?;
+
+        let embedded_timestep =
+            timestep_embedding(timesteps, TIMESTEP_DIM, dtype)?.apply(&self.time_in)?;
+        let temb = self.adaln.forward_autocast(&embedded_timestep.silu()?)?;
+        let context = context.apply(&self.caption_projection)?;
+        let mask_bias = ((context_mask.to_dtype(DType::F32)? - 1.)? * 1e9)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+
+        for block in &self.blocks {
+            xs = block.forward(&xs, &context, &mask_bias, &temb)?;
+        }
+
+        let m = self
+            .scale_shift_table
+            .unsqueeze(0)?
+            .broadcast_add(&embedded_timestep.unsqueeze(1)?)?
+            .chunk(2, 1)?;
+        let xs = scale_shift(&xs.apply(&self.norm_out)?, &m[1], &m[0])?;
+        let xs = self.proj_out.forward_autocast(&xs)?;
+        // (b, h * w, p * p * c) -> (b, c, h * p, w * p)
+        xs.reshape((b, h, w, p, p, self.out_channels))?
+            .permute((0, 5, 1, 3, 2, 4))?
+            .reshape((b, self.out_channels, h * p, w * p))?
+            .narrow(1, 0, self.in_channels)
+    }
+}
@@ -0,0 +1,511 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use diffusion_rs_backend::{QuantMethod, QuantizedConfig};
+use diffusion_rs_common::core::{DType, Module, Result, Tensor, D};
+use diffusion_rs_common::nn::{
+    layer_norm::RmsNormNonQuantized, Conv2d, Conv2dConfig, LayerNorm, RmsNorm,
+};
+use diffusion_rs_common::{conv2d, conv2d_no_bias, NiceProgressBar, VarBuilder};
+use serde::Deserialize;
+use tracing::{span, Span};
+
+use crate::models::pixart::{sincos_pos_embed, timestep_embedding};
+
+const TIMESTEP_DIM: usize = 256;
+
+fn default_mlp_ratio() -> f64 {
+    2.5
+}
+
+fn default_norm_eps() -> f64 {
+    1e-6
+}
+
+fn default_timestep_scale() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub num_attention_heads: usize,
+    pub attention_head_dim: usize,
+    pub num_cross_attention_heads: usize,
+    pub cross_attention_head_dim: usize,
+    pub cross_attention_dim: usize,
+    pub caption_channels: usize,
+    pub in_channels: usize,
+    pub out_channels: Option<usize>,
+    #[serde(default = "default_mlp_ratio")]
+    pub mlp_ratio: f64,
+    pub num_layers: usize,
+    pub patch_size: usize,
+    pub sample_size: usize,
+    /// Scale of the sinusoidal position embedding grid. There is no position embedding if it is not set.
+    pub interpolation_scale: Option<f64>,
+    #[serde(default)]
+    pub attention_bias: bool,
+    #[serde(default = "default_norm_eps")]
+    pub norm_eps: f64,
+    /// `rms_norm_across_heads` to normalize the queries and keys of the attention layers (Sana 1.5).
+    pub qk_norm: Option<String>,
+    #[serde(default)]
+    pub guidance_embeds: bool,
+    /// Factor of the timesteps given to the model, on top of `num_train_timesteps`.
+    #[serde(default = "default_timestep_scale")]
+    pub timestep_scale: f64,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+impl Config {
+    fn hidden_size(&self) -> usize {
+        self.num_attention_heads * self.attention_head_dim
+    }
+
+    pub fn out_channels(&self) -> usize {
+        self.out_channels.unwrap_or(self.in_channels)
+    }
+}
+
+fn layer_norm(dim: usize, eps: f64, vb: VarBuilder) -> Result<LayerNorm> {
+    let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
+    // Hack: use bias as 0s to take advantage of the fast kernel
+    let bs = ws.zeros_like()?;
+    Ok(LayerNorm::new(ws, bs, eps))
+}
+
+fn linear(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    cfg: &Config,
+    vb: VarBuilder,
+) -> Result<Arc<dyn QuantMethod>> {
+    if bias {
+        diffusion_rs_backend::linear(in_dim, out_dim, &cfg.quantization_config, vb)
+    } else {
+        diffusion_rs_backend::linear_no_bias(in_dim, out_dim, &cfg.quantization_config, vb)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MlpEmbedder {
+    linear_1: Arc<dyn QuantMethod>,
+    linear_2: Arc<dyn QuantMethod>,
+    /// Whether the activation is a tanh-approximated GELU instead of SiLU.
+    gelu: bool,
+}
+
+impl MlpEmbedder {
+    fn new(in_sz: usize, h_sz: usize, gelu: bool, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            linear_1: linear(in_sz, h_sz, true, cfg, vb.pp("linear_1"))?,
+            linear_2: linear(h_sz, h_sz, true, cfg, vb.pp("linear_2"))?,
+            gelu,
+        })
+    }
+}
+
+impl Module for MlpEmbedder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.linear_1.forward_autocast(xs)?;
+        let xs = if self.gelu { xs.gelu()? } else { xs.silu()? };
+        self.linear_2.forward_autocast(&xs)
+    }
+}
+
+fn scale_shift(xs: &Tensor, scale: &Tensor, shift: &Tensor) -> Result<Tensor> {
+    xs.broadcast_mul(&(scale + 1.)?)?.broadcast_add(shift)
+}
+
+/// The attention projections, with the optional RMS norm of the queries and keys across the heads.
+#[derive(Debug, Clone)]
+struct AttentionProjections {
+    to_q: Arc<dyn QuantMethod>,
+    to_k: Arc<dyn QuantMethod>,
+    to_v: Arc<dyn QuantMethod>,
+    to_out: Arc<dyn QuantMethod>,
+    norm: Option<(RmsNorm<RmsNormNonQuantized>, RmsNorm<RmsNormNonQuantized>)>,
+}
+
+impl AttentionProjections {
+    fn new(
+        dim: usize,
+        inner_dim: usize,
+        context_dim: usize,
+        bias: bool,
+        cfg: &Config,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let norm = match cfg.qk_norm.as_deref() {
+            None => None,
+            Some("rms_norm_across_heads") => Some((
+                RmsNorm::<RmsNormNonQuantized>::new(
+                    vb.pp("norm_q").get(inner_dim, "weight")?,
+                    1e-5,
+                ),
+                RmsNorm::<RmsNormNonQuantized>::new(
+                    vb.pp("norm_k").get(inner_dim, "weight")?,
+                    1e-5,
+                ),
+            )),
+            Some(other) => diffusion_rs_common::bail!("Unsupported Sana qk norm `{other}`"),
+        };
+        Ok(Self {
+            to_q: linear(dim, inner_dim, bias, cfg, vb.pp("to_q"))?,
+            to_k: linear(context_dim, inner_dim, bias, cfg, vb.pp("to_k"))?,
+            to_v: linear(context_dim, inner_dim, bias, cfg, vb.pp("to_v"))?,
+            to_out: linear(inner_dim, dim, true, cfg, vb.pp("to_out.0"))?,
+            norm,
+        })
+    }
+
+    /// The queries, keys and values, of shape `(b, seq_len, inner_dim)`.
+    fn qkv(&self, xs: &Tensor, context: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        let q = self.to_q.forward_autocast(xs)?;
+        let k = self.to_k.forward_autocast(context)?;
+        let v = self.to_v.forward_autocast(context)?;
+        let (q, k) = match &self.norm {
+            Some((norm_q, norm_k)) => (q.apply(norm_q)?, k.apply(norm_k)?),
+            None => (q, k),
+        };
+        Ok((q, k, v))
+    }
+}
+
+/// ReLU linear self-attention, whose cost is linear in the number of tokens.
+#[derive(Debug, Clone)]
+struct LinearAttention {
+    proj: AttentionProjections,
+    heads: usize,
+}
+
+impl LinearAttention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        Ok(Self {
+            proj: AttentionProjections::new(dim, dim, dim, cfg.attention_bias, cfg, vb)?,
+            heads: cfg.num_attention_heads,
+        })
+    }
+}
+
+impl Module for LinearAttention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        const EPS: f64 = 1e-15;
+        let (b, n, _) = xs.dims3()?;
+        let (q, k, v) = self.proj.qkv(xs, xs)?;
+        // (b, n, h * d) -> (b, h, d, n)
+        let split_heads = |xs: Tensor| -> Result<Tensor> {
+            xs.transpose(1, 2)?
+                .reshape((b, self.heads, (), n))?
+                .to_dtype(DType::F32)
+        };
+        let q = split_heads(q)?.relu()?.contiguous()?;
+        let k = split_heads(k)?.relu()?.t()?.contiguous()?;
+        let v = split_heads(v)?;
+        let d = v.dim(2)?;
+
+        // A row of ones in the values computes the normalizer.
+        let v = Tensor::cat(
+            &[
+                v,
+                Tensor::ones((b, self.heads, 1, n), DType::F32, xs.device())?,
+            ],
+            2,
+        )?;
+        let out = v.matmul(&k)?.matmul(&q)?;
+        let out = out
+            .narrow(2, 0, d)?
+            .broadcast_div(&(out.narrow(2, d, 1)? + EPS)?)?;
+        let out = out
+            .reshape((b, (), n))?
+            .transpose(1, 2)?
+            .to_dtype(xs.dtype())?;
+        self.proj.to_out.forward_autocast(&out)
+    }
+}
+
+/// Multi-head cross-attention to the text, with a key mask.
+#[derive(Debug, Clone)]
+struct CrossAttention {
+    proj: AttentionProjections,
+    heads: usize,
+}
+
+impl CrossAttention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let inner_dim = cfg.num_cross_attention_heads * cfg.cross_attention_head_dim;
+        Ok(Self {
+            proj: AttentionProjections::new(
+                cfg.hidden_size(),
+                inner_dim,
+                cfg.cross_attention_dim,
+                true,
+                cfg,
+                vb,
+            )?,
+            heads: cfg.num_cross_attention_heads,
+        })
+    }
+
+    /// `mask_bias` is added to the attention logits, of shape `(b, 1, 1, context_len)`.
+    fn forward(&self, xs: &Tensor, context: &Tensor, mask_bias: &Tensor) -> Result<Tensor> {
+        let b = xs.dim(0)?;
+        let (q, k, v) = self.proj.qkv(xs, context)?;
+        let split_heads = |xs: Tensor| -> Result<Tensor> {
+            let l = xs.dim(1)?;
+            xs.reshape((b, l, self.heads, ()))?
+                .transpose(1, 2)?
+                .contiguous()?
+                .to_dtype(DType::F32)
+        };
+        let (q, k, v) = (split_heads(q)?, split_heads(k)?, split_heads(v)?);
+        let scale = 1. / (q.dim(D::Minus1)? as f64).sqrt();
+        let logits = (q.matmul(&k.t()?)? * scale)?.broadcast_add(mask_bias)?;
+        let attn = diffusion_rs_common::nn::ops::softmax_last_dim(&logits)?.matmul(&v)?;
+        let attn = attn
+            .to_dtype(xs.dtype())?
+            .transpose(1, 2)?
+            .flatten_from(2)?;
+        self.proj.to_out.forward_autocast(&attn)
+    }
+}
+
+/// Gated inverted bottleneck convolution, with a depthwise 3x3 convolution, used as the feed-forward layer on
+/// the image.
+#[derive(Debug, Clone)]
+struct GluMbConv {
+    conv_inverted: Conv2d,
+    conv_depth: Conv2d,
+    conv_point: Conv2d,
+}
+
+impl GluMbConv {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        let hidden = (cfg.mlp_ratio * dim as f64) as usize;
+        let depth_cfg = Conv2dConfig {
+            padding: 1,
+            groups: 2 * hidden,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv_inverted: conv2d(
+                dim,
+                2 * hidden,
+                1,
+                Default::default(),
+                vb.pp("conv_inverted"),
+            )?,
+            conv_depth: conv2d(2 * hidden, 2 * hidden, 3, depth_cfg, vb.pp("conv_depth"))?,
+            conv_point: conv2d_no_bias(hidden, dim, 1, Default::default(), vb.pp("conv_point"))?,
+        })
+    }
+}
+
+impl Module for GluMbConv {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs
+            .apply(&self.conv_inverted)?
+            .silu()?
+            .apply(&self.conv_depth)?;
+        let xs = xs.chunk(2, 1)?;
+        (&xs[0] * xs[1].silu()?)?.apply(&self.conv_point)
+    }
+}
+
+/// A transformer block with adaLN-single modulation: linear self-attention, cross-attention to the text, then
+/// the convolutional feed-forward layer.
+#[derive(Debug, Clone)]
+struct TransformerBlock {
+    scale_shift_table: Tensor,
+    norm: LayerNorm,
+    attn1: LinearAttention,
+    attn2: CrossAttention,
+    ff: GluMbConv,
+    span: Span,
+}
+
+impl TransformerBlock {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let dim = cfg.hidden_size();
+        Ok(Self {
+            scale_shift_table: vb.get((6, dim), "scale_shift_table")?,
+            norm: layer_norm(dim, cfg.norm_eps, vb.clone())?,
+            attn1: LinearAttention::new(cfg, vb.pp("attn1"))?,
+            attn2: CrossAttention::new(cfg, vb.pp("attn2"))?,
+            ff: GluMbConv::new(cfg, vb.pp("ff"))?,
+            span: span!(tracing::Level::TRACE, "sana-block"),
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        context: &Tensor,
+        mask_bias: &Tensor,
+        temb: &Tensor,
+        (h, w): (usize, usize),
+    ) -> Result<Tensor> {
+        let _span = self.span.enter();
+        let (b, _, c) = xs.dims3()?;
+        // shift, scale and gate of the self-attention, then of the feed-forward layer.
+        let m = self
+            .scale_shift_table
+            .unsqueeze(0)?
+            .broadcast_add(&temb.reshape((b, 6, ()))?)?
+            .chunk(6, 1)?;
+
+        let normed = scale_shift(&xs.apply(&self.norm)?, &m[1], &m[0])?;
+        let xs = (xs + m[2].broadcast_mul(&self.attn1.forward(&normed)?)?)?;
+        let xs = (&xs + self.attn2.forward(&xs, context, mask_bias)?)?;
+        let normed = scale_shift(&xs.apply(&self.norm)?, &m[4], &m[3])?;
+        let ff = normed
+            .reshape((b, h, w, c))?
+            .permute((0, 3, 1, 2))?
+            .apply(&self.ff)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        &xs + m[5].broadcast_mul(&ff)?
+    }
+}
+
+/// The Sana diffusion transformer, as diffusers `SanaTransformer2DModel`. It predicts the velocity of the latents
+/// of shape `(b, c, h, w)` at the flow-matching sigmas, cross-attending to the Gemma 2 embeddings of the
+/// prompt.
+#[derive(Debug, Clone)]
+pub struct Sana {
+    patch_embed: Conv2d,
+    time_in: MlpEmbedder,
+    adaln: Arc<dyn QuantMethod>,
+    caption_projection: MlpEmbedder,
+    caption_norm: RmsNorm<RmsNormNonQuantized>,
+    blocks: Vec<TransformerBlock>,
+    norm_out: LayerNorm,
+    scale_shift_table: Tensor,
+    proj_out: Arc<dyn QuantMethod>,
+    patch_size: usize,
+    base_size: usize,
+    interpolation_scale: Option<f64>,
+    out_channels: usize,
+    timestep_scale: f64,
+}
+
+impl Sana {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        if cfg.guidance_embeds {
+            diffusion_rs_common::bail!("Guidance-distilled Sana models are not supported");
+        }
+        let dim = cfg.hidden_size();
+        let p = cfg.patch_size;
+        let patch_embed = conv2d(
+            cfg.in_channels,
+            dim,
+            p,
+            Conv2dConfig {
+                stride: p,
+                ..Default::default()
+            },
+            vb.pp("patch_embed.proj"),
+        )?;
+
+        let mut blocks = Vec::with_capacity(cfg.num_layers);
+        let vb_b = vb.pp("transformer_blocks");
+        for idx in NiceProgressBar::<_, 'r'>(0..cfg.num_layers, "Loading transformer blocks") {
+            blocks.push(TransformerBlock::new(cfg, vb_b.pp(idx))?);
+        }
+
+        Ok(Self {
+            patch_embed,
+            time_in: MlpEmbedder::new(
+                TIMESTEP_DIM,
+                dim,
+                false,
+                cfg,
+                vb.pp("time_embed.emb.timestep_embedder"),
+            )?,
+            adaln: linear(dim, 6 * dim, true, cfg, vb.pp("time_embed.linear"))?,
+            caption_projection: MlpEmbedder::new(
+                cfg.caption_channels,
+                dim,
+                true,
+                cfg,
+                vb.pp("caption_projection"),
+            )?,
+            caption_norm: RmsNorm::<RmsNormNonQuantized>::new(
+                vb.pp("caption_norm").get(dim, "weight")?,
+                1e-5,
+            ),
+            blocks,
+            norm_out: layer_norm(dim, cfg.norm_eps, vb.clone())?,
+            scale_shift_table: vb.get((2, dim), "scale_shift_table")?,
+            proj_out: linear(
+                dim,
+                p * p * cfg.out_channels(),
+                true,
+                cfg,
+                vb.pp("proj_out"),
+            )?,
+            patch_size: p,
+            base_size: cfg.sample_size / p,
+            interpolation_scale: cfg.interpolation_scale,
+            out_channels: cfg.out_channels(),
+            timestep_scale: cfg.timestep_scale,
+        })
+    }
+
+    /// Predict the velocity of the latents `xs` at the `sigmas`, conditioned on the text embeddings `context`.
+    /// `context_mask` is 1 for the text tokens and 0 for the padding, of shape `(b, context_len)`.
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        sigmas: &Tensor,
+        context: &Tensor,
+        context_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let (b, _c, h, w) = xs.dims4()?;
+        let p = self.patch_size;
+        let (h, w) = (h / p, w / p);
+        let dtype = xs.dtype();
+
+        let dim = self.scale_shift_table.dim(1)?;
+        let mut xs = xs
+            .apply(&self.patch_embed)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        if let Some(interpolation_scale) = self.interpolation_scale {
+            let pos_embed =
+                sincos_pos_embed(dim, h, w, self.base_size, interpolation_scale, xs.device())?;
+            xs = xs.broadcast_add(&pos_embed.to_dtype(dtype)?)?;
+        }
+
+        let timesteps = (sigmas * (1000. * self.timestep_scale))?;
+        let embedded_timestep =
+            timestep_embedding(&timesteps, TIMESTEP_DIM, dtype)?.apply(&self.time_in)?;
+        let temb = self.adaln.forward_autocast(&embedded_timestep.silu()?)?;
+        let context = context
+            .apply(&self.caption_projection)?
+            .apply(&self.caption_norm)?;
+        let mask_bias = ((context_mask.to_dtype(DType::F32)? - 1.)? * 1e9)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+
+        for block in &self.blocks {
+            xs = block.forward(&xs, &context, &mask_bias, &temb, (h, w))?;
+        }
+
+        let m = self
+            .scale_shift_table
+            .unsqueeze(0)?
+            .broadcast_add(&embedded_timestep.unsqueeze(1)?)?
+            .chunk(2, 1)?;
+        let xs = scale_shift(&xs.apply(&self.norm_out)?, &m[1], &m[0])?;
+        let xs = self.proj_out.forward_autocast(&xs)?;
+        // (b, h * w, p * p * c) -> (b, c, h * p, w * p)
+        xs.reshape((b, h, w, p, p, self.out_channels))?
+            .permute((0, 5, 1, 3, 2, 4))?
+            .reshape((b, self.out_channels, h * p, w * p))
+    }
+}
@@ -0,0 +1,188 @@
+#![allow(clippy::cast_precision_loss)]
+
+use diffusion_rs_common::core::{DType, Module, Result, Tensor};
+use diffusion_rs_common::nn::{Activation, Conv2d, Conv2dConfig, Embedding, LayerNorm, Linear};
+use diffusion_rs_common::{conv2d, embedding, layer_norm, linear, VarBuilder};
+use serde::Deserialize;
+
+fn default_num_channels() -> usize {
+    3
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::GeluPytorchTanh
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-6
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SiglipVisionConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    pub image_size: usize,
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+}
+
+impl Attention {
+    fn new(cfg: &SiglipVisionConfig, vb: VarBuilder) -> Result<Self> {
+        let h = cfg.hidden_size;
+        Ok(Self {
+            q_proj: linear(h, h, vb.pp("q_proj"))?,
+            k_proj: linear(h, h, vb.pp("k_proj"))?,
+            v_proj: linear(h, h, vb.pp("v_proj"))?,
+            out_proj: linear(h, h, vb.pp("out_proj"))?,
+            num_heads: cfg.num_attention_heads,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, l, h) = xs.dims3()?;
+        let head_dim = h / self.num_heads;
+        let heads = |xs: Tensor| -> Result<Tensor> {
+            xs.reshape((b, l, self.num_heads, head_dim))?
+                .transpose(1, 2)?
+                .to_dtype(DType::F32)?
+                .contiguous()
+        };
+        let q = heads(self.q_proj.forward(xs)?)?;
+        let k = heads(self.k_proj.forward(xs)?)?;
+        let v = heads(self.v_proj.forward(xs)?)?;
+        let scale = 1. / (head_dim as f64).sqrt();
+        let attn = diffusion_rs_backend::ops::sdpa(&q, &k, &v, scale as f32, 1.0)?
+            .to_dtype(xs.dtype())?
+            .transpose(1, 2)?
+            .reshape((b, l, h))?;
+        self.out_proj.forward(&attn)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    fc1: Linear,
+    fc2: Linear,
+    act: Activation,
+}
+
+impl Mlp {
+    fn new(cfg: &SiglipVisionConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            fc1: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("fc1"))?,
+            fc2: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("fc2"))?,
+            act: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.fc2.forward(&self.fc1.forward(xs)?.apply(&self.act)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EncoderLayer {
+    layer_norm1: LayerNorm,
+    self_attn: Attention,
+    layer_norm2: LayerNorm,
+    mlp: Mlp,
+}
+
+impl EncoderLayer {
+    fn new(cfg: &SiglipVisionConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            layer_norm1: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm1"))?,
+            self_attn: Attention::new(cfg, vb.pp("self_attn"))?,
+            layer_norm2: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("layer_norm2"))?,
+            mlp: Mlp::new(cfg, vb.pp("mlp"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = (xs + self.self_attn.forward(&xs.apply(&self.layer_norm1)?)?)?;
+        &xs + self.mlp.forward(&xs.apply(&self.layer_norm2)?)?
+    }
+}
+
+/// The vision tower of SigLIP, as `SiglipVisionModel`, without the attention pooling head.
+#[derive(Debug, Clone)]
+pub struct SiglipVisionTransformer {
+    patch_embedding: Conv2d,
+    position_embedding: Embedding,
+    layers: Vec<EncoderLayer>,
+    post_layernorm: LayerNorm,
+    image_size: usize,
+}
+
+impl SiglipVisionTransformer {
+    pub fn new(vb: VarBuilder, cfg: &SiglipVisionConfig) -> Result<Self> {
+        let vb = if vb.contains_tensor("vision_model.post_layernorm.weight") {
+            vb.pp("vision_model")
+        } else {
+            vb
+        };
+        let num_patches = (cfg.image_size / cfg.patch_size).pow(2);
+        let patch_embedding = conv2d(
+            cfg.num_channels,
+            cfg.hidden_size,
+            cfg.patch_size,
+            Conv2dConfig {
+                stride: cfg.patch_size,
+                ..Default::default()
+            },
+            vb.pp("embeddings.patch_embedding"),
+        )?;
+        let position_embedding = embedding(
+            num_patches,
+            cfg.hidden_size,
+            vb.pp("embeddings.position_embedding"),
+        )?;
+        let layers = (0..cfg.num_hidden_layers)
+            .map(|i| EncoderLayer::new(cfg, vb.pp("encoder.layers").pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let post_layernorm =
+            layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("post_layernorm"))?;
+        Ok(Self {
+            patch_embedding,
+            position_embedding,
+            layers,
+            post_layernorm,
+            image_size: cfg.image_size,
+        })
+    }
+
+    /// The height and width of the input images.
+    pub fn image_size(&self) -> usize {
+        self.image_size
+    }
+
+    /// Encode `(b, c, image_size, image_size)` pixel values, normalized to [-1, 1], into the last hidden state of
+    /// shape `(b, num_patches, hidden_size)`.
+    pub fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let xs = pixel_values
+            .apply(&self.patch_embedding)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let mut xs = xs.broadcast_add(&self.position_embedding.embeddings().unsqueeze(0)?)?;
+        for layer in &self.layers {
+            xs = layer.forward(&xs)?;
+        }
+        xs.apply(&self.post_layernorm)
+    }
+}
@@ -0,0 +1,3 @@
+mod model;
+
+pub use model::{AddedCondition, Config as UNet2DConditionConfig, UNet2DConditionModel};
@@ -0,0 +1,802 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use diffusion_rs_common::core::{DType, Module, Result, Tensor, D};
+use diffusion_rs_common::nn::{Activation, Conv2d, Conv2dConfig, GroupNorm, LayerNorm, Linear};
+use diffusion_rs_common::{conv2d, group_norm, layer_norm, linear, linear_no_bias, VarBuilder};
+use serde::Deserialize;
+use tracing::{span, Span};
+
+/// A config value which is either shared by all blocks or given per block.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PerBlock {
+    Shared(usize),
+    PerBlock(Vec<usize>),
+}
+
+impl PerBlock {
+    fn expand(&self, num_blocks: usize) -> Result<Vec<usize>> {
+        match self {
+            Self::Shared(x) => Ok(vec![*x; num_blocks]),
+            Self::PerBlock(xs) if xs.len() == num_blocks => Ok(xs.clone()),
+            Self::PerBlock(xs) => diffusion_rs_common::bail!(
+                "Expected one value per block ({num_blocks}), got {}",
+                xs.len()
+            ),
+        }
+    }
+}
+
+fn default_one() -> PerBlock {
+    PerBlock::Shared(1)
+}
+
+fn default_mid_block_scale_factor() -> f64 {
+    1.
+}
+
+fn default_flip_sin_to_cos() -> bool {
+    true
+}
+
+fn default_norm_eps() -> f64 {
+    1e-5
+}
+
+fn default_norm_num_groups() -> usize {
+    32
+}
+
+fn default_downsample_padding() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub in_channels: usize,
+    pub out_channels: usize,
+    pub block_out_channels: Vec<usize>,
+    pub down_block_types: Vec<String>,
+    pub up_block_types: Vec<String>,
+    pub layers_per_block: usize,
+    /// The number of attention heads. Older configs name this `attention_head_dim`.
+    pub attention_head_dim: PerBlock,
+    pub num_attention_heads: Option<PerBlock>,
+    pub cross_attention_dim: usize,
+    #[serde(default = "default_one")]
+    pub transformer_layers_per_block: PerBlock,
+    #[serde(default)]
+    pub use_linear_projection: bool,
+    #[serde(default = "default_flip_sin_to_cos")]
+    pub flip_sin_to_cos: bool,
+    #[serde(default)]
+    pub freq_shift: f64,
+    #[serde(default = "default_norm_eps")]
+    pub norm_eps: f64,
+    #[serde(default = "default_norm_num_groups")]
+    pub norm_num_groups: usize,
+    #[serde(default = "default_downsample_padding")]
+    pub downsample_padding: usize,
+    #[serde(default = "default_mid_block_scale_factor")]
+    pub mid_block_scale_factor: f64,
+    pub mid_block_type: Option<String>,
+    /// `text_time` for SDXL, which is conditioned on the pooled text embeddings and the image size and crop.
+    pub addition_embed_type: Option<String>,
+    pub addition_time_embed_dim: Option<usize>,
+    pub projection_class_embeddings_input_dim: Option<usize>,
+}
+
+impl Config {
+    fn num_attention_heads(&self) -> Result<Vec<usize>> {
+        self.num_attention_heads
+            .as_ref()
+            .unwrap_or(&self.attention_head_dim)
+            .expand(self.block_out_channels.len())
+    }
+}
+
+/// Sinusoidal timestep embedding, as diffusers `Timesteps`.
+fn timestep_embedding(
+    t: &Tensor,
+    dim: usize,
+    flip_sin_to_cos: bool,
+    freq_shift: f64,
+    dtype: DType,
+) -> Result<Tensor> {
+    const MAX_PERIOD: f64 = 10000.;
+    let half = dim / 2;
+    let exponent = (Tensor::arange(0, half as u32, t.device())?.to_dtype(DType::F32)?
+        * (-MAX_PERIOD.ln() / (half as f64 - freq_shift)))?;
+    let args = t
+        .to_dtype(DType::F32)?
+        .unsqueeze(1)?
+        .broadcast_mul(&exponent.exp()?.unsqueeze(0)?)?;
+    let emb = if flip_sin_to_cos {
+        Tensor::cat(&[args.cos()?, args.sin()?], D::Minus1)?
+    } else {
+        Tensor::cat(&[args.sin()?, args.cos()?], D::Minus1)?
+    };
+    emb.to_dtype(dtype)
+}
+
+#[derive(Debug, Clone)]
+struct TimestepEmbedding {
+    linear_1: Linear,
+    linear_2: Linear,
+}
+
+impl TimestepEmbedding {
+    fn new(in_channels: usize, time_embed_dim: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            linear_1: linear(in_channels, time_embed_dim, vb.pp("linear_1"))?,
+            linear_2: linear(time_embed_dim, time_embed_dim, vb.pp("linear_2"))?,
+        })
+    }
+}
+
+impl Module for TimestepEmbedding {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.linear_1)?.silu()?.apply(&self.linear_2)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ResnetBlock2D {
+    norm1: GroupNorm,
+    conv1: Conv2d,
+    time_emb_proj: Linear,
+    norm2: GroupNorm,
+    conv2: Conv2d,
+    conv_shortcut: Option<Conv2d>,
+    output_scale_factor: f64,
+    span: Span,
+}
+
+impl ResnetBlock2D {
+    fn new(
+        in_c: usize,
+        out_c: usize,
+        temb_c: usize,
+        output_scale_factor: f64,
+        cfg: &Config,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let conv_cfg = Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let conv_shortcut = if in_c == out_c {
+            None
+        } else {
+            Some(conv2d(
+                in_c,
+                out_c,
+                1,
+                Default::default(),
+                vb.pp("conv_shortcut"),
+            )?)
+        };
+        Ok(Self {
+            norm1: group_norm(cfg.norm_num_groups, in_c, cfg.norm_eps, vb.pp("norm1"))?,
+            conv1: conv2d(in_c, out_c, 3, conv_cfg, vb.pp("conv1"))?,
+            time_emb_proj: linear(temb_c, out_c, vb.pp("time_emb_proj"))?,
+            norm2: group_norm(cfg.norm_num_groups, out_c, cfg.norm_eps, vb.pp("norm2"))?,
+            conv2: conv2d(out_c, out_c, 3, conv_cfg, vb.pp("conv2"))?,
+            conv_shortcut,
+            output_scale_factor,
+            span: span!(tracing::Level::TRACE, "unet-resnet"),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, temb: &Tensor) -> Result<Tensor> {
+        let _span = self.span.enter();
+        let h = xs.apply(&self.norm1)?.silu()?.apply(&self.conv1)?;
+        let temb = temb
+            .silu()?
+            .apply(&self.time_emb_proj)?
+            .unsqueeze(D::Minus1)?
+            .unsqueeze(D::Minus1)?;
+        let h = h
+            .broadcast_add(&temb)?
+            .apply(&self.norm2)?
+            .silu()?
+            .apply(&self.conv2)?;
+        let shortcut = match &self.conv_shortcut {
+            Some(conv) => xs.apply(conv)?,
+            None => xs.clone(),
+        };
+        (shortcut + h)? / self.output_scale_factor
+    }
+}
+
+fn scaled_dot_product_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+    let dim = q.dim(D::Minus1)?;
+    let scale_factor = 1.0 / (dim as f64).sqrt();
+    diffusion_rs_backend::ops::sdpa(
+        &q.to_dtype(DType::F32)?,
+        &k.to_dtype(DType::F32)?,
+        &v.to_dtype(DType::F32)?,
+        scale_factor as f32,
+        1.0,
+    )?
+    .to_dtype(q.dtype())
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    to_q: Linear,
+    to_k: Linear,
+    to_v: Linear,
+    to_out: Linear,
+    heads: usize,
+}
+
+impl Attention {
+    fn new(
+        query_dim: usize,
+        context_dim: Option<usize>,
+        heads: usize,
+        head_dim: usize,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let inner_dim = heads * head_dim;
+        let context_dim = context_dim.unwrap_or(query_dim);
+        Ok(Self {
+            to_q: linear_no_bias(query_dim, inner_dim, vb.pp("to_q"))?,
+            to_k: linear_no_bias(context_dim, inner_dim, vb.pp("to_k"))?,
+            to_v: linear_no_bias(context_dim, inner_dim, vb.pp("to_v"))?,
+            to_out: linear(inner_dim, query_dim, vb.pp("to_out.0"))?,
+            heads,
+        })
+    }
+
+    fn heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b, seq, dim) = xs.dims3()?;
+        xs.reshape((b, seq, self.heads, dim / self.heads))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    fn forward(&self, xs: &Tensor, context: Option<&Tensor>) -> Result<Tensor> {
+        let context = context.unwrap_or(xs);
+        let q = self.heads(&xs.apply(&self.to_q)?)?;
+        let k = self.heads(&context.apply(&self.to_k)?)?;
+        let v = self.heads(&context.apply(&self.to_v)?)?;
+        scaled_dot_product_attention(&q, &k, &v)?
+            .transpose(1, 2)?
+            .flatten_from(2)?
+            .apply(&self.to_out)
+    }
+}
+
+/// Feed-forward layer with a GEGLU activation.
+#[derive(Debug, Clone)]
+struct FeedForward {
+    proj: Linear,
+    out: Linear,
+}
+
+impl FeedForward {
+    fn new(dim: usize, vb: VarBuilder) -> Result<Self> {
+        let inner_dim = dim * 4;
+        Ok(Self {
+            proj: linear(dim, inner_dim * 2, vb.pp("net.0.proj"))?,
+            out: linear(inner_dim, dim, vb.pp("net.2"))?,
+        })
+    }
+}
+
+impl Module for FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.apply(&self.proj)?;
+        let chunks = xs.chunk(2, D::Minus1)?;
+        (&chunks[0] * chunks[1].apply(&Activation::Gelu)?)?.apply(&self.out)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BasicTransformerBlock {
+    norm1: LayerNorm,
+    attn1: Attention,
+    norm2: LayerNorm,
+    attn2: Attention,
+    norm3: LayerNorm,
+    ff: FeedForward,
+}
+
+impl BasicTransformerBlock {
+    fn new(
+        dim: usize,
+        heads: usize,
+        head_dim: usize,
+        context_dim: usize,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        Ok(Self {
+            norm1: layer_norm(dim, 1e-5, vb.pp("norm1"))?,
+            attn1: Attention::new(dim, None, heads, head_dim, vb.pp("attn1"))?,
+            norm2: layer_norm(dim, 1e-5, vb.pp("norm2"))?,
+            attn2: Attention::new(dim, Some(context_dim), heads, head_dim, vb.pp("attn2"))?,
+            norm3: layer_norm(dim, 1e-5, vb.pp("norm3"))?,
+            ff: FeedForward::new(dim, vb.pp("ff"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, context: &Tensor) -> Result<Tensor> {
+        let xs = (self.attn1.forward(&xs.apply(&self.norm1)?, None)? + xs)?;
+        let xs = (self.attn2.forward(&xs.apply(&self.norm2)?, Some(context))? + xs)?;
+        xs.apply(&self.norm3)?.apply(&self.ff)? + xs
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Projection {
+    Conv(Conv2d),
+    Linear(Linear),
+}
+
+/// Spatial transformer over the image features, as diffusers `Transformer2DModel`.
+#[derive(Debug, Clone)]
+struct Transformer2D {
+    norm: GroupNorm,
+    proj_in: Projection,
+    transformer_blocks: Vec<BasicTransformerBlock>,
+    proj_out: Projection,
+    span: Span,
+}
+
+impl Transformer2D {
+    fn new(
+        channels: usize,
+        heads: usize,
+        num_layers: usize,
+        cfg: &Config,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let head_dim = channels / heads;
+        let inner_dim = heads * head_dim;
+        let (proj_in, proj_out) = if cfg.use_linear_projection {
+            (
+                Projection::Linear(linear(channels, inner_dim, vb.pp("proj_in"))?),
+                Projection::Linear(linear(inner_dim, channels, vb.pp("proj_out"))?),
+            )
+        } else {
+            (
+                Projection::Conv(conv2d(
+                    channels,
+                    inner_dim,
+                    1,
+                    Default::default(),
+                    vb.pp("proj_in"),
+                )?),
+                Projection::Conv(conv2d(
+                    inner_dim,
+                    channels,
+                    1,
+                    Default::default(),
+                    vb.pp("proj_out"),
+                )?),
+            )
+        };
+        let transformer_blocks = (0..num_layers)
+            .map(|i| {
+                BasicTransformerBlock::new(
+                    inner_dim,
+                    heads,
+                    head_dim,
+                    cfg.cross_attention_dim,
+                    vb.pp("transformer_blocks").pp(i),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            norm: group_norm(cfg.norm_num_groups, channels, 1e-6, vb.pp("norm"))?,
+            proj_in,
+            transformer_blocks,
+            proj_out,
+            span: span!(tracing::Level::TRACE, "unet-transformer"),
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, context: &Tensor) -> Result<Tensor> {
+        let _span = self.span.enter();
+        let (b, _c, h, w) = xs.dims4()?;
+        // (b, c, h, w) <-> (b, h * w, c)
+        let to_seq = |xs: Tensor| xs.flatten_from(2)?.transpose(1, 2)?.contiguous();
+        let from_seq = |xs: Tensor| {
+            let c = xs.dim(D::Minus1)?;
+            xs.transpose(1, 2)?.contiguous()?.reshape((b, c, h, w))
+        };
+
+        let residual = xs;
+        let xs = xs.apply(&self.norm)?;
+        let mut xs = match &self.proj_in {
+            Projection::Conv(conv) => to_seq(xs.apply(conv)?)?,
+            Projection::Linear(linear) => to_seq(xs)?.apply(linear)?,
+        };
+        for block in &self.transformer_blocks {
+            xs = block.forward(&xs, context)?;
+        }
+        let xs = match &self.proj_out {
+            Projection::Conv(conv) => from_seq(xs)?.apply(conv)?,
+            Projection::Linear(linear) => from_seq(xs.apply(linear)?)?,
+        };
+        xs + residual
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Downsample2D {
+    conv: Conv2d,
+}
+
+impl Downsample2D {
+    fn new(channels: usize, padding: usize, vb: VarBuilder) -> Result<Self> {
+        let conv_cfg = Conv2dConfig {
+            stride: 2,
+            padding,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv: conv2d(channels, channels, 3, conv_cfg, vb.pp("conv"))?,
+        })
+    }
+}
+
+impl Module for Downsample2D {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.conv)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Upsample2D {
+    conv: Conv2d,
+}
+
+impl Upsample2D {
+    fn new(channels: usize, vb: VarBuilder) -> Result<Self> {
+        let conv_cfg = Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv: conv2d(channels, channels, 3, conv_cfg, vb.pp("conv"))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, size: (usize, usize)) -> Result<Tensor> {
+        xs.upsample_nearest2d(size.0, size.1)?.apply(&self.conv)
+    }
+}
+
+/// A down block: `DownBlock2D`, or `CrossAttnDownBlock2D` if it has attentions.
+#[derive(Debug, Clone)]
+struct DownBlock {
+    resnets: Vec<ResnetBlock2D>,
+    attentions: Vec<Transformer2D>,
+    downsampler: Option<Downsample2D>,
+}
+
+/// An up block: `UpBlock2D`, or `CrossAttnUpBlock2D` if it has attentions.
+#[derive(Debug, Clone)]
+struct UpBlock {
+    resnets: Vec<ResnetBlock2D>,
+    attentions: Vec<Transformer2D>,
+    upsampler: Option<Upsample2D>,
+}
+
+#[derive(Debug, Clone)]
+struct MidBlock {
+    resnets: Vec<ResnetBlock2D>,
+    attentions: Vec<Transformer2D>,
+}
+
+/// The additional conditioning of SDXL models.
+#[derive(Debug, Clone)]
+pub struct AddedCondition {
+    /// Pooled text embeddings, of shape `(batch, dim)`.
+    pub text_embeds: Tensor,
+    /// Size and crop conditioning, of shape `(batch, n)`: the original height and width, the top and left crop,
+    /// then the target height and width or, for the refiner, the aesthetic score.
+    pub time_ids: Tensor,
+}
+
+/// Embedding of the `text_time` additional conditioning.
+#[derive(Debug, Clone)]
+struct TextTimeEmbedding {
+    add_embedding: TimestepEmbedding,
+    time_embed_dim: usize,
+}
+
+/// The conditional UNet of Stable Diffusion, as diffusers `UNet2DConditionModel`.
+#[derive(Debug, Clone)]
+pub struct UNet2DConditionModel {
+    conv_in: Conv2d,
+    time_embedding: TimestepEmbedding,
+    add_embedding: Option<TextTimeEmbedding>,
+    down_blocks: Vec<DownBlock>,
+    mid_block: MidBlock,
+    up_blocks: Vec<UpBlock>,
+    conv_norm_out: GroupNorm,
+    conv_out: Conv2d,
+    time_proj_dim: usize,
+    flip_sin_to_cos: bool,
+    freq_shift: f64,
+}
+
+impl UNet2DConditionModel {
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let num_blocks = cfg.block_out_channels.len();
+        if cfg.down_block_types.len() != num_blocks || cfg.up_block_types.len() != num_blocks {
+            diffusion_rs_common::bail!("Expected one down and up block type per block");
+        }
+        if let Some(mid) = cfg
+            .mid_block_type
+            .as_ref()
+            .filter(|x| *x != "UNetMidBlock2DCrossAttn")
+        {
+            diffusion_rs_common::bail!("Unsupported UNet mid block type `{mid}`");
+        }
+        let heads = cfg.num_attention_heads()?;
+        let transformer_layers = cfg.transformer_layers_per_block.expand(num_blocks)?;
+        let conv_cfg = Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        let boc = &cfg.block_out_channels;
+        let time_proj_dim = boc[0];
+        let temb_c = boc[0] * 4;
+
+        let conv_in = conv2d(cfg.in_channels, boc[0], 3, conv_cfg, vb.pp("conv_in"))?;
+        let time_embedding =
+            TimestepEmbedding::new(time_proj_dim, temb_c, vb.pp("time_embedding"))?;
+        let add_embedding = match cfg.addition_embed_type.as_deref() {
+            None => None,
+            Some("text_time") => {
+                let (Some(time_embed_dim), Some(in_c)) = (
+                    cfg.addition_time_embed_dim,
+                    cfg.projection_class_embeddings_input_dim,
+                ) else {
+                    diffusion_rs_common::bail!(
+                        "`text_time` conditioning requires `addition_time_embed_dim` and `projection_class_embeddings_input_dim`"
+                    );
+                };
+                Some(TextTimeEmbedding {
+                    add_embedding: TimestepEmbedding::new(in_c, temb_c, vb.pp("add_embedding"))?,
+                    time_embed_dim,
+                })
+            }
+            Some(other) => {
+                diffusion_rs_common::bail!("Unsupported UNet addition embedding `{other}`")
+            }
+        };
+
+        let mut down_blocks = Vec::with_capacity(num_blocks);
+        let mut out_c = boc[0];
+        for (i, block_type) in cfg.down_block_types.iter().enumerate() {
+            let vb = vb.pp("down_blocks").pp(i);
+            let in_c = out_c;
+            out_c = boc[i];
+            let has_attention = match block_type.as_str() {
+                "CrossAttnDownBlock2D" => true,
+                "DownBlock2D" => false,
+                other => diffusion_rs_common::bail!("Unsupported UNet down block type `{other}`"),
+            };
+            let mut resnets = Vec::with_capacity(cfg.layers_per_block);
+            let mut attentions = Vec::new();
+            for j in 0..cfg.layers_per_block {
+                let resnet_in = if j == 0 { in_c } else { out_c };
+                resnets.push(ResnetBlock2D::new(
+                    resnet_in,
+                    out_c,
+                    temb_c,
+                    1.,
+                    cfg,
+                    vb.pp("resnets").pp(j),
+                )?);
+                if has_attention {
+                    attentions.push(Transformer2D::new(
+                        out_c,
+                        heads[i],
+                        transformer_layers[i],
+                        cfg,
+                        vb.pp("attentions").pp(j),
+                    )?);
+                }
+            }
+            let downsampler = if i + 1 < num_blocks {
+                Some(Downsample2D::new(
+                    out_c,
+                    cfg.downsample_padding,
+                    vb.pp("downsamplers.0"),
+                )?)
+            } else {
+                None
+            };
+            down_blocks.push(DownBlock {
+                resnets,
+                attentions,
+                downsampler,
+            });
+        }
+
+        let mid_c = boc[num_blocks - 1];
+        let mid_block = {
+            let vb = vb.pp("mid_block");
+            MidBlock {
+                resnets: (0..2)
+                    .map(|j| {
+                        ResnetBlock2D::new(
+                            mid_c,
+                            mid_c,
+                            temb_c,
+                            cfg.mid_block_scale_factor,
+                            cfg,
+                            vb.pp("resnets").pp(j),
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+                attentions: vec![Transformer2D::new(
+                    mid_c,
+                    heads[num_blocks - 1],
+                    transformer_layers[num_blocks - 1],
+                    cfg,
+                    vb.pp("attentions.0"),
+                )?],
+            }
+        };
+
+        let mut up_blocks = Vec::with_capacity(num_blocks);
+        let mut out_c = mid_c;
+        for (i, block_type) in cfg.up_block_types.iter().enumerate() {
+            let vb = vb.pp("up_blocks").pp(i);
+            let rev = num_blocks - 1 - i;
+            let prev_out_c = out_c;
+            out_c = boc[rev];
+            let in_c = boc[rev.saturating_sub(1)];
+            let has_attention = match block_type.as_str() {
+                "CrossAttnUpBlock2D" => true,
+                "UpBlock2D" => false,
+                other => diffusion_rs_common::bail!("Unsupported UNet up block type `{other}`"),
+            };
+            let num_layers = cfg.layers_per_block + 1;
+            let mut resnets = Vec::with_capacity(num_layers);
+            let mut attentions = Vec::new();
+            for j in 0..num_layers {
+                let skip_c = if j == num_layers - 1 { in_c } else { out_c };
+                let resnet_in = if j == 0 { prev_out_c } else { out_c };
+                resnets.push(ResnetBlock2D::new(
+                    resnet_in + skip_c,
+                    out_c,
+                    temb_c,
+                    1.,
+                    cfg,
+                    vb.pp("resnets").pp(j),
+                )?);
+                if has_attention {
+                    attentions.push(Transformer2D::new(
+                        out_c,
+                        heads[rev],
+                        transformer_layers[rev],
+                        cfg,
+                        vb.pp("attentions").pp(j),
+                    )?);
+                }
+            }
+            let upsampler = if i + 1 < num_blocks {
+                Some(Upsample2D::new(out_c, vb.pp("upsamplers.0"))?)
+            } else {
+                None
+            };
+            up_blocks.push(UpBlock {
+                resnets,
+                attentions,
+                upsampler,
+            });
+        }
+
+        Ok(Self {
+            conv_in,
+            time_embedding,
+            add_embedding,
+            down_blocks,
+            mid_block,
+            up_blocks,
+            conv_norm_out: group_norm(
+                cfg.norm_num_groups,
+                boc[0],
+                cfg.norm_eps,
+                vb.pp("conv_norm_out"),
+            )?,
+            conv_out: conv2d(boc[0], cfg.out_channels, 3, conv_cfg, vb.pp("conv_out"))?,
+            time_proj_dim,
+            flip_sin_to_cos: cfg.flip_sin_to_cos,
+            freq_shift: cfg.freq_shift,
+        })
+    }
+
+    /// Predict the noise (or velocity) for the latents `xs` at the (possibly fractional) timesteps `t`,
+    /// conditioned on the text embeddings `context`, and for SDXL models, on `added`.
+    pub fn forward(
+        &self,
+        xs: &Tensor,
+        t: &Tensor,
+        context: &Tensor,
+        added: Option<&AddedCondition>,
+    ) -> Result<Tensor> {
+        let t_emb = timestep_embedding(
+            t,
+            self.time_proj_dim,
+            self.flip_sin_to_cos,
+            self.freq_shift,
+            xs.dtype(),
+        )?;
+        let mut temb = t_emb.apply(&self.time_embedding)?;
+        match (&self.add_embedding, added) {
+            (Some(embedding), Some(added)) => {
+                let b = added.time_ids.dim(0)?;
+                let time_embeds = timestep_embedding(
+                    &added.time_ids.flatten_all()?,
+                    embedding.time_embed_dim,
+                    self.flip_sin_to_cos,
+                    self.freq_shift,
+                    xs.dtype(),
+                )?
+                .reshape((b, ()))?;
+                let add_embeds = Tensor::cat(
+                    &[&added.text_embeds.to_dtype(xs.dtype())?, &time_embeds],
+                    D::Minus1,
+                )?;
+                temb = (temb + add_embeds.apply(&embedding.add_embedding)?)?;
+            }
+            (Some(_), None) => {
+                diffusion_rs_common::bail!("This UNet requires the text and time conditioning")
+            }
+            (None, _) => (),
+        }
+
+        let mut xs = xs.apply(&self.conv_in)?;
+        let mut skips = vec![xs.clone()];
+        for block in &self.down_blocks {
+            for (i, resnet) in block.resnets.iter().enumerate() {
+                xs = resnet.forward(&xs, &temb)?;
+                if let Some(attn) = block.attentions.get(i) {
+                    xs = attn.forward(&xs, context)?;
+                }
+                skips.push(xs.clone());
+            }
+            if let Some(downsampler) = &block.downsampler {
+                xs = xs.apply(downsampler)?;
+                skips.push(xs.clone());
+            }
+        }
+
+        xs = self.mid_block.resnets[0].forward(&xs, &temb)?;
+        xs = self.mid_block.attentions[0].forward(&xs, context)?;
+        xs = self.mid_block.resnets[1].forward(&xs, &temb)?;
+
+        for block in &self.up_blocks {
+            for (i, resnet) in block.resnets.iter().enumerate() {
+                let skip = skips
+                    .pop()
+                    .ok_or_else(|| diffusion_rs_common::core::Error::Msg("Missing skip".into()))?;
+                xs = Tensor::cat(&[&xs, &skip], 1)?;
+                xs = resnet.forward(&xs, &temb)?;
+                if let Some(attn) = block.attentions.get(i) {
+                    xs = attn.forward(&xs, context)?;
+                }
+            }
+            if let Some(upsampler) = &block.upsampler {
+                // Upsample to the size of the next skip connection, which handles odd sizes.
+                let size = match skips.last() {
+                    Some(skip) => (skip.dim(2)?, skip.dim(3)?),
+                    None => (xs.dim(2)? * 2, xs.dim(3)? * 2),
+                };
+                xs = upsampler.forward(&xs, size)?;
+            }
+        }
+
+        xs.apply(&self.conv_norm_out)?.silu()?.apply(&self.conv_out)
+    }
+}
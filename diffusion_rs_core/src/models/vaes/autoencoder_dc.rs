@@ -0,0 +1,658 @@
+use diffusion_rs_common::core::{DType, Module, Result, Tensor, D};
+use diffusion_rs_common::nn::{ops, Activation, Conv2d, Conv2dConfig, Linear};
+use diffusion_rs_common::{conv2d, conv2d_no_bias, linear_no_bias, VarBuilder};
+use serde::Deserialize;
+
+use super::VAEModel;
+
+/// A config value which is either shared by all blocks or given per block.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PerBlock<T> {
+    Shared(T),
+    PerBlock(Vec<T>),
+}
+
+impl<T: Clone> PerBlock<T> {
+    fn expand(&self, num_blocks: usize) -> Result<Vec<T>> {
+        match self {
+            Self::Shared(x) => Ok(vec![x.clone(); num_blocks]),
+            Self::PerBlock(xs) if xs.len() == num_blocks => Ok(xs.clone()),
+            Self::PerBlock(xs) => diffusion_rs_common::bail!(
+                "Expected one value per block ({num_blocks}), got {}",
+                xs.len()
+            ),
+        }
+    }
+}
+
+fn default_res_block() -> PerBlock<String> {
+    PerBlock::Shared("ResBlock".to_string())
+}
+
+fn default_rms_norm() -> PerBlock<String> {
+    PerBlock::Shared("rms_norm".to_string())
+}
+
+fn default_silu() -> PerBlock<Activation> {
+    PerBlock::Shared(Activation::Silu)
+}
+
+fn default_upsample_block_type() -> String {
+    "pixel_shuffle".to_string()
+}
+
+fn default_downsample_block_type() -> String {
+    "pixel_unshuffle".to_string()
+}
+
+fn default_scaling_factor() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AutoencoderDcConfig {
+    pub in_channels: usize,
+    pub latent_channels: usize,
+    pub attention_head_dim: usize,
+    #[serde(default = "default_res_block")]
+    pub encoder_block_types: PerBlock<String>,
+    #[serde(default = "default_res_block")]
+    pub decoder_block_types: PerBlock<String>,
+    pub encoder_block_out_channels: Vec<usize>,
+    pub decoder_block_out_channels: Vec<usize>,
+    pub encoder_layers_per_block: Vec<usize>,
+    pub decoder_layers_per_block: Vec<usize>,
+    pub encoder_qkv_multiscales: Vec<Vec<usize>>,
+    pub decoder_qkv_multiscales: Vec<Vec<usize>>,
+    /// `interpolate` (nearest upsampling then convolution) or `pixel_shuffle`.
+    #[serde(default = "default_upsample_block_type")]
+    pub upsample_block_type: String,
+    /// `pixel_unshuffle`, or `Conv` for a strided convolution.
+    #[serde(default = "default_downsample_block_type")]
+    pub downsample_block_type: String,
+    #[serde(default = "default_rms_norm")]
+    pub decoder_norm_types: PerBlock<String>,
+    #[serde(default = "default_silu")]
+    pub decoder_act_fns: PerBlock<Activation>,
+    #[serde(default = "default_scaling_factor")]
+    pub scaling_factor: f64,
+}
+
+/// RMS norm over the channels of a `(b, c, h, w)` tensor, with a bias.
+#[derive(Debug, Clone)]
+struct ChannelRmsNorm {
+    weight: Tensor,
+    bias: Tensor,
+    eps: f64,
+}
+
+impl ChannelRmsNorm {
+    fn new(channels: usize, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            weight: vb.get(channels, "weight")?,
+            bias: vb.get(channels, "bias")?,
+            eps: 1e-5,
+        })
+    }
+}
+
+impl Module for ChannelRmsNorm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let dtype = xs.dtype();
+        let xs = xs.permute((0, 2, 3, 1))?.to_dtype(DType::F32)?;
+        let norm = (xs.sqr()?.mean_keepdim(D::Minus1)? + self.eps)?.sqrt()?;
+        xs.broadcast_div(&norm)?
+            .to_dtype(dtype)?
+            .broadcast_mul(&self.weight)?
+            .broadcast_add(&self.bias)?
+            .permute((0, 3, 1, 2))
+    }
+}
+
+fn conv3x3(in_channels: usize, out_channels: usize, vb: VarBuilder) -> Result<Conv2d> {
+    let cfg = Conv2dConfig {
+        padding: 1,
+        ..Default::default()
+    };
+    conv2d(in_channels, out_channels, 3, cfg, vb)
+}
+
+/// Repeat each channel `repeats` times, as `repeat_interleave` on the channel dimension.
+fn repeat_channels(xs: &Tensor, repeats: usize) -> Result<Tensor> {
+    let (b, c, h, w) = xs.dims4()?;
+    xs.unsqueeze(2)?
+        .broadcast_as((b, c, repeats, h, w))?
+        .reshape((b, c * repeats, h, w))
+}
+
+/// Average groups of `group_size` consecutive channels.
+fn average_channels(xs: &Tensor, group_size: usize) -> Result<Tensor> {
+    let (b, c, h, w) = xs.dims4()?;
+    xs.reshape((b, c / group_size, group_size, h, w))?.mean(2)
+}
+
+#[derive(Debug, Clone)]
+struct ResBlock {
+    conv1: Conv2d,
+    conv2: Conv2d,
+    norm: ChannelRmsNorm,
+    act: Activation,
+}
+
+impl ResBlock {
+    fn new(channels: usize, act: Activation, vb: VarBuilder) -> Result<Self> {
+        let cfg = Conv2dConfig {
+            padding: 1,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv1: conv3x3(channels, channels, vb.pp("conv1"))?,
+            conv2: conv2d_no_bias(channels, channels, 3, cfg, vb.pp("conv2"))?,
+            norm: ChannelRmsNorm::new(channels, vb.pp("norm"))?,
+            act,
+        })
+    }
+}
+
+impl Module for ResBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let h = xs
+            .apply(&self.conv1)?
+            .apply(&self.act)?
+            .apply(&self.conv2)?
+            .apply(&self.norm)?;
+        xs + h
+    }
+}
+
+/// Gated inverted bottleneck convolution, with a depthwise 3x3 convolution.
+#[derive(Debug, Clone)]
+struct GluMbConv {
+    conv_inverted: Conv2d,
+    conv_depth: Conv2d,
+    conv_point: Conv2d,
+    norm: ChannelRmsNorm,
+}
+
+impl GluMbConv {
+    fn new(channels: usize, vb: VarBuilder) -> Result<Self> {
+        const EXPAND_RATIO: usize = 4;
+        let hidden = EXPAND_RATIO * channels;
+        let depth_cfg = Conv2dConfig {
+            padding: 1,
+            groups: 2 * hidden,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv_inverted: conv2d(
+                channels,
+                2 * hidden,
+                1,
+                Default::default(),
+                vb.pp("conv_inverted"),
+            )?,
+            conv_depth: conv2d(2 * hidden, 2 * hidden, 3, depth_cfg, vb.pp("conv_depth"))?,
+            conv_point: conv2d_no_bias(
+                hidden,
+                channels,
+                1,
+                Default::default(),
+                vb.pp("conv_point"),
+            )?,
+            norm: ChannelRmsNorm::new(channels, vb.pp("norm"))?,
+        })
+    }
+}
+
+impl Module for GluMbConv {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let h = xs
+            .apply(&self.conv_inverted)?
+            .silu()?
+            .apply(&self.conv_depth)?;
+        let h = h.chunk(2, 1)?;
+        let h = (&h[0] * h[1].silu()?)?
+            .apply(&self.conv_point)?
+            .apply(&self.norm)?;
+        xs + h
+    }
+}
+
+/// Multi-scale ReLU linear attention: the queries, keys and values are also aggregated over the neighbouring
+/// pixels by a depthwise convolution for each extra scale, with their own heads.
+#[derive(Debug, Clone)]
+struct MultiscaleLinearAttention {
+    to_q: Linear,
+    to_k: Linear,
+    to_v: Linear,
+    to_qkv_multiscale: Vec<(Conv2d, Conv2d)>,
+    to_out: Linear,
+    norm_out: ChannelRmsNorm,
+    head_dim: usize,
+}
+
+impl MultiscaleLinearAttention {
+    fn new(
+        channels: usize,
+        head_dim: usize,
+        kernel_sizes: &[usize],
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let heads = channels / head_dim;
+        let inner = heads * head_dim;
+        let mut to_qkv_multiscale = Vec::with_capacity(kernel_sizes.len());
+        for (i, kernel_size) in kernel_sizes.iter().enumerate() {
+            let vb = vb.pp("to_qkv_multiscale").pp(i);
+            let proj_in = conv2d_no_bias(
+                3 * inner,
+                3 * inner,
+                *kernel_size,
+                Conv2dConfig {
+                    padding: kernel_size / 2,
+                    groups: 3 * inner,
+                    ..Default::default()
+                },
+                vb.pp("proj_in"),
+            )?;
+            let proj_out = conv2d_no_bias(
+                3 * inner,
+                3 * inner,
+                1,
+                Conv2dConfig {
+                    groups: 3 * heads,
+                    ..Default::default()
+                },
+                vb.pp("proj_out"),
+            )?;
+            to_qkv_multiscale.push((proj_in, proj_out));
+        }
+        Ok(Self {
+            to_q: linear_no_bias(channels, inner, vb.pp("to_q"))?,
+            to_k: linear_no_bias(channels, inner, vb.pp("to_k"))?,
+            to_v: linear_no_bias(channels, inner, vb.pp("to_v"))?,
+            to_out: linear_no_bias(inner * (1 + kernel_sizes.len()), channels, vb.pp("to_out"))?,
+            norm_out: ChannelRmsNorm::new(channels, vb.pp("norm_out"))?,
+            to_qkv_multiscale,
+            head_dim,
+        })
+    }
+}
+
+impl Module for MultiscaleLinearAttention {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        const EPS: f64 = 1e-15;
+        let (b, _c, h, w) = xs.dims4()?;
+        let dtype = xs.dtype();
+
+        let channels_last = xs.permute((0, 2, 3, 1))?;
+        let qkv = Tensor::cat(
+            &[
+                channels_last.apply(&self.to_q)?,
+                channels_last.apply(&self.to_k)?,
+                channels_last.apply(&self.to_v)?,
+            ],
+            D::Minus1,
+        )?
+        .permute((0, 3, 1, 2))?
+        .contiguous()?;
+        let mut scales = vec![qkv.clone()];
+        for (proj_in, proj_out) in &self.to_qkv_multiscale {
+            scales.push(qkv.apply(proj_in)?.apply(proj_out)?);
+        }
+
+        // Each group of `3 * head_dim` channels holds the queries, keys and values of a head.
+        let qkv = Tensor::cat(&scales, 1)?.to_dtype(DType::F32)?.reshape((
+            b,
+            (),
+            3 * self.head_dim,
+            h * w,
+        ))?;
+        let q = qkv.narrow(2, 0, self.head_dim)?.relu()?;
+        let k = qkv.narrow(2, self.head_dim, self.head_dim)?.relu()?;
+        let v = qkv.narrow(2, 2 * self.head_dim, self.head_dim)?;
+
+        let attn = if h * w > self.head_dim {
+            // Linear attention, with a row of ones in the values to compute the normalizer.
+            let (b, g, d, n) = v.dims4()?;
+            let v = Tensor::cat(
+                &[v, Tensor::ones((b, g, 1, n), DType::F32, xs.device())?],
+                2,
+            )?;
+            let scores = v.matmul(&k.t()?.contiguous()?)?;
+            let out = scores.matmul(&q.contiguous()?)?;
+            out.narrow(2, 0, d)?
+                .broadcast_div(&(out.narrow(2, d, 1)? + EPS)?)?
+        } else {
+            let scores = k.t()?.contiguous()?.matmul(&q.contiguous()?)?;
+            let scores = scores.broadcast_div(&(scores.sum_keepdim(2)? + EPS)?)?;
+            v.contiguous()?.matmul(&scores)?
+        };
+
+        let attn = attn
+            .to_dtype(dtype)?
+            .reshape((b, (), h, w))?
+            .permute((0, 2, 3, 1))?
+            .apply(&self.to_out)?
+            .permute((0, 3, 1, 2))?
+            .apply(&self.norm_out)?;
+        xs + attn
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DownBlock {
+    conv: Conv2d,
+    pixel_unshuffle: bool,
+    group_size: usize,
+}
+
+impl DownBlock {
+    fn new(
+        in_channels: usize,
+        out_channels: usize,
+        pixel_unshuffle: bool,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let (conv_out_channels, stride) = if pixel_unshuffle {
+            (out_channels / 4, 1)
+        } else {
+            (out_channels, 2)
+        };
+        let cfg = Conv2dConfig {
+            padding: 1,
+            stride,
+            ..Default::default()
+        };
+        Ok(Self {
+            conv: conv2d(in_channels, conv_out_channels, 3, cfg, vb.pp("conv"))?,
+            pixel_unshuffle,
+            group_size: in_channels * 4 / out_channels,
+        })
+    }
+}
+
+impl Module for DownBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut h = xs.apply(&self.conv)?;
+        if self.pixel_unshuffle {
+            h = ops::pixel_unshuffle(&h, 2)?;
+        }
+        let shortcut = average_channels(&ops::pixel_unshuffle(xs, 2)?, self.group_size)?;
+        h + shortcut
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UpBlock {
+    conv: Conv2d,
+    interpolate: bool,
+    repeats: usize,
+}
+
+impl UpBlock {
+    fn new(
+        in_channels: usize,
+        out_channels: usize,
+        interpolate: bool,
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        let conv_out_channels = if interpolate {
+            out_channels
+        } else {
+            out_channels * 4
+        };
+        Ok(Self {
+            conv: conv3x3(in_channels, conv_out_channels, vb.pp("conv"))?,
+            interpolate,
+            repeats: out_channels * 4 / in_channels,
+        })
+    }
+}
+
+impl Module for UpBlock {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let h = if self.interpolate {
+            let (_, _, height, width) = xs.dims4()?;
+            xs.upsample_nearest2d(2 * height, 2 * width)?
+                .apply(&self.conv)?
+        } else {
+            ops::pixel_shuffle(&xs.apply(&self.conv)?, 2)?
+        };
+        let shortcut = ops::pixel_shuffle(&repeat_channels(xs, self.repeats)?, 2)?;
+        h + shortcut
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Block {
+    Res(ResBlock),
+    EfficientVit(MultiscaleLinearAttention, GluMbConv),
+    Down(DownBlock),
+    Up(UpBlock),
+}
+
+impl Block {
+    fn new(
+        block_type: &str,
+        channels: usize,
+        act: Activation,
+        head_dim: usize,
+        qkv_multiscales: &[usize],
+        vb: VarBuilder,
+    ) -> Result<Self> {
+        match block_type {
+            "ResBlock" => Ok(Self::Res(ResBlock::new(channels, act, vb)?)),
+            "EfficientViTBlock" => Ok(Self::EfficientVit(
+                MultiscaleLinearAttention::new(channels, head_dim, qkv_multiscales, vb.pp("attn"))?,
+                GluMbConv::new(channels, vb.pp("conv_out"))?,
+            )),
+            other => diffusion_rs_common::bail!("Unsupported AutoencoderDC block type `{other}`"),
+        }
+    }
+}
+
+impl Module for Block {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Res(block) => xs.apply(block),
+            Self::EfficientVit(attn, conv_out) => xs.apply(attn)?.apply(conv_out),
+            Self::Down(block) => xs.apply(block),
+            Self::Up(block) => xs.apply(block),
+        }
+    }
+}
+
+fn check_stages(name: &str, block_out_channels: &[usize], lens: &[usize]) -> Result<()> {
+    if lens.iter().any(|len| *len != block_out_channels.len()) {
+        diffusion_rs_common::bail!("Inconsistent number of AutoencoderDC {name} stages");
+    }
+    if block_out_channels.is_empty() {
+        diffusion_rs_common::bail!("AutoencoderDC {name} has no stages");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct Encoder {
+    conv_in: Conv2d,
+    blocks: Vec<Block>,
+    conv_out: Conv2d,
+    out_group_size: usize,
+}
+
+impl Encoder {
+    fn new(cfg: &AutoencoderDcConfig, vb: VarBuilder) -> Result<Self> {
+        let channels = &cfg.encoder_block_out_channels;
+        let layers = &cfg.encoder_layers_per_block;
+        let n = channels.len();
+        check_stages(
+            "encoder",
+            channels,
+            &[layers.len(), cfg.encoder_qkv_multiscales.len()],
+        )?;
+        if layers[0] == 0 {
+            diffusion_rs_common::bail!(
+                "AutoencoderDC encoders without a first stage are not supported"
+            );
+        }
+        let block_types = cfg.encoder_block_types.expand(n)?;
+        let pixel_unshuffle = cfg.downsample_block_type == "pixel_unshuffle";
+
+        let mut blocks = Vec::new();
+        for i in 0..n {
+            let vb = vb.pp("down_blocks").pp(i);
+            for j in 0..layers[i] {
+                blocks.push(Block::new(
+                    &block_types[i],
+                    channels[i],
+                    Activation::Silu,
+                    cfg.attention_head_dim,
+                    &cfg.encoder_qkv_multiscales[i],
+                    vb.pp(j),
+                )?);
+            }
+            if i + 1 < n && layers[i] > 0 {
+                blocks.push(Block::Down(DownBlock::new(
+                    channels[i],
+                    channels[i + 1],
+                    pixel_unshuffle,
+                    vb.pp(layers[i]),
+                )?));
+            }
+        }
+        Ok(Self {
+            conv_in: conv3x3(cfg.in_channels, channels[0], vb.pp("conv_in"))?,
+            blocks,
+            conv_out: conv3x3(channels[n - 1], cfg.latent_channels, vb.pp("conv_out"))?,
+            out_group_size: channels[n - 1] / cfg.latent_channels,
+        })
+    }
+}
+
+impl Module for Encoder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = xs.apply(&self.conv_in)?;
+        for block in &self.blocks {
+            xs = xs.apply(block)?;
+        }
+        xs.apply(&self.conv_out)? + average_channels(&xs, self.out_group_size)?
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Decoder {
+    conv_in: Conv2d,
+    in_repeats: usize,
+    blocks: Vec<Block>,
+    norm_out: ChannelRmsNorm,
+    conv_out: Conv2d,
+}
+
+impl Decoder {
+    fn new(cfg: &AutoencoderDcConfig, vb: VarBuilder) -> Result<Self> {
+        let channels = &cfg.decoder_block_out_channels;
+        let layers = &cfg.decoder_layers_per_block;
+        let n = channels.len();
+        check_stages(
+            "decoder",
+            channels,
+            &[layers.len(), cfg.decoder_qkv_multiscales.len()],
+        )?;
+        if layers[0] == 0 {
+            diffusion_rs_common::bail!(
+                "AutoencoderDC decoders without a first stage are not supported"
+            );
+        }
+        let block_types = cfg.decoder_block_types.expand(n)?;
+        let act_fns = cfg.decoder_act_fns.expand(n)?;
+        if let Some(norm_type) = cfg
+            .decoder_norm_types
+            .expand(n)?
+            .into_iter()
+            .find(|x| x != "rms_norm")
+        {
+            diffusion_rs_common::bail!("Unsupported AutoencoderDC norm type `{norm_type}`");
+        }
+        let interpolate = cfg.upsample_block_type == "interpolate";
+
+        // From the deepest stage to the first.
+        let mut blocks = Vec::new();
+        for i in (0..n).rev() {
+            let vb = vb.pp("up_blocks").pp(i);
+            let upsample = i + 1 < n && layers[i] > 0;
+            if upsample {
+                blocks.push(Block::Up(UpBlock::new(
+                    channels[i + 1],
+                    channels[i],
+                    interpolate,
+                    vb.pp(0),
+                )?));
+            }
+            for j in 0..layers[i] {
+                blocks.push(Block::new(
+                    &block_types[i],
+                    channels[i],
+                    act_fns[i],
+                    cfg.attention_head_dim,
+                    &cfg.decoder_qkv_multiscales[i],
+                    vb.pp(j + usize::from(upsample)),
+                )?);
+            }
+        }
+        Ok(Self {
+            conv_in: conv3x3(cfg.latent_channels, channels[n - 1], vb.pp("conv_in"))?,
+            in_repeats: channels[n - 1] / cfg.latent_channels,
+            blocks,
+            norm_out: ChannelRmsNorm::new(channels[0], vb.pp("norm_out"))?,
+            conv_out: conv3x3(channels[0], cfg.in_channels, vb.pp("conv_out"))?,
+        })
+    }
+}
+
+impl Module for Decoder {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = (xs.apply(&self.conv_in)? + repeat_channels(xs, self.in_repeats)?)?;
+        for block in &self.blocks {
+            xs = xs.apply(block)?;
+        }
+        xs.apply(&self.norm_out)?.relu()?.apply(&self.conv_out)
+    }
+}
+
+/// The deep compression autoencoder (DC-AE) of Sana, as diffusers `AutoencoderDC`. Its latents are not
+/// sampled from a distribution.
+#[derive(Debug, Clone)]
+pub struct AutoEncoderDc {
+    encoder: Encoder,
+    decoder: Decoder,
+    scale_factor: f64,
+}
+
+impl AutoEncoderDc {
+    pub fn new(cfg: &AutoencoderDcConfig, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            encoder: Encoder::new(cfg, vb.pp("encoder"))?,
+            decoder: Decoder::new(cfg, vb.pp("decoder"))?,
+            scale_factor: cfg.scaling_factor,
+        })
+    }
+}
+
+impl VAEModel for AutoEncoderDc {
+    fn encode(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.encoder)
+    }
+
+    fn decode(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.decoder)
+    }
+
+    fn shift_factor(&self) -> f64 {
+        0.
+    }
+
+    fn scale_factor(&self) -> f64 {
+        self.scale_factor
+    }
+}
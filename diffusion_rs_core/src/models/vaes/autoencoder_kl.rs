@@ -12,6 +12,14 @@ fn default_act() -> Activation {
     Activation::Silu
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_scaling_factor() -> f64 {
+    0.18215
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AutencoderKlConfig {
     pub in_channels: usize,
@@ -22,10 +30,15 @@ pub struct AutencoderKlConfig {
     pub act_fn: Activation,
     pub latent_channels: usize,
     pub norm_num_groups: usize,
+    #[serde(default = "default_scaling_factor")]
     pub scaling_factor: f64,
-    pub shift_factor: f64,
+    /// Not set by Stable Diffusion VAEs, which do not shift the latents.
+    pub shift_factor: Option<f64>,
+    #[serde(default = "default_true")]
     pub mid_block_add_attention: bool,
+    #[serde(default = "default_true")]
     pub use_quant_conv: bool,
+    #[serde(default = "default_true")]
     pub use_post_quant_conv: bool,
     pub down_block_types: Vec<String>,
     pub up_block_types: Vec<String>,
@@ -63,7 +76,8 @@ impl AutoEncoderKl {
     pub fn new(cfg: &AutencoderKlConfig, vb: VarBuilder) -> Result<Self> {
         let encoder = Encoder::new(&cfg.clone().into(), vb.pp("encoder"))?;
         let decoder = Decoder::new(&cfg.clone().into(), vb.pp("decoder"))?;
-        let reg = DiagonalGaussian::new(true, 1)?;
+        // Use the mean of the latent distribution so that encoding an image is deterministic.
+        let reg = DiagonalGaussian::new(false, 1)?;
         let quant_conv = if cfg.use_quant_conv {
             Some(diffusion_rs_common::conv2d(
                 2 * cfg.latent_channels,
@@ -91,7 +105,7 @@ impl AutoEncoderKl {
             decoder,
             reg,
             scale_factor: cfg.scaling_factor,
-            shift_factor: cfg.shift_factor,
+            shift_factor: cfg.shift_factor.unwrap_or(0.),
             quant_conv,
             post_quant_conv,
         })
@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use autoencoder_dc::{AutoEncoderDc, AutoencoderDcConfig};
 use autoencoder_kl::{AutencoderKlConfig, AutoEncoderKl};
 use diffusion_rs_common::{
     core::{DType, Device, Result, Tensor},
@@ -9,11 +10,11 @@ use serde::Deserialize;
 
 use diffusion_rs_common::{from_mmaped_safetensors, FileData, VarBuilder};
 
+mod autoencoder_dc;
 mod autoencoder_kl;
 mod vae;
 
 pub(crate) trait VAEModel: Send + Sync {
-    #[allow(dead_code)]
     /// This function *does not* handle scaling the tensor! If you want to do this, apply the following to the output:
     /// `(x - vae.shift_factor())? * self.scale_factor()`
     fn encode(&self, xs: &Tensor) -> Result<Tensor>;
@@ -42,6 +43,15 @@ fn load_autoencoder_kl(
     Ok(Arc::new(AutoEncoderKl::new(&cfg, vb)?))
 }
 
+fn load_autoencoder_dc(
+    cfg_json: &FileData,
+    vb: VarBuilder,
+    source: Arc<ModelSource>,
+) -> anyhow::Result<Arc<dyn VAEModel>> {
+    let cfg: AutoencoderDcConfig = serde_json::from_str(&cfg_json.read_to_string(&source)?)?;
+    Ok(Arc::new(AutoEncoderDc::new(&cfg, vb)?))
+}
+
 pub(crate) fn dispatch_load_vae_model(
     cfg_json: &FileData,
     safetensor_files: Vec<FileData>,
@@ -61,6 +71,7 @@ pub(crate) fn dispatch_load_vae_model(
     let VaeConfigShim { name } = serde_json::from_str(&cfg_json.read_to_string(&source)?)?;
     match name.as_str() {
         "AutoencoderKL" => load_autoencoder_kl(cfg_json, vb, source),
+        "AutoencoderDC" => load_autoencoder_dc(cfg_json, vb, source),
         other => anyhow::bail!("Unexpected VAE type `{other:?}`."),
     }
 }
@@ -0,0 +1,502 @@
+//! Discrete-time (DDPM family) schedulers, as used by Stable Diffusion.
+//!
+//! These models are trained on `x_t = sqrt(alpha_t) * x0 + sqrt(1 - alpha_t) * noise`, with the noise level
+//! `sigma_t = sqrt((1 - alpha_t) / alpha_t)`. The same trajectory is given in flow-matching form by
+//! `x = x_t / (sqrt(alpha_t) * (1 + sigma_t))` at the flow sigma `s = sigma_t / (1 + sigma_t)`, which lets the
+//! flow-matching samplers run these models: the Euler method in this form is DDIM.
+
+use diffusion_rs_common::core::{Result, Tensor};
+use serde::Deserialize;
+
+use super::{
+    sampling::SamplerType,
+    scheduler::{sanitize_python_json, SigmaSpacing},
+};
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BetaSchedule {
+    #[default]
+    Linear,
+    ScaledLinear,
+    #[serde(rename = "squaredcos_cap_v2")]
+    SquaredCosCapV2,
+}
+
+/// What the model predicts.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PredictionType {
+    /// The noise.
+    #[default]
+    Epsilon,
+    /// The velocity `sqrt(alpha_t) * noise - sqrt(1 - alpha_t) * x0`.
+    VPrediction,
+    /// The clean sample.
+    Sample,
+}
+
+/// How the inference timesteps are chosen among the training timesteps.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestepSpacing {
+    Linspace,
+    Leading,
+    Trailing,
+}
+
+/// Config of a discrete-time scheduler (`scheduler_config.json`). Missing fields take the diffusers defaults.
+///
+/// Any diffusers scheduler class is accepted: the class only selects the default sampler.
+#[derive(Deserialize, Clone)]
+pub struct DiscreteSchedulerConfig {
+    #[serde(rename = "_class_name")]
+    pub class_name: String,
+    #[serde(default = "default_num_train_timesteps")]
+    pub num_train_timesteps: usize,
+    #[serde(default = "default_beta_start")]
+    pub beta_start: f64,
+    #[serde(default = "default_beta_end")]
+    pub beta_end: f64,
+    #[serde(default)]
+    pub beta_schedule: BetaSchedule,
+    pub trained_betas: Option<Vec<f64>>,
+    #[serde(default)]
+    pub prediction_type: PredictionType,
+    /// Defaults to `leading` for the DDIM, DDPM and PNDM schedulers, and `linspace` otherwise.
+    pub timestep_spacing: Option<TimestepSpacing>,
+    #[serde(default)]
+    pub steps_offset: usize,
+    #[serde(default)]
+    pub use_karras_sigmas: bool,
+    #[serde(default)]
+    pub use_exponential_sigmas: bool,
+    #[serde(default)]
+    pub use_beta_sigmas: bool,
+    /// The order of the multistep DPM-Solver schedulers.
+    #[serde(default = "default_solver_order")]
+    pub solver_order: usize,
+}
+
+/// Scheduler classes without an equivalent sampler.
+const UNSUPPORTED_SCHEDULERS: [&str; 3] = [
+    "PNDMScheduler",
+    "LMSDiscreteScheduler",
+    "UniPCMultistepScheduler",
+];
+
+fn default_solver_order() -> usize {
+    2
+}
+
+fn default_num_train_timesteps() -> usize {
+    1000
+}
+
+fn default_beta_start() -> f64 {
+    0.0001
+}
+
+fn default_beta_end() -> f64 {
+    0.02
+}
+
+impl DiscreteSchedulerConfig {
+    /// Parse a `scheduler_config.json`, reading infinite and NaN values as null.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(&sanitize_python_json(json))
+    }
+
+    /// The sampler equivalent to the scheduler class, which is used when the generation parameters don't specify
+    /// one. The PNDM, LMS and UniPC schedulers have no equivalent sampler, so a sampler must be given explicitly
+    /// for models configured with them, such as Stable Diffusion 1.5.
+    pub fn default_sampler(&self) -> Result<SamplerType> {
+        Ok(match self.class_name.as_str() {
+            "EulerAncestralDiscreteScheduler" | "DDPMScheduler" => SamplerType::EulerAncestral,
+            "HeunDiscreteScheduler" => SamplerType::Heun,
+            // First order DPM-Solver++ is DDIM.
+            "DPMSolverMultistepScheduler" | "DPMSolverSinglestepScheduler"
+                if self.solver_order == 1 =>
+            {
+                SamplerType::Euler
+            }
+            "DPMSolverMultistepScheduler" | "DPMSolverSinglestepScheduler" => {
+                SamplerType::DpmPlusPlus2M
+            }
+            class if UNSUPPORTED_SCHEDULERS.contains(&class) => diffusion_rs_common::bail!(
+                "`{class}` is not supported, choose a sampler in the generation parameters."
+            ),
+            _ => SamplerType::Euler,
+        })
+    }
+
+    fn timestep_spacing(&self) -> TimestepSpacing {
+        self.timestep_spacing
+            .unwrap_or(match self.class_name.as_str() {
+                "DDIMScheduler" | "DDPMScheduler" | "PNDMScheduler" => TimestepSpacing::Leading,
+                _ => TimestepSpacing::Linspace,
+            })
+    }
+
+    fn betas(&self) -> Vec<f64> {
+        if let Some(betas) = &self.trained_betas {
+            return betas.clone();
+        }
+        let n = self.num_train_timesteps;
+        let linspace = |start: f64, end: f64| {
+            (0..n).map(move |i| {
+                if n == 1 {
+                    start
+                } else {
+                    start + (end - start) * i as f64 / (n - 1) as f64
+                }
+            })
+        };
+        match self.beta_schedule {
+            BetaSchedule::Linear => linspace(self.beta_start, self.beta_end).collect(),
+            BetaSchedule::ScaledLinear => linspace(self.beta_start.sqrt(), self.beta_end.sqrt())
+                .map(|x| x * x)
+                .collect(),
+            BetaSchedule::SquaredCosCapV2 => {
+                let alpha_bar = |t: f64| {
+                    ((t + 0.008) / 1.008 * std::f64::consts::FRAC_PI_2)
+                        .cos()
+                        .powi(2)
+                };
+                (0..n)
+                    .map(|i| {
+                        let t1 = i as f64 / n as f64;
+                        let t2 = (i + 1) as f64 / n as f64;
+                        (1. - alpha_bar(t2) / alpha_bar(t1)).min(0.999)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// The noise level of each training timestep.
+    pub fn schedule(&self) -> Result<DiscreteSchedule> {
+        let betas = self.betas();
+        if betas.is_empty() {
+            diffusion_rs_common::bail!("The scheduler has no training timesteps.");
+        }
+        let mut alpha_cumprod = 1.;
+        let sigmas = betas
+            .iter()
+            .map(|beta| {
+                alpha_cumprod *= 1. - beta;
+                ((1. - alpha_cumprod) / alpha_cumprod).sqrt()
+            })
+            .collect();
+        Ok(DiscreteSchedule {
+            sigmas,
+            prediction_type: self.prediction_type,
+        })
+    }
+
+    /// Build the flow sigma schedule for `num_steps` steps, ending with 0, as the diffusers `set_timesteps`.
+    /// If `spacing` is not specified, it comes from the config.
+    pub fn get_timesteps(
+        &self,
+        schedule: &DiscreteSchedule,
+        num_steps: usize,
+        spacing: Option<SigmaSpacing>,
+    ) -> Result<Vec<f64>> {
+        if num_steps == 0 {
+            diffusion_rs_common::bail!("The number of steps must be at least 1.");
+        }
+        let n_train = schedule.sigmas.len();
+        let last = (n_train - 1) as f64;
+        let timesteps: Vec<f64> = match self.timestep_spacing() {
+            TimestepSpacing::Linspace => (0..num_steps)
+                .map(|i| {
+                    if num_steps == 1 {
+                        last
+                    } else {
+                        last * (1. - i as f64 / (num_steps - 1) as f64)
+                    }
+                })
+                .collect(),
+            TimestepSpacing::Leading => {
+                let ratio = n_train / num_steps;
+                (0..num_steps)
+                    .rev()
+                    .map(|i| ((i * ratio + self.steps_offset) as f64).min(last))
+                    .collect()
+            }
+            TimestepSpacing::Trailing => {
+                let ratio = n_train as f64 / num_steps as f64;
+                (0..num_steps)
+                    .map(|i| ((n_train as f64 - i as f64 * ratio).round() - 1.).max(0.))
+                    .collect()
+            }
+        };
+        let sigmas = timesteps.iter().map(|t| schedule.t_to_sigma(*t)).collect();
+        let spacing = match spacing {
+            Some(spacing) => spacing,
+            None => SigmaSpacing::from_flags(
+                self.use_karras_sigmas,
+                self.use_exponential_sigmas,
+                self.use_beta_sigmas,
+            )?,
+        };
+        let mut sigmas: Vec<f64> = spacing
+            .apply(sigmas)
+            .into_iter()
+            .map(|sigma| sigma / (1. + sigma))
+            .collect();
+        sigmas.push(0.);
+        Ok(sigmas)
+    }
+}
+
+/// The noise levels of the training timesteps of a discrete-time model, and the conversions between the model
+/// and flow-matching forms.
+#[derive(Clone, Debug)]
+pub struct DiscreteSchedule {
+    /// Increasing noise level of each training timestep.
+    sigmas: Vec<f64>,
+    prediction_type: PredictionType,
+}
+
+impl DiscreteSchedule {
+    /// The noise level at a fractional timestep, interpolated linearly.
+    fn t_to_sigma(&self, t: f64) -> f64 {
+        let last = self.sigmas.len() - 1;
+        let t = t.clamp(0., last as f64);
+        let i = (t.floor() as usize).min(last.saturating_sub(1));
+        let Some(next) = self.sigmas.get(i + 1) else {
+            return self.sigmas[i];
+        };
+        let frac = t - i as f64;
+        self.sigmas[i] + frac * (next - self.sigmas[i])
+    }
+
+    /// The fractional timestep of a noise level, interpolated linearly in log-sigma as the diffusers
+    /// `_sigma_to_t`. At the training timesteps, it is the inverse of [`DiscreteSchedule::t_to_sigma`].
+    fn sigma_to_t(&self, sigma: f64) -> f64 {
+        let i = self.sigmas.partition_point(|s| *s < sigma);
+        if i == 0 {
+            return 0.;
+        }
+        if i == self.sigmas.len() {
+            return (self.sigmas.len() - 1) as f64;
+        }
+        let (lo, hi) = (self.sigmas[i - 1].ln(), self.sigmas[i].ln());
+        (i - 1) as f64 + (sigma.ln() - lo) / (hi - lo)
+    }
+
+    /// For the flow sigma `s`, the model timestep and the scale from the flow sample to the model input.
+    pub fn model_input(&self, s: f64) -> (f64, f64) {
+        let sigma = s / (1. - s);
+        let scale = 1. / ((1. - s) * (1. + sigma * sigma).sqrt());
+        (self.sigma_to_t(sigma), scale)
+    }
+
+    /// Convert the model prediction for the flow sample `x` at the flow sigma `s` to the flow velocity.
+    pub fn velocity(&self, x: &Tensor, pred: &Tensor, s: f64) -> Result<Tensor> {
+        let sigma = s / (1. - s);
+        let x_t = (x / (1. - s))?;
+        let x0 = match self.prediction_type {
+            PredictionType::Epsilon => (x_t - (pred * sigma)?)?,
+            PredictionType::VPrediction => {
+                let c = 1. / (1. + sigma * sigma);
+                ((x_t * c)? - (pred * (sigma * c.sqrt()))?)?
+            }
+            PredictionType::Sample => pred.clone(),
+        };
+        (x - x0)? / s
+    }
+
+    /// The scale of pure noise at the flow sigma `s`, so that the model input has unit variance.
+    pub fn noise_scale(s: f64) -> f64 {
+        ((1. - s).powi(2) + s * s).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{Device, Result, Tensor};
+
+    use super::{DiscreteSchedulerConfig, PredictionType, TimestepSpacing};
+    use crate::pipelines::sampling::SamplerType;
+
+    /// The Stable Diffusion 1.5 betas.
+    const SD_BETAS: &str =
+        r#""beta_start": 0.00085, "beta_end": 0.012, "beta_schedule": "scaled_linear""#;
+
+    #[test]
+    fn dpm_solver_config() {
+        // The PixArt-Sigma scheduler config, whose `lambda_min_clipped` is `-inf`.
+        let json = r#"{
+  "_class_name": "DPMSolverMultistepScheduler",
+  "_diffusers_version": "0.22.0.dev0",
+  "algorithm_type": "dpmsolver++",
+  "beta_end": 0.02,
+  "beta_schedule": "linear",
+  "beta_start": 0.0001,
+  "dynamic_thresholding_ratio": 0.995,
+  "euler_at_final": false,
+  "lambda_min_clipped": -Infinity,
+  "lower_order_final": true,
+  "num_train_timesteps": 1000,
+  "prediction_type": "epsilon",
+  "sample_max_value": 1.0,
+  "solver_order": 2,
+  "solver_type": "midpoint",
+  "steps_offset": 0,
+  "thresholding": false,
+  "timestep_spacing": "linspace",
+  "trained_betas": null,
+  "use_karras_sigmas": false,
+  "use_lu_lambdas": false,
+  "variance_type": null
+}"#;
+        let config = DiscreteSchedulerConfig::from_json(json).unwrap();
+        assert_eq!(config.solver_order, 2);
+        assert_eq!(config.timestep_spacing, Some(TimestepSpacing::Linspace));
+        assert_eq!(
+            config.default_sampler().unwrap(),
+            SamplerType::DpmPlusPlus2M
+        );
+    }
+
+    #[test]
+    fn timesteps_match_diffusers() -> Result<()> {
+        // `sigmas / (1 + sigmas)` after `set_timesteps(10)` of the diffusers Euler and DDIM schedulers, which compute
+        // the schedule in float32.
+        let cases = [
+            (
+                // Leading: timesteps 901, 801, ..., 1.
+                format!(
+                    r#"{{"_class_name": "EulerDiscreteScheduler", {SD_BETAS}, "steps_offset": 1, "timestep_spacing": "leading"}}"#
+                ),
+                vec![
+                    0.8935114641808076,
+                    0.8369856675936382,
+                    0.7699966280681276,
+                    0.6963119051803015,
+                    0.6188575506102812,
+                    0.5387912599471166,
+                    0.45523457406983725,
+                    0.36469628996043724,
+                    0.2571610181611321,
+                    0.03967531403647865,
+                ],
+            ),
+            (
+                // Trailing: timesteps 999, 899, ..., 99.
+                format!(
+                    r#"{{"_class_name": "DDIMScheduler", {SD_BETAS}, "timestep_spacing": "trailing"}}"#
+                ),
+                vec![
+                    0.9359575147844356,
+                    0.8925054953030818,
+                    0.835736032132309,
+                    0.7685764749380253,
+                    0.694792699656161,
+                    0.617281280790245,
+                    0.5371608920251671,
+                    0.45350988262389014,
+                    0.36276708591750484,
+                    0.2546623927774664,
+                ],
+            ),
+            (
+                // Linspace: timesteps 999, 888, ..., 0, with the default linear betas.
+                r#"{"_class_name": "EulerDiscreteScheduler"}"#.to_string(),
+                vec![
+                    0.9936871573010015,
+                    0.9820038707494799,
+                    0.9553919805656712,
+                    0.9046612929901171,
+                    0.8246639259257526,
+                    0.7186816017084461,
+                    0.5938849155786763,
+                    0.45113315972130213,
+                    0.27555695564858923,
+                    0.00990229360196895,
+                ],
+            ),
+        ];
+        for (json, expected) in cases {
+            let config = DiscreteSchedulerConfig::from_json(&json).unwrap();
+            let sigmas = config.get_timesteps(&config.schedule()?, 10, None)?;
+            assert_eq!(sigmas.len(), expected.len() + 1, "{json}");
+            assert_eq!(sigmas.last(), Some(&0.), "{json}");
+            for (sigma, expected) in sigmas.iter().zip(expected) {
+                assert!(
+                    (sigma - expected).abs() < 1e-6,
+                    "{json}: {sigma} != {expected}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn model_input_matches_diffusers() -> Result<()> {
+        let config = DiscreteSchedulerConfig::from_json(&format!(
+            r#"{{"_class_name": "EulerDiscreteScheduler", {SD_BETAS}}}"#
+        ))
+        .unwrap();
+        let schedule = config.schedule()?;
+        let flow = |sigma: f64| sigma / (1. + sigma);
+        for t in [0, 1, 500, 999] {
+            // The Euler scheduler runs the model at the timestep `t` on `x_t / sqrt(sigma^2 + 1)`, and the flow
+            // sample is `x_t / (1 + sigma)`.
+            let sigma = schedule.sigmas[t];
+            let (model_t, scale) = schedule.model_input(flow(sigma));
+            assert!((model_t - t as f64).abs() < 1e-6, "{model_t} != {t}");
+            let expected = (1. + sigma) / (sigma * sigma + 1.).sqrt();
+            assert!((scale - expected).abs() < 1e-9, "{scale} != {expected}");
+        }
+        // Between training timesteps, the timestep is interpolated in log-sigma as `_sigma_to_t`.
+        let sigma = (schedule.sigmas[500] * schedule.sigmas[501]).sqrt();
+        let (model_t, _) = schedule.model_input(flow(sigma));
+        assert!((model_t - 500.5).abs() < 1e-9, "{model_t} != 500.5");
+        Ok(())
+    }
+
+    #[test]
+    fn velocity_matches_diffusers() -> Result<()> {
+        let device = Device::Cpu;
+        let config = DiscreteSchedulerConfig::from_json(&format!(
+            r#"{{"_class_name": "EulerDiscreteScheduler", {SD_BETAS}}}"#
+        ))
+        .unwrap();
+        let x0 = Tensor::new(&[0.5f64, -1.25, 2.], &device)?;
+        let noise = Tensor::new(&[-0.75f64, 1.5, 0.25], &device)?;
+        let (sigma, next_sigma) = (3.5, 1.25);
+        let (s, next_s) = (sigma / (1. + sigma), next_sigma / (1. + next_sigma));
+        // The variance-preserving sample and the flow sample.
+        let alpha = 1. / (1. + sigma * sigma);
+        let x_t = ((&x0 + (&noise * sigma)?)? * alpha.sqrt())?;
+        let x = ((&x0 + (&noise * sigma)?)? / (1. + sigma))?;
+        for (prediction_type, pred) in [
+            (PredictionType::Epsilon, noise.clone()),
+            (
+                PredictionType::VPrediction,
+                ((&noise * alpha.sqrt())? - (&x0 * (1. - alpha).sqrt())?)?,
+            ),
+            (PredictionType::Sample, x0.clone()),
+        ] {
+            let mut schedule = config.schedule()?;
+            schedule.prediction_type = prediction_type;
+            let velocity = schedule.velocity(&x, &pred, s)?;
+            // An Euler step in the flow form is a DDIM step: the next sample keeps the predicted noise.
+            let next = (&x + (velocity * (next_s - s))?)?;
+            let next_alpha = 1. / (1. + next_sigma * next_sigma);
+            let ddim = ((&x0 * next_alpha.sqrt())? + (&noise * (1. - next_alpha).sqrt())?)?;
+            let next_t = (next * ((1. + next_sigma) * next_alpha.sqrt()))?;
+            let error = (next_t - ddim)?.abs()?.max(0)?.to_scalar::<f64>()?;
+            assert!(error < 1e-12, "{prediction_type:?}: {error}");
+            // The model input at `s` is the sample of the Euler scheduler, `x_t / sqrt(alpha_t) / sqrt(sigma^2 + 1)`,
+            // which is the variance-preserving sample.
+            let (_, scale) = schedule.model_input(s);
+            let error = ((&x * scale)? - &x_t)?.abs()?.max(0)?.to_scalar::<f64>()?;
+            assert!(error < 1e-12, "{prediction_type:?}: {error}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor};
+use diffusion_rs_common::from_mmaped_safetensors;
+use diffusion_rs_common::nn::Module;
+use image::DynamicImage;
+use tracing::info;
+
+use crate::models::{
+    ClipVisionConfig, ClipVisionModelWithProjection, FluxIpAdapter, FluxIpAdapterConditioning,
+    FluxModel,
+};
+use crate::pipelines::{clip_image_to_tensor, ImagePrompt, ModelFiles};
+
+/// An IP-Adapter of a FLUX pipeline, with the CLIP image encoder it was trained with.
+pub(super) struct IpAdapter {
+    pub name: String,
+    adapter: FluxIpAdapter,
+    image_encoder: ClipVisionModelWithProjection,
+    /// The scale used when a request does not select the active adapters.
+    pub scale: f64,
+    dtype: DType,
+    device: Device,
+}
+
+impl IpAdapter {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        tensors: HashMap<String, Tensor>,
+        image_encoder: ModelFiles,
+        scale: f64,
+        flux: &FluxModel,
+        dtype: DType,
+        device: &Device,
+        silent: bool,
+    ) -> Result<Self> {
+        let adapter = FluxIpAdapter::new(
+            tensors,
+            flux.num_double_blocks(),
+            flux.hidden_size(),
+            dtype,
+            device,
+        )?;
+
+        if !silent {
+            info!("loading IP-Adapter CLIP image encoder");
+        }
+        // The encoder may be given as a full CLIP model, whose config nests the vision config.
+        let mut config: serde_json::Value = serde_json::from_str(&image_encoder.config)?;
+        if let Some(mut vision_config) = config.get("vision_config").cloned() {
+            if let Some(projection_dim) = config.get("projection_dim") {
+                vision_config["projection_dim"] = projection_dim.clone();
+            }
+            config = vision_config;
+        }
+        let cfg: ClipVisionConfig = serde_json::from_value(config)?;
+        let adapter_dim = adapter.image_embedding_dim()?;
+        if cfg.projection_dim != adapter_dim {
+            anyhow::bail!(
+                "the image encoder embedding size {} does not match the IP-Adapter embedding size {adapter_dim}",
+                cfg.projection_dim
+            )
+        }
+        let vb = from_mmaped_safetensors(
+            image_encoder.safetensors,
+            Some(dtype),
+            device,
+            silent,
+            image_encoder.source,
+        )?;
+        let image_encoder = ClipVisionModelWithProjection::new(vb, &cfg)?;
+
+        Ok(Self {
+            name,
+            adapter,
+            image_encoder,
+            scale,
+            dtype,
+            device: device.clone(),
+        })
+    }
+
+    /// Compute the conditioning of the image prompts for a batch of `batch_size`, and the unconditional one of
+    /// black images for classifier-free guidance.
+    pub fn conditioning(
+        &self,
+        images: &[ImagePrompt],
+        scale: f64,
+        batch_size: usize,
+    ) -> diffusion_rs_common::core::Result<(FluxIpAdapterConditioning, FluxIpAdapterConditioning)>
+    {
+        let size = self.image_encoder.image_size();
+        #[allow(clippy::cast_possible_truncation)]
+        let black = DynamicImage::new_rgb8(size as u32, size as u32);
+        let encode = |images: Vec<&DynamicImage>| -> diffusion_rs_common::core::Result<Tensor> {
+            let pixel_values = images
+                .into_iter()
+                .map(|image| clip_image_to_tensor(image, size, self.dtype, &self.device))
+                .collect::<diffusion_rs_common::core::Result<Vec<_>>>()?;
+            self.image_encoder.forward(&Tensor::cat(&pixel_values, 0)?)
+        };
+        let weights = images.iter().map(|image| image.weight).collect::<Vec<_>>();
+        let embeds = encode(images.iter().map(|image| &image.image).collect())?;
+        let negative_embeds = encode(vec![&black; images.len()])?;
+        Ok((
+            self.adapter
+                .conditioning(&embeds, &weights, scale, batch_size)?,
+            self.adapter
+                .conditioning(&negative_embeds, &weights, scale, batch_size)?,
+        ))
+    }
+}
@@ -2,28 +2,82 @@ use std::sync::Mutex;
 use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
 use anyhow::Result;
-use diffusion_rs_common::core::{DType, Device, Tensor, D};
+use diffusion_rs_common::core::{DType, Device, IndexOp, Tensor, D};
 use diffusion_rs_common::nn::Module;
+use image::DynamicImage;
 use tokenizers::Tokenizer;
 use tracing::info;
 
 use crate::models::QuantizedModel;
 use crate::{
     models::{
-        dispatch_load_vae_model, ClipTextConfig, ClipTextTransformer, FluxConfig, FluxModel,
-        T5Config, T5EncoderModel, VAEModel,
+        dispatch_load_vae_model, ClipTextConfig, ClipTextTransformer, FluxConfig, FluxControlNet,
+        FluxControlNetConfig, FluxControlNetResiduals, FluxModel, T5Config, T5EncoderModel,
+        VAEModel,
     },
     pipelines::ComponentName,
 };
 use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
 
-use super::sampling::Sampler;
-use super::scheduler::SchedulerConfig;
-use super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+use super::lora::Loras;
+use super::noise::NoiseGenerator;
+use super::sampling::{sample, InpaintMask, SamplerType};
+use super::scheduler::{img2img_start_step, SchedulerConfig};
+use super::stable_diffusion::{with_textual_inversions, ClipTokenizer};
+use super::textual_inversion::TextualInversion;
+use super::{
+    image_to_tensor, mask_to_tensor, ComponentElem, DiffusionGenerationParams, Loader, ModelFiles,
+    ModelPipeline, Offloading,
+};
 
+mod ip_adapter;
+mod redux;
 mod sampling;
 
-pub struct FluxLoader;
+use ip_adapter::IpAdapter;
+
+pub use redux::FluxReduxLoader;
+
+pub(super) use sampling::calculate_shift;
+
+/// FLUX.1 Fill concatenates the packed masked-image latents (64 channels) and the packed 8x8 mask (256 channels)
+/// to the latents.
+const FILL_CONDITIONING_CHANNELS: usize = 320;
+
+/// FLUX.1 Depth and Canny concatenate the packed latents of the control image (64 channels) to the latents.
+const CONTROL_CONDITIONING_CHANNELS: usize = 64;
+
+/// The longest T5 prompt that Chroma was trained with, including the padding token.
+const CHROMA_MAX_SEQUENCE_LENGTH: usize = 512;
+
+/// The (width, height) resolutions FLUX.1 Kontext was trained with. Reference images are resized to the one
+/// closest to their aspect ratio.
+const KONTEXT_RESOLUTIONS: &[(usize, usize)] = &[
+    (672, 1568),
+    (688, 1504),
+    (720, 1456),
+    (752, 1392),
+    (800, 1328),
+    (832, 1248),
+    (880, 1184),
+    (944, 1104),
+    (1024, 1024),
+    (1104, 944),
+    (1184, 880),
+    (1248, 832),
+    (1328, 800),
+    (1392, 752),
+    (1456, 720),
+    (1504, 688),
+    (1568, 672),
+];
+
+pub struct FluxLoader {
+    /// Whether to load a Chroma pipeline, which has a single T5 text encoder and no CLIP.
+    pub chroma: bool,
+    /// Whether to load a FLUX.1 Kontext pipeline, which edits a reference image as instructed by the prompt.
+    pub kontext: bool,
+}
 
 impl Loader for FluxLoader {
     fn name(&self) -> &'static str {
@@ -31,6 +85,15 @@ impl Loader for FluxLoader {
     }
 
     fn required_component_names(&self) -> Vec<ComponentName> {
+        if self.chroma {
+            return vec![
+                ComponentName::Scheduler,
+                ComponentName::TextEncoder(1),
+                ComponentName::Tokenizer(1),
+                ComponentName::Transformer,
+                ComponentName::Vae,
+            ];
+        }
         vec![
             ComponentName::Scheduler,
             ComponentName::TextEncoder(1),
@@ -52,10 +115,21 @@ impl Loader for FluxLoader {
         source: Arc<ModelSource>,
     ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
         let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
-        let clip_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
-        let t5_component = components.remove(&ComponentName::TextEncoder(2)).unwrap();
-        let clip_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
-        let t5_tok_component = components.remove(&ComponentName::Tokenizer(2)).unwrap();
+        let (clip_component, clip_tok_component) = if self.chroma {
+            (None, None)
+        } else {
+            (
+                components.remove(&ComponentName::TextEncoder(1)),
+                components.remove(&ComponentName::Tokenizer(1)),
+            )
+        };
+        let t5_idx = if self.chroma { 1 } else { 2 };
+        let t5_component = components
+            .remove(&ComponentName::TextEncoder(t5_idx))
+            .unwrap();
+        let t5_tok_component = components
+            .remove(&ComponentName::Tokenizer(t5_idx))
+            .unwrap();
         let flux_component = components.remove(&ComponentName::Transformer).unwrap();
         let vae_component = components.remove(&ComponentName::Vae).unwrap();
 
@@ -65,46 +139,53 @@ impl Loader for FluxLoader {
         };
 
         let scheduler_config = if let ComponentElem::Config { files } = scheduler {
-            serde_json::from_str::<SchedulerConfig>(
+            SchedulerConfig::from_json(
                 &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
             )?
         } else {
             anyhow::bail!("expected scheduler config")
         };
-        let clip_tokenizer = if let ComponentElem::Other { files } = clip_tok_component {
-            let vocab_file = &files["tokenizer/vocab.json"];
-            let merges_file = &files["tokenizer/merges.txt"];
-
-            diffusion_rs_common::load_bpe_tokenizer(vocab_file, merges_file, &source)?
-        } else {
-            anyhow::bail!("incorrect storage of clip tokenizer")
-        };
         let t5_tokenizer = if let ComponentElem::Other { files } = t5_tok_component {
-            Tokenizer::from_bytes(files["tokenizer_2/tokenizer.json"].read_to_string(&source)?)
-                .map_err(anyhow::Error::msg)?
+            let file = if self.chroma {
+                "tokenizer/tokenizer.json"
+            } else {
+                "tokenizer_2/tokenizer.json"
+            };
+            let Some(file) = files.get(file) else {
+                anyhow::bail!("the T5 tokenizer must be given as `{file}`")
+            };
+            Tokenizer::from_bytes(file.read_to_string(&source)?).map_err(anyhow::Error::msg)?
         } else {
             anyhow::bail!("incorrect storage of t5 tokenizer")
         };
-        if !silent {
-            info!("loading CLIP model");
-        }
-        let clip_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = clip_component
-        {
-            let cfg: ClipTextConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+        let clip = match (clip_tok_component, clip_component) {
+            (Some(clip_tok_component), Some(clip_component)) => {
+                let clip_tokenizer = ClipTokenizer::load(clip_tok_component, "tokenizer", &source)?;
+                if !silent {
+                    info!("loading CLIP model");
+                }
+                let clip_component = if let ComponentElem::Model {
+                    safetensors,
+                    config,
+                } = clip_component
+                {
+                    let cfg: ClipTextConfig =
+                        serde_json::from_str(&config.read_to_string(&source)?)?;
 
-            let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                device,
-                silent,
-                source.clone(),
-            )?;
-            ClipTextTransformer::new(vb.pp("text_model"), &cfg)?
-        } else {
-            anyhow::bail!("incorrect storage of clip model")
+                    let vb = from_mmaped_safetensors(
+                        safetensors.into_values().collect(),
+                        Some(dtype),
+                        device,
+                        silent,
+                        source.clone(),
+                    )?;
+                    ClipTextTransformer::new(vb.pp("text_model"), &cfg)?
+                } else {
+                    anyhow::bail!("incorrect storage of clip model")
+                };
+                Some((clip_tokenizer, clip_component))
+            }
+            _ => None,
         };
         if !silent {
             info!("loading T5 model");
@@ -148,12 +229,33 @@ impl Loader for FluxLoader {
         if !silent {
             info!("loading FLUX model");
         }
+        let (fill, control);
         let flux_component = if let ComponentElem::Model {
             safetensors,
             config,
         } = flux_component
         {
             let cfg: FluxConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            if cfg.is_chroma() != self.chroma {
+                anyhow::bail!(
+                    "the transformer {} a Chroma model, unlike the pipeline",
+                    if cfg.is_chroma() { "is" } else { "is not" }
+                )
+            }
+            let Some(conditioning_channels) = cfg.in_channels.checked_sub(cfg.out_channels())
+            else {
+                anyhow::bail!(
+                    "unsupported FLUX model with {} input channels, fewer than its {} output channels",
+                    cfg.in_channels,
+                    cfg.out_channels()
+                )
+            };
+            (fill, control) = match conditioning_channels {
+                0 => (false, false),
+                FILL_CONDITIONING_CHANNELS => (true, false),
+                CONTROL_CONDITIONING_CHANNELS => (false, true),
+                other => anyhow::bail!("unsupported FLUX model with {other} conditioning channels"),
+            };
             let vb = from_mmaped_safetensors(
                 safetensors.into_values().collect(),
                 Some(dtype),
@@ -171,16 +273,32 @@ impl Loader for FluxLoader {
                 "FLUX pipeline using a guidance-distilled model: {}",
                 flux_component.is_guidance()
             );
+            if fill {
+                info!("FLUX pipeline using a FLUX.1 Fill model");
+            }
+            if control {
+                info!("FLUX pipeline using a FLUX.1 Depth or Canny model");
+            }
+            if self.kontext {
+                info!("FLUX pipeline using a FLUX.1 Kontext model");
+            }
         }
 
         let pipeline = FluxPipeline {
-            clip_tokenizer: Arc::new(clip_tokenizer),
-            clip_model: clip_component,
+            clip,
             t5_tokenizer: Arc::new(t5_tokenizer),
             t5_model: t5_component,
             vae_model: vae_component,
             flux_model: flux_component,
             scheduler_config,
+            fill,
+            control,
+            kontext: self.kontext,
+            prior: None,
+            controlnets: Vec::new(),
+            ip_adapters: Vec::new(),
+            loras: Loras::default(),
+            dtype,
             device: device.clone(),
         };
 
@@ -189,13 +307,27 @@ impl Loader for FluxLoader {
 }
 
 pub struct FluxPipeline {
-    clip_tokenizer: Arc<Tokenizer>,
-    clip_model: ClipTextTransformer,
+    /// The CLIP tokenizer and text model, which Chroma does not use.
+    clip: Option<(ClipTokenizer, ClipTextTransformer)>,
     t5_tokenizer: Arc<Tokenizer>,
     t5_model: T5EncoderModel,
     vae_model: Arc<dyn VAEModel>,
     flux_model: FluxModel,
     scheduler_config: SchedulerConfig,
+    /// Whether the transformer is a FLUX.1 Fill model, conditioned on a masked image and its mask.
+    fill: bool,
+    /// Whether the transformer is a FLUX.1 Depth or Canny model, conditioned on the latents of a control image.
+    control: bool,
+    /// Whether the transformer is a FLUX.1 Kontext model, conditioned on the tokens of a reference image.
+    kontext: bool,
+    /// A prior such as FLUX.1 Redux, which encodes the image prompts into extra text tokens.
+    prior: Option<Arc<Mutex<dyn ModelPipeline>>>,
+    /// ControlNets, selected by index by the control images.
+    controlnets: Vec<FluxControlNet>,
+    /// IP-Adapters, which condition the generations on the image prompts.
+    ip_adapters: Vec<IpAdapter>,
+    loras: Loras,
+    dtype: DType,
     device: Device,
 }
 
@@ -219,6 +351,101 @@ impl FluxPipeline {
 
         Ok(t5_tokens)
     }
+
+    /// Encode the prompts for Chroma. Each prompt is encoded on its own and keeps a single padding token, as Chroma
+    /// was trained with. The embeddings are then zero-padded to a common length, and the returned mask excludes
+    /// that padding.
+    fn chroma_t5_embeds(
+        &self,
+        prompts: Vec<String>,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let tokenizations = self
+            .t5_tokenizer
+            .encode_batch(prompts, true)
+            .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?;
+        let mut embeds = Vec::with_capacity(tokenizations.len());
+        for tokenization in tokenizations {
+            let mut ids = tokenization.get_ids().to_vec();
+            ids.truncate(CHROMA_MAX_SEQUENCE_LENGTH - 1);
+            ids.push(0);
+            let ids = Tensor::new(ids, &self.device)?.unsqueeze(0)?;
+            embeds.push(self.t5_model.forward(&ids)?);
+        }
+
+        let max_len = embeds.iter().map(|e| e.dims()[1]).max().unwrap();
+        let mut mask = Vec::with_capacity(embeds.len() * max_len);
+        let mut padded = Vec::with_capacity(embeds.len());
+        for embed in embeds {
+            let len = embed.dim(1)?;
+            mask.extend((0..max_len).map(|i| u8::from(i < len)));
+            padded.push(embed.pad_with_zeros(1, 0, max_len - len)?);
+        }
+        let mask = Tensor::from_vec(mask, (padded.len(), max_len), &self.device)?;
+        Ok((Tensor::cat(&padded, 0)?, mask))
+    }
+
+    /// Encode an image into unpacked latents, applying the VAE shift and scale factors.
+    fn encode_image(
+        &self,
+        image: &DynamicImage,
+        height: usize,
+        width: usize,
+        dtype: DType,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let height = height.div_ceil(16) * 16;
+        let width = width.div_ceil(16) * 16;
+        let xs = image_to_tensor(image, height, width, dtype, &self.device)?;
+        let latents = self.vae_model.encode(&xs)?;
+        (latents - self.vae_model.shift_factor())? * self.vae_model.scale_factor()
+    }
+
+    /// Encode a FLUX.1 Kontext reference image into unpacked latents, at the trained resolution closest to its
+    /// aspect ratio.
+    fn encode_reference_image(
+        &self,
+        image: &DynamicImage,
+        dtype: DType,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let aspect_ratio = image.width() as f64 / image.height() as f64;
+        let (width, height) = KONTEXT_RESOLUTIONS
+            .iter()
+            .copied()
+            .min_by(|(w1, h1), (w2, h2)| {
+                let d1 = (aspect_ratio - *w1 as f64 / *h1 as f64).abs();
+                let d2 = (aspect_ratio - *w2 as f64 / *h2 as f64).abs();
+                d1.total_cmp(&d2)
+            })
+            .unwrap();
+        self.encode_image(image, height, width, dtype)
+    }
+
+    /// Build the FLUX.1 Fill conditioning: the packed latents of the masked image, concatenated with the binarized
+    /// mask folded into 8x8 patches and packed. The result has `FILL_CONDITIONING_CHANNELS` channels.
+    fn fill_conditioning(
+        &self,
+        image: &DynamicImage,
+        mask: &DynamicImage,
+        height: usize,
+        width: usize,
+        dtype: DType,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let height = height.div_ceil(16) * 16;
+        let width = width.div_ceil(16) * 16;
+        let image = image_to_tensor(image, height, width, dtype, &self.device)?;
+        let mask = mask_to_tensor(mask, height, width, DType::F32, &self.device)?
+            .ge(0.5)?
+            .to_dtype(dtype)?;
+        let masked_image = image.broadcast_mul(&(1. - &mask)?)?;
+        let latents = self.vae_model.encode(&masked_image)?;
+        let latents = ((latents - self.vae_model.shift_factor())? * self.vae_model.scale_factor())?;
+
+        let (h, w) = (height / 8, width / 8);
+        let mask = mask
+            .reshape((1, h, 8, w, 8))?
+            .permute((0, 2, 4, 1, 3))?
+            .reshape((1, 64, h, w))?;
+        Tensor::cat(&[sampling::pack(&latents)?, sampling::pack(&mask)?], 2)
+    }
 }
 
 impl ModelPipeline for FluxPipeline {
@@ -228,6 +455,8 @@ impl ModelPipeline for FluxPipeline {
         params: DiffusionGenerationParams,
         offloading_type: Option<Offloading>,
     ) -> diffusion_rs_common::core::Result<Tensor> {
+        self.loras.activate(params.loras.as_ref())?;
+
         match offloading_type {
             Some(Offloading::Full) => {
                 self.t5_model.to_device(&self.device)?;
@@ -235,24 +464,49 @@ impl ModelPipeline for FluxPipeline {
             None => (),
         }
 
-        let mut t5_input_ids = Tensor::new(
-            Self::tokenize_and_pad(prompts.clone(), &self.t5_tokenizer)?,
-            &self.device,
-        )?;
+        // With true CFG, the negative prompts are encoded in the same batch so that they are padded the same way.
+        // Chroma is not guidance-distilled, so `guidance_scale` is its classifier-free guidance scale.
+        let bs = prompts.len();
+        let chroma = self.flux_model.is_chroma();
+        let cfg_scale = if chroma {
+            params.guidance_scale
+        } else {
+            params.true_cfg_scale
+        };
+        let true_cfg = cfg_scale > 1.;
+        let mut all_prompts = prompts;
+        if true_cfg {
+            let negative_prompt = params.negative_prompt.clone().unwrap_or_default();
+            all_prompts.extend(vec![negative_prompt; bs]);
+        }
 
-        if !self.flux_model.is_guidance() {
-            match t5_input_ids.dim(1)?.cmp(&256) {
-                Ordering::Greater => {
-                    diffusion_rs_common::bail!("T5 embedding length greater than 256, please shrink the prompt or use the -dev (with guidance distillation) version.")
-                }
-                Ordering::Less | Ordering::Equal => {
-                    t5_input_ids =
-                        t5_input_ids.pad_with_zeros(D::Minus1, 0, 256 - t5_input_ids.dim(1)?)?;
+        let (t5_embed, t5_mask) = if chroma {
+            let (t5_embed, t5_mask) = self.chroma_t5_embeds(all_prompts.clone())?;
+            (t5_embed, Some(t5_mask))
+        } else {
+            let mut t5_input_ids = Tensor::new(
+                Self::tokenize_and_pad(all_prompts.clone(), &self.t5_tokenizer)?,
+                &self.device,
+            )?;
+
+            if !self.flux_model.is_guidance() {
+                match t5_input_ids.dim(1)?.cmp(&256) {
+                    Ordering::Greater => {
+                        diffusion_rs_common::bail!("T5 embedding length greater than 256, please shrink the prompt or use the -dev (with guidance distillation) version.")
+                    }
+                    Ordering::Less | Ordering::Equal => {
+                        t5_input_ids = t5_input_ids.pad_with_zeros(
+                            D::Minus1,
+                            0,
+                            256 - t5_input_ids.dim(1)?,
+                        )?;
+                    }
                 }
             }
-        }
 
-        let t5_embed = self.t5_model.forward(&t5_input_ids)?;
+            let t5_embed = self.t5_model.forward(&t5_input_ids)?;
+            (t5_embed, None)
+        };
 
         match offloading_type {
             Some(Offloading::Full) => {
@@ -261,33 +515,263 @@ impl ModelPipeline for FluxPipeline {
             None => (),
         }
 
-        let clip_input_ids = Tensor::new(
-            Self::tokenize_and_pad(prompts, &self.clip_tokenizer)?,
-            self.clip_model.device(),
-        )?;
-        let clip_embed = self.clip_model.forward(&clip_input_ids)?;
+        let clip_embed = match &self.clip {
+            Some((clip_tokenizer, clip_model)) => {
+                let (clip_tokenizer, clip_model) = with_textual_inversions(
+                    clip_tokenizer,
+                    clip_model,
+                    &params.textual_inversions,
+                )?;
+                let clip_input_ids = clip_tokenizer.tokenize(all_prompts, clip_model.device())?;
+                Some(clip_model.forward(&clip_input_ids)?)
+            }
+            None if !params.textual_inversions.is_empty() => {
+                diffusion_rs_common::bail!(
+                    "This pipeline has no CLIP text encoder for textual inversion embeddings."
+                )
+            }
+            None => None,
+        };
+
+        let narrow = |xs: &Option<Tensor>, start: usize| {
+            xs.as_ref().map(|xs| xs.narrow(0, start, bs)).transpose()
+        };
+        let negative_embeds = if true_cfg {
+            Some((
+                t5_embed.narrow(0, bs, bs)?,
+                narrow(&clip_embed, bs)?,
+                narrow(&t5_mask, bs)?,
+            ))
+        } else {
+            None
+        };
+        let mut t5_embed = t5_embed.narrow(0, 0, bs)?;
+        let clip_embed = narrow(&clip_embed, 0)?;
+        let mut t5_mask = narrow(&t5_mask, 0)?;
 
+        // The image prompt tokens condition the prompts only, not the negative prompts.
+        if !params.image_prompts.is_empty() && self.prior.is_none() && self.ip_adapters.is_empty() {
+            diffusion_rs_common::bail!("Image prompts require a prior such as FLUX.1 Redux, attached with `Pipeline::set_prior`, or an IP-Adapter, loaded with `Pipeline::load_ip_adapter`.")
+        }
+        if let (false, Some(prior)) = (params.image_prompts.is_empty(), &self.prior) {
+            let image_tokens = prior
+                .lock()
+                .expect("Could not lock prior!")
+                .encode_image_prompts(&params.image_prompts, t5_embed.dtype(), t5_embed.device())?
+                .repeat((bs, 1, 1))?;
+            if let Some(mask) = &t5_mask {
+                let image_mask =
+                    Tensor::ones((bs, image_tokens.dim(1)?), mask.dtype(), mask.device())?;
+                t5_mask = Some(Tensor::cat(&[mask, &image_mask], 1)?);
+            }
+            t5_embed = Tensor::cat(&[&t5_embed, &image_tokens], 1)?;
+        }
+
+        // The IP-Adapters attend to the image prompts, and to black images for the negative prompts.
+        if let Some(scales) = &params.ip_adapters {
+            if let Some(name) = scales
+                .keys()
+                .find(|name| !self.ip_adapters.iter().any(|a| &a.name == *name))
+            {
+                diffusion_rs_common::bail!("IP-Adapter `{name}` is not loaded.")
+            }
+        }
+        let mut ip_adapters = Vec::new();
+        let mut negative_ip_adapters = Vec::new();
+        if !params.image_prompts.is_empty() {
+            for ip_adapter in &self.ip_adapters {
+                let scale = match &params.ip_adapters {
+                    Some(scales) => match scales.get(&ip_adapter.name) {
+                        Some(scale) => *scale,
+                        None => continue,
+                    },
+                    None => ip_adapter.scale,
+                };
+                let (conditioning, negative_conditioning) =
+                    ip_adapter.conditioning(&params.image_prompts, scale, bs)?;
+                ip_adapters.push(conditioning);
+                negative_ip_adapters.push(negative_conditioning);
+            }
+        }
+
+        let mut rng = NoiseGenerator::new(&params, bs)?;
         let mut img = sampling::get_noise(
-            t5_embed.dim(0)?,
+            &mut rng,
             params.height,
             params.width,
+            t5_embed.dtype(),
             t5_embed.device(),
-        )?
-        .to_dtype(t5_embed.dtype())?;
+        )?;
 
-        let state = sampling::State::new(&t5_embed, &clip_embed, &img)?;
+        // The sequence length of the packed latents.
+        let image_seq_len = (img.dim(2)? / 2) * (img.dim(3)? / 2);
         let mu = sampling::calculate_shift(
-            img.dims()[1],
+            image_seq_len,
             self.scheduler_config.base_image_seq_len,
             self.scheduler_config.max_image_seq_len,
             self.scheduler_config.base_shift,
             self.scheduler_config.max_shift,
         );
-        let timesteps = self
-            .scheduler_config
-            .get_timesteps(params.num_steps, Some(mu))?;
+        // As in diffusers, the base sigmas are evenly spaced from 1 to `1 / num_steps`.
+        let sigmas = params.sigmas.clone().unwrap_or_else(|| {
+            (1..=params.num_steps)
+                .rev()
+                .map(|i| i as f64 / params.num_steps as f64)
+                .collect()
+        });
+        let mut timesteps = self.scheduler_config.get_timesteps(
+            params.num_steps,
+            Some(mu),
+            params.sigma_spacing,
+            Some(&sigmas),
+        )?;
+        let sampler_type = params
+            .sampler
+            .unwrap_or_else(|| SamplerType::from(&self.scheduler_config));
+        self.scheduler_config
+            .check_sampling(sampler_type, params.init_image.is_some())?;
+
+        if self.fill
+            && params
+                .init_image
+                .as_ref()
+                .is_none_or(|init_image| init_image.mask.is_none())
+        {
+            diffusion_rs_common::bail!("FLUX.1 Fill models require an initial image and a mask.");
+        }
+
+        let ref_img = match (&params.reference_image, self.kontext) {
+            (Some(image), true) => Some(
+                self.encode_reference_image(image, img.dtype())?
+                    .to_device(img.device())?,
+            ),
+            (Some(_), false) => {
+                diffusion_rs_common::bail!("Only FLUX.1 Kontext models support a reference image.")
+            }
+            (None, _) => None,
+        };
+
+        if (self.fill || self.control) && !params.control_images.is_empty() {
+            diffusion_rs_common::bail!(
+                "FLUX.1 Fill, Depth and Canny models do not support ControlNets."
+            );
+        }
+        // Each control image is encoded and packed like the latents.
+        let mut controls = Vec::with_capacity(params.control_images.len());
+        for control in &params.control_images {
+            let Some(controlnet) = self.controlnets.get(control.controlnet) else {
+                diffusion_rs_common::bail!(
+                    "ControlNet {} is not loaded, load it with `Pipeline::load_controlnet`.",
+                    control.controlnet
+                )
+            };
+            if !(0. ..=1.).contains(&control.start) || !(control.start..=1.).contains(&control.end)
+            {
+                diffusion_rs_common::bail!(
+                    "Expected 0 <= start <= end <= 1 for a control image, got start {} and end {}.",
+                    control.start,
+                    control.end
+                )
+            }
+            let cond = self
+                .encode_image(&control.image, params.height, params.width, img.dtype())?
+                .to_device(img.device())?;
+            controls.push((controlnet, control, sampling::pack(&cond)?));
+        }
+
+        // The latents of the FLUX.1 Fill or FLUX.1 Depth and Canny conditioning, concatenated to the latents
+        // along the channels.
+        let mut channel_cond = match (&params.control_image, self.control) {
+            (Some(image), true) => Some(
+                sampling::pack(
+                    &self
+                        .encode_image(image, params.height, params.width, img.dtype())?
+                        .to_device(img.device())?,
+                )?
+                .repeat((img.dim(0)?, 1, 1))?,
+            ),
+            (None, true) => {
+                diffusion_rs_common::bail!("FLUX.1 Depth and Canny models require a control image.")
+            }
+            (Some(_), false) => {
+                diffusion_rs_common::bail!("Only FLUX.1 Depth and Canny models take a control image, use `control_images` with a ControlNet otherwise.")
+            }
+            (None, false) => None,
+        };
+
+        let mut inpaint = None;
+        if let Some(init_image) = &params.init_image {
+            // Noise the image latents to the first remaining timestep.
+            // FLUX.1 Fill regenerates the masked area from pure noise by default, as diffusers.
+            let strength = match init_image.strength {
+                Some(strength) => strength,
+                None if self.fill => 1.,
+                None => init_image.strength_or_default(),
+            };
+            let start = img2img_start_step(timesteps.len() - 1, strength)?;
+            timesteps.drain(..start);
+            let sigma = timesteps[0];
+            let latents = self
+                .encode_image(&init_image.image, params.height, params.width, img.dtype())?
+                .to_device(img.device())?
+                .repeat((img.dim(0)?, 1, 1, 1))?;
+            let noise = img;
+            img = ((&noise * sigma)? + (&latents * (1. - sigma))?)?;
+
+            if let (true, Some(mask)) = (self.fill, &init_image.mask) {
+                // The model regenerates the masked area itself, conditioned on the rest of the image.
+                let cond = self
+                    .fill_conditioning(
+                        &init_image.image,
+                        mask,
+                        params.height,
+                        params.width,
+                        img.dtype(),
+                    )?
+                    .to_device(img.device())?
+                    .repeat((img.dim(0)?, 1, 1))?;
+                channel_cond = Some(cond);
+            } else if let Some(mask) = &init_image.mask {
+                // One mask value per latent pixel, packed like the latents.
+                let (b, c, h, w) = latents.dims4()?;
+                let mask = mask_to_tensor(mask, h, w, latents.dtype(), latents.device())?
+                    .repeat((b, c, 1, 1))?;
+                inpaint = Some(InpaintMask {
+                    mask: sampling::pack(&mask)?,
+                    image_latents: sampling::pack(&latents)?,
+                    noise: sampling::pack(&noise)?,
+                });
+            }
+        }
+
+        let mut state = sampling::State::new(
+            &t5_embed,
+            clip_embed.as_ref(),
+            t5_mask.as_ref(),
+            &img,
+            ref_img.as_ref(),
+        )?;
+        state.ip_adapters = ip_adapters;
+        let negative_state = match &negative_embeds {
+            Some((t5_embed, clip_embed, t5_mask)) => {
+                let mut negative_state = sampling::State::new(
+                    t5_embed,
+                    clip_embed.as_ref(),
+                    t5_mask.as_ref(),
+                    &img,
+                    ref_img.as_ref(),
+                )?;
+                negative_state.ip_adapters = negative_ip_adapters;
+                Some(negative_state)
+            }
+            None => None,
+        };
+        // The conditional and unconditional passes are batched, unless offloading to save memory.
+        let batched_state = match (&negative_state, offloading_type) {
+            (Some(negative_state), None) => Some(state.cat(negative_state)?),
+            _ => None,
+        };
 
-        let bs = img.dim(0)?;
         let dev = img.device();
 
         match offloading_type {
@@ -297,25 +781,108 @@ impl ModelPipeline for FluxPipeline {
             None => (),
         }
 
-        let guidance = if self.flux_model.is_guidance() {
+        let guidance = if self.flux_model.is_guidance()
+            || controls
+                .iter()
+                .any(|(controlnet, _, _)| controlnet.is_guidance())
+        {
             Some(Tensor::full(params.guidance_scale as f32, bs, dev)?)
         } else {
             None
         };
-        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+        let forward = |img: &Tensor,
+                       state: &sampling::State,
+                       t_vec: &Tensor|
+         -> diffusion_rs_common::core::Result<Tensor> {
+            let b = img.dim(0)?;
+            let guidance = match &guidance {
+                Some(guidance) => Some(guidance.repeat(b / bs)?),
+                None => None,
+            };
+            let ref_img = match (&state.ref_img, &state.ref_img_ids) {
+                (Some(ref_img), Some(ref_img_ids)) => Some((ref_img, ref_img_ids)),
+                _ => None,
+            };
+
+            // The step is found from the sigma, so that the intermediate evaluations of second order samplers
+            // follow the control windows too.
+            let mut residuals: Option<FluxControlNetResiduals> = None;
+            if !controls.is_empty() {
+                let sigma = t_vec.i(0)?.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+                let num_steps = timesteps.len() - 1;
+                let step = timesteps
+                    .iter()
+                    .position(|t| *t as f32 <= sigma)
+                    .unwrap_or(num_steps);
+                for (controlnet, control, cond) in &controls {
+                    if !control.is_active(step, num_steps) {
+                        continue;
+                    }
+                    let control_residuals = controlnet.forward(
+                        img,
+                        &state.img_ids,
+                        &cond.repeat((b, 1, 1))?,
+                        control.mode,
+                        &state.txt,
+                        &state.txt_ids,
+                        state.txt_mask.as_ref(),
+                        t_vec,
+                        state.vec.as_ref(),
+                        guidance.as_ref(),
+                        control.scale,
+                    )?;
+                    residuals = Some(match residuals {
+                        Some(residuals) => residuals.add(&control_residuals)?,
+                        None => control_residuals,
+                    });
+                }
+            }
+
             self.flux_model.forward(
                 img,
                 &state.img_ids,
+                ref_img,
                 &state.txt,
                 &state.txt_ids,
+                state.txt_mask.as_ref(),
                 t_vec,
-                &state.vec,
+                state.vec.as_ref(),
                 guidance.as_ref(),
+                residuals.as_ref(),
+                &state.ip_adapters,
             )
         };
+        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+            let img = match &channel_cond {
+                Some(cond) => Tensor::cat(&[img, cond], 2)?,
+                None => img.clone(),
+            };
+            let Some(negative_state) = &negative_state else {
+                return forward(&img, &state, t_vec);
+            };
+            let (pred, negative_pred) = match &batched_state {
+                Some(batched_state) => {
+                    let img = Tensor::cat(&[&img, &img], 0)?;
+                    let t_vec = Tensor::cat(&[t_vec, t_vec], 0)?;
+                    let pred = forward(&img, batched_state, &t_vec)?;
+                    (pred.narrow(0, 0, bs)?, pred.narrow(0, bs, bs)?)
+                }
+                None => (
+                    forward(&img, &state, t_vec)?,
+                    forward(&img, negative_state, t_vec)?,
+                ),
+            };
+            &negative_pred + ((pred - &negative_pred)? * cfg_scale)?
+        };
 
-        let sampler = Sampler::new(&self.scheduler_config.scheduler_type);
-        img = sampler.sample(&timesteps, &state.img, step)?;
+        let mut sampler = sampler_type.sampler(params.eta, rng)?;
+        img = sample(
+            sampler.as_mut(),
+            &timesteps,
+            &state.img,
+            step,
+            inpaint.as_ref(),
+        )?;
 
         match offloading_type {
             Some(Offloading::Full) => {
@@ -333,4 +900,125 @@ impl ModelPipeline for FluxPipeline {
 
         Ok(img)
     }
+
+    fn set_prior(&mut self, prior: Arc<Mutex<dyn ModelPipeline>>) -> Result<()> {
+        self.prior = Some(prior);
+        Ok(())
+    }
+
+    fn load_controlnet(&mut self, files: ModelFiles, silent: bool) -> Result<()> {
+        if self.flux_model.is_chroma() {
+            anyhow::bail!("Chroma pipelines do not support ControlNets.")
+        }
+        let cfg: FluxControlNetConfig = serde_json::from_str(&files.config)?;
+        if cfg.base.hidden_size() != self.flux_model.hidden_size() {
+            anyhow::bail!(
+                "the ControlNet hidden size {} does not match the transformer hidden size {}",
+                cfg.base.hidden_size(),
+                self.flux_model.hidden_size()
+            )
+        }
+        if !silent {
+            info!("loading FLUX ControlNet");
+        }
+        let vb = from_mmaped_safetensors(
+            files.safetensors,
+            Some(self.dtype),
+            &self.device,
+            silent,
+            files.source,
+        )?;
+        let controlnet = FluxControlNet::new(&cfg, vb)?;
+        if !silent {
+            info!(
+                "FLUX ControlNet {} is a Union ControlNet: {}",
+                self.controlnets.len(),
+                controlnet.is_union()
+            );
+        }
+        self.controlnets.push(controlnet);
+        Ok(())
+    }
+
+    fn load_ip_adapter(
+        &mut self,
+        name: String,
+        tensors: HashMap<String, Tensor>,
+        image_encoder: ModelFiles,
+        scale: f64,
+        silent: bool,
+    ) -> Result<()> {
+        if self
+            .ip_adapters
+            .iter()
+            .any(|ip_adapter| ip_adapter.name == name)
+        {
+            anyhow::bail!("IP-Adapter `{name}` is already loaded.")
+        }
+        if !silent {
+            info!("loading FLUX IP-Adapter `{name}`");
+        }
+        let ip_adapter = IpAdapter::new(
+            name,
+            tensors,
+            image_encoder,
+            scale,
+            &self.flux_model,
+            self.dtype,
+            &self.device,
+            silent,
+        )?;
+        self.ip_adapters.push(ip_adapter);
+        Ok(())
+    }
+
+    fn unload_ip_adapter(&mut self, name: &str) -> Result<()> {
+        let Some(idx) = self
+            .ip_adapters
+            .iter()
+            .position(|ip_adapter| ip_adapter.name == name)
+        else {
+            anyhow::bail!("IP-Adapter `{name}` is not loaded.")
+        };
+        self.ip_adapters.remove(idx);
+        Ok(())
+    }
+
+    fn load_lora(
+        &mut self,
+        name: String,
+        tensors: HashMap<String, Tensor>,
+        scale: f64,
+        merge: bool,
+    ) -> diffusion_rs_common::core::Result<()> {
+        let adapter = self.flux_model.lora_adapter(tensors)?;
+        self.loras.load(
+            self.flux_model.named_layers(),
+            name,
+            adapter,
+            scale,
+            merge,
+            self.dtype,
+        )
+    }
+
+    fn unload_lora(&mut self, name: &str) -> diffusion_rs_common::core::Result<()> {
+        self.loras.unload(self.flux_model.named_layers(), name)
+    }
+
+    fn load_textual_inversion(
+        &mut self,
+        textual_inversion: &TextualInversion,
+    ) -> diffusion_rs_common::core::Result<()> {
+        let Some((clip_tokenizer, clip_model)) = &mut self.clip else {
+            diffusion_rs_common::bail!(
+                "This pipeline has no CLIP text encoder for textual inversion embeddings."
+            )
+        };
+        clip_tokenizer.add_textual_inversion(clip_model, textual_inversion)
+    }
+
+    fn loras(&self) -> Vec<(String, f64)> {
+        self.loras.list()
+    }
 }
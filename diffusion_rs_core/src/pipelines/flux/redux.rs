@@ -0,0 +1,128 @@
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor};
+use diffusion_rs_common::nn::Module;
+use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+use tracing::info;
+
+use crate::models::{ReduxConfig, ReduxImageEncoder, SiglipVisionConfig, SiglipVisionTransformer};
+use crate::pipelines::{
+    image_to_tensor, ComponentElem, ComponentName, DiffusionGenerationParams, ImagePrompt, Loader,
+    ModelPipeline, Offloading,
+};
+
+pub struct FluxReduxLoader;
+
+impl Loader for FluxReduxLoader {
+    fn name(&self) -> &'static str {
+        "flux_redux"
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        vec![ComponentName::ImageEncoder, ComponentName::ImageEmbedder]
+    }
+
+    fn load_from_components(
+        &self,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        device: &Device,
+        dtype: DType,
+        silent: bool,
+        _offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let encoder_component = components.remove(&ComponentName::ImageEncoder).unwrap();
+        let embedder_component = components.remove(&ComponentName::ImageEmbedder).unwrap();
+
+        if !silent {
+            info!("loading SigLIP model");
+        }
+        let image_encoder = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = encoder_component
+        {
+            let cfg: SiglipVisionConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source.clone(),
+            )?;
+            SiglipVisionTransformer::new(vb, &cfg)?
+        } else {
+            anyhow::bail!("incorrect storage of image encoder")
+        };
+        if !silent {
+            info!("loading Redux image embedder");
+        }
+        let image_embedder = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = embedder_component
+        {
+            let cfg: ReduxConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source,
+            )?;
+            ReduxImageEncoder::new(&cfg, vb)?
+        } else {
+            anyhow::bail!("incorrect storage of image embedder")
+        };
+
+        let pipeline = FluxReduxPipeline {
+            image_encoder,
+            image_embedder,
+            dtype,
+            device: device.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+}
+
+/// The FLUX.1 Redux prior: it encodes image prompts with SigLIP and projects them into extra T5-space tokens,
+/// which a FLUX pipeline appends to the text tokens.
+pub struct FluxReduxPipeline {
+    image_encoder: SiglipVisionTransformer,
+    image_embedder: ReduxImageEncoder,
+    dtype: DType,
+    device: Device,
+}
+
+impl ModelPipeline for FluxReduxPipeline {
+    fn forward(
+        &mut self,
+        _prompts: Vec<String>,
+        _params: DiffusionGenerationParams,
+        _offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        diffusion_rs_common::bail!(
+            "FLUX.1 Redux is a prior and cannot generate images: attach it to a FLUX pipeline with `Pipeline::set_prior`."
+        )
+    }
+
+    fn encode_image_prompts(
+        &mut self,
+        images: &[ImagePrompt],
+        dtype: DType,
+        device: &Device,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let size = self.image_encoder.image_size();
+        let mut tokens = Vec::with_capacity(images.len());
+        for ImagePrompt { image, weight } in images {
+            let pixel_values = image_to_tensor(image, size, size, self.dtype, &self.device)?;
+            let xs = self.image_encoder.forward(&pixel_values)?;
+            let xs = (self.image_embedder.forward(&xs)? * *weight)?;
+            tokens.push(xs.to_dtype(dtype)?.to_device(device)?);
+        }
+        Tensor::cat(&tokens, 1)
+    }
+}
@@ -1,16 +1,20 @@
 #![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
 
-use diffusion_rs_common::core::{Device, Result, Tensor};
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+use crate::models::FluxIpAdapterConditioning;
+use crate::pipelines::noise::NoiseGenerator;
 
 pub fn get_noise(
-    num_samples: usize,
+    rng: &mut NoiseGenerator,
     height: usize,
     width: usize,
+    dtype: DType,
     device: &Device,
 ) -> Result<Tensor> {
     let height = (height + 15) / 16 * 2;
     let width = (width + 15) / 16 * 2;
-    Tensor::randn(0f32, 1., (num_samples, 16, height, width), device)
+    rng.randn(&[16, height, width], dtype, device)
 }
 
 #[derive(Debug, Clone)]
@@ -19,43 +23,149 @@ pub struct State {
     pub img_ids: Tensor,
     pub txt: Tensor,
     pub txt_ids: Tensor,
-    pub vec: Tensor,
+    /// Mask of the text tokens to attend to, when the prompts are padded to a common length (Chroma, or a prompt
+    /// and a negative prompt of different lengths run in the same batch).
+    pub txt_mask: Option<Tensor>,
+    /// The pooled CLIP embeddings, which Chroma does not use.
+    pub vec: Option<Tensor>,
+    /// Packed latents of the FLUX.1 Kontext reference image, appended to the image tokens.
+    pub ref_img: Option<Tensor>,
+    pub ref_img_ids: Option<Tensor>,
+    /// The image prompt tokens of the active IP-Adapters.
+    pub ip_adapters: Vec<FluxIpAdapterConditioning>,
+}
+
+/// Position ids of the packed `(h / 2) * (w / 2)` tokens of a latent image, with `index` as the first axis.
+fn position_ids(
+    bs: usize,
+    h: usize,
+    w: usize,
+    index: u32,
+    dtype: DType,
+    dev: &Device,
+) -> Result<Tensor> {
+    let img_ids = Tensor::stack(
+        &[
+            Tensor::full(index, (h / 2, w / 2), dev)?,
+            Tensor::arange(0u32, h as u32 / 2, dev)?
+                .reshape(((), 1))?
+                .broadcast_as((h / 2, w / 2))?,
+            Tensor::arange(0u32, w as u32 / 2, dev)?
+                .reshape((1, ()))?
+                .broadcast_as((h / 2, w / 2))?,
+        ],
+        2,
+    )?
+    .to_dtype(dtype)?;
+    let img_ids = img_ids.reshape((1, h / 2 * w / 2, 3))?;
+    img_ids.repeat((bs, 1, 1))
 }
 
 impl State {
-    pub fn new(t5_emb: &Tensor, clip_emb: &Tensor, img: &Tensor) -> Result<Self> {
+    pub fn new(
+        t5_emb: &Tensor,
+        clip_emb: Option<&Tensor>,
+        txt_mask: Option<&Tensor>,
+        img: &Tensor,
+        ref_img: Option<&Tensor>,
+    ) -> Result<Self> {
         let dtype = img.dtype();
-        let (bs, c, h, w) = img.dims4()?;
+        let (bs, _c, h, w) = img.dims4()?;
         let dev = img.device();
-        let img = img.reshape((bs, c, h / 2, 2, w / 2, 2))?; // (b, c, h, ph, w, pw)
-        let img = img.permute((0, 2, 4, 1, 3, 5))?; // (b, h, w, c, ph, pw)
-        let img = img.reshape((bs, h / 2 * w / 2, c * 4))?;
-        let img_ids = Tensor::stack(
-            &[
-                Tensor::full(0u32, (h / 2, w / 2), dev)?,
-                Tensor::arange(0u32, h as u32 / 2, dev)?
-                    .reshape(((), 1))?
-                    .broadcast_as((h / 2, w / 2))?,
-                Tensor::arange(0u32, w as u32 / 2, dev)?
-                    .reshape((1, ()))?
-                    .broadcast_as((h / 2, w / 2))?,
-            ],
-            2,
-        )?
-        .to_dtype(dtype)?;
-        let img_ids = img_ids.reshape((1, h / 2 * w / 2, 3))?;
-        let img_ids = img_ids.repeat((bs, 1, 1))?;
-        let txt = t5_emb.repeat(bs)?;
+        let img = pack(img)?;
+        let img_ids = position_ids(bs, h, w, 0, dtype, dev)?;
+        // The reference image tokens are told apart from the generated ones by their first position id axis.
+        let (ref_img, ref_img_ids) = match ref_img {
+            Some(ref_img) => {
+                let (_b, _c, h, w) = ref_img.dims4()?;
+                (
+                    Some(pack(&ref_img.repeat((bs, 1, 1, 1))?)?),
+                    Some(position_ids(bs, h, w, 1, dtype, dev)?),
+                )
+            }
+            None => (None, None),
+        };
+        let txt = t5_emb.clone();
         let txt_ids = Tensor::zeros((bs, txt.dim(1)?, 3), dtype, dev)?;
-        let vec = clip_emb.repeat(bs)?;
         Ok(Self {
             img,
             img_ids,
             txt,
             txt_ids,
-            vec,
+            txt_mask: txt_mask.cloned(),
+            vec: clip_emb.cloned(),
+            ref_img,
+            ref_img_ids,
+            ip_adapters: Vec::new(),
+        })
+    }
+}
+
+impl State {
+    /// Concatenate two states along the batch dimension. If the texts have different lengths, such as a prompt
+    /// followed by image prompt tokens and a negative prompt without them, the shorter one is padded with
+    /// masked out zero tokens.
+    pub fn cat(&self, other: &Self) -> Result<Self> {
+        let cat = |a: &Option<Tensor>, b: &Option<Tensor>| match (a, b) {
+            (Some(a), Some(b)) => Tensor::cat(&[a, b], 0).map(Some),
+            _ => Ok(None),
+        };
+        let txt_len = self.txt.dim(1)?.max(other.txt.dim(1)?);
+        let (txt, txt_ids, txt_mask) = if self.txt.dim(1)? == other.txt.dim(1)? {
+            (
+                Tensor::cat(&[&self.txt, &other.txt], 0)?,
+                Tensor::cat(&[&self.txt_ids, &other.txt_ids], 0)?,
+                cat(&self.txt_mask, &other.txt_mask)?,
+            )
+        } else {
+            let (txt, txt_ids, txt_mask) = self.padded_txt(txt_len)?;
+            let (other_txt, other_txt_ids, other_txt_mask) = other.padded_txt(txt_len)?;
+            (
+                Tensor::cat(&[txt, other_txt], 0)?,
+                Tensor::cat(&[txt_ids, other_txt_ids], 0)?,
+                Some(Tensor::cat(&[txt_mask, other_txt_mask], 0)?),
+            )
+        };
+        Ok(Self {
+            img: Tensor::cat(&[&self.img, &other.img], 0)?,
+            img_ids: Tensor::cat(&[&self.img_ids, &other.img_ids], 0)?,
+            txt,
+            txt_ids,
+            txt_mask,
+            vec: cat(&self.vec, &other.vec)?,
+            ref_img: cat(&self.ref_img, &other.ref_img)?,
+            ref_img_ids: cat(&self.ref_img_ids, &other.ref_img_ids)?,
+            ip_adapters: self
+                .ip_adapters
+                .iter()
+                .zip(&other.ip_adapters)
+                .map(|(a, b)| a.cat(b))
+                .collect::<Result<Vec<_>>>()?,
         })
     }
+
+    /// The text tokens, their position ids and their mask, padded with masked out zero tokens to `len`.
+    fn padded_txt(&self, len: usize) -> Result<(Tensor, Tensor, Tensor)> {
+        let (b, txt_len, _) = self.txt.dims3()?;
+        let pad = len - txt_len;
+        let mask = match &self.txt_mask {
+            Some(mask) => mask.to_dtype(DType::U8)?,
+            None => Tensor::ones((b, txt_len), DType::U8, self.txt.device())?,
+        };
+        Ok((
+            self.txt.pad_with_zeros(1, 0, pad)?,
+            self.txt_ids.pad_with_zeros(1, 0, pad)?,
+            mask.pad_with_zeros(1, 0, pad)?,
+        ))
+    }
+}
+
+/// Pack latents into a sequence of 2x2 patches: `(b, c, h, w)` to `(b, h / 2 * w / 2, c * 4)`.
+pub fn pack(xs: &Tensor) -> Result<Tensor> {
+    let (b, c, h, w) = xs.dims4()?;
+    xs.reshape((b, c, h / 2, 2, w / 2, 2))? // (b, c, h, ph, w, pw)
+        .permute((0, 2, 4, 1, 3, 5))? // (b, h, w, c, ph, pw)
+        .reshape((b, h / 2 * w / 2, c * 4))
 }
 
 pub fn unpack(xs: &Tensor, height: usize, width: usize) -> Result<Tensor> {
@@ -78,3 +188,58 @@ pub fn calculate_shift(
     let b = base_shift - m * base_seq_len as f64;
     image_seq_len as f64 * m + b
 }
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+    use super::State;
+
+    fn state(txt_len: usize, txt_mask: Option<&[u8]>) -> Result<State> {
+        let dev = Device::Cpu;
+        let txt = Tensor::ones((1, txt_len, 4), DType::F32, &dev)?;
+        let txt_mask = txt_mask
+            .map(|mask| Tensor::new(mask, &dev)?.unsqueeze(0))
+            .transpose()?;
+        let img = Tensor::zeros((1, 16, 4, 4), DType::F32, &dev)?;
+        State::new(&txt, None, txt_mask.as_ref(), &img, None)
+    }
+
+    fn mask(state: &State) -> Result<Option<Vec<Vec<u8>>>> {
+        state
+            .txt_mask
+            .as_ref()
+            .map(|mask| mask.to_vec2())
+            .transpose()
+    }
+
+    #[test]
+    fn cat_pads_image_prompt_tokens() -> Result<()> {
+        // A prompt with 3 image prompt tokens and a negative prompt without them.
+        let batched = state(5, None)?.cat(&state(2, None)?)?;
+        assert_eq!(batched.txt.dims(), [2, 5, 4]);
+        assert_eq!(batched.txt_ids.dims(), [2, 5, 3]);
+        assert_eq!(batched.img.dims(), [2, 4, 64]);
+        assert_eq!(
+            mask(&batched)?,
+            Some(vec![vec![1, 1, 1, 1, 1], vec![1, 1, 0, 0, 0]])
+        );
+        assert_eq!(
+            batched.txt.sum_keepdim(2)?.squeeze(2)?.to_vec2::<f32>()?,
+            [[4., 4., 4., 4., 4.], [4., 4., 0., 0., 0.]]
+        );
+
+        // Padded prompts (Chroma) keep their own mask.
+        let batched = state(3, Some(&[1, 1, 0]))?.cat(&state(4, Some(&[1, 0, 0, 0]))?)?;
+        assert_eq!(
+            mask(&batched)?,
+            Some(vec![vec![1, 1, 0, 0], vec![1, 0, 0, 0]])
+        );
+
+        // Texts of the same length are concatenated as they are.
+        let batched = state(3, None)?.cat(&state(3, None)?)?;
+        assert_eq!(batched.txt.dims(), [2, 3, 4]);
+        assert_eq!(mask(&batched)?, None);
+        Ok(())
+    }
+}
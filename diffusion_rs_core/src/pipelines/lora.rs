@@ -0,0 +1,217 @@
+use std::{collections::HashMap, sync::Arc};
+
+use diffusion_rs_backend::{ActiveLoras, QuantMethod};
+use diffusion_rs_common::core::{DType, Result};
+use tracing::info;
+
+use crate::models::LoraAdapter;
+
+struct LoadedLora {
+    name: String,
+    scale: f64,
+    /// Merged adapters are kept to subtract their delta when they are removed. Quantized weights are requantized
+    /// each time, so they are only restored up to the quantization error. Runtime adapters live in the layers they
+    /// are attached to.
+    merged: Option<LoraAdapter>,
+}
+
+/// The LoRA adapters loaded into a model.
+#[derive(Default)]
+pub(crate) struct Loras {
+    loaded: Vec<LoadedLora>,
+    active: ActiveLoras,
+}
+
+impl Loras {
+    /// Merge the adapter into `layers`, or attach it to be applied at runtime if `merge` is false.
+    pub fn load(
+        &mut self,
+        layers: Vec<(String, &mut Arc<dyn QuantMethod>)>,
+        name: String,
+        adapter: LoraAdapter,
+        scale: f64,
+        merge: bool,
+        dtype: DType,
+    ) -> Result<()> {
+        if self.loaded.iter().any(|lora| lora.name == name) {
+            diffusion_rs_common::bail!("A LoRA adapter named `{name}` is already loaded.");
+        }
+        let merged = if merge {
+            info!(
+                "merging LoRA adapter `{name}` ({} layers) with scale {scale}",
+                adapter.weights.len()
+            );
+            adapter.merge(layers, scale)?;
+            Some(adapter)
+        } else {
+            info!(
+                "loading runtime LoRA adapter `{name}` ({} layers) with scale {scale}",
+                adapter.weights.len()
+            );
+            adapter.attach(layers, &name, dtype, &self.active)?;
+            None
+        };
+        self.loaded.push(LoadedLora {
+            name,
+            scale,
+            merged,
+        });
+        Ok(())
+    }
+
+    pub fn unload(
+        &mut self,
+        layers: Vec<(String, &mut Arc<dyn QuantMethod>)>,
+        name: &str,
+    ) -> Result<()> {
+        let Some(idx) = self.loaded.iter().position(|lora| lora.name == name) else {
+            diffusion_rs_common::bail!("No LoRA adapter named `{name}` is loaded.");
+        };
+        let lora = &self.loaded[idx];
+        match &lora.merged {
+            Some(adapter) => adapter.merge(layers, -lora.scale)?,
+            None => LoraAdapter::detach(layers, name),
+        }
+        self.loaded.remove(idx);
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<(String, f64)> {
+        self.loaded
+            .iter()
+            .map(|lora| (lora.name.clone(), lora.scale))
+            .collect()
+    }
+
+    /// Select the runtime adapters for a request. If `scales` is not specified, all runtime adapters are active
+    /// with the scale they were loaded with.
+    pub fn activate(&self, scales: Option<&HashMap<String, f64>>) -> Result<()> {
+        let runtime = self
+            .loaded
+            .iter()
+            .filter(|lora| lora.merged.is_none())
+            .collect::<Vec<_>>();
+        let scales = match scales {
+            Some(scales) => {
+                if let Some(name) = scales
+                    .keys()
+                    .find(|name| !runtime.iter().any(|lora| lora.name == **name))
+                {
+                    diffusion_rs_common::bail!("No runtime LoRA adapter named `{name}` is loaded.");
+                }
+                scales.clone()
+            }
+            None => runtime
+                .iter()
+                .map(|lora| (lora.name.clone(), lora.scale))
+                .collect(),
+        };
+        self.active.set(scales);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use diffusion_rs_backend::{QuantMethod, QuantMethodConfig, UnquantLinear};
+    use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+    use diffusion_rs_common::nn::Linear;
+
+    use super::Loras;
+    use crate::models::{LoraAdapter, LoraWeights};
+
+    fn layer(value: f32) -> Result<Arc<dyn QuantMethod>> {
+        let w = (Tensor::ones((2, 2), DType::F32, &Device::Cpu)? * value as f64)?;
+        Ok(Arc::new(UnquantLinear::new(
+            QuantMethodConfig::Unquantized(Linear::new(w, None)),
+        )?))
+    }
+
+    /// An adapter adding `value` to every weight of the given layers, with `in_features` inputs.
+    fn adapter(layers: &[&str], value: f32, in_features: usize) -> Result<LoraAdapter> {
+        let mut weights = HashMap::new();
+        for name in layers {
+            weights.insert(
+                name.to_string(),
+                LoraWeights {
+                    down: Tensor::ones((1, in_features), DType::F32, &Device::Cpu)?,
+                    up: (Tensor::ones((2, 1), DType::F32, &Device::Cpu)? * value as f64)?,
+                    alpha: None,
+                },
+            );
+        }
+        Ok(LoraAdapter { weights })
+    }
+
+    fn layers<'a>(
+        a: &'a mut Arc<dyn QuantMethod>,
+        b: &'a mut Arc<dyn QuantMethod>,
+    ) -> Vec<(String, &'a mut Arc<dyn QuantMethod>)> {
+        vec![("a".to_string(), a), ("b".to_string(), b)]
+    }
+
+    fn weight(layer: &Arc<dyn QuantMethod>) -> Result<f32> {
+        layer
+            .dequantize_w(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+            .map(|w| w[0])
+    }
+
+    #[test]
+    fn merged_adapters_are_removed() -> Result<()> {
+        let (mut a, mut b) = (layer(1.)?, layer(2.)?);
+        let mut loras = Loras::default();
+        loras.load(
+            layers(&mut a, &mut b),
+            "first".to_string(),
+            adapter(&["a", "b"], 10., 2)?,
+            1.,
+            true,
+            DType::F32,
+        )?;
+        loras.load(
+            layers(&mut a, &mut b),
+            "second".to_string(),
+            adapter(&["a"], 100., 2)?,
+            1.,
+            true,
+            DType::F32,
+        )?;
+        assert_eq!((weight(&a)?, weight(&b)?), (111., 12.));
+
+        loras.unload(layers(&mut a, &mut b), "first")?;
+        assert_eq!((weight(&a)?, weight(&b)?), (101., 2.));
+
+        loras.unload(layers(&mut a, &mut b), "second")?;
+        assert_eq!((weight(&a)?, weight(&b)?), (1., 2.));
+        assert!(loras.list().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_merge_leaves_layers_unchanged() -> Result<()> {
+        let (original_a, original_b) = (layer(1.)?, layer(2.)?);
+        let (mut a, mut b) = (original_a.clone(), original_b.clone());
+        let mut adapter = adapter(&["a"], 10., 2)?;
+        adapter
+            .weights
+            .extend(self::adapter(&["b"], 10., 3)?.weights);
+
+        let mut loras = Loras::default();
+        let result = loras.load(
+            layers(&mut a, &mut b),
+            "bad".to_string(),
+            adapter,
+            1.,
+            true,
+            DType::F32,
+        );
+        assert!(result.is_err());
+        assert!(Arc::ptr_eq(&a, &original_a) && Arc::ptr_eq(&b, &original_b));
+        assert!(loras.list().is_empty());
+        Ok(())
+    }
+}
@@ -1,25 +1,47 @@
+mod discrete_scheduler;
 mod flux;
+mod lora;
+mod noise;
+mod pixart;
 mod sampling;
+mod sana;
 mod scheduler;
+mod stable_diffusion;
+mod stable_diffusion_3;
+mod textual_inversion;
 
 use std::{
     collections::HashMap,
     fmt::Display,
+    path::Path,
     sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
 use diffusion_rs_common::core::{DType, Device, Tensor};
-use flux::FluxLoader;
-use image::{DynamicImage, RgbImage};
+use flux::{FluxLoader, FluxReduxLoader};
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+use pixart::PixArtSigmaLoader;
+use sana::SanaLoader;
 use serde::Deserialize;
+use stable_diffusion::{StableDiffusionLoader, StableDiffusionXLLoader};
+use stable_diffusion_3::StableDiffusion3Loader;
 
 use diffusion_rs_common::{FileData, FileLoader, ModelSource, NiceProgressBar, TokenSource};
 use tracing::info;
 
 use crate::TryIntoDType;
+use noise::NoiseGenerator;
+use sampling::InpaintMask;
+
+pub use noise::NoiseSource;
+pub use sampling::SamplerType;
+pub use scheduler::SigmaSpacing;
+pub use textual_inversion::TextualInversion;
 
 /// Generation parameters.
+///
+/// The defaults are suitable for FLUX.1-dev.
 #[derive(Debug, Clone)]
 pub struct DiffusionGenerationParams {
     pub height: usize,
@@ -27,9 +49,279 @@ pub struct DiffusionGenerationParams {
     /// The number of denoising steps. More denoising steps usually lead to a higher quality image at the
     /// expense of slower inference but depends on the model being used.
     pub num_steps: usize,
+    /// Spacing of the sigma schedule. If not specified, it is chosen from the scheduler config of the model.
+    pub sigma_spacing: Option<SigmaSpacing>,
+    /// An explicit sigma schedule, in decreasing order in (0, 1], without the terminal 0. If specified, it
+    /// replaces the evenly spaced base sigmas and sets the number of steps, ignoring `num_steps`. The usual
+    /// shift and `sigma_spacing` are still applied.
+    pub sigmas: Option<Vec<f64>>,
     /// Higher guidance scale encourages to generate images that are closely linked to the text `prompt`,
-    /// usually at the expense of lower image quality.
+    /// usually at the expense of lower image quality. For models without guidance distillation, such as Stable
+    /// Diffusion, this is the classifier-free guidance scale, enabled when greater than 1.
     pub guidance_scale: f64,
+    /// Scale for true classifier-free guidance. If greater than 1, the model is also run on `negative_prompt`
+    /// and the prediction is `negative + true_cfg_scale * (positive - negative)`. This doubles the cost of each
+    /// step, and is needed for models without guidance distillation.
+    pub true_cfg_scale: f64,
+    /// Sampling method. If not specified, it is chosen from the scheduler config of the model.
+    pub sampler: Option<SamplerType>,
+    /// Amount of fresh noise injected at each step by stochastic samplers, between 0 (deterministic) and 1.
+    /// The noise is drawn from the same seeded generators as the initial noise.
+    pub eta: f64,
+    /// Prompt for the unconditional pass of classifier-free guidance, used for all images of a batch. If not
+    /// specified, an empty prompt is used. Ignored unless classifier-free guidance is enabled, by
+    /// `true_cfg_scale`, or by `guidance_scale` for models without guidance distillation.
+    pub negative_prompt: Option<String>,
+    /// Seed for the random noise. Generating with the same prompt, parameters and seed gives the same image.
+    /// If not specified, a random seed is used. In a batch, the image for prompt `i` uses `seed + i`.
+    pub seed: Option<u64>,
+    /// Per-image seeds for a batch of prompts. If specified, this must have one seed per prompt and takes
+    /// precedence over `seed`.
+    pub seeds: Option<Vec<u64>>,
+    /// Random number generator used to create the noise.
+    pub noise_source: NoiseSource,
+    /// Start from an existing image instead of pure noise (image-to-image generation).
+    pub init_image: Option<InitImage>,
+    /// Image to edit as instructed by the prompt, for instruction-based editing models such as FLUX.1 Kontext.
+    /// It is resized to the trained resolution closest to its aspect ratio, independently of the output size.
+    pub reference_image: Option<DynamicImage>,
+    /// Images to condition the generation on, alongside or instead of the prompt, for image variation or style
+    /// and subject consistency. These require a prior such as FLUX.1 Redux, attached with
+    /// [`Pipeline::set_prior`], or an IP-Adapter loaded with [`Pipeline::load_ip_adapter`].
+    pub image_prompts: Vec<ImagePrompt>,
+    /// Control images for structural control, such as edge, depth or pose maps. Each is given to one of the
+    /// ControlNets loaded with [`Pipeline::load_controlnet`], and their residuals are summed.
+    pub control_images: Vec<ControlImage>,
+    /// Control image for structural conditioning models, such as FLUX.1 Depth and Canny, which take its latents
+    /// as extra input channels instead of using a ControlNet. It is resized to the requested height and width.
+    pub control_image: Option<DynamicImage>,
+    /// Fraction of the denoising schedule run by the base model when a refiner is attached, between 0 and 1.
+    /// The refiner runs the rest. Ignored without a refiner.
+    pub refiner_start: f64,
+    /// Runtime LoRA adapters to activate for this request, by name, with their scales. If not specified, all
+    /// runtime adapters are active with the scale they were loaded with. Merged adapters are always active.
+    pub loras: Option<HashMap<String, f64>>,
+    /// IP-Adapters to condition on the image prompts for this request, by name, with their scales. If not
+    /// specified, all IP-Adapters are active with the scale they were loaded with.
+    pub ip_adapters: Option<HashMap<String, f64>>,
+    /// Textual inversion embeddings to use for this request only, in addition to those loaded with
+    /// [`Pipeline::load_textual_inversion`]. Their placeholder tokens can be used in the prompts.
+    pub textual_inversions: Vec<TextualInversion>,
+}
+
+impl Default for DiffusionGenerationParams {
+    fn default() -> Self {
+        Self {
+            height: 720,
+            width: 1280,
+            num_steps: 50,
+            sigma_spacing: None,
+            sigmas: None,
+            guidance_scale: 3.5,
+            true_cfg_scale: 1.0,
+            sampler: None,
+            eta: 1.0,
+            negative_prompt: None,
+            seed: None,
+            seeds: None,
+            noise_source: NoiseSource::default(),
+            init_image: None,
+            reference_image: None,
+            image_prompts: Vec::new(),
+            control_images: Vec::new(),
+            control_image: None,
+            refiner_start: 0.8,
+            loras: None,
+            ip_adapters: None,
+            textual_inversions: Vec::new(),
+        }
+    }
+}
+
+/// An existing image to start the generation from.
+#[derive(Debug, Clone)]
+pub struct InitImage {
+    /// The image to transform. It is resized to the requested height and width.
+    pub image: DynamicImage,
+    /// How much to transform the image, between 0 and 1. The image is noised to this point of the denoising
+    /// schedule, and only the remaining `strength * num_steps` steps are run. A strength of 1 ignores the image.
+    /// If not specified, defaults to 1 for FLUX.1 Fill models, which regenerate the masked area from pure noise,
+    /// and to [`InitImage::DEFAULT_STRENGTH`] otherwise.
+    pub strength: Option<f64>,
+    /// Mask for inpainting: only the white areas of the image are regenerated, the black areas are kept. Grey
+    /// values blend between the two. It is resized to the latent resolution.
+    pub mask: Option<DynamicImage>,
+}
+
+impl InitImage {
+    /// The strength used when it is not specified, for most models.
+    pub const DEFAULT_STRENGTH: f64 = 0.6;
+
+    pub(crate) fn strength_or_default(&self) -> f64 {
+        self.strength.unwrap_or(Self::DEFAULT_STRENGTH)
+    }
+}
+
+/// An image to condition the generation on, with the weight of its conditioning.
+#[derive(Debug, Clone)]
+pub struct ImagePrompt {
+    pub image: DynamicImage,
+    /// Scale of the conditioning tokens of the image. 1 is the trained strength, and lower values give more
+    /// room to the text prompt.
+    pub weight: f64,
+}
+
+/// A control image for a ControlNet, with the strength and the part of the denoising schedule it applies to.
+#[derive(Debug, Clone)]
+pub struct ControlImage {
+    /// The control image, such as an edge, depth or pose map. It is resized to the requested height and width.
+    pub image: DynamicImage,
+    /// Index of the ControlNet to use, in the order they were loaded with [`Pipeline::load_controlnet`].
+    pub controlnet: usize,
+    /// Scale of the ControlNet residuals.
+    pub scale: f64,
+    /// Fraction of the denoising schedule at which the control starts to apply, between 0 and 1.
+    pub start: f64,
+    /// Fraction of the denoising schedule at which the control stops to apply, between `start` and 1.
+    pub end: f64,
+    /// The control mode of a Union ControlNet. For the InstantX Union ControlNet: 0 canny, 1 tile, 2 depth,
+    /// 3 blur, 4 pose, 5 gray and 6 low quality.
+    pub mode: Option<usize>,
+}
+
+impl ControlImage {
+    /// Whether the control applies to step `step` out of `num_steps`.
+    pub(crate) fn is_active(&self, step: usize, num_steps: usize) -> bool {
+        let num_steps = num_steps as f64;
+        step as f64 / num_steps >= self.start && (step + 1) as f64 / num_steps <= self.end
+    }
+}
+
+/// Convert an image to a `(1, 3, height, width)` tensor with values in [-1, 1], resizing it if necessary.
+pub(crate) fn image_to_tensor(
+    image: &DynamicImage,
+    height: usize,
+    width: usize,
+    dtype: DType,
+    device: &Device,
+) -> diffusion_rs_common::core::Result<Tensor> {
+    #[allow(clippy::cast_possible_truncation)]
+    let image = image
+        .resize_exact(width as u32, height as u32, FilterType::Lanczos3)
+        .to_rgb8();
+    let xs = Tensor::from_vec(image.into_raw(), (height, width, 3), &Device::Cpu)?
+        .permute((2, 0, 1))?
+        .unsqueeze(0)?
+        .to_dtype(DType::F32)?;
+    ((xs / 127.5)? - 1.)?.to_dtype(dtype)?.to_device(device)
+}
+
+/// Convert a mask to a `(1, 1, height, width)` tensor with values in [0, 1], resizing it if necessary.
+pub(crate) fn mask_to_tensor(
+    mask: &DynamicImage,
+    height: usize,
+    width: usize,
+    dtype: DType,
+    device: &Device,
+) -> diffusion_rs_common::core::Result<Tensor> {
+    #[allow(clippy::cast_possible_truncation)]
+    let mask = mask
+        .resize_exact(width as u32, height as u32, FilterType::Triangle)
+        .to_luma8();
+    let xs = Tensor::from_vec(mask.into_raw(), (1, 1, height, width), &Device::Cpu)?
+        .to_dtype(DType::F32)?;
+    (xs / 255.)?.to_dtype(dtype)?.to_device(device)
+}
+
+/// The mean and standard deviation of each channel of the CLIP image preprocessing.
+const CLIP_IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const CLIP_IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// Convert an image to the `(1, 3, size, size)` input of a CLIP image encoder: its shortest side is resized to
+/// `size`, it is center cropped and normalized with the CLIP mean and standard deviation.
+pub(crate) fn clip_image_to_tensor(
+    image: &DynamicImage,
+    size: usize,
+    dtype: DType,
+    device: &Device,
+) -> diffusion_rs_common::core::Result<Tensor> {
+    #[allow(clippy::cast_possible_truncation)]
+    let size = size as u32;
+    let (width, height) = (image.width(), image.height());
+    let (new_width, new_height) = if width < height {
+        (size, (height * size).div_ceil(width))
+    } else {
+        ((width * size).div_ceil(height), size)
+    };
+    let image = image
+        .resize_exact(new_width, new_height, FilterType::CatmullRom)
+        .crop_imm((new_width - size) / 2, (new_height - size) / 2, size, size)
+        .to_rgb8();
+    let size = size as usize;
+    let xs = Tensor::from_vec(image.into_raw(), (size, size, 3), &Device::Cpu)?
+        .permute((2, 0, 1))?
+        .unsqueeze(0)?
+        .to_dtype(DType::F32)?;
+    let mean = Tensor::new(&CLIP_IMAGE_MEAN, &Device::Cpu)?.reshape((1, 3, 1, 1))?;
+    let std = Tensor::new(&CLIP_IMAGE_STD, &Device::Cpu)?.reshape((1, 3, 1, 1))?;
+    (xs / 255.)?
+        .broadcast_sub(&mean)?
+        .broadcast_div(&std)?
+        .to_dtype(dtype)?
+        .to_device(device)
+}
+
+/// The config and `.safetensors` weights of a standalone model, such as a ControlNet or an image encoder.
+pub struct ModelFiles {
+    pub config: String,
+    pub safetensors: Vec<FileData>,
+    pub source: Arc<ModelSource>,
+}
+
+impl ModelFiles {
+    /// Read the `config.json` and `.safetensors` weights in `dir`, or at the root of the source if `dir` is not
+    /// present. Only the files directly in that directory are used: some repositories ship other checkpoints in
+    /// subdirectories.
+    fn load(
+        mut source: ModelSource,
+        dir: Option<&str>,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+    ) -> Result<Self> {
+        let mut loader = FileLoader::from_model_source(&mut source, silent, token, revision)?;
+        let files = loader.list_files()?;
+        let dir = dir
+            .map(|dir| format!("{dir}/"))
+            .filter(|dir| files.contains(&format!("{dir}config.json")))
+            .unwrap_or_default();
+        let files = files
+            .into_iter()
+            .filter_map(|file| {
+                let name = file.strip_prefix(&dir)?;
+                (!name.contains('/')).then_some(file.clone())
+            })
+            .collect::<Vec<_>>();
+        let config_file = format!("{dir}config.json");
+        if !files.contains(&config_file) {
+            anyhow::bail!("Expected `{config_file}` file present.");
+        }
+        let config = loader
+            .read_file_copied(&config_file, false)?
+            .read_to_string_owned()?;
+        let safetensors = safetensors_variant(&files)
+            .into_iter()
+            .map(|file| loader.read_file(file, false))
+            .collect::<Result<Vec<_>>>()?;
+        if safetensors.is_empty() {
+            anyhow::bail!("Expected `.safetensors` weights present.");
+        }
+        Ok(Self {
+            config,
+            safetensors,
+            source: Arc::new(source),
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -46,13 +338,42 @@ pub(crate) enum ComponentElem {
     },
 }
 
+/// Select one variant of the `.safetensors` weights of a component: the default weights if present, else the
+/// `fp16` variant, else all of them. Some repositories ship several variants (`.fp16`, `.non_ema`...) side by side.
+fn safetensors_variant(files: &[String]) -> Vec<&String> {
+    let variant = |file: &String| {
+        let name = file.rsplit('/').next().unwrap_or(file);
+        let stem = name.trim_end_matches(".safetensors");
+        stem.split_once('.')
+            .map(|(_, variant)| variant.split('-').next().unwrap_or(variant).to_string())
+    };
+    let safetensors = files
+        .iter()
+        .filter(|file| file.ends_with(".safetensors"))
+        .collect::<Vec<_>>();
+    for wanted in [None, Some("fp16".to_string())] {
+        let selected = safetensors
+            .iter()
+            .filter(|file| variant(file) == wanted)
+            .copied()
+            .collect::<Vec<_>>();
+        if !selected.is_empty() {
+            return selected;
+        }
+    }
+    safetensors
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ComponentName {
     Scheduler,
     TextEncoder(usize),
     Tokenizer(usize),
     Transformer,
+    Unet,
     Vae,
+    ImageEncoder,
+    ImageEmbedder,
 }
 
 impl Display for ComponentName {
@@ -60,7 +381,10 @@ impl Display for ComponentName {
         match self {
             Self::Scheduler => write!(f, "scheduler"),
             Self::Transformer => write!(f, "transformer"),
+            Self::Unet => write!(f, "unet"),
             Self::Vae => write!(f, "vae"),
+            Self::ImageEncoder => write!(f, "image_encoder"),
+            Self::ImageEmbedder => write!(f, "image_embedder"),
             Self::TextEncoder(1) => write!(f, "text_encoder"),
             Self::TextEncoder(x) => write!(f, "text_encoder_{x}"),
             Self::Tokenizer(1) => write!(f, "tokenizer"),
@@ -98,6 +422,96 @@ pub trait ModelPipeline: Send + Sync {
         params: DiffusionGenerationParams,
         offloading_type: Option<Offloading>,
     ) -> diffusion_rs_common::core::Result<Tensor>;
+
+    /// Load a LoRA adapter with the given scale. It is merged into the model weights if `merge` is true, and
+    /// applied at runtime otherwise.
+    fn load_lora(
+        &mut self,
+        _name: String,
+        _tensors: HashMap<String, Tensor>,
+        _scale: f64,
+        _merge: bool,
+    ) -> diffusion_rs_common::core::Result<()> {
+        diffusion_rs_common::bail!("This pipeline does not support LoRA adapters.")
+    }
+
+    /// Remove a LoRA adapter.
+    fn unload_lora(&mut self, _name: &str) -> diffusion_rs_common::core::Result<()> {
+        diffusion_rs_common::bail!("This pipeline does not support LoRA adapters.")
+    }
+
+    /// Names and scales of the LoRA adapters, in the order they were loaded.
+    fn loras(&self) -> Vec<(String, f64)> {
+        Vec::new()
+    }
+
+    /// Attach a refiner, which runs the last denoising steps of each generation.
+    fn set_refiner(&mut self, _refiner: Arc<Mutex<dyn ModelPipeline>>) -> Result<()> {
+        anyhow::bail!("This pipeline does not support a refiner.")
+    }
+
+    /// Run as a refiner: denoise the `latents` of another pipeline over the remaining `timesteps`, and return
+    /// the latents. Stochastic samplers draw their noise from `rng`, forked from the other pipeline's.
+    fn refine(
+        &mut self,
+        _prompts: Vec<String>,
+        _params: &DiffusionGenerationParams,
+        _latents: &Tensor,
+        _timesteps: &[f64],
+        _inpaint: Option<&InpaintMask>,
+        _rng: NoiseGenerator,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        diffusion_rs_common::bail!("This pipeline cannot be used as a refiner.")
+    }
+
+    /// Load a ControlNet from its config and `.safetensors` weights. It is given the control images which select
+    /// it by index, in the order the ControlNets are loaded.
+    fn load_controlnet(&mut self, _files: ModelFiles, _silent: bool) -> Result<()> {
+        anyhow::bail!("This pipeline does not support ControlNets.")
+    }
+
+    /// Load an IP-Adapter from its weights, with the CLIP image encoder it was trained with. It conditions the
+    /// generations on their image prompts, with scale `scale` by default.
+    fn load_ip_adapter(
+        &mut self,
+        _name: String,
+        _tensors: HashMap<String, Tensor>,
+        _image_encoder: ModelFiles,
+        _scale: f64,
+        _silent: bool,
+    ) -> Result<()> {
+        anyhow::bail!("This pipeline does not support IP-Adapters.")
+    }
+
+    /// Remove an IP-Adapter.
+    fn unload_ip_adapter(&mut self, _name: &str) -> Result<()> {
+        anyhow::bail!("This pipeline does not support IP-Adapters.")
+    }
+
+    /// Add a textual inversion embedding to the CLIP text encoders, so that its placeholder token can be used in
+    /// the prompts.
+    fn load_textual_inversion(
+        &mut self,
+        _textual_inversion: &TextualInversion,
+    ) -> diffusion_rs_common::core::Result<()> {
+        diffusion_rs_common::bail!("This pipeline does not support textual inversion embeddings.")
+    }
+
+    /// Attach a prior, such as FLUX.1 Redux, which encodes the image prompts of each generation.
+    fn set_prior(&mut self, _prior: Arc<Mutex<dyn ModelPipeline>>) -> Result<()> {
+        anyhow::bail!("This pipeline does not support a prior.")
+    }
+
+    /// Run as a prior: encode image prompts into a `(1, seq_len, hidden_size)` sequence of conditioning tokens,
+    /// in the given dtype and on the given device.
+    fn encode_image_prompts(
+        &mut self,
+        _images: &[ImagePrompt],
+        _dtype: DType,
+        _device: &Device,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        diffusion_rs_common::bail!("This pipeline cannot be used as a prior.")
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -144,7 +558,29 @@ impl Pipeline {
             )?;
 
             let model_loader: Box<dyn Loader> = match name.as_str() {
-                "FluxPipeline" => Box::new(FluxLoader),
+                "FluxPipeline" | "FluxFillPipeline" | "FluxControlPipeline" => {
+                    Box::new(FluxLoader {
+                        chroma: false,
+                        kontext: false,
+                    })
+                }
+                "FluxKontextPipeline" => Box::new(FluxLoader {
+                    chroma: false,
+                    kontext: true,
+                }),
+                "FluxPriorReduxPipeline" => Box::new(FluxReduxLoader),
+                "ChromaPipeline" => Box::new(FluxLoader {
+                    chroma: true,
+                    kontext: false,
+                }),
+                "StableDiffusionPipeline" => Box::new(StableDiffusionLoader),
+                "StableDiffusionXLPipeline" => Box::new(StableDiffusionXLLoader { refiner: false }),
+                "StableDiffusionXLImg2ImgPipeline" => {
+                    Box::new(StableDiffusionXLLoader { refiner: true })
+                }
+                "StableDiffusion3Pipeline" => Box::new(StableDiffusion3Loader),
+                "PixArtSigmaPipeline" => Box::new(PixArtSigmaLoader),
+                "SanaPipeline" => Box::new(SanaLoader),
                 other => anyhow::bail!("Unexpected loader type `{other:?}`."),
             };
 
@@ -176,10 +612,7 @@ impl Pipeline {
                     .any(|file| file.ends_with(".safetensors"))
                 {
                     let mut safetensors = HashMap::new();
-                    for file in files_for_component
-                        .iter()
-                        .filter(|file| file.ends_with(".safetensors"))
-                    {
+                    for file in safetensors_variant(&files_for_component) {
                         safetensors.insert(file.clone(), loader.read_file(file, from_transformer)?);
                     }
                     ComponentElem::Model {
@@ -235,6 +668,144 @@ impl Pipeline {
         })
     }
 
+    /// Load a LoRA adapter from a `.safetensors` file and merge it into the model weights, scaled by `scale`.
+    ///
+    /// Adapters in the diffusers, kohya and BFL formats are supported. Several adapters can be loaded under
+    /// different names, in which case they are stacked.
+    pub fn load_lora(
+        &self,
+        name: impl ToString,
+        path: impl AsRef<Path>,
+        scale: f64,
+    ) -> anyhow::Result<()> {
+        let tensors = diffusion_rs_common::core::safetensors::load(path, &Device::Cpu)?;
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.load_lora(name.to_string(), tensors, scale, true)?;
+        Ok(())
+    }
+
+    /// Load a LoRA adapter from a `.safetensors` file without modifying the model weights. It is applied at
+    /// runtime, which keeps quantized weights exact and allows each request to select the active adapters and
+    /// their scales with [`DiffusionGenerationParams::loras`]. By default, it is active with scale `scale`.
+    pub fn load_runtime_lora(
+        &self,
+        name: impl ToString,
+        path: impl AsRef<Path>,
+        scale: f64,
+    ) -> anyhow::Result<()> {
+        let tensors = diffusion_rs_common::core::safetensors::load(path, &Device::Cpu)?;
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.load_lora(name.to_string(), tensors, scale, false)?;
+        Ok(())
+    }
+
+    /// Remove a LoRA adapter which was loaded with [`Pipeline::load_lora`] or [`Pipeline::load_runtime_lora`].
+    pub fn unload_lora(&self, name: &str) -> anyhow::Result<()> {
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.unload_lora(name)?;
+        Ok(())
+    }
+
+    /// Names and scales of the loaded LoRA adapters, in the order they were loaded.
+    pub fn loras(&self) -> Vec<(String, f64)> {
+        self.model.lock().expect("Could not lock model!").loras()
+    }
+
+    /// Load a textual inversion embedding from a `.safetensors`, `.pt` or `.bin` file, in the A1111, diffusers or
+    /// SDXL format, and add it to the CLIP text encoders. Its placeholder token, `token` or by default the one
+    /// named in the file or the file name, can then be used in the prompts.
+    ///
+    /// To use an embedding for a single request, see [`DiffusionGenerationParams::textual_inversions`].
+    pub fn load_textual_inversion(
+        &self,
+        path: impl AsRef<Path>,
+        token: Option<String>,
+    ) -> anyhow::Result<()> {
+        let textual_inversion = TextualInversion::from_file(path, token)?;
+        info!(
+            "loading textual inversion with token `{}`.",
+            textual_inversion.token
+        );
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.load_textual_inversion(&textual_inversion)?;
+        Ok(())
+    }
+
+    /// Attach a refiner pipeline, such as the SDXL refiner, which takes over from this pipeline for the last
+    /// denoising steps of each generation. The switch point is set by [`DiffusionGenerationParams::refiner_start`].
+    pub fn set_refiner(&self, refiner: &Pipeline) -> anyhow::Result<()> {
+        if Arc::ptr_eq(&self.model, &refiner.model) {
+            anyhow::bail!("A pipeline cannot be its own refiner.");
+        }
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.set_refiner(refiner.model.clone())
+    }
+
+    /// Load a ControlNet, such as a FLUX ControlNet for canny, depth or pose control, from a model ID or a local
+    /// path with a `config.json` and `.safetensors` weights.
+    ///
+    /// ControlNets are used by the [`DiffusionGenerationParams::control_images`] which select them by index, in
+    /// the order they were loaded.
+    pub fn load_controlnet(
+        &self,
+        source: ModelSource,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+    ) -> anyhow::Result<()> {
+        info!("loading ControlNet from source: {source}.");
+        let files = ModelFiles::load(source, None, silent, token, revision)?;
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.load_controlnet(files, silent)
+    }
+
+    /// Load an IP-Adapter, such as the XLabs FLUX IP-Adapter, from a `.safetensors` file, with the CLIP image
+    /// encoder it was trained with, from a model ID or a local path with a `config.json` and `.safetensors`
+    /// weights, at the root or in `image_encoder/`.
+    ///
+    /// IP-Adapters condition the generations on their [`DiffusionGenerationParams::image_prompts`]. By default,
+    /// they are active with scale `scale`, and each request can select the active adapters and their scales with
+    /// [`DiffusionGenerationParams::ip_adapters`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn load_ip_adapter(
+        &self,
+        name: impl ToString,
+        path: impl AsRef<Path>,
+        image_encoder: ModelSource,
+        scale: f64,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+    ) -> anyhow::Result<()> {
+        info!("loading IP-Adapter image encoder from source: {image_encoder}.");
+        let image_encoder = ModelFiles::load(
+            image_encoder,
+            Some("image_encoder"),
+            silent,
+            token,
+            revision,
+        )?;
+        let tensors = diffusion_rs_common::core::safetensors::load(path, &Device::Cpu)?;
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.load_ip_adapter(name.to_string(), tensors, image_encoder, scale, silent)
+    }
+
+    /// Remove an IP-Adapter which was loaded with [`Pipeline::load_ip_adapter`].
+    pub fn unload_ip_adapter(&self, name: &str) -> anyhow::Result<()> {
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.unload_ip_adapter(name)
+    }
+
+    /// Attach a prior pipeline, such as FLUX.1 Redux, which encodes the
+    /// [`DiffusionGenerationParams::image_prompts`] of each generation of this pipeline.
+    pub fn set_prior(&self, prior: &Pipeline) -> anyhow::Result<()> {
+        if Arc::ptr_eq(&self.model, &prior.model) {
+            anyhow::bail!("A pipeline cannot be its own prior.");
+        }
+        let mut model = self.model.lock().expect("Could not lock model!");
+        model.set_prior(prior.model.clone())
+    }
+
     /// Generate images based on prompts and generation parameters.
     ///
     /// If a multiple prompts are specified, they are padded and run together as a batch.
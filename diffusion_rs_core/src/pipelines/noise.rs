@@ -0,0 +1,150 @@
+use diffusion_rs_common::core::{torch_generator::TorchGenerator, DType, Device, Result, Tensor};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use tracing::info;
+
+use super::DiffusionGenerationParams;
+
+/// Random number generator used to create the noise.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NoiseSource {
+    /// The default generator.
+    #[default]
+    Native,
+    /// Reproduces PyTorch's CPU generator, so that a seed gives the same starting latent as
+    /// `torch.Generator("cpu").manual_seed(seed)` does in diffusers. For a batch, this matches passing one
+    /// generator per image.
+    Torch,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Generator {
+    Native(StdRng),
+    Torch(TorchGenerator),
+}
+
+/// Seeded random number generators for a batch of images.
+///
+/// Each image draws from its own generator so that a given seed produces the same image regardless of the batch
+/// it is generated in.
+pub(crate) struct NoiseGenerator {
+    rngs: Vec<Generator>,
+}
+
+impl NoiseGenerator {
+    /// Create the generators for `batch_size` images.
+    ///
+    /// - If `params.seeds` is specified, it must contain one seed per image.
+    /// - Otherwise, image `i` uses `params.seed + i`. If no seed is specified, a random one is chosen.
+    pub fn new(params: &DiffusionGenerationParams, batch_size: usize) -> Result<Self> {
+        let seeds = match (&params.seeds, params.seed) {
+            (Some(seeds), _) => {
+                if seeds.len() != batch_size {
+                    diffusion_rs_common::bail!(
+                        "Expected {batch_size} seeds (one per prompt), got {}.",
+                        seeds.len()
+                    );
+                }
+                seeds.clone()
+            }
+            (None, seed) => {
+                let seed = seed.unwrap_or_else(|| {
+                    let seed = rand::random();
+                    info!("no seed specified, using seed {seed}");
+                    seed
+                });
+                (0..batch_size as u64)
+                    .map(|i| seed.wrapping_add(i))
+                    .collect()
+            }
+        };
+        let rngs = seeds
+            .into_iter()
+            .map(|seed| match params.noise_source {
+                NoiseSource::Native => Generator::Native(StdRng::seed_from_u64(seed)),
+                NoiseSource::Torch => Generator::Torch(TorchGenerator::new(seed)),
+            })
+            .collect();
+        Ok(Self { rngs })
+    }
+
+    /// Generators for a later stage of the same generation, such as a refiner. Each is seeded from the
+    /// corresponding generator here, so that its noise is reproducible but does not repeat this stream.
+    pub fn fork(&mut self) -> Self {
+        let rngs = self
+            .rngs
+            .iter_mut()
+            .map(|rng| match rng {
+                Generator::Native(rng) => Generator::Native(StdRng::seed_from_u64(rng.gen())),
+                Generator::Torch(rng) => Generator::Torch(TorchGenerator::new(rng.random64())),
+            })
+            .collect();
+        Self { rngs }
+    }
+
+    /// Sample a `(batch_size, ..shape)` tensor from a standard normal distribution.
+    ///
+    /// Sampling always happens on the CPU so that the result does not depend on the device. The PyTorch
+    /// generator samples directly in `dtype`, as the values depend on it.
+    pub fn randn(&mut self, shape: &[usize], dtype: DType, device: &Device) -> Result<Tensor> {
+        let mut dims = vec![1];
+        dims.extend_from_slice(shape);
+        let numel = shape.iter().product::<usize>();
+        let mut samples = Vec::with_capacity(self.rngs.len());
+        for rng in &mut self.rngs {
+            let sample = match rng {
+                Generator::Native(rng) => {
+                    let data = (0..numel)
+                        .map(|_| rng.sample::<f32, _>(StandardNormal))
+                        .collect::<Vec<_>>();
+                    Tensor::from_vec(data, dims.clone(), &Device::Cpu)?.to_dtype(dtype)?
+                }
+                Generator::Torch(rng) => rng.randn(dims.clone(), dtype, &Device::Cpu)?,
+            };
+            samples.push(sample);
+        }
+        Tensor::cat(&samples, 0)?.to_device(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+    use super::{NoiseGenerator, NoiseSource};
+    use crate::pipelines::DiffusionGenerationParams;
+
+    fn values(t: Tensor) -> Result<Vec<f32>> {
+        t.flatten_all()?.to_vec1()
+    }
+
+    #[test]
+    fn forked_noise_is_seeded_and_distinct() -> Result<()> {
+        for noise_source in [NoiseSource::Native, NoiseSource::Torch] {
+            let params = DiffusionGenerationParams {
+                seed: Some(7),
+                noise_source,
+                ..Default::default()
+            };
+            let draw = |fork: bool| -> Result<(Vec<f32>, Vec<f32>)> {
+                let mut rng = NoiseGenerator::new(&params, 2)?;
+                let mut forked = if fork {
+                    rng.fork()
+                } else {
+                    NoiseGenerator::new(&params, 2)?
+                };
+                let base = values(rng.randn(&[8], DType::F32, &Device::Cpu)?)?;
+                let forked = values(forked.randn(&[8], DType::F32, &Device::Cpu)?)?;
+                Ok((base, forked))
+            };
+            let (base, forked) = draw(true)?;
+            assert_eq!((base.clone(), forked.clone()), draw(true)?);
+            // A new generator replays the base stream, the fork does not.
+            let (_, fresh) = draw(false)?;
+            assert_ne!(forked, fresh);
+            assert_ne!(forked, base);
+            assert_ne!(forked[..8], forked[8..]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+use crate::models::QuantizedModel;
+use crate::{
+    models::{
+        dispatch_load_vae_model, PixArtConfig, PixArtModel, T5Config, T5EncoderModel, VAEModel,
+    },
+    pipelines::ComponentName,
+};
+use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+
+use super::discrete_scheduler::{DiscreteSchedule, DiscreteSchedulerConfig};
+use super::noise::NoiseGenerator;
+use super::sampling::sample_model;
+use super::stable_diffusion::{
+    check_discrete_params, decode_latents, initial_latents, t5_tokenize,
+};
+use super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+
+/// The maximum number of T5 tokens of a prompt.
+const T5_MAX_TOKENS: usize = 300;
+
+/// Loader for PixArt-Sigma models.
+///
+/// The scheduler class only selects the default sampler, see [`DiscreteSchedulerConfig::default_sampler`].
+pub struct PixArtSigmaLoader;
+
+impl Loader for PixArtSigmaLoader {
+    fn name(&self) -> &'static str {
+        "pixart-sigma"
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        vec![
+            ComponentName::Scheduler,
+            ComponentName::TextEncoder(1),
+            ComponentName::Tokenizer(1),
+            ComponentName::Transformer,
+            ComponentName::Vae,
+        ]
+    }
+
+    fn load_from_components(
+        &self,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        device: &Device,
+        dtype: DType,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
+        let t5_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
+        let t5_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
+        let transformer_component = components.remove(&ComponentName::Transformer).unwrap();
+        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+
+        // The transformer is small, only T5 is offloaded.
+        let t5_device = match offloading_type {
+            Some(Offloading::Full) => Device::Cpu,
+            None => device.clone(),
+        };
+
+        let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            DiscreteSchedulerConfig::from_json(
+                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+            )?
+        } else {
+            anyhow::bail!("expected scheduler config")
+        };
+        let schedule = scheduler_config.schedule()?;
+
+        let t5_tokenizer = if let ComponentElem::Other { files } = t5_tok_component {
+            let Some(file) = files.get("tokenizer/tokenizer.json") else {
+                anyhow::bail!(
+                    "the T5 tokenizer must be given as `tokenizer/tokenizer.json`, convert it with the `tokenizers` library"
+                )
+            };
+            Tokenizer::from_bytes(file.read_to_string(&source)?).map_err(anyhow::Error::msg)?
+        } else {
+            anyhow::bail!("incorrect storage of t5 tokenizer")
+        };
+        if !silent {
+            info!("loading T5 model");
+        }
+        let t5_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = t5_component
+        {
+            let cfg: T5Config = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                &t5_device,
+                silent,
+                source.clone(),
+            )?;
+            T5EncoderModel::new(vb, &cfg)?
+        } else {
+            anyhow::bail!("incorrect storage of t5 model")
+        };
+        if !silent {
+            info!("loading VAE model");
+        }
+        let vae_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = vae_component
+        {
+            dispatch_load_vae_model(
+                &config,
+                safetensors.into_values().collect(),
+                device,
+                dtype,
+                silent,
+                source.clone(),
+            )?
+        } else {
+            anyhow::bail!("incorrect storage of vae model")
+        };
+        if !silent {
+            info!("loading PixArt model");
+        }
+        let (transformer_model, latent_channels) = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = transformer_component
+        {
+            let cfg: PixArtConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source,
+            )?;
+            (PixArtModel::new(&cfg, vb)?, cfg.in_channels)
+        } else {
+            anyhow::bail!("incorrect storage of pixart model")
+        };
+
+        let pipeline = PixArtSigmaPipeline {
+            t5_tokenizer,
+            t5_model,
+            vae_model,
+            transformer_model,
+            latent_channels,
+            scheduler_config,
+            schedule,
+            dtype,
+            device: device.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+}
+
+pub struct PixArtSigmaPipeline {
+    t5_tokenizer: Tokenizer,
+    t5_model: T5EncoderModel,
+    vae_model: Arc<dyn VAEModel>,
+    transformer_model: PixArtModel,
+    latent_channels: usize,
+    scheduler_config: DiscreteSchedulerConfig,
+    schedule: DiscreteSchedule,
+    dtype: DType,
+    device: Device,
+}
+
+impl PixArtSigmaPipeline {
+    /// Encode the prompts with T5, returning the embeddings padded with zeros to the longest prompt and the mask
+    /// of the prompt tokens. Each prompt is encoded on its own so that the padding does not change its embeddings.
+    fn encode_prompts(
+        &mut self,
+        prompts: Vec<String>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let input_ids = t5_tokenize(&self.t5_tokenizer, prompts, T5_MAX_TOKENS)?;
+
+        if let Some(Offloading::Full) = offloading_type {
+            self.t5_model.to_device(&self.device)?;
+        }
+        let max_len = input_ids.iter().map(Vec::len).max().unwrap_or(0);
+        let mut embeds = Vec::with_capacity(input_ids.len());
+        let mut mask = Vec::with_capacity(input_ids.len());
+        for ids in &input_ids {
+            let embed = self
+                .t5_model
+                .forward(&Tensor::new(ids.as_slice(), &self.device)?.unsqueeze(0)?)?;
+            embeds.push(embed.pad_with_zeros(1, 0, max_len - ids.len())?);
+            let mut tokens_mask = vec![1u8; ids.len()];
+            tokens_mask.resize(max_len, 0);
+            mask.push(tokens_mask);
+        }
+        if let Some(Offloading::Full) = offloading_type {
+            self.t5_model.to_device(&Device::Cpu)?;
+        }
+
+        Ok((Tensor::cat(&embeds, 0)?, Tensor::new(mask, &self.device)?))
+    }
+}
+
+impl ModelPipeline for PixArtSigmaPipeline {
+    fn forward(
+        &mut self,
+        prompts: Vec<String>,
+        params: DiffusionGenerationParams,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        check_discrete_params(&params)?;
+        if !params.textual_inversions.is_empty() {
+            diffusion_rs_common::bail!(
+                "This pipeline has no CLIP text encoder for textual inversion embeddings."
+            );
+        }
+
+        // Classifier-free guidance: the negative prompts are encoded and run in the same batch.
+        let bs = prompts.len();
+        let cfg = params.guidance_scale > 1.;
+        let mut all_prompts = prompts;
+        if cfg {
+            let negative_prompt = params.negative_prompt.clone().unwrap_or_default();
+            all_prompts.extend(vec![negative_prompt; bs]);
+        }
+        let (context, context_mask) = self.encode_prompts(all_prompts, offloading_type)?;
+
+        // The latents are patchified in 2x2 patches.
+        let height = params.height.div_ceil(16) * 16;
+        let width = params.width.div_ceil(16) * 16;
+        let mut rng = NoiseGenerator::new(&params, bs)?;
+        let noise = rng.randn(
+            &[self.latent_channels, height / 8, width / 8],
+            self.dtype,
+            &self.device,
+        )?;
+
+        let mut timesteps = self.scheduler_config.get_timesteps(
+            &self.schedule,
+            params.num_steps,
+            params.sigma_spacing,
+        )?;
+        let (img, inpaint) = initial_latents(
+            self.vae_model.as_ref(),
+            self.dtype,
+            &params,
+            noise,
+            DiscreteSchedule::noise_scale(timesteps[0]),
+            &mut timesteps,
+            bs,
+            height,
+            width,
+        )?;
+
+        let model = |x: &Tensor, s: f64| -> diffusion_rs_common::core::Result<Tensor> {
+            let (t, scale) = self.schedule.model_input(s);
+            let x_in = (x * scale)?;
+            let x_in = if cfg {
+                Tensor::cat(&[&x_in, &x_in], 0)?
+            } else {
+                x_in
+            };
+            let t = Tensor::full(t as f32, x_in.dim(0)?, &self.device)?;
+            let pred = self
+                .transformer_model
+                .forward(&x_in, &t, &context, &context_mask)?;
+            let pred = if cfg {
+                let negative_pred = pred.narrow(0, bs, bs)?;
+                let pred = pred.narrow(0, 0, bs)?;
+                (&negative_pred + ((pred - &negative_pred)? * params.guidance_scale)?)?
+            } else {
+                pred
+            };
+            self.schedule.velocity(x, &pred, s)
+        };
+
+        let mut sampler = params
+            .sampler
+            .map_or_else(|| self.scheduler_config.default_sampler(), Ok)?
+            .sampler(params.eta, rng)?;
+        let img = sample_model(sampler.as_mut(), &timesteps, &img, &model, inpaint.as_ref())?;
+
+        decode_latents(self.vae_model.as_ref(), self.dtype, &img)
+    }
+}
@@ -3,47 +3,402 @@ use diffusion_rs_common::{
     NiceProgressBar,
 };
 
-use super::scheduler::SchedulerType;
-
-pub enum Sampler {
-    FlowMatchEulerDiscrete,
-}
-
-impl Sampler {
-    pub fn new(ty: &SchedulerType) -> Self {
-        match ty {
-            SchedulerType::FlowMatchEulerDiscrete => Self::FlowMatchEulerDiscrete,
-        }
-    }
-
-    /// Run the denoising process over the given image.
-    ///
-    /// Expects a step closure:
-    /// ```ignore
-    /// fn(img: &Tensor, t_vec: &Tensor) -> Result<Tensor>;
-    /// ``````
-    pub fn sample(
-        &self,
-        timesteps: &[f64],
-        img: &Tensor,
-        step: impl Fn(&Tensor, &Tensor) -> Result<Tensor>,
-    ) -> Result<Tensor> {
-        match self {
-            Self::FlowMatchEulerDiscrete => {
-                let b_sz = img.dim(0)?;
-                let dev = img.device();
-                let t_vec = Tensor::full(1f32, b_sz, dev)?;
-                let mut img = img.clone();
-                for window in NiceProgressBar::<_, 'g'>(timesteps.windows(2), "Denoise loop") {
-                    let (t_curr, t_prev) = match window {
-                        [a, b] => (a, b),
-                        _ => continue,
-                    };
-                    let pred = step(&img, &(&t_vec * *t_curr)?)?;
-                    img = (img + pred * (t_prev - t_curr))?
-                }
-                Ok(img)
+use super::{
+    noise::NoiseGenerator,
+    scheduler::{SchedulerConfig, SchedulerType},
+};
+
+/// The known region of an image being inpainted. After each denoising step, it is re-imposed from the
+/// source latents, noised to the current timestep.
+pub struct InpaintMask {
+    /// 1 where the image is regenerated and 0 where it is kept, with the same shape as the latents.
+    pub mask: Tensor,
+    /// Latents of the source image.
+    pub image_latents: Tensor,
+    /// The initial noise.
+    pub noise: Tensor,
+}
+
+impl InpaintMask {
+    fn blend(&self, img: &Tensor, t: f64) -> Result<Tensor> {
+        let known = ((&self.noise * t)? + (&self.image_latents * (1. - t))?)?;
+        let keep = (1. - &self.mask)?;
+        (img * &self.mask)? + (known * keep)?
+    }
+}
+
+/// Sampling method for flow-matching models. If not specified in the generation parameters, it is chosen from
+/// the scheduler config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SamplerType {
+    /// First order Euler method.
+    Euler,
+    /// Second order Heun method. Each step except the last runs the model twice.
+    Heun,
+    /// Multistep second order DPM-Solver++ (2M). It runs the model once per step, like Euler, and usually
+    /// reaches the same quality in fewer steps.
+    #[value(name = "dpmpp-2m")]
+    DpmPlusPlus2M,
+    /// Stochastic (ancestral) Euler method: each step overshoots towards the clean image and injects fresh
+    /// noise, by an amount controlled by `eta`. This gives more varied textures.
+    EulerAncestral,
+    /// Euler method that fully renoises the data prediction to the next sigma with fresh noise at each step, as
+    /// the `stochastic_sampling` scheduler option.
+    StochasticEuler,
+}
+
+impl From<&SchedulerConfig> for SamplerType {
+    fn from(value: &SchedulerConfig) -> Self {
+        match value.scheduler_type {
+            SchedulerType::FlowMatchEulerDiscrete if value.stochastic_sampling => {
+                Self::StochasticEuler
             }
+            SchedulerType::FlowMatchEulerDiscrete => Self::Euler,
+            SchedulerType::FlowMatchHeunDiscrete => Self::Heun,
+            // First order DPM-Solver++ is the Euler method.
+            SchedulerType::DPMSolverMultistep if value.solver_order == 1 => Self::Euler,
+            SchedulerType::DPMSolverMultistep => Self::DpmPlusPlus2M,
+        }
+    }
+}
+
+impl SamplerType {
+    /// Create the sampler. Stochastic samplers draw their noise from `rng` with the strength `eta`.
+    pub(crate) fn sampler(&self, eta: f64, rng: NoiseGenerator) -> Result<Box<dyn Sampler>> {
+        Ok(match self {
+            Self::Euler => Box::new(EulerSampler),
+            Self::Heun => Box::new(HeunSampler),
+            Self::DpmPlusPlus2M => Box::new(DpmPlusPlus2MSampler::default()),
+            Self::EulerAncestral => Box::new(EulerAncestralSampler::new(eta, rng)?),
+            Self::StochasticEuler => Box::new(StochasticEulerSampler { rng }),
+        })
+    }
+}
+
+/// The velocity prediction of the model at a given sigma: `fn(img: &Tensor, sigma: f64) -> Result<Tensor>`.
+pub type Model<'a> = dyn Fn(&Tensor, f64) -> Result<Tensor> + 'a;
+
+/// An integration method for the flow-matching ODE `dx/dsigma = v(x, sigma)`, where the model predicts the
+/// velocity `v`. Samplers may keep state between steps, so a new sampler should be used for each generation.
+pub trait Sampler {
+    /// Advance `img` from `sigmas[i]` to `sigmas[i + 1]`.
+    fn step(&mut self, model: &Model, img: &Tensor, sigmas: &[f64], i: usize) -> Result<Tensor>;
+}
+
+/// Run the denoising process over the given image.
+///
+/// Expects a step closure:
+/// ```ignore
+/// fn(img: &Tensor, t_vec: &Tensor) -> Result<Tensor>;
+/// ``````
+///
+/// If an inpainting mask is given, the known region is re-imposed after each step.
+pub fn sample(
+    sampler: &mut dyn Sampler,
+    timesteps: &[f64],
+    img: &Tensor,
+    step: impl Fn(&Tensor, &Tensor) -> Result<Tensor>,
+    inpaint: Option<&InpaintMask>,
+) -> Result<Tensor> {
+    let b_sz = img.dim(0)?;
+    let dev = img.device();
+    let t_vec = Tensor::full(1f32, b_sz, dev)?;
+    let model = |img: &Tensor, sigma: f64| step(img, &(&t_vec * sigma)?);
+    sample_model(sampler, timesteps, img, &model, inpaint)
+}
+
+/// Run the denoising process over the given image, with a model taking the sigma as a scalar.
+///
+/// If an inpainting mask is given, the known region is re-imposed after each step.
+pub fn sample_model(
+    sampler: &mut dyn Sampler,
+    timesteps: &[f64],
+    img: &Tensor,
+    model: &Model,
+    inpaint: Option<&InpaintMask>,
+) -> Result<Tensor> {
+    let mut img = img.clone();
+    let num_steps = timesteps.len().saturating_sub(1);
+    for i in NiceProgressBar::<_, 'g'>(0..num_steps, "Denoise loop") {
+        img = sampler.step(model, &img, timesteps, i)?;
+        if let Some(inpaint) = inpaint {
+            img = inpaint.blend(&img, timesteps[i + 1])?;
+        }
+    }
+    Ok(img)
+}
+
+pub struct EulerSampler;
+
+impl Sampler for EulerSampler {
+    fn step(&mut self, model: &Model, img: &Tensor, sigmas: &[f64], i: usize) -> Result<Tensor> {
+        let (sigma, sigma_next) = (sigmas[i], sigmas[i + 1]);
+        let v = model(img, sigma)?;
+        img + (v * (sigma_next - sigma))?
+    }
+}
+
+/// Heun's method, as `FlowMatchHeunDiscreteScheduler`. The step to sigma 0 is a Euler step.
+pub struct HeunSampler;
+
+impl Sampler for HeunSampler {
+    fn step(&mut self, model: &Model, img: &Tensor, sigmas: &[f64], i: usize) -> Result<Tensor> {
+        let (sigma, sigma_next) = (sigmas[i], sigmas[i + 1]);
+        let dt = sigma_next - sigma;
+        let v = model(img, sigma)?;
+        let euler = (img + (&v * dt)?)?;
+        if sigma_next == 0. {
+            return Ok(euler);
+        }
+        let v_next = model(&euler, sigma_next)?;
+        img + (((v + v_next)? * 0.5)? * dt)?
+    }
+}
+
+/// Multistep DPM-Solver++ (2M) with flow sigmas, as `DPMSolverMultistepScheduler` with `use_flow_sigmas`.
+///
+/// With `x = (1 - sigma) * x0 + sigma * noise`, the data prediction is `x0 = x - sigma * v` and the half
+/// log-SNR is `lambda = ln(1 - sigma) - ln(sigma)`. The first step, and the step to sigma 0, are first order.
+#[derive(Default)]
+pub struct DpmPlusPlus2MSampler {
+    /// The previous data prediction and its lambda.
+    prev: Option<(Tensor, f64)>,
+}
+
+impl Sampler for DpmPlusPlus2MSampler {
+    fn step(&mut self, model: &Model, img: &Tensor, sigmas: &[f64], i: usize) -> Result<Tensor> {
+        let (sigma, sigma_next) = (sigmas[i], sigmas[i + 1]);
+        let lambda = |sigma: f64| (1. - sigma).ln() - sigma.ln();
+
+        let v = model(img, sigma)?;
+        let x0 = (img - (v * sigma)?)?;
+        let prev = self.prev.replace((x0.clone(), lambda(sigma)));
+
+        if sigma_next == 0. {
+            return Ok(x0);
+        }
+        let alpha_next = 1. - sigma_next;
+        let h = lambda(sigma_next) - lambda(sigma);
+        let x0_coef = -alpha_next * (-h).exp_m1();
+        let x = (img * (sigma_next / sigma))?;
+
+        match prev {
+            Some((prev_x0, prev_lambda))
+                if prev_lambda.is_finite() && lambda(sigma).is_finite() =>
+            {
+                let r = (lambda(sigma) - prev_lambda) / h;
+                // D0 + D1 / 2, with D1 = (x0 - prev_x0) / r
+                let d = ((&x0 * (1. + 0.5 / r))? - (prev_x0 * (0.5 / r))?)?;
+                x + (d * x0_coef)?
+            }
+            _ => x + (x0 * x0_coef)?,
+        }
+    }
+}
+
+/// Ancestral Euler method for rectified flow.
+///
+/// Each step takes a Euler step to `sigma_down < sigma_next`, then scales the result and adds fresh noise to
+/// reach the marginal at `sigma_next`. With `eta = 0` this is the Euler method, and `eta = 1` gives the full
+/// ancestral step. The step to sigma 0 is deterministic.
+pub struct EulerAncestralSampler {
+    eta: f64,
+    rng: NoiseGenerator,
+}
+
+impl EulerAncestralSampler {
+    pub(crate) fn new(eta: f64, rng: NoiseGenerator) -> Result<Self> {
+        if !(0. ..=1.).contains(&eta) {
+            diffusion_rs_common::bail!("`eta` must be between 0 and 1, got {eta}.");
         }
+        Ok(Self { eta, rng })
+    }
+}
+
+impl Sampler for EulerAncestralSampler {
+    fn step(&mut self, model: &Model, img: &Tensor, sigmas: &[f64], i: usize) -> Result<Tensor> {
+        let (sigma, sigma_next) = (sigmas[i], sigmas[i + 1]);
+        let v = model(img, sigma)?;
+        let x0 = (img - (&v * sigma)?)?;
+        if sigma_next == 0. {
+            return Ok(x0);
+        }
+
+        let sigma_down = sigma_next * (1. + (sigma_next / sigma - 1.) * self.eta);
+        let alpha_next = 1. - sigma_next;
+        let alpha_down = 1. - sigma_down;
+        let renoise = (sigma_next.powi(2) - (sigma_down * alpha_next / alpha_down).powi(2))
+            .max(0.)
+            .sqrt();
+
+        let ratio = sigma_down / sigma;
+        let x = ((img * ratio)? + (x0 * (1. - ratio))?)?;
+        if self.eta == 0. {
+            return Ok(x);
+        }
+        let noise = self
+            .rng
+            .randn(&img.dims()[1..], img.dtype(), img.device())?;
+        (x * (alpha_next / alpha_down))? + (noise * renoise)?
+    }
+}
+
+/// Euler method with full renoising, as `FlowMatchEulerDiscreteScheduler` with `stochastic_sampling`: the next
+/// sample is `(1 - sigma_next) * x0 + sigma_next * noise`.
+pub struct StochasticEulerSampler {
+    rng: NoiseGenerator,
+}
+
+impl Sampler for StochasticEulerSampler {
+    fn step(&mut self, model: &Model, img: &Tensor, sigmas: &[f64], i: usize) -> Result<Tensor> {
+        let (sigma, sigma_next) = (sigmas[i], sigmas[i + 1]);
+        let v = model(img, sigma)?;
+        let x0 = (img - (v * sigma)?)?;
+        if sigma_next == 0. {
+            return Ok(x0);
+        }
+        let noise = self
+            .rng
+            .randn(&img.dims()[1..], img.dtype(), img.device())?;
+        (x0 * (1. - sigma_next))? + (noise * sigma_next)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+
+    use super::{
+        sample_model, DpmPlusPlus2MSampler, EulerAncestralSampler, EulerSampler, HeunSampler,
+        Sampler,
+    };
+    use crate::pipelines::{noise::NoiseGenerator, DiffusionGenerationParams};
+
+    /// Standard deviation of the data, which follows `N(0, S^2)`.
+    const S: f64 = 0.5;
+
+    /// The standard deviation of the noised data at `sigma`.
+    fn std(sigma: f64) -> f64 {
+        ((1. - sigma).powi(2) * S * S + sigma * sigma).sqrt()
+    }
+
+    /// The exact velocity for Gaussian data, which is linear in `x`. The solution of the ODE keeps `x / std` constant.
+    fn model(x: &Tensor, sigma: f64) -> Result<Tensor> {
+        x * ((sigma - (1. - sigma) * S * S) / std(sigma).powi(2))
+    }
+
+    /// `num_steps` steps between sigma 0.8 and 0.05, uniform in log-SNR.
+    fn sigmas(num_steps: usize) -> Vec<f64> {
+        let lambda = |sigma: f64| (1. - sigma).ln() - sigma.ln();
+        let (start, end) = (lambda(0.8), lambda(0.05));
+        (0..=num_steps)
+            .map(|i| {
+                let lambda = start + (end - start) * i as f64 / num_steps as f64;
+                1. / (1. + lambda.exp())
+            })
+            .collect()
+    }
+
+    fn error(sampler: &mut dyn Sampler, num_steps: usize) -> Result<f64> {
+        let img = Tensor::ones((1, 1), DType::F64, &Device::Cpu)?;
+        let sigmas = sigmas(num_steps);
+        let img = sample_model(sampler, &sigmas, &img, &model, None)?;
+        let exact = std(sigmas[num_steps]) / std(sigmas[0]);
+        Ok((img.flatten_all()?.to_vec1::<f64>()?[0] - exact).abs())
+    }
+
+    /// The ratio of the errors with 20 and 40 steps: about 2 for a first order method, and 4 for a second order one.
+    fn convergence(sampler: impl Fn() -> Box<dyn Sampler>) -> Result<f64> {
+        Ok(error(sampler().as_mut(), 20)? / error(sampler().as_mut(), 40)?)
+    }
+
+    #[test]
+    fn euler_is_first_order() -> Result<()> {
+        let ratio = convergence(|| Box::new(EulerSampler))?;
+        assert!((1.8..2.2).contains(&ratio), "{ratio}");
+        Ok(())
+    }
+
+    #[test]
+    fn heun_is_second_order() -> Result<()> {
+        let ratio = convergence(|| Box::new(HeunSampler))?;
+        assert!((3.6..4.4).contains(&ratio), "{ratio}");
+        assert!(error(&mut HeunSampler, 20)? < 1e-3);
+        Ok(())
+    }
+
+    #[test]
+    fn dpmpp_2m_is_second_order() -> Result<()> {
+        let ratio = convergence(|| Box::<DpmPlusPlus2MSampler>::default())?;
+        assert!((3.6..4.4).contains(&ratio), "{ratio}");
+        assert!(error(&mut DpmPlusPlus2MSampler::default(), 40)? < 5e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn heun_step() -> Result<()> {
+        // For `v = a * x`, a Heun step multiplies `x` by `1 + a dt + (a dt)^2 / 2`, and the step to sigma 0 is a
+        // Euler step.
+        let a = -1.5;
+        let model = |x: &Tensor, _: f64| x * a;
+        let img = Tensor::ones((1, 1), DType::F64, &Device::Cpu)?;
+        let value = |img: Tensor| -> Result<f64> { Ok(img.flatten_all()?.to_vec1::<f64>()?[0]) };
+
+        let step = value(HeunSampler.step(&model, &img, &[0.6, 0.2], 0)?)?;
+        let a_dt = a * -0.4;
+        assert!((step - (1. + a_dt + a_dt * a_dt / 2.)).abs() < 1e-12);
+        let step = value(HeunSampler.step(&model, &img, &[0.2, 0.], 0)?)?;
+        assert!((step - (1. + a * -0.2)).abs() < 1e-12);
+        Ok(())
+    }
+
+    fn rng(seed: u64) -> Result<NoiseGenerator> {
+        let params = DiffusionGenerationParams {
+            seed: Some(seed),
+            ..Default::default()
+        };
+        NoiseGenerator::new(&params, 2)
+    }
+
+    fn ancestral(eta: f64, seed: u64) -> Result<Vec<Vec<f64>>> {
+        let img = Tensor::arange(0., 3., &Device::Cpu)?
+            .reshape((1, 3))?
+            .repeat((2, 1))?;
+        let mut sampler = EulerAncestralSampler::new(eta, rng(seed)?)?;
+        sample_model(&mut sampler, &sigmas(8), &img, &model, None)?.to_vec2::<f64>()
+    }
+
+    #[test]
+    fn ancestral_without_noise_is_euler() -> Result<()> {
+        let img = Tensor::arange(0., 3., &Device::Cpu)?
+            .reshape((1, 3))?
+            .repeat((2, 1))?;
+        let euler = sample_model(&mut EulerSampler, &sigmas(8), &img, &model, None)?;
+        for (a, b) in ancestral(0., 0)?
+            .concat()
+            .iter()
+            .zip(euler.flatten_all()?.to_vec1::<f64>()?)
+        {
+            assert!((a - b).abs() < 1e-12, "{a} != {b}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ancestral_noise_is_seeded() -> Result<()> {
+        assert_eq!(ancestral(1., 42)?, ancestral(1., 42)?);
+        assert_ne!(ancestral(1., 42)?, ancestral(1., 43)?);
+        assert_ne!(ancestral(0.5, 42)?, ancestral(0., 42)?);
+        // The images of the batch start from the same latents, but each has its own generator.
+        let batch = ancestral(1., 42)?;
+        assert_ne!(batch[0], batch[1]);
+        let batch = ancestral(0., 42)?;
+        assert_eq!(batch[0], batch[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn ancestral_eta_is_checked() {
+        assert!(EulerAncestralSampler::new(1.5, rng(0).unwrap()).is_err());
     }
 }
@@ -0,0 +1,384 @@
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+use crate::models::QuantizedModel;
+use crate::{
+    models::{dispatch_load_vae_model, Gemma2Config, Gemma2Model, SanaConfig, SanaModel, VAEModel},
+    pipelines::ComponentName,
+};
+use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+
+use super::flux::calculate_shift;
+use super::noise::NoiseGenerator;
+use super::sampling::{sample, SamplerType};
+use super::scheduler::SchedulerConfig;
+use super::stable_diffusion::{check_params, decode_latents, initial_latents};
+use super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+
+/// The maximum number of Gemma tokens of a prompt, after the instruction.
+const GEMMA_MAX_TOKENS: usize = 300;
+
+/// The downsampling factor of the DC-AE autoencoder.
+const VAE_SCALE_FACTOR: usize = 32;
+
+/// The instruction prepended to the prompts, which asks Gemma to think of an enhanced prompt. Only the embeddings
+/// of the first token and of the end of the instruction followed by the prompt are kept.
+const COMPLEX_HUMAN_INSTRUCTION: [&str; 8] = [
+    "Given a user prompt, generate an 'Enhanced prompt' that provides detailed visual descriptions suitable for image generation. Evaluate the level of detail in the user prompt:",
+    "- If the prompt is simple, focus on adding specifics about colors, shapes, sizes, textures, and spatial relationships to create vivid and concrete scenes.",
+    "- If the prompt is already detailed, refine and enhance the existing details slightly without overcomplicating.",
+    "Here are examples of how to transform or refine prompts:",
+    "- User Prompt: A cat sleeping -> Enhanced: A small, fluffy white cat curled up in a round shape, sleeping peacefully on a warm sunny windowsill, surrounded by pots of blooming red flowers.",
+    "- User Prompt: A busy city street -> Enhanced: A bustling city street scene at dusk, featuring glowing street lamps, a diverse crowd of people in colorful clothing, and a double-decker bus passing by towering glass skyscrapers.",
+    "Please generate only the enhanced description for the prompt below and avoid including any additional commentary or evaluations:",
+    "User Prompt: ",
+];
+
+pub struct SanaLoader;
+
+impl Loader for SanaLoader {
+    fn name(&self) -> &'static str {
+        "sana"
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        vec![
+            ComponentName::Scheduler,
+            ComponentName::TextEncoder(1),
+            ComponentName::Tokenizer(1),
+            ComponentName::Transformer,
+            ComponentName::Vae,
+        ]
+    }
+
+    fn load_from_components(
+        &self,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        device: &Device,
+        dtype: DType,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
+        let gemma_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
+        let gemma_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
+        let transformer_component = components.remove(&ComponentName::Transformer).unwrap();
+        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+
+        // Only Gemma is offloaded, the transformer is used at every step.
+        let gemma_device = match offloading_type {
+            Some(Offloading::Full) => Device::Cpu,
+            None => device.clone(),
+        };
+
+        let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            SchedulerConfig::from_json(
+                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+            )?
+        } else {
+            anyhow::bail!("expected scheduler config")
+        };
+
+        let gemma_tokenizer = if let ComponentElem::Other { files } = gemma_tok_component {
+            let Some(file) = files.get("tokenizer/tokenizer.json") else {
+                anyhow::bail!("the Gemma tokenizer must be given as `tokenizer/tokenizer.json`")
+            };
+            Tokenizer::from_bytes(file.read_to_string(&source)?).map_err(anyhow::Error::msg)?
+        } else {
+            anyhow::bail!("incorrect storage of gemma tokenizer")
+        };
+        if !silent {
+            info!("loading Gemma model");
+        }
+        let gemma_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = gemma_component
+        {
+            let cfg: Gemma2Config = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                &gemma_device,
+                silent,
+                source.clone(),
+            )?;
+            Gemma2Model::new(vb, &cfg)?
+        } else {
+            anyhow::bail!("incorrect storage of gemma model")
+        };
+        if !silent {
+            info!("loading VAE model");
+        }
+        let vae_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = vae_component
+        {
+            dispatch_load_vae_model(
+                &config,
+                safetensors.into_values().collect(),
+                device,
+                dtype,
+                silent,
+                source.clone(),
+            )?
+        } else {
+            anyhow::bail!("incorrect storage of vae model")
+        };
+        if !silent {
+            info!("loading Sana model");
+        }
+        let (transformer_model, latent_channels, patch_size) = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = transformer_component
+        {
+            let cfg: SanaConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            if cfg.in_channels != cfg.out_channels() {
+                anyhow::bail!(
+                    "unsupported Sana model with {} input and {} output channels",
+                    cfg.in_channels,
+                    cfg.out_channels()
+                );
+            }
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source,
+            )?;
+            (SanaModel::new(&cfg, vb)?, cfg.in_channels, cfg.patch_size)
+        } else {
+            anyhow::bail!("incorrect storage of sana model")
+        };
+
+        let pipeline = SanaPipeline {
+            gemma_tokenizer,
+            gemma_model,
+            vae_model,
+            transformer_model,
+            latent_channels,
+            patch_size,
+            scheduler_config,
+            dtype,
+            device: device.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+}
+
+pub struct SanaPipeline {
+    gemma_tokenizer: Tokenizer,
+    gemma_model: Gemma2Model,
+    vae_model: Arc<dyn VAEModel>,
+    transformer_model: SanaModel,
+    latent_channels: usize,
+    patch_size: usize,
+    scheduler_config: SchedulerConfig,
+    dtype: DType,
+    device: Device,
+}
+
+impl SanaPipeline {
+    /// Encode the prompts with Gemma, returning the embeddings padded with zeros to the longest prompt and the
+    /// mask of the prompt tokens. The prompts are lowercased. With `instruction`, they are prefixed with the
+    /// complex human instruction, and only the embeddings of the first token and of the last
+    /// `GEMMA_MAX_TOKENS - 1` tokens are kept.
+    fn encode_prompts(
+        &mut self,
+        prompts: Vec<String>,
+        instruction: bool,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let to_msg = |e: tokenizers::Error| diffusion_rs_common::core::Error::Msg(e.to_string());
+        let prompts = prompts
+            .iter()
+            .map(|prompt| prompt.trim().to_lowercase())
+            .collect::<Vec<_>>();
+        let (prompts, max_len) = if instruction {
+            let chi_prompt = COMPLEX_HUMAN_INSTRUCTION.join("\n");
+            let num_chi_tokens = self
+                .gemma_tokenizer
+                .encode(chi_prompt.as_str(), true)
+                .map_err(to_msg)?
+                .get_ids()
+                .len();
+            let prompts = prompts
+                .into_iter()
+                .map(|prompt| format!("{chi_prompt}{prompt}"))
+                .collect();
+            (prompts, num_chi_tokens + GEMMA_MAX_TOKENS - 2)
+        } else {
+            (prompts, GEMMA_MAX_TOKENS)
+        };
+        let encodings = self
+            .gemma_tokenizer
+            .encode_batch(prompts, true)
+            .map_err(to_msg)?;
+
+        let mut embeds = Vec::with_capacity(encodings.len());
+        for encoding in &encodings {
+            let ids = encoding.get_ids();
+            let ids = &ids[..ids.len().min(max_len)];
+            let embed = self
+                .gemma_model
+                .forward(&Tensor::new(ids, &self.device)?.unsqueeze(0)?)?;
+            // The first token, then the tokens within the last `GEMMA_MAX_TOKENS - 1` positions of the padded
+            // sequence.
+            let start = max_len + 1 - GEMMA_MAX_TOKENS;
+            let embed = if start > 1 && start < ids.len() {
+                Tensor::cat(
+                    &[
+                        embed.narrow(1, 0, 1)?,
+                        embed.narrow(1, start, ids.len() - start)?,
+                    ],
+                    1,
+                )?
+            } else if start > 1 {
+                embed.narrow(1, 0, 1)?
+            } else {
+                embed
+            };
+            embeds.push(embed);
+        }
+
+        let seq_len = embeds
+            .iter()
+            .map(|embed| embed.dim(1))
+            .collect::<diffusion_rs_common::core::Result<Vec<_>>>()?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+        let mut mask = Vec::with_capacity(embeds.len());
+        for embed in &mut embeds {
+            let len = embed.dim(1)?;
+            *embed = embed.pad_with_zeros(1, 0, seq_len - len)?;
+            let mut tokens_mask = vec![1u8; len];
+            tokens_mask.resize(seq_len, 0);
+            mask.push(tokens_mask);
+        }
+        Ok((Tensor::cat(&embeds, 0)?, Tensor::new(mask, &self.device)?))
+    }
+}
+
+impl ModelPipeline for SanaPipeline {
+    fn forward(
+        &mut self,
+        prompts: Vec<String>,
+        params: DiffusionGenerationParams,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        check_params(&params)?;
+        if !params.textual_inversions.is_empty() {
+            diffusion_rs_common::bail!(
+                "This pipeline has no CLIP text encoder for textual inversion embeddings."
+            );
+        }
+
+        // Classifier-free guidance: the negative prompts are encoded without the instruction and run in the
+        // same batch.
+        let bs = prompts.len();
+        let cfg = params.guidance_scale > 1.;
+        if let Some(Offloading::Full) = offloading_type {
+            self.gemma_model.to_device(&self.device)?;
+        }
+        let (context, context_mask) = if cfg {
+            let negative_prompt = params.negative_prompt.clone().unwrap_or_default();
+            let (context, mask) = self.encode_prompts(prompts, true)?;
+            let (negative_context, negative_mask) =
+                self.encode_prompts(vec![negative_prompt; bs], false)?;
+            // Pad both to the same length.
+            let len = context.dim(1)?.max(negative_context.dim(1)?);
+            let pad = |xs: Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+                let n = xs.dim(1)?;
+                xs.pad_with_zeros(1, 0, len - n)
+            };
+            (
+                Tensor::cat(&[pad(context)?, pad(negative_context)?], 0)?,
+                Tensor::cat(&[pad(mask)?, pad(negative_mask)?], 0)?,
+            )
+        } else {
+            self.encode_prompts(prompts, true)?
+        };
+        if let Some(Offloading::Full) = offloading_type {
+            self.gemma_model.to_device(&Device::Cpu)?;
+        }
+        let context = context.to_dtype(self.dtype)?;
+
+        let multiple = VAE_SCALE_FACTOR * self.patch_size;
+        let height = params.height.div_ceil(multiple) * multiple;
+        let width = params.width.div_ceil(multiple) * multiple;
+        let (latent_h, latent_w) = (height / VAE_SCALE_FACTOR, width / VAE_SCALE_FACTOR);
+        let mut rng = NoiseGenerator::new(&params, bs)?;
+        let noise = rng.randn(
+            &[self.latent_channels, latent_h, latent_w],
+            self.dtype,
+            &self.device,
+        )?;
+
+        let mu = if self.scheduler_config.use_dynamic_shifting {
+            Some(calculate_shift(
+                (latent_h / self.patch_size) * (latent_w / self.patch_size),
+                self.scheduler_config.base_image_seq_len,
+                self.scheduler_config.max_image_seq_len,
+                self.scheduler_config.base_shift,
+                self.scheduler_config.max_shift,
+            ))
+        } else {
+            None
+        };
+        let mut timesteps = self.scheduler_config.get_timesteps(
+            params.num_steps,
+            mu,
+            params.sigma_spacing,
+            params.sigmas.as_deref(),
+        )?;
+        let sampler_type = params
+            .sampler
+            .unwrap_or_else(|| SamplerType::from(&self.scheduler_config));
+        self.scheduler_config
+            .check_sampling(sampler_type, params.init_image.is_some())?;
+
+        // Flow-matching models start from unscaled noise.
+        let (img, inpaint) = initial_latents(
+            self.vae_model.as_ref(),
+            self.dtype,
+            &params,
+            noise,
+            1.,
+            &mut timesteps,
+            bs,
+            height,
+            width,
+        )?;
+
+        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+            if !cfg {
+                return self
+                    .transformer_model
+                    .forward(img, t_vec, &context, &context_mask);
+            }
+            let img = Tensor::cat(&[img, img], 0)?;
+            let t_vec = Tensor::cat(&[t_vec, t_vec], 0)?;
+            let pred = self
+                .transformer_model
+                .forward(&img, &t_vec, &context, &context_mask)?;
+            let negative_pred = pred.narrow(0, bs, bs)?;
+            let pred = pred.narrow(0, 0, bs)?;
+            &negative_pred + ((pred - &negative_pred)? * params.guidance_scale)?
+        };
+
+        let mut sampler = sampler_type.sampler(params.eta, rng)?;
+        let img = sample(sampler.as_mut(), &timesteps, &img, step, inpaint.as_ref())?;
+
+        decode_latents(self.vae_model.as_ref(), self.dtype, &img)
+    }
+}
@@ -1,52 +1,779 @@
 use diffusion_rs_common::core::{Context, Result};
 use serde::Deserialize;
 
+use super::sampling::SamplerType;
+
+/// Config of a flow-matching scheduler (`scheduler_config.json`). Missing fields take the diffusers defaults.
 #[derive(Deserialize, Clone)]
 pub struct SchedulerConfig {
     #[serde(rename = "_class_name")]
     pub scheduler_type: SchedulerType,
+    #[serde(default = "default_num_train_timesteps")]
+    pub num_train_timesteps: usize,
+    #[serde(default = "default_base_image_seq_len")]
     pub base_image_seq_len: usize,
+    #[serde(default = "default_base_shift")]
     pub base_shift: f64,
+    #[serde(default = "default_max_image_seq_len")]
     pub max_image_seq_len: usize,
+    #[serde(default = "default_max_shift")]
     pub max_shift: f64,
+    /// Named `flow_shift` in the DPM-Solver config.
+    #[serde(default = "default_shift", alias = "flow_shift")]
     pub shift: f64,
+    #[serde(default)]
     pub use_dynamic_shifting: bool,
+    /// If set, the shifted sigmas are stretched so that the last one is this value.
+    #[serde(default)]
+    pub shift_terminal: Option<f64>,
+    #[serde(default)]
+    pub time_shift_type: TimeShiftType,
+    /// Use `1 - sigma`, ending at 1 instead of 0, for models trained with the reversed convention. This is only
+    /// supported by the Euler sampler, without an initial image.
+    #[serde(default)]
+    pub invert_sigmas: bool,
+    #[serde(default)]
+    pub use_karras_sigmas: bool,
+    #[serde(default)]
+    pub use_exponential_sigmas: bool,
+    #[serde(default)]
+    pub use_beta_sigmas: bool,
+    /// Renoise the data prediction to the next sigma with fresh noise at each step.
+    #[serde(default)]
+    pub stochastic_sampling: bool,
+    /// Whether `DPMSolverMultistepScheduler` uses the flow-matching sigmas. Only flow sigmas are supported.
+    #[serde(default)]
+    pub use_flow_sigmas: bool,
+    #[serde(default = "default_solver_order")]
+    pub solver_order: usize,
+}
+
+fn default_num_train_timesteps() -> usize {
+    1000
+}
+
+fn default_base_image_seq_len() -> usize {
+    256
+}
+
+fn default_base_shift() -> f64 {
+    0.5
+}
+
+fn default_max_image_seq_len() -> usize {
+    4096
+}
+
+fn default_max_shift() -> f64 {
+    1.15
+}
+
+fn default_shift() -> f64 {
+    1.0
+}
+
+fn default_solver_order() -> usize {
+    2
+}
+
+/// Python writes infinite and NaN values, such as the default `lambda_min_clipped` of the DPM-Solver schedulers,
+/// as bare `Infinity` and `NaN` which are not valid JSON. Replace them with null, leaving strings untouched.
+pub(crate) fn sanitize_python_json(json: &str) -> String {
+    const NON_FINITE: [&str; 3] = ["-Infinity", "Infinity", "NaN"];
+    let mut out = String::with_capacity(json.len());
+    let (mut in_string, mut escaped) = (false, false);
+    let mut i = 0;
+    while let Some(c) = json[i..].chars().next() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if let Some(value) = NON_FINITE
+            .iter()
+            .find(|value| json[i..].starts_with(*value))
+        {
+            out.push_str("null");
+            i += value.len();
+            continue;
+        }
+        out.push(c);
+        i += c.len_utf8();
+    }
+    out
 }
 
 #[derive(Deserialize, Clone)]
 pub enum SchedulerType {
     #[serde(rename = "FlowMatchEulerDiscreteScheduler")]
     FlowMatchEulerDiscrete,
+    #[serde(rename = "FlowMatchHeunDiscreteScheduler")]
+    FlowMatchHeunDiscrete,
+    /// DPM-Solver++ with `use_flow_sigmas`, as used by Sana.
+    #[serde(rename = "DPMSolverMultistepScheduler")]
+    DPMSolverMultistep,
+}
+
+/// The function used for dynamic shifting.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeShiftType {
+    /// `exp(mu) / (exp(mu) + (1 / t - 1))`
+    #[default]
+    Exponential,
+    /// `mu / (mu + (1 / t - 1))`
+    Linear,
+}
+
+/// The index of the first timestep to run for image-to-image generation, given the fraction of the schedule
+/// to run (`strength`).
+pub fn img2img_start_step(num_steps: usize, strength: f64) -> Result<usize> {
+    if !(0. ..=1.).contains(&strength) {
+        diffusion_rs_common::bail!("Strength must be between 0 and 1, got {strength}.");
+    }
+    let init_steps = (num_steps as f64 * strength).min(num_steps as f64);
+    let start = (num_steps as f64 - init_steps).max(0.) as usize;
+    if start >= num_steps {
+        diffusion_rs_common::bail!(
+            "Strength {strength} with {num_steps} steps leaves no denoising steps to run."
+        );
+    }
+    Ok(start)
+}
+
+/// Spacing of the sigmas between the largest and smallest shifted sigma.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SigmaSpacing {
+    /// Use the shifted sigmas as they are.
+    #[default]
+    Linear,
+    /// Karras et al. (2022) spacing with `rho = 7`, which concentrates the steps at low noise levels.
+    Karras,
+    /// Evenly spaced in log-sigma.
+    Exponential,
+    /// Quantiles of a Beta(0.6, 0.6) distribution, which concentrates the steps at both ends of the schedule.
+    Beta,
 }
 
-fn time_shift(mu: f64, sigma: f64, t: f64) -> f64 {
-    let e = mu.exp();
-    e / (e + (1. / t - 1.).powf(sigma))
+const KARRAS_RHO: f64 = 7.;
+const BETA_ALPHA: f64 = 0.6;
+const BETA_BETA: f64 = 0.6;
+
+impl SigmaSpacing {
+    /// The spacing selected by the `use_karras_sigmas`, `use_exponential_sigmas` and `use_beta_sigmas`
+    /// scheduler options.
+    pub(crate) fn from_flags(karras: bool, exponential: bool, beta: bool) -> Result<Self> {
+        match (karras, exponential, beta) {
+            (false, false, false) => Ok(Self::Linear),
+            (true, false, false) => Ok(Self::Karras),
+            (false, true, false) => Ok(Self::Exponential),
+            (false, false, true) => Ok(Self::Beta),
+            _ => diffusion_rs_common::bail!(
+                "Only one of `use_karras_sigmas`, `use_exponential_sigmas` and `use_beta_sigmas` can be set."
+            ),
+        }
+    }
+
+    /// Respace `sigmas`, sorted in decreasing order, over the same range.
+    pub(crate) fn apply(&self, sigmas: Vec<f64>) -> Vec<f64> {
+        let n = sigmas.len();
+        let (Some(&sigma_max), Some(&sigma_min)) = (sigmas.first(), sigmas.last()) else {
+            return sigmas;
+        };
+        let ramp = |i: usize| {
+            if n == 1 {
+                0.
+            } else {
+                i as f64 / (n - 1) as f64
+            }
+        };
+        match self {
+            Self::Linear => sigmas,
+            Self::Karras => {
+                let min_inv_rho = sigma_min.powf(1. / KARRAS_RHO);
+                let max_inv_rho = sigma_max.powf(1. / KARRAS_RHO);
+                (0..n)
+                    .map(|i| (max_inv_rho + ramp(i) * (min_inv_rho - max_inv_rho)).powf(KARRAS_RHO))
+                    .collect()
+            }
+            Self::Exponential => {
+                let (log_max, log_min) = (sigma_max.ln(), sigma_min.ln());
+                (0..n)
+                    .map(|i| (log_max + ramp(i) * (log_min - log_max)).exp())
+                    .collect()
+            }
+            Self::Beta => (0..n)
+                .map(|i| {
+                    let ppf = beta_ppf(1. - ramp(i), BETA_ALPHA, BETA_BETA);
+                    sigma_min + ppf * (sigma_max - sigma_min)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Natural log of the gamma function (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+    for (i, c) in COEFS.iter().enumerate() {
+        ser += c / (x + 1. + i as f64);
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+/// Continued fraction for the incomplete beta function (modified Lentz's method).
+fn beta_cf(x: f64, a: f64, b: f64) -> f64 {
+    const EPS: f64 = 1e-15;
+    const TINY: f64 = 1e-300;
+    let (qab, qap, qam) = (a + b, a + 1., a - 1.);
+    let mut c = 1.;
+    let mut d = 1. - qab * x / qap;
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1. / d;
+    let mut h = d;
+    for m in 1..=300 {
+        let m = m as f64;
+        let m2 = 2. * m;
+        for aa in [
+            m * (b - m) * x / ((qam + m2) * (a + m2)),
+            -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2)),
+        ] {
+            d = 1. + aa * d;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = 1. + aa / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1. / d;
+            h *= d * c;
+        }
+        if (d * c - 1.).abs() < EPS {
+            break;
+        }
+    }
+    h
+}
+
+/// Regularized incomplete beta function, the CDF of the Beta(a, b) distribution.
+fn beta_cdf(x: f64, a: f64, b: f64) -> f64 {
+    if x <= 0. {
+        return 0.;
+    }
+    if x >= 1. {
+        return 1.;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1. - x).ln()).exp();
+    if x < (a + 1.) / (a + b + 2.) {
+        front * beta_cf(x, a, b) / a
+    } else {
+        1. - front * beta_cf(1. - x, b, a) / b
+    }
+}
+
+/// Inverse of [`beta_cdf`], by bisection.
+fn beta_ppf(p: f64, a: f64, b: f64) -> f64 {
+    if p <= 0. {
+        return 0.;
+    }
+    if p >= 1. {
+        return 1.;
+    }
+    let (mut lo, mut hi) = (0f64, 1f64);
+    for _ in 0..100 {
+        let mid = 0.5 * (lo + hi);
+        if beta_cdf(mid, a, b) < p {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+impl TimeShiftType {
+    fn apply(&self, mu: f64, sigma: f64, t: f64) -> f64 {
+        let m = match self {
+            Self::Exponential => mu.exp(),
+            Self::Linear => mu,
+        };
+        m / (m + (1. / t - 1.).powf(sigma))
+    }
 }
 
 impl SchedulerConfig {
-    pub fn get_timesteps(&self, num_steps: usize, mu: Option<f64>) -> Result<Vec<f64>> {
-        let mut sigmas: Vec<f64> = (0..=num_steps)
-            .map(|v| v as f64 / num_steps as f64)
-            .rev()
-            .collect();
+    /// Parse a `scheduler_config.json`, reading infinite and NaN values as null.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(&sanitize_python_json(json))
+    }
+
+    /// Check that the schedule can be sampled with `sampler`, with or without an initial image.
+    ///
+    /// With `invert_sigmas` the sigmas rise to 1. Only the Euler method, whose step does not depend on the
+    /// direction of the schedule, supports this, and an initial image cannot be noised to the first sigma.
+    pub(crate) fn check_sampling(&self, sampler: SamplerType, init_image: bool) -> Result<()> {
+        if self.invert_sigmas {
+            if sampler != SamplerType::Euler {
+                diffusion_rs_common::bail!(
+                    "`invert_sigmas` schedules are only supported by the Euler sampler, got {sampler:?}."
+                );
+            }
+            if init_image {
+                diffusion_rs_common::bail!(
+                    "`invert_sigmas` schedules do not support image-to-image generation or inpainting."
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The sigma spacing selected by the `use_*_sigmas` options.
+    pub fn sigma_spacing(&self) -> Result<SigmaSpacing> {
+        SigmaSpacing::from_flags(
+            self.use_karras_sigmas,
+            self.use_exponential_sigmas,
+            self.use_beta_sigmas,
+        )
+    }
+
+    fn static_shift(&self, sigma: f64) -> f64 {
+        self.shift * sigma / (1. + (self.shift - 1.) * sigma)
+    }
+
+    /// Stretch the sigmas so that the last one is `shift_terminal`.
+    fn stretch_shift_to_terminal(&self, sigmas: Vec<f64>, shift_terminal: f64) -> Vec<f64> {
+        let Some(last) = sigmas.last() else {
+            return sigmas;
+        };
+        let scale_factor = (1. - last) / (1. - shift_terminal);
+        sigmas
+            .iter()
+            .map(|sigma| 1. - (1. - sigma) / scale_factor)
+            .collect()
+    }
+
+    /// Build the sigma schedule as `FlowMatchEulerDiscreteScheduler::set_timesteps`, ending with the terminal
+    /// sigma (0, or 1 with `invert_sigmas`). The model timesteps are the sigmas, without the
+    /// `num_train_timesteps` factor.
+    ///
+    /// The base sigmas are `sigmas` when given, in which case they set the number of steps. Otherwise they are
+    /// evenly spaced over the training schedule. They are shifted (with `mu` for dynamic shifting), stretched to
+    /// `shift_terminal`, then respaced with `spacing` over the shifted range. If `spacing` is not specified, it
+    /// comes from the config.
+    pub fn get_timesteps(
+        &self,
+        num_steps: usize,
+        mu: Option<f64>,
+        spacing: Option<SigmaSpacing>,
+        sigmas: Option<&[f64]>,
+    ) -> Result<Vec<f64>> {
+        let sigmas: Vec<f64> = match sigmas {
+            Some(sigmas) => {
+                if sigmas.is_empty() {
+                    diffusion_rs_common::bail!("The sigma list must not be empty.");
+                }
+                if let Some(sigma) = sigmas.iter().find(|s| !(**s > 0. && **s <= 1.)) {
+                    diffusion_rs_common::bail!(
+                        "Sigmas must be in (0, 1], got {sigma}. The terminal 0 is added automatically."
+                    );
+                }
+                if sigmas.windows(2).any(|w| w[1] >= w[0]) {
+                    diffusion_rs_common::bail!("Sigmas must be strictly decreasing.");
+                }
+                sigmas.to_vec()
+            }
+            None => {
+                if num_steps == 0 {
+                    diffusion_rs_common::bail!("The number of steps must be at least 1.");
+                }
+                let ramp = |i: usize, n: usize| {
+                    if n == 1 {
+                        0.
+                    } else {
+                        i as f64 / (n - 1) as f64
+                    }
+                };
+                match self.scheduler_type {
+                    SchedulerType::FlowMatchEulerDiscrete
+                    | SchedulerType::FlowMatchHeunDiscrete => {
+                        // The training sigmas go from 1 to `1 / num_train_timesteps`, statically shifted when
+                        // dynamic shifting is off.
+                        let sigma_max = 1.;
+                        let mut sigma_min = 1. / self.num_train_timesteps as f64;
+                        if !self.use_dynamic_shifting {
+                            sigma_min = self.static_shift(sigma_min);
+                        }
+                        (0..num_steps)
+                            .map(|i| sigma_max + ramp(i, num_steps) * (sigma_min - sigma_max))
+                            .collect()
+                    }
+                    SchedulerType::DPMSolverMultistep => {
+                        if !self.use_flow_sigmas {
+                            diffusion_rs_common::bail!(
+                                "`DPMSolverMultistepScheduler` is only supported with `use_flow_sigmas`."
+                            );
+                        }
+                        // `1 - alphas` with the alphas evenly spaced from 1 to `1 / num_train_timesteps` over
+                        // `num_steps + 1` values, in decreasing order and without the final 0.
+                        let sigma_max = 1. - 1. / self.num_train_timesteps as f64;
+                        (0..num_steps)
+                            .map(|i| sigma_max * (1. - ramp(i, num_steps + 1)))
+                            .collect()
+                    }
+                }
+            }
+        };
+        let spacing = match spacing {
+            Some(spacing) => spacing,
+            None => self.sigma_spacing()?,
+        };
         match self.scheduler_type {
-            SchedulerType::FlowMatchEulerDiscrete => {
-                if self.use_dynamic_shifting {
+            SchedulerType::FlowMatchEulerDiscrete
+            | SchedulerType::FlowMatchHeunDiscrete
+            | SchedulerType::DPMSolverMultistep => {
+                let mut sigmas = if self.use_dynamic_shifting {
                     let mu = mu.context("`mu` is required for dynamic shifting")?;
-                    sigmas = sigmas
+                    sigmas
                         .iter()
-                        .map(|sigma| time_shift(mu, 1., *sigma))
-                        .collect();
+                        .map(|sigma| self.time_shift_type.apply(mu, 1., *sigma))
+                        .collect()
                 } else {
-                    sigmas = sigmas
+                    sigmas
                         .iter()
-                        .map(|sigma| self.shift * sigma / (1. + (self.shift - 1.) * sigma))
-                        .collect();
+                        .map(|sigma| self.static_shift(*sigma))
+                        .collect()
+                };
+                if let Some(shift_terminal) = self.shift_terminal.filter(|x| *x != 0.) {
+                    sigmas = self.stretch_shift_to_terminal(sigmas, shift_terminal);
                 }
 
+                let mut sigmas = spacing.apply(sigmas);
+                if self.invert_sigmas {
+                    sigmas.iter_mut().for_each(|sigma| *sigma = 1. - *sigma);
+                    sigmas.push(1.);
+                } else {
+                    sigmas.push(0.);
+                }
                 Ok(sigmas)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{beta_ppf, sanitize_python_json, SchedulerConfig, SigmaSpacing};
+    use crate::pipelines::sampling::SamplerType;
+
+    fn assert_close(actual: &[f64], expected: &[f64], tol: f64) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?} != {expected:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tol, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn beta_ppf_matches_scipy() {
+        // `scipy.stats.beta.ppf(p, a, b)`
+        for (p, a, b, expected) in [
+            (0.001, 0.6, 0.6, 1.8558738517452004e-05),
+            (0.05, 0.6, 0.6, 0.012554456873611407),
+            (0.25, 0.6, 0.6, 0.175680378652695),
+            (0.5, 0.6, 0.6, 0.5),
+            (0.75, 0.6, 0.6, 0.824319621347305),
+            (0.9, 0.6, 0.6, 0.9604155944112192),
+            (0.999, 0.6, 0.6, 0.9999814412614826),
+            (0.1, 2., 5., 0.09259525891312875),
+            (0.7, 2., 5., 0.36035769038002025),
+        ] {
+            let actual = beta_ppf(p, a, b);
+            assert!(
+                (actual - expected).abs() < 1e-8,
+                "ppf({p}, {a}, {b}) = {actual}, expected {expected}"
+            );
+        }
+        // The arcsine distribution, whose quantiles are `sin(pi p / 2)^2`.
+        for p in [0.1, 0.3, 0.6, 0.95] {
+            let expected = (std::f64::consts::FRAC_PI_2 * p).sin().powi(2);
+            assert!((beta_ppf(p, 0.5, 0.5) - expected).abs() < 1e-8);
+        }
+        assert_eq!(beta_ppf(0., 0.6, 0.6), 0.);
+        assert_eq!(beta_ppf(1., 0.6, 0.6), 1.);
+    }
+
+    #[test]
+    fn spacings_match_k_diffusion() {
+        let sigmas = vec![1., 0.8, 0.5, 0.3, 0.1];
+        assert_eq!(SigmaSpacing::Linear.apply(sigmas.clone()), sigmas);
+        // `get_sigmas_karras(5, 0.1, 1.)` without the final 0.
+        assert_close(
+            &SigmaSpacing::Karras.apply(sigmas.clone()),
+            &[
+                1.,
+                0.6013450694649575,
+                0.3474829837397731,
+                0.19163830181456254,
+                0.1,
+            ],
+            1e-12,
+        );
+        // `get_sigmas_exponential(5, 0.1, 1.)` without the final 0.
+        assert_close(
+            &SigmaSpacing::Exponential.apply(sigmas.clone()),
+            &[
+                1.,
+                0.5623413251903491,
+                0.31622776601683794,
+                0.1778279410038923,
+                0.1,
+            ],
+            1e-12,
+        );
+        // `_convert_to_beta` in diffusers, with `alpha = beta = 0.6`.
+        assert_close(
+            &SigmaSpacing::Beta.apply(sigmas.clone()),
+            &[1., 0.8418876592125745, 0.55, 0.2581123407874255, 0.1],
+            1e-8,
+        );
+        for spacing in [
+            SigmaSpacing::Karras,
+            SigmaSpacing::Exponential,
+            SigmaSpacing::Beta,
+        ] {
+            assert_close(&spacing.apply(vec![0.5]), &[0.5], 1e-12);
+            assert!(spacing.apply(vec![]).is_empty());
+        }
+    }
+
+    #[test]
+    fn spacing_flags() {
+        assert_eq!(
+            SigmaSpacing::from_flags(false, false, false).unwrap(),
+            SigmaSpacing::Linear
+        );
+        assert_eq!(
+            SigmaSpacing::from_flags(true, false, false).unwrap(),
+            SigmaSpacing::Karras
+        );
+        assert_eq!(
+            SigmaSpacing::from_flags(false, true, false).unwrap(),
+            SigmaSpacing::Exponential
+        );
+        assert_eq!(
+            SigmaSpacing::from_flags(false, false, true).unwrap(),
+            SigmaSpacing::Beta
+        );
+        assert!(SigmaSpacing::from_flags(true, false, true).is_err());
+    }
+
+    #[test]
+    fn custom_sigmas() {
+        let config = SchedulerConfig::from_json(
+            r#"{"_class_name": "FlowMatchEulerDiscreteScheduler", "shift": 3.0}"#,
+        )
+        .unwrap();
+        // The custom sigmas set the number of steps and are shifted.
+        let timesteps = config
+            .get_timesteps(10, None, None, Some(&[1., 0.5, 0.2]))
+            .unwrap();
+        assert_close(&timesteps, &[1., 0.75, 0.6 / 1.4, 0.], 1e-12);
+
+        for sigmas in [
+            &[][..],
+            &[1., 0.][..],
+            &[1.5, 0.5][..],
+            &[1., -0.5][..],
+            &[f64::NAN][..],
+        ] {
+            assert!(
+                config.get_timesteps(10, None, None, Some(sigmas)).is_err(),
+                "{sigmas:?}"
+            );
+        }
+        for sigmas in [&[0.5, 0.5][..], &[0.2, 0.5][..]] {
+            assert!(
+                config.get_timesteps(10, None, None, Some(sigmas)).is_err(),
+                "{sigmas:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn timesteps_match_diffusers() {
+        let flux_sigmas =
+            |n: usize| -> Vec<f64> { (1..=n).rev().map(|i| i as f64 / n as f64).collect() };
+        // `FlowMatchEulerDiscreteScheduler.from_config(config).set_timesteps(num_steps, sigmas=sigmas, mu=mu)`,
+        // which computes in float32.
+        let cases = [
+            ("{}", 4, None, None, vec![1., 0.667, 0.334, 0.001, 0.]),
+            (
+                r#"{"shift": 3.0}"#,
+                5,
+                None,
+                None,
+                vec![
+                    1.,
+                    0.900359066427289,
+                    0.7511210762331838,
+                    0.5029850746268658,
+                    0.008928571428571378,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"use_dynamic_shifting": true}"#,
+                4,
+                Some(1.15),
+                Some(flux_sigmas(4)),
+                vec![
+                    1.,
+                    0.9045307667386396,
+                    0.7595109169491111,
+                    0.5128441015091338,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"use_dynamic_shifting": true, "time_shift_type": "linear"}"#,
+                4,
+                Some(0.8),
+                Some(flux_sigmas(4)),
+                vec![
+                    1.,
+                    0.7058823529411765,
+                    0.4444444444444445,
+                    0.2105263157894737,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"use_dynamic_shifting": true, "shift_terminal": 0.1}"#,
+                5,
+                Some(2.05),
+                Some(flux_sigmas(5)),
+                vec![
+                    1.,
+                    0.9174416810018926,
+                    0.7907201396137771,
+                    0.5714599694340898,
+                    0.1,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"shift": 3.0, "use_karras_sigmas": true}"#,
+                5,
+                None,
+                None,
+                vec![
+                    1.,
+                    0.4003215529203492,
+                    0.13959911614280726,
+                    0.04037270690617308,
+                    0.008928571428571378,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"shift": 3.0, "use_exponential_sigmas": true}"#,
+                5,
+                None,
+                None,
+                vec![
+                    1.,
+                    0.3073940764756317,
+                    0.09449111825230654,
+                    0.029046010030317475,
+                    0.008928571428571376,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"shift": 3.0, "use_beta_sigmas": true}"#,
+                5,
+                None,
+                None,
+                vec![
+                    1.,
+                    0.8258881961567041,
+                    0.5044642857142857,
+                    0.1830403752718673,
+                    0.008928571428571378,
+                    0.,
+                ],
+            ),
+            (
+                r#"{"shift": 3.0, "invert_sigmas": true}"#,
+                4,
+                None,
+                None,
+                vec![
+                    0.,
+                    0.14230769230769247,
+                    0.3978494623655916,
+                    0.9910714285714283,
+                    1.,
+                ],
+            ),
+        ];
+        for (config, num_steps, mu, sigmas, expected) in cases {
+            let json = config.replacen(
+                '{',
+                r#"{"_class_name": "FlowMatchEulerDiscreteScheduler", "#,
+                1,
+            );
+            let json = json.replace(", }", "}");
+            let config = SchedulerConfig::from_json(&json).unwrap();
+            let timesteps = config
+                .get_timesteps(num_steps, mu, None, sigmas.as_deref())
+                .unwrap();
+            assert_close(&timesteps, &expected, 1e-6);
+        }
+    }
+
+    #[test]
+    fn inverted_sigmas_are_checked() {
+        let json = r#"{"_class_name": "FlowMatchEulerDiscreteScheduler", "invert_sigmas": true}"#;
+        let config = SchedulerConfig::from_json(json).unwrap();
+        assert!(config.check_sampling(SamplerType::Euler, false).is_ok());
+        assert!(config.check_sampling(SamplerType::Euler, true).is_err());
+        assert!(config.check_sampling(SamplerType::Heun, false).is_err());
+        assert!(config
+            .check_sampling(SamplerType::DpmPlusPlus2M, false)
+            .is_err());
+
+        let config =
+            SchedulerConfig::from_json(r#"{"_class_name": "FlowMatchEulerDiscreteScheduler"}"#)
+                .unwrap();
+        assert!(config.check_sampling(SamplerType::Heun, true).is_ok());
+    }
+
+    #[test]
+    fn python_json_is_sanitized() {
+        assert_eq!(
+            sanitize_python_json(
+                r#"{"a": -Infinity, "b":Infinity, "c": [NaN, 1.0], "d": "NaN \" Infinity"}"#
+            ),
+            r#"{"a": null, "b":null, "c": [null, 1.0], "d": "NaN \" Infinity"}"#
+        );
+    }
+}
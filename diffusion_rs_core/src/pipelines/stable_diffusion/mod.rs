@@ -0,0 +1,570 @@
+mod xl;
+
+use std::borrow::Cow;
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor};
+use serde::Deserialize;
+use tokenizers::{AddedToken, Tokenizer};
+use tracing::{info, warn};
+
+use crate::{
+    models::{
+        dispatch_load_vae_model, ClipTextConfig, ClipTextModelWithProjection, ClipTextTransformer,
+        UNet2DConditionConfig, UNet2DConditionModel, VAEModel,
+    },
+    pipelines::ComponentName,
+};
+use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+
+use super::discrete_scheduler::{DiscreteSchedule, DiscreteSchedulerConfig};
+use super::noise::NoiseGenerator;
+use super::sampling::{sample_model, InpaintMask};
+use super::scheduler::img2img_start_step;
+use super::textual_inversion::TextualInversion;
+use super::{
+    image_to_tensor, mask_to_tensor, ComponentElem, DiffusionGenerationParams, Loader,
+    ModelPipeline, Offloading,
+};
+
+pub(crate) use xl::StableDiffusionXLLoader;
+
+/// The context length of the CLIP text encoder.
+const CLIP_MAX_TOKENS: usize = 77;
+const CLIP_BOS_TOKEN: &str = "<|startoftext|>";
+const CLIP_EOS_TOKEN: &str = "<|endoftext|>";
+
+/// Loader for Stable Diffusion 1.5 and 2.1 models.
+///
+/// The scheduler class only selects the default sampler, see [`DiscreteSchedulerConfig::default_sampler`].
+pub struct StableDiffusionLoader;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    Object { content: String },
+}
+
+#[derive(Deserialize)]
+struct SpecialTokensMap {
+    pad_token: Option<SpecialToken>,
+}
+
+/// A CLIP tokenizer, with its special tokens.
+#[derive(Clone)]
+pub(crate) struct ClipTokenizer {
+    tokenizer: Tokenizer,
+    bos: u32,
+    eos: u32,
+    pad: u32,
+    /// The placeholder tokens of the textual inversions, by id, with the ids of their vectors.
+    textual_inversions: HashMap<u32, Vec<u32>>,
+}
+
+/// A CLIP text encoder, whose token embeddings can be extended with textual inversions.
+pub(crate) trait ClipTextEncoder: Clone {
+    fn text_model_mut(&mut self) -> &mut ClipTextTransformer;
+}
+
+impl ClipTextEncoder for ClipTextTransformer {
+    fn text_model_mut(&mut self) -> &mut ClipTextTransformer {
+        self
+    }
+}
+
+impl ClipTextEncoder for ClipTextModelWithProjection {
+    fn text_model_mut(&mut self) -> &mut ClipTextTransformer {
+        self.text_model_mut()
+    }
+}
+
+/// The tokenizer and text encoder with the textual inversions of a request added, if any. They are copies, and
+/// the weights of the text encoder are shared.
+pub(crate) fn with_textual_inversions<'a, M: ClipTextEncoder>(
+    tokenizer: &'a ClipTokenizer,
+    model: &'a M,
+    textual_inversions: &[TextualInversion],
+) -> diffusion_rs_common::core::Result<(Cow<'a, ClipTokenizer>, Cow<'a, M>)> {
+    if textual_inversions.is_empty() {
+        return Ok((Cow::Borrowed(tokenizer), Cow::Borrowed(model)));
+    }
+    let mut tokenizer = tokenizer.clone();
+    let mut model = model.clone();
+    for textual_inversion in textual_inversions {
+        tokenizer.add_textual_inversion(&mut model, textual_inversion)?;
+    }
+    Ok((Cow::Owned(tokenizer), Cow::Owned(model)))
+}
+
+impl ClipTokenizer {
+    /// Load the tokenizer component in `dir`, from its `tokenizer.json` if present, and otherwise from its
+    /// `vocab.json` and `merges.txt`. The padding token is `<|endoftext|>` for SD 1.x and `!` for SD 2.x, as given
+    /// by its `special_tokens_map.json`.
+    pub(crate) fn load(elem: ComponentElem, dir: &str, source: &ModelSource) -> Result<Self> {
+        // A tokenizer with a `tokenizer.json` and no `merges.txt` only has JSON files.
+        let (ComponentElem::Other { files } | ComponentElem::Config { files }) = elem else {
+            anyhow::bail!("incorrect storage of clip tokenizer")
+        };
+        let tokenizer = match files.get(&format!("{dir}/tokenizer.json")) {
+            Some(file) => {
+                let mut tokenizer = Tokenizer::from_bytes(file.read_to_string(source)?)
+                    .map_err(anyhow::Error::msg)?;
+                // Truncation and padding are done in `tokenize`, after expanding the textual inversions.
+                tokenizer
+                    .with_truncation(None)
+                    .map_err(anyhow::Error::msg)?
+                    .with_padding(None);
+                tokenizer
+            }
+            None => {
+                let vocab_file = &files[&format!("{dir}/vocab.json")];
+                let merges_file = &files[&format!("{dir}/merges.txt")];
+                diffusion_rs_common::load_clip_tokenizer(vocab_file, merges_file, source)?
+            }
+        };
+
+        let pad_token = match files.get(&format!("{dir}/special_tokens_map.json")) {
+            Some(file) => {
+                let map: SpecialTokensMap = serde_json::from_str(&file.read_to_string(source)?)?;
+                match map.pad_token {
+                    Some(SpecialToken::Content(content) | SpecialToken::Object { content }) => {
+                        content
+                    }
+                    None => CLIP_EOS_TOKEN.to_string(),
+                }
+            }
+            None => CLIP_EOS_TOKEN.to_string(),
+        };
+        let token_id = |token: &str| {
+            tokenizer
+                .token_to_id(token)
+                .ok_or_else(|| anyhow::anyhow!("CLIP tokenizer has no `{token}` token"))
+        };
+        Ok(Self {
+            bos: token_id(CLIP_BOS_TOKEN)?,
+            eos: token_id(CLIP_EOS_TOKEN)?,
+            pad: token_id(&pad_token)?,
+            tokenizer,
+            textual_inversions: HashMap::new(),
+        })
+    }
+
+    /// Add a textual inversion: its vectors are appended to the token embeddings of `model`, and its placeholder
+    /// token is added to the vocabulary and replaced by their ids.
+    pub(crate) fn add_textual_inversion(
+        &mut self,
+        model: &mut impl ClipTextEncoder,
+        textual_inversion: &TextualInversion,
+    ) -> diffusion_rs_common::core::Result<()> {
+        let token = &textual_inversion.token;
+        if self.tokenizer.token_to_id(token).is_some() {
+            diffusion_rs_common::bail!("The token `{token}` is already in the CLIP vocabulary.");
+        }
+        let model = model.text_model_mut();
+        let vectors = textual_inversion.vectors(model.hidden_size())?;
+        let num_vectors = vectors.dim(0)? as u32;
+        let first = model.add_token_embeddings(&vectors)?;
+        self.tokenizer.add_tokens(&[AddedToken::from(token, false)]);
+        let Some(id) = self.tokenizer.token_to_id(token) else {
+            diffusion_rs_common::bail!("Could not add the token `{token}` to the CLIP vocabulary.");
+        };
+        self.textual_inversions
+            .insert(id, (first..first + num_vectors).collect());
+        Ok(())
+    }
+
+    /// Tokenize the prompts to the CLIP context length: the start token, the prompt tokens (truncated if
+    /// necessary), the end token, then padding.
+    pub(crate) fn tokenize(
+        &self,
+        prompts: Vec<String>,
+        device: &Device,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(prompts, false)
+            .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?;
+        let input_ids = encodings
+            .iter()
+            .map(|encoding| {
+                let ids = encoding
+                    .get_ids()
+                    .iter()
+                    .flat_map(|id| match self.textual_inversions.get(id) {
+                        Some(vectors) => vectors.as_slice(),
+                        None => std::slice::from_ref(id),
+                    })
+                    .copied()
+                    .collect::<Vec<_>>();
+                let ids = &ids[..ids.len().min(CLIP_MAX_TOKENS - 2)];
+                let mut tokens = Vec::with_capacity(CLIP_MAX_TOKENS);
+                tokens.push(self.bos);
+                tokens.extend_from_slice(ids);
+                tokens.push(self.eos);
+                tokens.resize(CLIP_MAX_TOKENS, self.pad);
+                tokens
+            })
+            .collect::<Vec<_>>();
+        Tensor::new(input_ids, device)
+    }
+}
+
+/// Prepare the starting latents from the `noise`: pure noise scaled by `noise_scale`, or for image-to-image
+/// generation, the latents of the initial image noised to the first remaining timestep, with the inpainting mask
+/// if any. The skipped timesteps are removed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn initial_latents(
+    vae: &dyn VAEModel,
+    vae_dtype: DType,
+    params: &DiffusionGenerationParams,
+    noise: Tensor,
+    noise_scale: f64,
+    timesteps: &mut Vec<f64>,
+    bs: usize,
+    height: usize,
+    width: usize,
+) -> diffusion_rs_common::core::Result<(Tensor, Option<InpaintMask>)> {
+    let Some(init_image) = &params.init_image else {
+        return Ok(((&noise * noise_scale)?, None));
+    };
+    let start = img2img_start_step(timesteps.len() - 1, init_image.strength_or_default())?;
+    timesteps.drain(..start);
+    let sigma = timesteps[0];
+
+    let xs = image_to_tensor(&init_image.image, height, width, vae_dtype, noise.device())?;
+    let latents = ((vae.encode(&xs)? - vae.shift_factor())? * vae.scale_factor())?
+        .to_dtype(noise.dtype())?
+        .repeat((bs, 1, 1, 1))?;
+    let img = ((&noise * sigma)? + (&latents * (1. - sigma))?)?;
+    let inpaint = match &init_image.mask {
+        Some(mask) => {
+            let (b, c, h, w) = latents.dims4()?;
+            let mask = mask_to_tensor(mask, h, w, latents.dtype(), latents.device())?
+                .repeat((b, c, 1, 1))?;
+            Some(InpaintMask {
+                mask,
+                image_latents: latents,
+                noise,
+            })
+        }
+        None => None,
+    };
+    Ok((img, inpaint))
+}
+
+/// Decode the latents into an image with values in 0..=255.
+pub(crate) fn decode_latents(
+    vae: &dyn VAEModel,
+    vae_dtype: DType,
+    img: &Tensor,
+) -> diffusion_rs_common::core::Result<Tensor> {
+    let img = ((img.to_dtype(vae_dtype)? / vae.scale_factor())? + vae.shift_factor())?;
+    let img = vae.decode(&img)?;
+    ((img.clamp(-1f32, 1f32)? + 1.0)? * 127.5)?.to_dtype(DType::U8)
+}
+
+/// Tokenize the prompts for T5, truncated to `max_tokens`. As with the tokenizer's own truncation, a truncated
+/// prompt still ends with the `</s>` token.
+pub(crate) fn t5_tokenize(
+    tokenizer: &Tokenizer,
+    prompts: Vec<String>,
+    max_tokens: usize,
+) -> diffusion_rs_common::core::Result<Vec<Vec<u32>>> {
+    Ok(tokenizer
+        .encode_batch(prompts, true)
+        .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?
+        .into_iter()
+        .map(|encoding| {
+            let mut ids = encoding.get_ids().to_vec();
+            if ids.len() > max_tokens {
+                let eos = ids[ids.len() - 1];
+                ids.truncate(max_tokens - 1);
+                ids.push(eos);
+            }
+            ids
+        })
+        .collect())
+}
+
+/// Check the generation parameters which only the FLUX pipeline supports.
+pub(crate) fn check_params(
+    params: &DiffusionGenerationParams,
+) -> diffusion_rs_common::core::Result<()> {
+    if params.loras.is_some() {
+        diffusion_rs_common::bail!("This pipeline does not support LoRA adapters.");
+    }
+    if !params.control_images.is_empty() || params.control_image.is_some() {
+        diffusion_rs_common::bail!("This pipeline does not support control images.");
+    }
+    if params.ip_adapters.is_some() || !params.image_prompts.is_empty() {
+        diffusion_rs_common::bail!("This pipeline does not support IP-Adapter image prompts.");
+    }
+    if params.reference_image.is_some() {
+        diffusion_rs_common::bail!("This pipeline does not support reference images.");
+    }
+    Ok(())
+}
+
+/// Check the generation parameters which discrete-time pipelines don't support.
+pub(crate) fn check_discrete_params(
+    params: &DiffusionGenerationParams,
+) -> diffusion_rs_common::core::Result<()> {
+    if params.sigmas.is_some() {
+        diffusion_rs_common::bail!(
+            "Custom sigma schedules are only supported for flow-matching models."
+        );
+    }
+    check_params(params)
+}
+
+impl Loader for StableDiffusionLoader {
+    fn name(&self) -> &'static str {
+        "stable-diffusion"
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        vec![
+            ComponentName::Scheduler,
+            ComponentName::TextEncoder(1),
+            ComponentName::Tokenizer(1),
+            ComponentName::Unet,
+            ComponentName::Vae,
+        ]
+    }
+
+    fn load_from_components(
+        &self,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        device: &Device,
+        dtype: DType,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
+        let clip_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
+        let clip_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
+        let unet_component = components.remove(&ComponentName::Unet).unwrap();
+        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+
+        if offloading_type.is_some() {
+            warn!("offloading is not supported for Stable Diffusion models, ignoring it");
+        }
+
+        let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            DiscreteSchedulerConfig::from_json(
+                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+            )?
+        } else {
+            anyhow::bail!("expected scheduler config")
+        };
+        let schedule = scheduler_config.schedule()?;
+
+        let clip_tokenizer = ClipTokenizer::load(clip_tok_component, "tokenizer", &source)?;
+
+        if !silent {
+            info!("loading CLIP model");
+        }
+        let clip_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = clip_component
+        {
+            let cfg: ClipTextConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source.clone(),
+            )?;
+            ClipTextTransformer::new(vb.pp("text_model"), &cfg)?
+        } else {
+            anyhow::bail!("incorrect storage of clip model")
+        };
+        if !silent {
+            info!("loading VAE model");
+        }
+        let vae_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = vae_component
+        {
+            dispatch_load_vae_model(
+                &config,
+                safetensors.into_values().collect(),
+                device,
+                dtype,
+                silent,
+                source.clone(),
+            )?
+        } else {
+            anyhow::bail!("incorrect storage of vae model")
+        };
+        if !silent {
+            info!("loading UNet model");
+        }
+        let (unet_model, latent_channels) =
+            load_unet(unet_component, device, dtype, silent, source)?;
+
+        if !silent {
+            info!(
+                "Stable Diffusion pipeline using {:?} prediction",
+                scheduler_config.prediction_type
+            );
+        }
+
+        let pipeline = StableDiffusionPipeline {
+            clip_tokenizer,
+            clip_model,
+            vae_model,
+            unet_model,
+            latent_channels,
+            scheduler_config,
+            schedule,
+            dtype,
+            device: device.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+}
+
+/// Load a UNet component, returning it with its number of latent channels.
+fn load_unet(
+    elem: ComponentElem,
+    device: &Device,
+    dtype: DType,
+    silent: bool,
+    source: Arc<ModelSource>,
+) -> Result<(UNet2DConditionModel, usize)> {
+    let ComponentElem::Model {
+        safetensors,
+        config,
+    } = elem
+    else {
+        anyhow::bail!("incorrect storage of unet model")
+    };
+    let cfg: UNet2DConditionConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+    if cfg.in_channels != cfg.out_channels {
+        anyhow::bail!(
+            "unsupported UNet with {} input and {} output channels",
+            cfg.in_channels,
+            cfg.out_channels
+        );
+    }
+    let vb = from_mmaped_safetensors(
+        safetensors.into_values().collect(),
+        Some(dtype),
+        device,
+        silent,
+        source,
+    )?;
+    Ok((UNet2DConditionModel::new(&cfg, vb)?, cfg.out_channels))
+}
+
+pub struct StableDiffusionPipeline {
+    clip_tokenizer: ClipTokenizer,
+    clip_model: ClipTextTransformer,
+    vae_model: Arc<dyn VAEModel>,
+    unet_model: UNet2DConditionModel,
+    latent_channels: usize,
+    scheduler_config: DiscreteSchedulerConfig,
+    schedule: DiscreteSchedule,
+    dtype: DType,
+    device: Device,
+}
+
+impl ModelPipeline for StableDiffusionPipeline {
+    fn forward(
+        &mut self,
+        prompts: Vec<String>,
+        params: DiffusionGenerationParams,
+        _offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        check_discrete_params(&params)?;
+
+        // Classifier-free guidance: the negative prompts are encoded and run in the same batch.
+        let bs = prompts.len();
+        let cfg = params.guidance_scale > 1.;
+        let mut all_prompts = prompts;
+        if cfg {
+            let negative_prompt = params.negative_prompt.clone().unwrap_or_default();
+            all_prompts.extend(vec![negative_prompt; bs]);
+        }
+        let (clip_tokenizer, clip_model) = with_textual_inversions(
+            &self.clip_tokenizer,
+            &self.clip_model,
+            &params.textual_inversions,
+        )?;
+        let input_ids = clip_tokenizer.tokenize(all_prompts, clip_model.device())?;
+        let context = clip_model
+            .forward_with_mask(&input_ids, usize::MAX)?
+            .to_device(&self.device)?;
+
+        let height = params.height.div_ceil(8) * 8;
+        let width = params.width.div_ceil(8) * 8;
+        let mut rng = NoiseGenerator::new(&params, bs)?;
+        let noise = rng.randn(
+            &[self.latent_channels, height / 8, width / 8],
+            self.dtype,
+            &self.device,
+        )?;
+
+        let mut timesteps = self.scheduler_config.get_timesteps(
+            &self.schedule,
+            params.num_steps,
+            params.sigma_spacing,
+        )?;
+        let (img, inpaint) = initial_latents(
+            self.vae_model.as_ref(),
+            self.dtype,
+            &params,
+            noise,
+            DiscreteSchedule::noise_scale(timesteps[0]),
+            &mut timesteps,
+            bs,
+            height,
+            width,
+        )?;
+
+        let model = |x: &Tensor, s: f64| -> diffusion_rs_common::core::Result<Tensor> {
+            let (t, scale) = self.schedule.model_input(s);
+            let x_in = (x * scale)?;
+            let x_in = if cfg {
+                Tensor::cat(&[&x_in, &x_in], 0)?
+            } else {
+                x_in
+            };
+            let t = Tensor::full(t as f32, x_in.dim(0)?, &self.device)?;
+            let pred = self.unet_model.forward(&x_in, &t, &context, None)?;
+            let pred = if cfg {
+                let negative_pred = pred.narrow(0, bs, bs)?;
+                let pred = pred.narrow(0, 0, bs)?;
+                (&negative_pred + ((pred - &negative_pred)? * params.guidance_scale)?)?
+            } else {
+                pred
+            };
+            self.schedule.velocity(x, &pred, s)
+        };
+
+        let mut sampler = params
+            .sampler
+            .map_or_else(|| self.scheduler_config.default_sampler(), Ok)?
+            .sampler(params.eta, rng)?;
+        let img = sample_model(sampler.as_mut(), &timesteps, &img, &model, inpaint.as_ref())?;
+
+        decode_latents(self.vae_model.as_ref(), self.dtype, &img)
+    }
+
+    fn load_textual_inversion(
+        &mut self,
+        textual_inversion: &TextualInversion,
+    ) -> diffusion_rs_common::core::Result<()> {
+        self.clip_tokenizer
+            .add_textual_inversion(&mut self.clip_model, textual_inversion)
+    }
+}
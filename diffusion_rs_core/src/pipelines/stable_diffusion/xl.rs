@@ -0,0 +1,454 @@
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor, D};
+use tracing::{info, warn};
+
+use crate::{
+    models::{
+        dispatch_load_vae_model, AddedCondition, ClipTextConfig, ClipTextModelWithProjection,
+        ClipTextTransformer, UNet2DConditionModel, VAEModel,
+    },
+    pipelines::ComponentName,
+};
+use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+
+use super::super::discrete_scheduler::{DiscreteSchedule, DiscreteSchedulerConfig};
+use super::super::noise::NoiseGenerator;
+use super::super::sampling::{sample_model, InpaintMask};
+use super::super::textual_inversion::TextualInversion;
+use super::super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+use super::{
+    check_discrete_params, decode_latents, initial_latents, load_unet, with_textual_inversions,
+    ClipTokenizer,
+};
+
+/// Aesthetic scores of the refiner conditioning, for the prompt and the negative prompt.
+const AESTHETIC_SCORE: f64 = 6.0;
+const NEGATIVE_AESTHETIC_SCORE: f64 = 2.5;
+
+/// Loader for SDXL models. The refiner only has the second (OpenCLIP bigG) text encoder.
+///
+/// The scheduler class only selects the default sampler, see [`DiscreteSchedulerConfig::default_sampler`].
+pub struct StableDiffusionXLLoader {
+    pub refiner: bool,
+}
+
+fn load_clip_config(elem: &ComponentElem, source: &ModelSource) -> Result<ClipTextConfig> {
+    let ComponentElem::Model { config, .. } = elem else {
+        anyhow::bail!("incorrect storage of clip model")
+    };
+    Ok(serde_json::from_str(&config.read_to_string(source)?)?)
+}
+
+impl Loader for StableDiffusionXLLoader {
+    fn name(&self) -> &'static str {
+        if self.refiner {
+            "stable-diffusion-xl-refiner"
+        } else {
+            "stable-diffusion-xl"
+        }
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        let mut names = vec![
+            ComponentName::Scheduler,
+            ComponentName::TextEncoder(2),
+            ComponentName::Tokenizer(2),
+            ComponentName::Unet,
+            ComponentName::Vae,
+        ];
+        if !self.refiner {
+            names.extend([ComponentName::TextEncoder(1), ComponentName::Tokenizer(1)]);
+        }
+        names
+    }
+
+    fn load_from_components(
+        &self,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        device: &Device,
+        dtype: DType,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
+        let clip2_component = components.remove(&ComponentName::TextEncoder(2)).unwrap();
+        let clip2_tok_component = components.remove(&ComponentName::Tokenizer(2)).unwrap();
+        let unet_component = components.remove(&ComponentName::Unet).unwrap();
+        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+
+        if offloading_type.is_some() {
+            warn!("offloading is not supported for Stable Diffusion models, ignoring it");
+        }
+
+        let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            DiscreteSchedulerConfig::from_json(
+                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+            )?
+        } else {
+            anyhow::bail!("expected scheduler config")
+        };
+        let schedule = scheduler_config.schedule()?;
+
+        let clip1 = if self.refiner {
+            None
+        } else {
+            let clip_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
+            let clip_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
+            let tokenizer = ClipTokenizer::load(clip_tok_component, "tokenizer", &source)?;
+            if !silent {
+                info!("loading CLIP model");
+            }
+            let cfg = load_clip_config(&clip_component, &source)?;
+            let ComponentElem::Model { safetensors, .. } = clip_component else {
+                unreachable!()
+            };
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source.clone(),
+            )?;
+            Some((
+                tokenizer,
+                ClipTextTransformer::new(vb.pp("text_model"), &cfg)?,
+            ))
+        };
+
+        let clip2_tokenizer = ClipTokenizer::load(clip2_tok_component, "tokenizer_2", &source)?;
+        if !silent {
+            info!("loading OpenCLIP bigG model");
+        }
+        let clip2_model = {
+            let cfg = load_clip_config(&clip2_component, &source)?;
+            let ComponentElem::Model { safetensors, .. } = clip2_component else {
+                unreachable!()
+            };
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                device,
+                silent,
+                source.clone(),
+            )?;
+            ClipTextModelWithProjection::new(vb, &cfg)?
+        };
+
+        if !silent {
+            info!("loading VAE model");
+        }
+        // The SDXL VAE overflows in float16.
+        let vae_dtype = if dtype == DType::F16 {
+            DType::F32
+        } else {
+            dtype
+        };
+        let vae_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = vae_component
+        {
+            dispatch_load_vae_model(
+                &config,
+                safetensors.into_values().collect(),
+                device,
+                vae_dtype,
+                silent,
+                source.clone(),
+            )?
+        } else {
+            anyhow::bail!("incorrect storage of vae model")
+        };
+        if !silent {
+            info!("loading UNet model");
+        }
+        let (unet_model, latent_channels) =
+            load_unet(unet_component, device, dtype, silent, source)?;
+
+        let pipeline = StableDiffusionXLPipeline {
+            clip1,
+            clip2_tokenizer,
+            clip2_model,
+            vae_model,
+            vae_dtype,
+            unet_model,
+            latent_channels,
+            scheduler_config,
+            schedule,
+            refiner: None,
+            is_refiner: self.refiner,
+            dtype,
+            device: device.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+}
+
+pub struct StableDiffusionXLPipeline {
+    clip1: Option<(ClipTokenizer, ClipTextTransformer)>,
+    clip2_tokenizer: ClipTokenizer,
+    clip2_model: ClipTextModelWithProjection,
+    vae_model: Arc<dyn VAEModel>,
+    vae_dtype: DType,
+    unet_model: UNet2DConditionModel,
+    latent_channels: usize,
+    scheduler_config: DiscreteSchedulerConfig,
+    schedule: DiscreteSchedule,
+    refiner: Option<Arc<Mutex<dyn ModelPipeline>>>,
+    is_refiner: bool,
+    dtype: DType,
+    device: Device,
+}
+
+impl StableDiffusionXLPipeline {
+    /// Encode the prompts: the penultimate hidden states of the text encoders, concatenated, and the projected
+    /// pooled output of the second one.
+    fn encode_prompts(
+        &self,
+        prompts: Vec<String>,
+        textual_inversions: &[TextualInversion],
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let mut hidden_states = Vec::new();
+        if let Some((tokenizer, model)) = &self.clip1 {
+            let (tokenizer, model) = with_textual_inversions(tokenizer, model, textual_inversions)?;
+            let input_ids = tokenizer.tokenize(prompts.clone(), model.device())?;
+            let (_, penultimate) = model.forward_with_penultimate(&input_ids, usize::MAX)?;
+            hidden_states.push(penultimate.to_device(&self.device)?);
+        }
+        let (tokenizer, model) =
+            with_textual_inversions(&self.clip2_tokenizer, &self.clip2_model, textual_inversions)?;
+        let input_ids = tokenizer.tokenize(prompts, model.device())?;
+        let (penultimate, pooled) = model.forward_with_penultimate(&input_ids)?;
+        hidden_states.push(penultimate.to_device(&self.device)?);
+        Ok((
+            Tensor::cat(&hidden_states, D::Minus1)?,
+            pooled.to_device(&self.device)?,
+        ))
+    }
+
+    /// The size and crop conditioning: the generated image is uncropped at its original size.
+    fn time_ids(
+        &self,
+        height: usize,
+        width: usize,
+        negative: bool,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let (h, w) = (height as f32, width as f32);
+        let ids = if self.is_refiner {
+            let score = if negative {
+                NEGATIVE_AESTHETIC_SCORE
+            } else {
+                AESTHETIC_SCORE
+            };
+            vec![h, w, 0., 0., score as f32]
+        } else {
+            vec![h, w, 0., 0., h, w]
+        };
+        Tensor::new(ids, &self.device)?.unsqueeze(0)
+    }
+
+    /// Denoise `img` over the `timesteps`, returning the latents.
+    #[allow(clippy::too_many_arguments)]
+    fn denoise(
+        &self,
+        prompts: Vec<String>,
+        params: &DiffusionGenerationParams,
+        img: &Tensor,
+        timesteps: &[f64],
+        inpaint: Option<&InpaintMask>,
+        rng: NoiseGenerator,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        let bs = prompts.len();
+        let (_, _, h, w) = img.dims4()?;
+        let (height, width) = (h * 8, w * 8);
+
+        // Classifier-free guidance: the negative prompts are encoded and run in the same batch. Without a
+        // negative prompt, the base model uses zero embeddings.
+        let cfg = params.guidance_scale > 1.;
+        let encode_negative = cfg && (params.negative_prompt.is_some() || self.is_refiner);
+        let mut all_prompts = prompts;
+        if encode_negative {
+            let negative_prompt = params.negative_prompt.clone().unwrap_or_default();
+            all_prompts.extend(vec![negative_prompt; bs]);
+        }
+        let (mut context, mut text_embeds) =
+            self.encode_prompts(all_prompts, &params.textual_inversions)?;
+        let mut time_ids = self.time_ids(height, width, false)?.repeat((bs, 1))?;
+        if cfg {
+            if !encode_negative {
+                context = Tensor::cat(&[&context, &context.zeros_like()?], 0)?;
+                text_embeds = Tensor::cat(&[&text_embeds, &text_embeds.zeros_like()?], 0)?;
+            }
+            let negative_time_ids = self.time_ids(height, width, true)?.repeat((bs, 1))?;
+            time_ids = Tensor::cat(&[&time_ids, &negative_time_ids], 0)?;
+        }
+        let added = AddedCondition {
+            text_embeds,
+            time_ids,
+        };
+
+        let model = |x: &Tensor, s: f64| -> diffusion_rs_common::core::Result<Tensor> {
+            let (t, scale) = self.schedule.model_input(s);
+            let x_in = (x * scale)?;
+            let x_in = if cfg {
+                Tensor::cat(&[&x_in, &x_in], 0)?
+            } else {
+                x_in
+            };
+            let t = Tensor::full(t as f32, x_in.dim(0)?, &self.device)?;
+            let pred = self.unet_model.forward(&x_in, &t, &context, Some(&added))?;
+            let pred = if cfg {
+                let negative_pred = pred.narrow(0, bs, bs)?;
+                let pred = pred.narrow(0, 0, bs)?;
+                (&negative_pred + ((pred - &negative_pred)? * params.guidance_scale)?)?
+            } else {
+                pred
+            };
+            self.schedule.velocity(x, &pred, s)
+        };
+
+        let mut sampler = params
+            .sampler
+            .map_or_else(|| self.scheduler_config.default_sampler(), Ok)?
+            .sampler(params.eta, rng)?;
+        sample_model(sampler.as_mut(), timesteps, img, &model, inpaint)
+    }
+
+    /// The number of steps run before the refiner takes over: those at training timesteps of at least
+    /// `(1 - refiner_start) * num_train_timesteps`, as the diffusers `denoising_end`.
+    fn refiner_split(
+        &self,
+        timesteps: &[f64],
+        refiner_start: f64,
+    ) -> diffusion_rs_common::core::Result<usize> {
+        if !(0. ..=1.).contains(&refiner_start) {
+            diffusion_rs_common::bail!("The refiner start must be between 0 and 1.");
+        }
+        let cutoff =
+            (self.scheduler_config.num_train_timesteps as f64 * (1. - refiner_start)).round();
+        Ok(timesteps[..timesteps.len() - 1]
+            .iter()
+            .take_while(|s| self.schedule.model_input(**s).0.round() >= cutoff)
+            .count())
+    }
+}
+
+impl ModelPipeline for StableDiffusionXLPipeline {
+    fn forward(
+        &mut self,
+        prompts: Vec<String>,
+        params: DiffusionGenerationParams,
+        _offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        check_discrete_params(&params)?;
+
+        let bs = prompts.len();
+        let height = params.height.div_ceil(8) * 8;
+        let width = params.width.div_ceil(8) * 8;
+        let mut rng = NoiseGenerator::new(&params, bs)?;
+        let noise = rng.randn(
+            &[self.latent_channels, height / 8, width / 8],
+            self.dtype,
+            &self.device,
+        )?;
+
+        let mut timesteps = self.scheduler_config.get_timesteps(
+            &self.schedule,
+            params.num_steps,
+            params.sigma_spacing,
+        )?;
+        let (img, inpaint) = initial_latents(
+            self.vae_model.as_ref(),
+            self.vae_dtype,
+            &params,
+            noise,
+            DiscreteSchedule::noise_scale(timesteps[0]),
+            &mut timesteps,
+            bs,
+            height,
+            width,
+        )?;
+
+        let split = match &self.refiner {
+            Some(_) => self.refiner_split(&timesteps, params.refiner_start)?,
+            None => timesteps.len() - 1,
+        };
+        // The refiner continues with its own noise, distinct from the base model's.
+        let refiner_rng = (split < timesteps.len() - 1).then(|| rng.fork());
+        let mut img = self.denoise(
+            prompts.clone(),
+            &params,
+            &img,
+            &timesteps[..=split],
+            inpaint.as_ref(),
+            rng,
+        )?;
+        if let (Some(refiner), Some(refiner_rng)) = (&self.refiner, refiner_rng) {
+            let mut refiner = refiner.lock().expect("Could not lock refiner!");
+            img = refiner.refine(
+                prompts,
+                &params,
+                &img,
+                &timesteps[split..],
+                inpaint.as_ref(),
+                refiner_rng,
+            )?;
+        }
+
+        decode_latents(self.vae_model.as_ref(), self.vae_dtype, &img)
+    }
+
+    fn set_refiner(&mut self, refiner: Arc<Mutex<dyn ModelPipeline>>) -> Result<()> {
+        if self.is_refiner {
+            anyhow::bail!("A refiner cannot have its own refiner.");
+        }
+        self.refiner = Some(refiner);
+        Ok(())
+    }
+
+    fn load_textual_inversion(
+        &mut self,
+        textual_inversion: &TextualInversion,
+    ) -> diffusion_rs_common::core::Result<()> {
+        // The embedding is added to both text encoders, or to neither if it does not fit one of them.
+        let textual_inversions = std::slice::from_ref(textual_inversion);
+        let clip1 = match &self.clip1 {
+            Some((tokenizer, model)) => {
+                let (tokenizer, model) =
+                    with_textual_inversions(tokenizer, model, textual_inversions)?;
+                Some((tokenizer.into_owned(), model.into_owned()))
+            }
+            None => None,
+        };
+        let (clip2_tokenizer, clip2_model) =
+            with_textual_inversions(&self.clip2_tokenizer, &self.clip2_model, textual_inversions)?;
+        (self.clip2_tokenizer, self.clip2_model) =
+            (clip2_tokenizer.into_owned(), clip2_model.into_owned());
+        self.clip1 = clip1;
+        Ok(())
+    }
+
+    fn refine(
+        &mut self,
+        prompts: Vec<String>,
+        params: &DiffusionGenerationParams,
+        latents: &Tensor,
+        timesteps: &[f64],
+        inpaint: Option<&InpaintMask>,
+        rng: NoiseGenerator,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        self.denoise(
+            prompts,
+            params,
+            &latents.to_dtype(self.dtype)?,
+            timesteps,
+            inpaint,
+            rng,
+        )
+    }
+}
@@ -0,0 +1,411 @@
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device, Tensor, D};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+use crate::models::QuantizedModel;
+use crate::{
+    models::{
+        dispatch_load_vae_model, ClipTextConfig, ClipTextModelWithProjection, MMDiTConfig,
+        MMDiTModel, T5Config, T5EncoderModel, VAEModel,
+    },
+    pipelines::ComponentName,
+};
+use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+
+use super::flux::calculate_shift;
+use super::noise::NoiseGenerator;
+use super::sampling::{sample, SamplerType};
+use super::scheduler::SchedulerConfig;
+use super::stable_diffusion::{
+    check_params, decode_latents, initial_latents, t5_tokenize, with_textual_inversions,
+    ClipTokenizer,
+};
+use super::textual_inversion::TextualInversion;
+use super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+
+/// The number of T5 tokens of the text conditioning.
+const T5_MAX_TOKENS: usize = 256;
+
+pub struct StableDiffusion3Loader;
+
+/// Load a CLIP text encoder with a projection of its pooled output.
+fn load_clip(
+    elem: ComponentElem,
+    device: &Device,
+    dtype: DType,
+    silent: bool,
+    source: Arc<ModelSource>,
+) -> Result<ClipTextModelWithProjection> {
+    let ComponentElem::Model {
+        safetensors,
+        config,
+    } = elem
+    else {
+        anyhow::bail!("incorrect storage of clip model")
+    };
+    let cfg: ClipTextConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+    let vb = from_mmaped_safetensors(
+        safetensors.into_values().collect(),
+        Some(dtype),
+        device,
+        silent,
+        source,
+    )?;
+    Ok(ClipTextModelWithProjection::new(vb, &cfg)?)
+}
+
+impl Loader for StableDiffusion3Loader {
+    fn name(&self) -> &'static str {
+        "stable-diffusion-3"
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        vec![
+            ComponentName::Scheduler,
+            ComponentName::TextEncoder(1),
+            ComponentName::TextEncoder(2),
+            ComponentName::TextEncoder(3),
+            ComponentName::Tokenizer(1),
+            ComponentName::Tokenizer(2),
+            ComponentName::Tokenizer(3),
+            ComponentName::Transformer,
+            ComponentName::Vae,
+        ]
+    }
+
+    fn load_from_components(
+        &self,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        device: &Device,
+        dtype: DType,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
+        let clip_l_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
+        let clip_g_component = components.remove(&ComponentName::TextEncoder(2)).unwrap();
+        let t5_component = components.remove(&ComponentName::TextEncoder(3)).unwrap();
+        let clip_l_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
+        let clip_g_tok_component = components.remove(&ComponentName::Tokenizer(2)).unwrap();
+        let t5_tok_component = components.remove(&ComponentName::Tokenizer(3)).unwrap();
+        let mmdit_component = components.remove(&ComponentName::Transformer).unwrap();
+        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+
+        let t5_mmdit_device = match offloading_type {
+            Some(Offloading::Full) => Device::Cpu,
+            None => device.clone(),
+        };
+
+        let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            SchedulerConfig::from_json(
+                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+            )?
+        } else {
+            anyhow::bail!("expected scheduler config")
+        };
+        let clip_l_tokenizer = ClipTokenizer::load(clip_l_tok_component, "tokenizer", &source)?;
+        let clip_g_tokenizer = ClipTokenizer::load(clip_g_tok_component, "tokenizer_2", &source)?;
+        let t5_tokenizer = if let ComponentElem::Other { files } = t5_tok_component {
+            Tokenizer::from_bytes(files["tokenizer_3/tokenizer.json"].read_to_string(&source)?)
+                .map_err(anyhow::Error::msg)?
+        } else {
+            anyhow::bail!("incorrect storage of t5 tokenizer")
+        };
+
+        if !silent {
+            info!("loading CLIP models");
+        }
+        let clip_l_model = load_clip(clip_l_component, device, dtype, silent, source.clone())?;
+        let clip_g_model = load_clip(clip_g_component, device, dtype, silent, source.clone())?;
+        if !silent {
+            info!("loading T5 model");
+        }
+        let t5_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = t5_component
+        {
+            let cfg: T5Config = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                &t5_mmdit_device,
+                silent,
+                source.clone(),
+            )?;
+            T5EncoderModel::new(vb, &cfg)?
+        } else {
+            anyhow::bail!("incorrect storage of t5 model")
+        };
+        if !silent {
+            info!("loading VAE model");
+        }
+        let vae_model = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = vae_component
+        {
+            dispatch_load_vae_model(
+                &config,
+                safetensors.into_values().collect(),
+                device,
+                dtype,
+                silent,
+                source.clone(),
+            )?
+        } else {
+            anyhow::bail!("incorrect storage of vae model")
+        };
+        if !silent {
+            info!("loading MMDiT model");
+        }
+        let (mmdit_model, latent_channels, joint_attention_dim) = if let ComponentElem::Model {
+            safetensors,
+            config,
+        } = mmdit_component
+        {
+            let cfg: MMDiTConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            if cfg.in_channels != cfg.out_channels {
+                anyhow::bail!(
+                    "unsupported MMDiT model with {} input and {} output channels",
+                    cfg.in_channels,
+                    cfg.out_channels
+                );
+            }
+            let vb = from_mmaped_safetensors(
+                safetensors.into_values().collect(),
+                Some(dtype),
+                &t5_mmdit_device,
+                silent,
+                source,
+            )?;
+            (
+                MMDiTModel::new(&cfg, vb)?,
+                cfg.out_channels,
+                cfg.joint_attention_dim,
+            )
+        } else {
+            anyhow::bail!("incorrect storage of mmdit model")
+        };
+
+        let pipeline = StableDiffusion3Pipeline {
+            clip_l_tokenizer,
+            clip_l_model,
+            clip_g_tokenizer,
+            clip_g_model,
+            t5_tokenizer,
+            t5_model,
+            vae_model,
+            mmdit_model,
+            latent_channels,
+            joint_attention_dim,
+            scheduler_config,
+            dtype,
+            device: device.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+}
+
+pub struct StableDiffusion3Pipeline {
+    clip_l_tokenizer: ClipTokenizer,
+    clip_l_model: ClipTextModelWithProjection,
+    clip_g_tokenizer: ClipTokenizer,
+    clip_g_model: ClipTextModelWithProjection,
+    t5_tokenizer: Tokenizer,
+    t5_model: T5EncoderModel,
+    vae_model: Arc<dyn VAEModel>,
+    mmdit_model: MMDiTModel,
+    latent_channels: usize,
+    joint_attention_dim: usize,
+    scheduler_config: SchedulerConfig,
+    dtype: DType,
+    device: Device,
+}
+
+impl StableDiffusion3Pipeline {
+    /// Tokenize the prompts for T5, truncated and padded to `T5_MAX_TOKENS`.
+    fn t5_tokenize(&self, prompts: Vec<String>) -> diffusion_rs_common::core::Result<Tensor> {
+        let input_ids = t5_tokenize(&self.t5_tokenizer, prompts, T5_MAX_TOKENS)?
+            .into_iter()
+            .map(|mut ids| {
+                ids.resize(T5_MAX_TOKENS, 0);
+                ids
+            })
+            .collect::<Vec<_>>();
+        Tensor::new(input_ids, &self.device)
+    }
+
+    /// Encode the prompts into the joint text tokens and the pooled text embeddings. The penultimate hidden
+    /// states of both CLIP encoders are concatenated and zero-padded to the T5 width, then followed by the T5
+    /// tokens.
+    fn encode_prompts(
+        &mut self,
+        prompts: Vec<String>,
+        textual_inversions: &[TextualInversion],
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let (clip_l_tokenizer, clip_l_model) = with_textual_inversions(
+            &self.clip_l_tokenizer,
+            &self.clip_l_model,
+            textual_inversions,
+        )?;
+        let clip_l_ids = clip_l_tokenizer.tokenize(prompts.clone(), clip_l_model.device())?;
+        let (clip_l_embed, clip_l_pooled) = clip_l_model.forward_with_penultimate(&clip_l_ids)?;
+        let (clip_g_tokenizer, clip_g_model) = with_textual_inversions(
+            &self.clip_g_tokenizer,
+            &self.clip_g_model,
+            textual_inversions,
+        )?;
+        let clip_g_ids = clip_g_tokenizer.tokenize(prompts.clone(), clip_g_model.device())?;
+        let (clip_g_embed, clip_g_pooled) = clip_g_model.forward_with_penultimate(&clip_g_ids)?;
+
+        let clip_embed = Tensor::cat(&[clip_l_embed, clip_g_embed], D::Minus1)?;
+        let clip_embed = clip_embed.pad_with_zeros(
+            D::Minus1,
+            0,
+            self.joint_attention_dim - clip_embed.dim(D::Minus1)?,
+        )?;
+        let pooled = Tensor::cat(&[clip_l_pooled, clip_g_pooled], D::Minus1)?;
+
+        if let Some(Offloading::Full) = offloading_type {
+            self.t5_model.to_device(&self.device)?;
+        }
+        let t5_embed = self.t5_model.forward(&self.t5_tokenize(prompts)?)?;
+        if let Some(Offloading::Full) = offloading_type {
+            self.t5_model.to_device(&Device::Cpu)?;
+        }
+
+        let txt = Tensor::cat(
+            &[
+                clip_embed.to_device(&self.device)?,
+                t5_embed.to_dtype(clip_embed.dtype())?,
+            ],
+            1,
+        )?;
+        Ok((txt, pooled.to_device(&self.device)?))
+    }
+}
+
+impl ModelPipeline for StableDiffusion3Pipeline {
+    fn forward(
+        &mut self,
+        prompts: Vec<String>,
+        params: DiffusionGenerationParams,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        check_params(&params)?;
+
+        // Classifier-free guidance: the negative prompts are encoded and run in the same batch.
+        let bs = prompts.len();
+        let cfg = params.guidance_scale > 1.;
+        let mut all_prompts = prompts;
+        if cfg {
+            let negative_prompt = params.negative_prompt.clone().unwrap_or_default();
+            all_prompts.extend(vec![negative_prompt; bs]);
+        }
+        let (txt, pooled) =
+            self.encode_prompts(all_prompts, &params.textual_inversions, offloading_type)?;
+
+        // The latents are patchified in 2x2 patches.
+        let height = params.height.div_ceil(16) * 16;
+        let width = params.width.div_ceil(16) * 16;
+        let mut rng = NoiseGenerator::new(&params, bs)?;
+        let noise = rng.randn(
+            &[self.latent_channels, height / 8, width / 8],
+            self.dtype,
+            &self.device,
+        )?;
+
+        let mu = if self.scheduler_config.use_dynamic_shifting {
+            Some(calculate_shift(
+                (height / 16) * (width / 16),
+                self.scheduler_config.base_image_seq_len,
+                self.scheduler_config.max_image_seq_len,
+                self.scheduler_config.base_shift,
+                self.scheduler_config.max_shift,
+            ))
+        } else {
+            None
+        };
+        let mut timesteps = self.scheduler_config.get_timesteps(
+            params.num_steps,
+            mu,
+            params.sigma_spacing,
+            params.sigmas.as_deref(),
+        )?;
+        let sampler_type = params
+            .sampler
+            .unwrap_or_else(|| SamplerType::from(&self.scheduler_config));
+        self.scheduler_config
+            .check_sampling(sampler_type, params.init_image.is_some())?;
+
+        // Flow-matching models start from unscaled noise.
+        let (img, inpaint) = initial_latents(
+            self.vae_model.as_ref(),
+            self.dtype,
+            &params,
+            noise,
+            1.,
+            &mut timesteps,
+            bs,
+            height,
+            width,
+        )?;
+
+        if let Some(Offloading::Full) = offloading_type {
+            self.mmdit_model.to_device(&self.device)?;
+        }
+
+        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+            if !cfg {
+                return self.mmdit_model.forward(img, &txt, t_vec, &pooled);
+            }
+            let img = Tensor::cat(&[img, img], 0)?;
+            let t_vec = Tensor::cat(&[t_vec, t_vec], 0)?;
+            let pred = self.mmdit_model.forward(&img, &txt, &t_vec, &pooled)?;
+            let negative_pred = pred.narrow(0, bs, bs)?;
+            let pred = pred.narrow(0, 0, bs)?;
+            &negative_pred + ((pred - &negative_pred)? * params.guidance_scale)?
+        };
+
+        let mut sampler = sampler_type.sampler(params.eta, rng)?;
+        let img = sample(sampler.as_mut(), &timesteps, &img, step, inpaint.as_ref())?;
+
+        if let Some(Offloading::Full) = offloading_type {
+            self.mmdit_model.to_device(&Device::Cpu)?;
+        }
+
+        decode_latents(self.vae_model.as_ref(), self.dtype, &img)
+    }
+
+    fn load_textual_inversion(
+        &mut self,
+        textual_inversion: &TextualInversion,
+    ) -> diffusion_rs_common::core::Result<()> {
+        // The embedding is added to both CLIP encoders, or to neither if it does not fit one of them.
+        let textual_inversions = std::slice::from_ref(textual_inversion);
+        let (clip_l_tokenizer, clip_l_model) = with_textual_inversions(
+            &self.clip_l_tokenizer,
+            &self.clip_l_model,
+            textual_inversions,
+        )?;
+        let (clip_l_tokenizer, clip_l_model) =
+            (clip_l_tokenizer.into_owned(), clip_l_model.into_owned());
+        let (clip_g_tokenizer, clip_g_model) = with_textual_inversions(
+            &self.clip_g_tokenizer,
+            &self.clip_g_model,
+            textual_inversions,
+        )?;
+        (self.clip_g_tokenizer, self.clip_g_model) =
+            (clip_g_tokenizer.into_owned(), clip_g_model.into_owned());
+        (self.clip_l_tokenizer, self.clip_l_model) = (clip_l_tokenizer, clip_l_model);
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{pickle, safetensors, Device, Tensor};
+
+/// The keys of the vectors in the common embedding formats, which do not name the placeholder token: A1111
+/// `.safetensors` and `.pt` files, and SDXL embeddings with vectors for both text encoders.
+const GENERIC_KEYS: &[&str] = &["emb_params", "*", "clip_l", "clip_g"];
+
+/// A textual inversion embedding: a placeholder token and its learned vectors, for each text encoder it was
+/// trained for. The placeholder token in the prompts is replaced by its vectors.
+#[derive(Debug, Clone)]
+pub struct TextualInversion {
+    /// The placeholder token to use in the prompts.
+    pub token: String,
+    tensors: HashMap<String, Tensor>,
+}
+
+impl TextualInversion {
+    /// Load an embedding from a `.safetensors` file, or a `.pt` or `.bin` PyTorch file. The A1111, diffusers and
+    /// SDXL formats are supported.
+    ///
+    /// If `token` is not specified, it is the token named in the file for the diffusers format, and the file
+    /// name without the extension otherwise.
+    pub fn from_file(path: impl AsRef<Path>, token: Option<String>) -> Result<Self> {
+        let path = path.as_ref();
+        let tensors: HashMap<String, Tensor> =
+            if path.extension().is_some_and(|ext| ext == "safetensors") {
+                safetensors::load(path, &Device::Cpu)?
+            } else {
+                // A1111 embeddings store their vectors in a nested `string_to_param` dict.
+                pickle::read_all_with_key(path, Some("string_to_param"))
+                    .or_else(|_| pickle::read_all(path))?
+                    .into_iter()
+                    .collect()
+            };
+        if tensors.is_empty() {
+            anyhow::bail!("No embedding vectors found in `{}`.", path.display());
+        }
+
+        let token = match token {
+            Some(token) => token,
+            None => match tensors.keys().collect::<Vec<_>>().as_slice() {
+                [key] if !GENERIC_KEYS.contains(&key.as_str()) => key.to_string(),
+                _ => path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .ok_or_else(|| anyhow::anyhow!("Expected a token for `{}`.", path.display()))?,
+            },
+        };
+        if token.trim().is_empty() || token.contains(char::is_whitespace) {
+            anyhow::bail!("Invalid textual inversion token `{token}`.");
+        }
+        Ok(Self { token, tensors })
+    }
+
+    /// The `(num_vectors, hidden_size)` vectors for a text encoder of hidden size `hidden_size`.
+    pub(crate) fn vectors(&self, hidden_size: usize) -> diffusion_rs_common::core::Result<Tensor> {
+        let mut matching = self
+            .tensors
+            .values()
+            .filter(|vectors| vectors.dims().last() == Some(&hidden_size));
+        match (matching.next(), matching.next()) {
+            (Some(vectors), None) => vectors.reshape(((), hidden_size)),
+            (None, _) => diffusion_rs_common::bail!(
+                "Textual inversion `{}` has no vectors for a text encoder of size {hidden_size}.",
+                self.token
+            ),
+            (Some(_), Some(_)) => diffusion_rs_common::bail!(
+                "Textual inversion `{}` has several vectors for a text encoder of size {hidden_size}.",
+                self.token
+            ),
+        }
+    }
+}
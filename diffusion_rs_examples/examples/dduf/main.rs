@@ -56,6 +56,7 @@ fn main() -> anyhow::Result<()> {
             width: 1280,
             num_steps: args.num_steps,
             guidance_scale: args.guidance_scale,
+            ..Default::default()
         },
     )?;
 
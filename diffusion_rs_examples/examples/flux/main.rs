@@ -70,6 +70,7 @@ fn main() -> anyhow::Result<()> {
             width: 1280,
             num_steps,
             guidance_scale,
+            ..Default::default()
         },
     )?;
 
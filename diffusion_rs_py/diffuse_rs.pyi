@@ -21,6 +21,56 @@ class Offloading(Enum):
 
     Full = 0
 
+@dataclass
+class NoiseSource(Enum):
+    """
+    Random number generator used to create the noise.
+
+    - `Native`: the default generator.
+    - `Torch`: reproduces PyTorch's CPU generator, so that a seed gives the same starting latent as
+        `torch.Generator("cpu").manual_seed(seed)` in diffusers.
+    """
+
+    Native = 0
+    Torch = 1
+
+@dataclass
+class SamplerType(Enum):
+    """
+    Sampling method for flow-matching models.
+
+    - `Euler`: first order Euler method.
+    - `Heun`: second order Heun method. Each step except the last runs the model twice.
+    - `DpmPlusPlus2M`: multistep second order DPM-Solver++ (2M). It runs the model once per step and usually
+        reaches the same quality as Euler in fewer steps.
+    - `EulerAncestral`: stochastic Euler method, which injects fresh seeded noise at each step, by an amount
+        controlled by `eta`.
+    - `StochasticEuler`: Euler method that fully renoises the prediction at each step, as the
+        `stochastic_sampling` scheduler option.
+    """
+
+    Euler = 0
+    Heun = 1
+    DpmPlusPlus2M = 2
+    EulerAncestral = 3
+    StochasticEuler = 4
+
+@dataclass
+class SigmaSpacing(Enum):
+    """
+    Spacing of the sigma schedule, between the largest and smallest shifted sigma.
+
+    - `Linear`: use the shifted sigmas as they are.
+    - `Karras`: Karras et al. spacing, which concentrates the steps at low noise levels.
+    - `Exponential`: evenly spaced in log-sigma.
+    - `Beta`: quantiles of a Beta(0.6, 0.6) distribution, which concentrates the steps at both ends.
+    """
+
+    Linear = 0
+    Karras = 1
+    Exponential = 2
+    Beta = 3
+
 @dataclass
 class ModelSource(Enum):
     """
@@ -34,16 +84,95 @@ class ModelSource(Enum):
     class DdufFile:
         file: str
 
+@dataclass
+class ControlImage:
+    """
+    A control image for a ControlNet loaded with `Pipeline.load_controlnet`.
+
+    - `image`: encoded control image (e.g. PNG bytes), such as an edge, depth or pose map.
+    - `controlnet`: index of the ControlNet to use, in the order they were loaded.
+    - `scale`: scale of the ControlNet residuals.
+    - `start`, `end`: fractions of the denoising schedule between which the control applies.
+    - `mode`: control mode of a Union ControlNet. For the InstantX Union ControlNet: 0 canny, 1 tile,
+        2 depth, 3 blur, 4 pose, 5 gray and 6 low quality.
+    """
+
+    image: bytes
+    controlnet: int = 0
+    scale: float = 1.0
+    start: float = 0.0
+    end: float = 1.0
+    mode: int | None = None
+
 @dataclass
 class DiffusionGenerationParams:
     """
     Generation parameters for diffusion models
+
+    - `sigma_spacing`: spacing of the sigma schedule. If not specified, it is chosen from the scheduler config
+        of the model.
+    - `sigmas`: explicit sigma schedule, decreasing in (0, 1], without the terminal 0. It overrides
+        `num_steps`; the shift and `sigma_spacing` are still applied.
+    - `true_cfg_scale`: scale for true classifier-free guidance. If greater than 1, the model is also run on
+        `negative_prompt` and the predictions are combined. This doubles the cost of each step.
+    - `sampler`: sampling method. If not specified, it is chosen from the scheduler config of the model.
+    - `eta`: amount of fresh noise injected at each step by stochastic samplers, between 0 (deterministic)
+        and 1.
+    - `negative_prompt`: prompt for the unconditional pass of classifier-free guidance. If not specified,
+        an empty prompt is used.
+    - `seed`: seed for the random noise. If not specified, a random seed is used. In a batch, the image for
+        prompt `i` uses `seed + i`.
+    - `seeds`: per-image seeds for a batch, one per prompt. Takes precedence over `seed`.
+    - `noise_source`: random number generator used to create the noise.
+    - `init_image`: encoded image (e.g. PNG bytes) to start from instead of pure noise (image-to-image).
+    - `strength`: how much to transform `init_image`, between 0 and 1. Only the last `strength * num_steps`
+        denoising steps are run. If not specified, defaults to 1 for FLUX.1 Fill models and to 0.6 otherwise.
+    - `mask_image`: encoded mask for inpainting `init_image`. White areas are regenerated, black areas are kept.
+        Requires `init_image`.
+    - `refiner_start`: fraction of the denoising schedule run by the base model when a refiner is attached,
+        between 0 and 1. The refiner runs the rest.
+    - `loras`: runtime LoRA adapters to activate for this request, by name, with their scales. If not
+        specified, all runtime adapters are active with the scale they were loaded with.
+    - `reference_image`: encoded image to edit as instructed by the prompt, for instruction-based editing
+        models such as FLUX.1 Kontext.
+    - `image_prompts`: encoded images with their weights, to condition the generation on alongside or instead
+        of the prompt. These require a prior such as FLUX.1 Redux, attached with `Pipeline.set_prior`, or an
+        IP-Adapter loaded with `Pipeline.load_ip_adapter`.
+    - `control_images`: control images for structural control, each given to one of the ControlNets loaded
+        with `Pipeline.load_controlnet`. Their residuals are summed.
+    - `control_image`: encoded control image for structural conditioning models such as FLUX.1 Depth and
+        Canny, which take it as extra input channels instead of using a ControlNet.
+    - `ip_adapters`: IP-Adapters to condition on the image prompts for this request, by name, with their
+        scales. If not specified, all IP-Adapters are active with the scale they were loaded with.
+    - `textual_inversions`: textual inversion embeddings (`.safetensors` or `.pt` paths) to use for this request
+        only, with their tokens. If a token is `None`, it is named by the file, or is the file name without the
+        extension.
     """
 
     height: int
     width: int
     num_steps: int
     guidance_scale: float
+    sigma_spacing: SigmaSpacing | None = None
+    sigmas: list[float] | None = None
+    true_cfg_scale: float = 1.0
+    sampler: SamplerType | None = None
+    eta: float = 1.0
+    negative_prompt: str | None = None
+    seed: int | None = None
+    seeds: list[int] | None = None
+    noise_source: NoiseSource = NoiseSource.Native
+    init_image: bytes | None = None
+    strength: float | None = None
+    mask_image: bytes | None = None
+    refiner_start: float = 0.8
+    loras: dict[str, float] | None = None
+    reference_image: bytes | None = None
+    image_prompts: list[tuple[bytes, float]] | None = None
+    control_images: list[ControlImage] | None = None
+    control_image: bytes | None = None
+    ip_adapters: dict[str, float] | None = None
+    textual_inversions: list[tuple[str, str | None]] | None = None
 
 class Pipeline:
     def __init__(
@@ -68,6 +197,95 @@ class Pipeline:
         """
         ...
 
+    def load_lora(self, name: str, path: str, scale: float = 1.0) -> None:
+        """
+        Load a LoRA adapter from a `.safetensors` file and merge it into the model weights, scaled by `scale`.
+
+        Adapters in the diffusers, kohya and BFL formats are supported. Several adapters can be loaded under
+        different names, in which case they are stacked.
+        """
+        ...
+
+    def load_runtime_lora(self, name: str, path: str, scale: float = 1.0) -> None:
+        """
+        Load a LoRA adapter from a `.safetensors` file without modifying the model weights. It is applied at
+        runtime, so each request can select the active adapters and their scales with
+        `DiffusionGenerationParams.loras`. By default, it is active with scale `scale`.
+        """
+        ...
+
+    def unload_lora(self, name: str) -> None:
+        """
+        Remove a LoRA adapter which was loaded with `load_lora` or `load_runtime_lora`.
+        """
+        ...
+
+    def loras(self) -> list[tuple[str, float]]:
+        """
+        Names and scales of the loaded LoRA adapters, in the order they were loaded.
+        """
+        ...
+
+    def set_refiner(self, refiner: Pipeline) -> None:
+        """
+        Attach a refiner pipeline, such as the SDXL refiner, which takes over from this pipeline for the last
+        denoising steps of each generation. The switch point is set by `DiffusionGenerationParams.refiner_start`.
+        """
+        ...
+
+    def set_prior(self, prior: Pipeline) -> None:
+        """
+        Attach a prior pipeline, such as FLUX.1 Redux, which encodes the `DiffusionGenerationParams.image_prompts`
+        of each generation of this pipeline.
+        """
+        ...
+
+    def load_controlnet(
+        self,
+        source: ModelSource,
+        silent: bool = False,
+        token: str | None = None,
+        revision: str | None = None,
+    ) -> None:
+        """
+        Load a ControlNet, such as a FLUX ControlNet for canny, depth or pose control. It is used by the
+        `DiffusionGenerationParams.control_images` which select it by index, in the order the ControlNets
+        were loaded.
+        """
+        ...
+
+    def load_ip_adapter(
+        self,
+        name: str,
+        path: str,
+        image_encoder: ModelSource,
+        scale: float = 1.0,
+        silent: bool = False,
+        token: str | None = None,
+        revision: str | None = None,
+    ) -> None:
+        """
+        Load an IP-Adapter, such as the XLabs FLUX IP-Adapter, from a `.safetensors` file, with the CLIP image
+        encoder it was trained with, such as `openai/clip-vit-large-patch14`. It conditions the generations on
+        their `DiffusionGenerationParams.image_prompts`, with scale `scale` unless the request selects the
+        active adapters and their scales with `DiffusionGenerationParams.ip_adapters`.
+        """
+        ...
+
+    def unload_ip_adapter(self, name: str) -> None:
+        """
+        Remove an IP-Adapter which was loaded with `load_ip_adapter`.
+        """
+        ...
+
+    def load_textual_inversion(self, path: str, token: str | None = None) -> None:
+        """
+        Load a textual inversion embedding for the CLIP text encoders from a `.safetensors` or `.pt` file. Its
+        placeholder token can then be used in all prompts. If `token` is not specified, it is named by the file,
+        or is the file name without the extension.
+        """
+        ...
+
     def forward(
         self,
         prompts: list[str],
@@ -1,4 +1,4 @@
-use std::io::Cursor;
+use std::{collections::HashMap, io::Cursor};
 
 use pyo3::{
     pyclass, pymethods, pymodule,
@@ -16,6 +16,32 @@ pub enum Offloading {
     Full,
 }
 
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NoiseSource {
+    Native,
+    Torch,
+}
+
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SamplerType {
+    Euler,
+    Heun,
+    DpmPlusPlus2M,
+    EulerAncestral,
+    StochasticEuler,
+}
+
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SigmaSpacing {
+    Linear,
+    Karras,
+    Exponential,
+    Beta,
+}
+
 #[pyclass]
 #[derive(Clone, Debug)]
 pub enum ModelSource {
@@ -31,6 +57,26 @@ pub struct DiffusionGenerationParams {
     pub width: usize,
     pub num_steps: usize,
     pub guidance_scale: f64,
+    pub sigma_spacing: Option<SigmaSpacing>,
+    pub sigmas: Option<Vec<f64>>,
+    pub true_cfg_scale: f64,
+    pub sampler: Option<SamplerType>,
+    pub eta: f64,
+    pub negative_prompt: Option<String>,
+    pub seed: Option<u64>,
+    pub seeds: Option<Vec<u64>>,
+    pub noise_source: NoiseSource,
+    pub init_image: Option<Vec<u8>>,
+    pub strength: Option<f64>,
+    pub mask_image: Option<Vec<u8>>,
+    pub refiner_start: f64,
+    pub loras: Option<HashMap<String, f64>>,
+    pub reference_image: Option<Vec<u8>>,
+    pub image_prompts: Option<Vec<(Vec<u8>, f64)>>,
+    pub control_images: Option<Vec<ControlImage>>,
+    pub control_image: Option<Vec<u8>>,
+    pub ip_adapters: Option<HashMap<String, f64>>,
+    pub textual_inversions: Option<Vec<(String, Option<String>)>>,
 }
 
 #[pyclass(eq, eq_int)]
@@ -51,23 +97,137 @@ impl DiffusionGenerationParams {
         width,
         num_steps,
         guidance_scale,
+        true_cfg_scale = 1.0,
+        sigma_spacing = None,
+        sigmas = None,
+        sampler = None,
+        eta = 1.0,
+        negative_prompt = None,
+        seed = None,
+        seeds = None,
+        noise_source = NoiseSource::Native,
+        init_image = None,
+        strength = None,
+        mask_image = None,
+        refiner_start = 0.8,
+        loras = None,
+        reference_image = None,
+        image_prompts = None,
+        control_images = None,
+        control_image = None,
+        ip_adapters = None,
+        textual_inversions = None,
     ))]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         height: usize,
         width: usize,
         num_steps: usize,
         guidance_scale: f64,
+        true_cfg_scale: f64,
+        sigma_spacing: Option<SigmaSpacing>,
+        sigmas: Option<Vec<f64>>,
+        sampler: Option<SamplerType>,
+        eta: f64,
+        negative_prompt: Option<String>,
+        seed: Option<u64>,
+        seeds: Option<Vec<u64>>,
+        noise_source: NoiseSource,
+        init_image: Option<Vec<u8>>,
+        strength: Option<f64>,
+        mask_image: Option<Vec<u8>>,
+        refiner_start: f64,
+        loras: Option<HashMap<String, f64>>,
+        reference_image: Option<Vec<u8>>,
+        image_prompts: Option<Vec<(Vec<u8>, f64)>>,
+        control_images: Option<Vec<ControlImage>>,
+        control_image: Option<Vec<u8>>,
+        ip_adapters: Option<HashMap<String, f64>>,
+        textual_inversions: Option<Vec<(String, Option<String>)>>,
     ) -> PyResult<Self> {
         Ok(Self {
             height,
             width,
             num_steps,
             guidance_scale,
+            true_cfg_scale,
+            sigma_spacing,
+            sigmas,
+            sampler,
+            eta,
+            negative_prompt,
+            seed,
+            seeds,
+            noise_source,
+            init_image,
+            strength,
+            mask_image,
+            refiner_start,
+            loras,
+            reference_image,
+            image_prompts,
+            control_images,
+            control_image,
+            ip_adapters,
+            textual_inversions,
         })
     }
 
     pub fn __repr__(&self) -> String {
-        format!("DiffusionGenerationParams(height = {}, width = {}, num_steps = {}, guidance_scale = {})", self.height,self.width,self.num_steps,self.guidance_scale)
+        format!("DiffusionGenerationParams(height = {}, width = {}, num_steps = {}, guidance_scale = {}, true_cfg_scale = {}, sigma_spacing = {:?}, sigmas = {:?}, sampler = {:?}, eta = {}, negative_prompt = {:?}, seed = {:?}, seeds = {:?}, noise_source = {:?}, init_image = {}, strength = {:?}, mask_image = {}, refiner_start = {}, loras = {:?}, reference_image = {}, image_prompts = {}, control_images = {}, control_image = {}, ip_adapters = {:?}, textual_inversions = {:?})", self.height,self.width,self.num_steps,self.guidance_scale,self.true_cfg_scale,self.sigma_spacing,self.sigmas,self.sampler,self.eta,self.negative_prompt,self.seed,self.seeds,self.noise_source,if self.init_image.is_some() { "Some(..)" } else { "None" },self.strength,if self.mask_image.is_some() { "Some(..)" } else { "None" },self.refiner_start,self.loras,if self.reference_image.is_some() { "Some(..)" } else { "None" },self.image_prompts.as_ref().map_or("None".to_string(), |prompts| format!("[{} image(s)]", prompts.len())),self.control_images.as_ref().map_or("None".to_string(), |images| format!("[{} image(s)]", images.len())),if self.control_image.is_some() { "Some(..)" } else { "None" },self.ip_adapters,self.textual_inversions)
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Clone, Debug)]
+pub struct ControlImage {
+    pub image: Vec<u8>,
+    pub controlnet: usize,
+    pub scale: f64,
+    pub start: f64,
+    pub end: f64,
+    pub mode: Option<usize>,
+}
+
+#[pymethods]
+impl ControlImage {
+    #[new]
+    #[pyo3(signature = (
+        image,
+        controlnet = 0,
+        scale = 1.0,
+        start = 0.0,
+        end = 1.0,
+        mode = None,
+    ))]
+    pub fn new(
+        image: Vec<u8>,
+        controlnet: usize,
+        scale: f64,
+        start: f64,
+        end: f64,
+        mode: Option<usize>,
+    ) -> Self {
+        Self {
+            image,
+            controlnet,
+            scale,
+            start,
+            end,
+            mode,
+        }
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "ControlImage(controlnet = {}, scale = {}, start = {}, end = {}, mode = {:?})",
+            self.controlnet, self.scale, self.start, self.end, self.mode
+        )
     }
 
     pub fn __str__(&self) -> String {
@@ -123,11 +283,166 @@ impl Pipeline {
         ))
     }
 
+    #[pyo3(signature = (name, path, scale = 1.0))]
+    fn load_lora(&self, name: String, path: String, scale: f64) -> PyResult<()> {
+        self.0
+            .load_lora(name, path, scale)
+            .map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (name, path, scale = 1.0))]
+    fn load_runtime_lora(&self, name: String, path: String, scale: f64) -> PyResult<()> {
+        self.0
+            .load_runtime_lora(name, path, scale)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn unload_lora(&self, name: String) -> PyResult<()> {
+        self.0.unload_lora(&name).map_err(wrap_anyhow_error)
+    }
+
+    fn loras(&self) -> Vec<(String, f64)> {
+        self.0.loras()
+    }
+
+    fn set_refiner(&self, refiner: &Pipeline) -> PyResult<()> {
+        self.0.set_refiner(&refiner.0).map_err(wrap_anyhow_error)
+    }
+
+    fn set_prior(&self, prior: &Pipeline) -> PyResult<()> {
+        self.0.set_prior(&prior.0).map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (source, silent = false, token = None, revision = None))]
+    fn load_controlnet(
+        &self,
+        source: ModelSource,
+        silent: bool,
+        token: Option<String>,
+        revision: Option<String>,
+    ) -> PyResult<()> {
+        let token = token
+            .map(diffusion_rs_core::TokenSource::Literal)
+            .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
+        let source = match source {
+            ModelSource::DdufFile { file } => {
+                diffusion_rs_core::ModelSource::dduf(file).map_err(wrap_anyhow_error)?
+            }
+            ModelSource::ModelId { model_id } => {
+                diffusion_rs_core::ModelSource::from_model_id(model_id)
+            }
+        };
+        self.0
+            .load_controlnet(source, silent, token, revision)
+            .map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (name, path, image_encoder, scale = 1.0, silent = false, token = None, revision = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn load_ip_adapter(
+        &self,
+        name: String,
+        path: String,
+        image_encoder: ModelSource,
+        scale: f64,
+        silent: bool,
+        token: Option<String>,
+        revision: Option<String>,
+    ) -> PyResult<()> {
+        let token = token
+            .map(diffusion_rs_core::TokenSource::Literal)
+            .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
+        let image_encoder = match image_encoder {
+            ModelSource::DdufFile { file } => {
+                diffusion_rs_core::ModelSource::dduf(file).map_err(wrap_anyhow_error)?
+            }
+            ModelSource::ModelId { model_id } => {
+                diffusion_rs_core::ModelSource::from_model_id(model_id)
+            }
+        };
+        self.0
+            .load_ip_adapter(name, path, image_encoder, scale, silent, token, revision)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn unload_ip_adapter(&self, name: String) -> PyResult<()> {
+        self.0.unload_ip_adapter(&name).map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (path, token = None))]
+    fn load_textual_inversion(&self, path: String, token: Option<String>) -> PyResult<()> {
+        self.0
+            .load_textual_inversion(path, token)
+            .map_err(wrap_anyhow_error)
+    }
+
     fn forward(
         &self,
         prompts: Vec<String>,
         params: DiffusionGenerationParams,
     ) -> PyResult<Vec<Py<PyBytes>>> {
+        let init_image = match params.init_image {
+            Some(data) => Some(diffusion_rs_core::InitImage {
+                image: image::load_from_memory(&data).map_err(|e| wrap_anyhow_error(e.into()))?,
+                strength: params.strength,
+                mask: params
+                    .mask_image
+                    .map(|data| image::load_from_memory(&data))
+                    .transpose()
+                    .map_err(|e| wrap_anyhow_error(e.into()))?,
+            }),
+            None if params.mask_image.is_some() => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "`mask_image` requires an `init_image` to inpaint",
+                ))
+            }
+            None => None,
+        };
+        let reference_image = params
+            .reference_image
+            .map(|data| image::load_from_memory(&data))
+            .transpose()
+            .map_err(|e| wrap_anyhow_error(e.into()))?;
+        let image_prompts = params
+            .image_prompts
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(data, weight)| {
+                Ok(diffusion_rs_core::ImagePrompt {
+                    image: image::load_from_memory(&data)
+                        .map_err(|e| wrap_anyhow_error(e.into()))?,
+                    weight,
+                })
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let control_images = params
+            .control_images
+            .unwrap_or_default()
+            .into_iter()
+            .map(|control| {
+                Ok(diffusion_rs_core::ControlImage {
+                    image: image::load_from_memory(&control.image)
+                        .map_err(|e| wrap_anyhow_error(e.into()))?,
+                    controlnet: control.controlnet,
+                    scale: control.scale,
+                    start: control.start,
+                    end: control.end,
+                    mode: control.mode,
+                })
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let textual_inversions = params
+            .textual_inversions
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(path, token)| diffusion_rs_core::TextualInversion::from_file(path, token))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(wrap_anyhow_error)?;
+        let control_image = params
+            .control_image
+            .map(|data| image::load_from_memory(&data))
+            .transpose()
+            .map_err(|e| wrap_anyhow_error(e.into()))?;
         let images = self
             .0
             .forward(
@@ -136,7 +451,43 @@ impl Pipeline {
                     height: params.height,
                     width: params.width,
                     num_steps: params.num_steps,
+                    sigma_spacing: params.sigma_spacing.map(|spacing| match spacing {
+                        SigmaSpacing::Linear => diffusion_rs_core::SigmaSpacing::Linear,
+                        SigmaSpacing::Karras => diffusion_rs_core::SigmaSpacing::Karras,
+                        SigmaSpacing::Exponential => diffusion_rs_core::SigmaSpacing::Exponential,
+                        SigmaSpacing::Beta => diffusion_rs_core::SigmaSpacing::Beta,
+                    }),
+                    sigmas: params.sigmas,
                     guidance_scale: params.guidance_scale,
+                    true_cfg_scale: params.true_cfg_scale,
+                    sampler: params.sampler.map(|sampler| match sampler {
+                        SamplerType::Euler => diffusion_rs_core::SamplerType::Euler,
+                        SamplerType::Heun => diffusion_rs_core::SamplerType::Heun,
+                        SamplerType::DpmPlusPlus2M => diffusion_rs_core::SamplerType::DpmPlusPlus2M,
+                        SamplerType::EulerAncestral => {
+                            diffusion_rs_core::SamplerType::EulerAncestral
+                        }
+                        SamplerType::StochasticEuler => {
+                            diffusion_rs_core::SamplerType::StochasticEuler
+                        }
+                    }),
+                    eta: params.eta,
+                    negative_prompt: params.negative_prompt,
+                    seed: params.seed,
+                    seeds: params.seeds,
+                    noise_source: match params.noise_source {
+                        NoiseSource::Native => diffusion_rs_core::NoiseSource::Native,
+                        NoiseSource::Torch => diffusion_rs_core::NoiseSource::Torch,
+                    },
+                    init_image,
+                    reference_image,
+                    image_prompts,
+                    control_images,
+                    control_image,
+                    refiner_start: params.refiner_start,
+                    loras: params.loras,
+                    ip_adapters: params.ip_adapters,
+                    textual_inversions,
                 },
             )
             .map_err(wrap_anyhow_error)?;
@@ -158,7 +509,11 @@ impl Pipeline {
 #[pymodule]
 fn diffusion_rs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<ModelSource>()?;
+    m.add_class::<NoiseSource>()?;
+    m.add_class::<SamplerType>()?;
+    m.add_class::<SigmaSpacing>()?;
     m.add_class::<DiffusionGenerationParams>()?;
+    m.add_class::<ControlImage>()?;
     m.add_class::<Pipeline>()?;
     Ok(())
 }